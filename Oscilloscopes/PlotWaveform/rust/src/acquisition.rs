@@ -0,0 +1,382 @@
+//! Waiting for acquisitions to complete.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::analysis::crossings::Edge;
+use crate::device::no_progress;
+use crate::settings::AcquisitionMode;
+use crate::waveform::pack_query;
+use crate::{check_channel, DataRange, OscilloscopeWaveform, Result, ScopeError, TransferFormat};
+
+/// Default time to wait for a trigger before giving up.
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Extra VISA timeout on top of the remaining wait, so the instrument gets
+/// to answer before the read is aborted.
+const WAIT_IO_MARGIN: Duration = Duration::from_secs(1);
+
+/// Pause between `SEQuence:WAIT?` polls that report "not complete".
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// First pause between trigger status polls of a single acquisition. It
+/// doubles after every poll up to `STATUS_POLL_MAX`, so short waits return
+/// quickly and long ones don't flood the instrument with queries.
+const STATUS_POLL_INITIAL: Duration = Duration::from_millis(10);
+const STATUS_POLL_MAX: Duration = Duration::from_millis(500);
+
+/// Failure to complete an acquisition.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CaptureError {
+    /// No trigger arrived within the configured wait timeout. The
+    /// acquisition was stopped.
+    #[error("No trigger within {:.1} seconds", .0.as_secs_f32())]
+    TriggerTimeout(Duration),
+}
+
+/// How a single acquisition ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquisitionStatus {
+    /// A trigger event completed the acquisition.
+    Triggered,
+    /// No trigger event arrived, the acquisition was completed with
+    /// [`force_trigger`](OscilloscopeWaveform::force_trigger).
+    ForceTriggered,
+    /// The acquisition is still waiting for a trigger.
+    TimedOut,
+}
+
+/// State of the trigger system, from `TRIGger:STATus?`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerStatus {
+    /// Filling the pre-trigger part of the record.
+    Armed,
+    /// Waiting for a trigger.
+    Ready,
+    /// Triggered, the record is being completed.
+    Triggered,
+    /// Acquiring without a trigger in auto mode.
+    Auto,
+    /// No acquisition running.
+    Stopped,
+}
+
+impl TriggerStatus {
+    fn parse(response: &str) -> Result<Self> {
+        match response.to_ascii_uppercase().as_str() {
+            "ARM" | "ARMED" => Ok(TriggerStatus::Armed),
+            "READ" | "READY" | "WAIT" => Ok(TriggerStatus::Ready),
+            "TD" | "TRIG" | "TRIGGERED" => Ok(TriggerStatus::Triggered),
+            "AUTO" => Ok(TriggerStatus::Auto),
+            "STOP" | "STOPPED" => Ok(TriggerStatus::Stopped),
+            _ => Err(ScopeError::UnexpectedResponse {
+                command: "TRIGger:STATus?".to_string(),
+                response: response.to_string(),
+            }),
+        }
+    }
+
+    /// True while the instrument acquires, i.e. in any state but stopped.
+    pub fn is_running(self) -> bool {
+        self != TriggerStatus::Stopped
+    }
+}
+
+/// Whether the instrument acquires without trigger events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TriggerSweep {
+    /// Acquire on trigger events, and without them after a while.
+    #[default]
+    Auto,
+    /// Acquire on trigger events only.
+    Normal,
+}
+
+impl TriggerSweep {
+    fn scpi_name(self) -> &'static str {
+        match self {
+            TriggerSweep::Auto => "AUTO",
+            TriggerSweep::Normal => "NORMal",
+        }
+    }
+}
+
+/// Edge trigger setup, applied with
+/// [`configure_trigger`](OscilloscopeWaveform::configure_trigger).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriggerConfig {
+    /// Channel the trigger watches.
+    pub source: u8,
+    pub level_v: f32,
+    /// Edge to trigger on, [`Edge::Both`] for either.
+    pub slope: Edge,
+    pub sweep: TriggerSweep,
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        Self { source: 1, level_v: 0.0, slope: Edge::Rising, sweep: TriggerSweep::Auto }
+    }
+}
+
+/// Average captures point by point.
+///
+/// Useful when the instrument runs freely and several `get_waveform_data`
+/// results are accumulated by the caller. Captures of different lengths
+/// are averaged over the length of the shortest one.
+pub fn average_waveforms(waveforms: &[Vec<f32>]) -> Vec<f32> {
+    let Some(len) = waveforms.iter().map(Vec::len).min() else {
+        return Vec::new();
+    };
+    if waveforms.iter().any(|waveform| waveform.len() != len) {
+        warn!("Captures differ in length, averaging the first {} samples", len);
+    }
+
+    let count = waveforms.len() as f64;
+    (0..len)
+        .map(|i| (waveforms.iter().map(|waveform| waveform[i] as f64).sum::<f64>() / count) as f32)
+        .collect()
+}
+
+impl OscilloscopeWaveform {
+    /// Set how long `get_waveform_data` waits for the acquisition to trigger.
+    pub fn set_wait_timeout(&mut self, timeout: Duration) {
+        self.wait_timeout = timeout;
+    }
+
+    /// Set up an edge trigger.
+    pub fn configure_trigger(&self, config: &TriggerConfig) -> Result<()> {
+        self.require_channel(config.source)?;
+        if !config.level_v.is_finite() {
+            return Err(ScopeError::InvalidArgument(format!("Invalid trigger level {} V", config.level_v)));
+        }
+        let slope = match config.slope {
+            Edge::Rising => "POSitive",
+            Edge::Falling => "NEGative",
+            Edge::Both => "EITHer",
+        };
+        self.send_command(&format!("TRIGger:EDGE:SOURce CHAN{}", config.source))?;
+        self.send_command(&format!("TRIGger:EDGE:LEVel {}", config.level_v))?;
+        self.send_command(&format!("TRIGger:EDGE:SLOPe {}", slope))?;
+        self.send_command(&format!("TRIGger:SWEep {}", config.sweep.scpi_name()))?;
+        self.verify_no_errors("trigger setup")?;
+        info!("Trigger: {:?} edge of channel {} through {} V, {:?} sweep", config.slope, config.source,
+            config.level_v, config.sweep);
+        Ok(())
+    }
+
+    /// Current state of the trigger system.
+    pub fn trigger_status(&self) -> Result<TriggerStatus> {
+        TriggerStatus::parse(&self.query("TRIGger:STATus?")?)
+    }
+
+    /// Start continuous acquisition (`RUN`) or stop it (`STOP`), e.g. to
+    /// return the instrument to the state it was found in.
+    pub fn set_running(&self, running: bool) -> Result<()> {
+        self.send_command(if running { "RUN" } else { "STOP" })?;
+        self.verify_no_errors("run control")
+    }
+
+    /// Arm a single acquisition (`SINGle`). It stops once a trigger event
+    /// has filled the record.
+    pub fn arm_single(&self) -> Result<()> {
+        self.send_command("SINGle")?;
+        self.verify_no_errors("single trigger")
+    }
+
+    /// Trigger the armed acquisition now (`TFORce`), whether or not the
+    /// trigger condition is met.
+    pub fn force_trigger(&self) -> Result<()> {
+        self.send_command("TFORce")?;
+        self.verify_no_errors("forced trigger")
+    }
+
+    /// Wait up to `timeout` for an acquisition armed with
+    /// [`arm_single`](Self::arm_single) to complete.
+    ///
+    /// The trigger status is polled, first after 10 ms and then at doubling
+    /// intervals of up to 0.5 s, so a wait for a rare event keeps the
+    /// instrument free for other queries. Returns `Triggered` once the
+    /// acquisition has stopped, or `TimedOut` with the acquisition still
+    /// armed.
+    pub fn wait_for_acquisition(&self, timeout: Duration) -> Result<AcquisitionStatus> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = STATUS_POLL_INITIAL;
+        loop {
+            if !self.trigger_status()?.is_running() {
+                info!("Acquisition complete");
+                return Ok(AcquisitionStatus::Triggered);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(AcquisitionStatus::TimedOut);
+            }
+            thread::sleep(interval.min(remaining));
+            interval = (interval * 2).min(STATUS_POLL_MAX);
+        }
+    }
+
+    /// Capture a channel from a single acquisition, e.g. to catch a rare
+    /// glitch, in RAW format.
+    ///
+    /// Waits up to `timeout` for a trigger event. With `force_after`, the
+    /// acquisition is force-triggered once that much time has passed
+    /// without one, and the status tells which of the two it was. Without
+    /// a trigger in time, the acquisition is stopped and
+    /// `CaptureError::TriggerTimeout` is returned.
+    pub fn capture_single(&self, channel: u8, timeout: Duration, force_after: Option<Duration>)
+        -> Result<(AcquisitionStatus, Vec<f32>, Vec<f32>)> {
+        self.prepare_single(channel, TransferFormat::Raw)?;
+        self.arm_single()?;
+        self.complete_single(channel, TransferFormat::Raw, timeout, force_after)
+    }
+
+    /// Enable only `channel` and set its transfer type, before a single
+    /// acquisition is armed.
+    pub(crate) fn prepare_single(&self, channel: u8, data_transfer_type: TransferFormat) -> Result<()> {
+        check_channel(channel)?;
+        self.enable_only_channels(&[channel])?;
+        self.send_command(&format!("CHAN{}:DATa:TYPE {}", channel, data_transfer_type))?;
+        self.verify_no_errors("data type configuration")
+    }
+
+    /// Wait for an armed single acquisition as described for
+    /// [`capture_single`](Self::capture_single) and read `channel`.
+    pub(crate) fn complete_single(&self, channel: u8, data_transfer_type: TransferFormat, timeout: Duration,
+        force_after: Option<Duration>) -> Result<(AcquisitionStatus, Vec<f32>, Vec<f32>)> {
+        let start = Instant::now();
+        let mut status = self.wait_for_acquisition(force_after.map_or(timeout, |force| force.min(timeout)))?;
+        if status == AcquisitionStatus::TimedOut && force_after.is_some() {
+            info!("No trigger within {:?}, forcing one", start.elapsed());
+            self.force_trigger()?;
+            status = match self.wait_for_acquisition(timeout.saturating_sub(start.elapsed()))? {
+                AcquisitionStatus::Triggered => AcquisitionStatus::ForceTriggered,
+                status => status,
+            };
+        }
+        if status == AcquisitionStatus::TimedOut {
+            warn!("No trigger within {:?}, stopping acquisition", timeout);
+            self.send_command("STOP")?;
+            return Err(CaptureError::TriggerTimeout(timeout).into());
+        }
+
+        let (time_values, waveform) = self.read_waveform(&pack_query(channel, DataRange::All, data_transfer_type),
+            data_transfer_type, None, self.channel_scaling(channel)?, &no_progress)?;
+        Ok((status, time_values, waveform))
+    }
+
+    /// Capture a channel averaged over `averages` triggers.
+    ///
+    /// The instrument is left in averaging mode afterwards. The wait timeout
+    /// applies to every trigger.
+    pub fn capture_averaged(&self, channel: u8, averages: u16, dtype: TransferFormat) -> Result<(Vec<f32>, Vec<f32>)> {
+        check_channel(channel)?;
+        let mode = self.set_acquisition_mode(AcquisitionMode::Average { count: averages })?;
+        info!("Averaging {} acquisitions", mode.sequences());
+        self.capture(channel, DataRange::All, dtype, None, mode.sequences())
+    }
+
+    /// Wait until the running acquisition has completed `sequences`
+    /// triggered sequences.
+    ///
+    /// `SEQuence:WAIT?` is polled until it reports completion. If that takes
+    /// longer than the wait timeout per sequence, the acquisition is stopped
+    /// and `CaptureError::TriggerTimeout` is returned. The VISA timeout is
+    /// raised for the wait and restored afterwards.
+    pub(crate) fn wait_for_sequence(&self, sequences: u32) -> Result<()> {
+        let timeout = self.wait_timeout.saturating_mul(sequences.max(1));
+        let previous_timeout = self.io_timeout()?;
+        let result = self.poll_sequence_wait(sequences, timeout);
+        self.set_io_timeout(previous_timeout)?;
+
+        match result {
+            Err(ScopeError::Timeout | ScopeError::Capture(_)) => {
+                warn!("No trigger within {:?}, stopping acquisition", timeout);
+                // Abort the pending query before sending anything else
+                self.device.clear()?;
+                self.send_command("STOP")?;
+                Err(CaptureError::TriggerTimeout(timeout).into())
+            }
+            result => result,
+        }
+    }
+
+    fn poll_sequence_wait(&self, sequences: u32, timeout: Duration) -> Result<()> {
+        let command = format!("SEQuence:WAIT? {}", sequences);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(CaptureError::TriggerTimeout(timeout).into());
+            }
+            self.set_io_timeout(remaining + WAIT_IO_MARGIN)?;
+
+            let response = self.query(&command)?;
+            match response.as_str() {
+                "1" => {
+                    info!("Acquisition complete");
+                    return Ok(());
+                }
+                // Not complete yet, ask again
+                "0" => thread::sleep(WAIT_POLL_INTERVAL.min(remaining)),
+                _ => {
+                    return Err(ScopeError::UnexpectedResponse { command, response })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+
+    #[test]
+    fn averages_point_by_point() {
+        let waveforms = vec![vec![1.0, 2.0, -1.0], vec![3.0, 2.0, 0.0], vec![2.0, 5.0, 1.0]];
+        assert_eq!(average_waveforms(&waveforms), [2.0, 3.0, 0.0]);
+    }
+
+    #[test]
+    fn parses_trigger_status() {
+        assert_eq!(TriggerStatus::parse("td").unwrap(), TriggerStatus::Triggered);
+        assert_eq!(TriggerStatus::parse("WAIT").unwrap(), TriggerStatus::Ready);
+        assert!(!TriggerStatus::parse("STOP").unwrap().is_running());
+        assert!(TriggerStatus::parse("AUTO").unwrap().is_running());
+        assert!(TriggerStatus::parse("RUNNING").is_err());
+    }
+
+    #[test]
+    fn captures_single_shots_with_and_without_trigger() {
+        let scope = |triggered| OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(
+            SimulationConfig { triggered, ..SimulationConfig::default() })));
+
+        let (status, time, waveform) = scope(true).capture_single(1, Duration::from_secs(1), None).unwrap();
+        assert_eq!(status, AcquisitionStatus::Triggered);
+        assert_eq!((time.len(), waveform.len()), (10_000, 10_000));
+
+        let scope = scope(false);
+        scope.arm_single().unwrap();
+        assert_eq!(scope.trigger_status().unwrap(), TriggerStatus::Ready);
+        assert_eq!(scope.wait_for_acquisition(Duration::from_millis(30)).unwrap(), AcquisitionStatus::TimedOut);
+
+        let forced = scope.capture_single(2, Duration::from_secs(1), Some(Duration::from_millis(20))).unwrap();
+        assert_eq!(forced.0, AcquisitionStatus::ForceTriggered);
+        assert_eq!(forced.2.len(), 10_000);
+
+        let error = scope.capture_single(1, Duration::from_millis(20), None).unwrap_err();
+        assert!(matches!(error, ScopeError::Capture(CaptureError::TriggerTimeout(_))), "{:?}", error);
+        assert_eq!(scope.trigger_status().unwrap(), TriggerStatus::Stopped);
+    }
+
+    #[test]
+    fn truncates_to_shortest_capture() {
+        let waveforms = vec![vec![1.0, 2.0, 3.0], vec![3.0, 4.0]];
+        assert_eq!(average_waveforms(&waveforms), [2.0, 3.0]);
+        assert!(average_waveforms(&[]).is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! Offline simulation of an oscilloscope bandwidth limit.
+
+use std::f64::consts::PI;
+
+use anyhow::{Result, anyhow};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Width of the Gaussian roll-off, the standard deviation of the Gaussian
+/// the ideal low-pass response is smoothed with, relative to the cutoff.
+const ROLL_OFF_FRACTION: f64 = 0.5;
+
+/// The ideal response is smoothed into `Φ((edge - f) / σ)`, which falls to
+/// -3 dB this many standard deviations below its edge.
+const MINUS_3DB_SIGMAS: f64 = 0.5449;
+
+/// Half length of the kernel in standard deviations of its Gaussian
+/// window, beyond which the window is below 0.04%.
+const KERNEL_HALF_WIDTH_SIGMAS: f64 = 4.0;
+
+/// Kernels up to this many taps are applied by direct convolution, longer
+/// ones with FFTs.
+const MAX_DIRECT_TAPS: usize = 64;
+
+/// Low-pass kernel with its -3 dB point at `cutoff_hz`.
+///
+/// A sinc is multiplied by a Gaussian window, which turns the brick-wall
+/// response of the sinc into a Gaussian roll-off. The sinc's edge is
+/// raised above the cutoff so the smoothed response is down 3 dB at it.
+/// The taps sum to 1 for unity gain at DC.
+fn gaussian_sinc_kernel(sample_rate_hz: f64, cutoff_hz: f64) -> Vec<f64> {
+    let sigma_hz = ROLL_OFF_FRACTION * cutoff_hz;
+    let edge_hz = (cutoff_hz + MINUS_3DB_SIGMAS * sigma_hz).min(sample_rate_hz / 2.0);
+    let sigma_samples = sample_rate_hz / (2.0 * PI * sigma_hz);
+    let half = (KERNEL_HALF_WIDTH_SIGMAS * sigma_samples).ceil() as i64;
+
+    let edge = edge_hz / sample_rate_hz;
+    let kernel: Vec<f64> = (-half..=half)
+        .map(|n| {
+            let n = n as f64;
+            let sinc = if n == 0.0 { 2.0 * edge } else { (2.0 * PI * edge * n).sin() / (PI * n) };
+            sinc * (-0.5 * (n / sigma_samples).powi(2)).exp()
+        })
+        .collect();
+    let sum: f64 = kernel.iter().sum();
+    kernel.into_iter().map(|tap| tap / sum).collect()
+}
+
+/// Full linear convolution, summed tap by tap.
+fn convolve_direct(signal: &[f64], kernel: &[f64]) -> Vec<f64> {
+    let mut output = vec![0.0; signal.len() + kernel.len() - 1];
+    for (i, &x) in signal.iter().enumerate() {
+        for (out, &tap) in output[i..].iter_mut().zip(kernel) {
+            *out += x * tap;
+        }
+    }
+    output
+}
+
+/// Full linear convolution with FFT overlap-add. Blocks of the signal are
+/// convolved with the kernel's spectrum, and their tails added to the
+/// start of the next block's result.
+fn convolve_overlap_add(signal: &[f64], kernel: &[f64]) -> Vec<f64> {
+    let fft_len = (2 * kernel.len()).next_power_of_two();
+    let block_len = fft_len - kernel.len() + 1;
+    let mut planner = FftPlanner::<f64>::new();
+    let forward = planner.plan_fft_forward(fft_len);
+    let inverse = planner.plan_fft_inverse(fft_len);
+
+    let mut kernel_spectrum: Vec<Complex<f64>> = kernel.iter().map(|&tap| Complex::new(tap, 0.0)).collect();
+    kernel_spectrum.resize(fft_len, Complex::new(0.0, 0.0));
+    forward.process(&mut kernel_spectrum);
+
+    let mut output = vec![0.0; signal.len() + kernel.len() - 1];
+    for (block_index, block) in signal.chunks(block_len).enumerate() {
+        let mut buffer: Vec<Complex<f64>> = block.iter().map(|&x| Complex::new(x, 0.0)).collect();
+        buffer.resize(fft_len, Complex::new(0.0, 0.0));
+        forward.process(&mut buffer);
+        for (value, tap) in buffer.iter_mut().zip(&kernel_spectrum) {
+            *value *= tap;
+        }
+        inverse.process(&mut buffer);
+
+        let start = block_index * block_len;
+        let len = (block.len() + kernel.len() - 1).min(output.len() - start);
+        // The inverse transform is not normalized
+        for (out, value) in output[start..start + len].iter_mut().zip(&buffer) {
+            *out += value.re / fft_len as f64;
+        }
+    }
+    output
+}
+
+/// Simulate capturing `waveform` through a bandwidth limit of `cutoff_hz`,
+/// such as the 20 MHz limit of an oscilloscope input.
+///
+/// The waveform is filtered with a windowed-sinc FIR whose response rolls
+/// off like a Gaussian and is down 3 dB at `cutoff_hz`. The filter is
+/// centred, so edges are smoothed without being delayed, and the waveform
+/// is extended with its first and last sample so both ends settle. The
+/// result has as many samples as the input.
+///
+/// `cutoff_hz` must lie below the Nyquist frequency, and the kernel may be
+/// at most half as long as the waveform. Low cutoffs need long kernels,
+/// about `1.3 * sample_rate_hz / cutoff_hz` taps.
+pub fn apply_bandwidth_limit(waveform: &[f32], sample_rate_hz: f64, cutoff_hz: f64) -> Result<Vec<f32>> {
+    if !sample_rate_hz.is_finite() || sample_rate_hz <= 0.0 {
+        return Err(anyhow!("Invalid sample rate {} Hz", sample_rate_hz));
+    }
+    if !cutoff_hz.is_finite() || cutoff_hz <= 0.0 {
+        return Err(anyhow!("Invalid cutoff frequency {} Hz", cutoff_hz));
+    }
+    if cutoff_hz >= sample_rate_hz / 2.0 {
+        return Err(anyhow!("Cutoff {} Hz must lie below the Nyquist frequency of {} Hz",
+            cutoff_hz, sample_rate_hz / 2.0));
+    }
+    let kernel = gaussian_sinc_kernel(sample_rate_hz, cutoff_hz);
+    if kernel.len() > waveform.len() / 2 {
+        return Err(anyhow!("A {} Hz bandwidth limit needs a kernel of {} taps, more than half of the {} samples",
+            cutoff_hz, kernel.len(), waveform.len()));
+    }
+
+    let half = kernel.len() / 2;
+    let first = waveform[0] as f64;
+    let last = waveform[waveform.len() - 1] as f64;
+    let extended: Vec<f64> = std::iter::repeat_n(first, half)
+        .chain(waveform.iter().map(|&v| v as f64))
+        .chain(std::iter::repeat_n(last, half))
+        .collect();
+    let filtered = if kernel.len() <= MAX_DIRECT_TAPS {
+        convolve_direct(&extended, &kernel)
+    } else {
+        convolve_overlap_add(&extended, &kernel)
+    };
+    // Full convolution of the extended signal, the input spans the middle
+    Ok(filtered[2 * half..2 * half + waveform.len()].iter().map(|&v| v as f32).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RMS of the middle half, away from the ends.
+    fn rms(samples: &[f32]) -> f32 {
+        let middle = &samples[samples.len() / 4..3 * samples.len() / 4];
+        (middle.iter().map(|v| v * v).sum::<f32>() / middle.len() as f32).sqrt()
+    }
+
+    fn sine(frequency_hz: f64, sample_rate_hz: f64, len: usize) -> Vec<f32> {
+        (0..len).map(|n| (2.0 * PI * frequency_hz * n as f64 / sample_rate_hz).sin() as f32).collect()
+    }
+
+    #[test]
+    fn attenuates_by_3_db_at_the_cutoff() {
+        let rate = 1e9;
+        for cutoff in [100e6, 20e6] {
+            let gain = |frequency| {
+                let input = sine(frequency, rate, 4000);
+                rms(&apply_bandwidth_limit(&input, rate, cutoff).unwrap()) / rms(&input)
+            };
+            assert!((gain(cutoff) - 0.5f32.sqrt()).abs() < 0.03, "{} Hz: {}", cutoff, gain(cutoff));
+            assert!(gain(cutoff / 10.0) > 0.99, "{} Hz: {}", cutoff, gain(cutoff / 10.0));
+            assert!(gain(2.5 * cutoff) < 0.01, "{} Hz: {}", cutoff, gain(2.5 * cutoff));
+        }
+
+        // DC and the ends pass unchanged
+        let filtered = apply_bandwidth_limit(&[1.5; 1000], rate, 20e6).unwrap();
+        assert_eq!(filtered.len(), 1000);
+        assert!(filtered.iter().all(|v| (v - 1.5).abs() < 1e-5));
+    }
+
+    #[test]
+    fn overlap_add_matches_direct_convolution() {
+        let signal: Vec<f64> = (0..1000).map(|n| ((n * 37) % 101) as f64 - 50.0).collect();
+        let kernel = gaussian_sinc_kernel(1e9, 5e6);
+        assert!(kernel.len() > MAX_DIRECT_TAPS);
+        let direct = convolve_direct(&signal, &kernel);
+        let fft = convolve_overlap_add(&signal, &kernel);
+        assert_eq!(direct.len(), fft.len());
+        assert!(direct.iter().zip(&fft).all(|(a, b)| (a - b).abs() < 1e-9));
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        let waveform = sine(1e6, 1e9, 1000);
+        assert!(apply_bandwidth_limit(&waveform, 1e9, 500e6).is_err());
+        assert!(apply_bandwidth_limit(&waveform, 1e9, 0.0).is_err());
+        assert!(apply_bandwidth_limit(&waveform, 0.0, 1e6).is_err());
+        // 1 MHz needs a kernel of about 1300 taps
+        assert!(apply_bandwidth_limit(&waveform, 1e9, 1e6).is_err());
+        assert!(apply_bandwidth_limit(&[], 1e9, 20e6).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Comparison of two captures of the same signal, e.g. before and after a
+//! firmware change.
+//!
+//! Unlike [`waveform_diff`], which compares samples by index, the records
+//! may differ in sample rate, length and trigger position. They are put on
+//! a common time base and aligned first.
+
+use log::info;
+
+use crate::decoders::interpolate;
+use crate::diff::{plot_diff, violation_regions, waveform_diff, WaveformDiffResult};
+use crate::{PlotOptions, WaveformRecord};
+
+/// Most time shifts tried by the alignment search. Wider searches take
+/// coarser steps.
+const MAX_SHIFT_STEPS: usize = 1_000;
+
+/// Most samples the error of a candidate shift is computed over. Longer
+/// records are sampled evenly.
+const MAX_ALIGNMENT_SAMPLES: usize = 10_000;
+
+/// How far two records may differ in a [`compare`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompareTolerance {
+    /// Largest allowed deviation of a sample in volts.
+    pub voltage: f32,
+    /// Largest trigger time offset searched for in seconds, 0 to compare
+    /// the records as captured.
+    pub max_time_shift_s: f32,
+}
+
+impl Default for CompareTolerance {
+    fn default() -> Self {
+        Self { voltage: 0.05, max_time_shift_s: 0.0 }
+    }
+}
+
+/// A stretch of time over which the records differ by more than the
+/// tolerance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviationRegion {
+    pub start_s: f32,
+    pub end_s: f32,
+    /// Largest deviation within the region.
+    pub max_deviation: f32,
+}
+
+/// Outcome of [`compare`], with both records on the common time base.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareReport {
+    /// Delay of the actual record relative to the reference that was
+    /// removed before comparing, within the searched range.
+    pub time_shift_s: f32,
+    /// Common time base, on the reference's time axis.
+    pub time: Vec<f32>,
+    pub reference: Vec<f32>,
+    /// The actual record, interpolated onto `time` and aligned.
+    pub actual: Vec<f32>,
+    /// Actual minus reference at every point of `time`.
+    pub error: Vec<f32>,
+    /// Maximum and RMS deviation, and the points outside the tolerance.
+    pub diff: WaveformDiffResult,
+    pub regions: Vec<DeviationRegion>,
+}
+
+impl CompareReport {
+    /// True if the records overlap and every point is within the
+    /// tolerance.
+    pub fn pass(&self) -> bool {
+        !self.time.is_empty() && self.diff.pass
+    }
+
+    /// Plot both records with the regions outside the tolerance shaded.
+    pub fn plot(&self, options: &PlotOptions) -> anyhow::Result<()> {
+        plot_diff(&self.time, &self.reference, &self.actual, &self.diff, options)
+    }
+}
+
+/// Indices of `grid` at which `shift + grid[i]` lies within `span`.
+fn overlap(grid: &[f32], span: (f32, f32), shift: f32) -> std::ops::Range<usize> {
+    let start = grid.partition_point(|&t| t + shift < span.0);
+    let end = grid.partition_point(|&t| t + shift <= span.1);
+    start..end.max(start)
+}
+
+/// Mean squared difference of the reference and the actual record delayed
+/// by `shift`, over their overlap.
+fn alignment_error(time: &[f32], reference: &[f32], actual: (&[f32], &[f32]), shift: f32) -> Option<f64> {
+    let span = (*actual.0.first()?, *actual.0.last()?);
+    let range = overlap(time, span, shift);
+    if range.is_empty() {
+        return None;
+    }
+    let stride = range.len().div_ceil(MAX_ALIGNMENT_SAMPLES);
+    let indices: Vec<usize> = range.step_by(stride).collect();
+    let shifted: Vec<f32> = indices.iter().map(|&i| time[i] + shift).collect();
+    let values = interpolate(actual.0, actual.1, &shifted);
+    let sum: f64 = indices.iter().zip(&values)
+        .map(|(&i, &value)| (value as f64 - reference[i] as f64).powi(2))
+        .sum();
+    Some(sum / indices.len() as f64)
+}
+
+/// The shift within `max_shift` that minimizes the alignment error, in
+/// steps of the grid's sample interval refined with a parabola through the
+/// best step and its neighbours.
+fn best_shift(time: &[f32], reference: &[f32], actual: (&[f32], &[f32]), max_shift: f32) -> f32 {
+    let step = if time.len() > 1 { (time[time.len() - 1] - time[0]) / (time.len() - 1) as f32 } else { 0.0 };
+    if max_shift <= 0.0 || step <= 0.0 {
+        return 0.0;
+    }
+    let steps = ((max_shift / step).ceil() as usize).min(MAX_SHIFT_STEPS / 2);
+    let step = max_shift / steps.max(1) as f32;
+    let errors: Vec<Option<f64>> = (0..=2 * steps)
+        .map(|k| alignment_error(time, reference, actual, (k as f32 - steps as f32) * step))
+        .collect();
+    let Some(best) = (0..errors.len()).filter(|&k| errors[k].is_some())
+        .min_by(|&a, &b| errors[a].unwrap().total_cmp(&errors[b].unwrap())) else {
+        return 0.0;
+    };
+
+    let mut offset = 0.0;
+    if let (Some(Some(before)), Some(Some(after))) = (best.checked_sub(1).map(|k| errors[k]), errors.get(best + 1)) {
+        let at = errors[best].unwrap();
+        let curvature = before - 2.0 * at + after;
+        if curvature > 0.0 {
+            offset = (0.5 * (before - after) / curvature).clamp(-0.5, 0.5);
+        }
+    }
+    ((best as f64 - steps as f64 + offset) * step as f64) as f32
+}
+
+/// Compare `actual` with the `reference` record.
+///
+/// The record with fewer samples is linearly interpolated onto the time
+/// base of the other, so captures at different sample rates can be
+/// compared. Within `tolerance.max_time_shift_s` the delay of `actual`
+/// that best matches the reference is searched for and removed, which
+/// absorbs small differences of the trigger position. Only the time both
+/// records cover after the shift is compared.
+pub fn compare(reference: &WaveformRecord, actual: &WaveformRecord, tolerance: CompareTolerance) -> CompareReport {
+    let reference: (Vec<f32>, Vec<f32>) = (reference.time_values().collect(), reference.voltages().collect());
+    let actual: (Vec<f32>, Vec<f32>) = (actual.time_values().collect(), actual.voltages().collect());
+    compare_traces((&reference.0, &reference.1), (&actual.0, &actual.1), tolerance)
+}
+
+/// [`compare`] for `(time, voltage)` traces, e.g. a capture imported with
+/// [`import_json`](crate::export::import_json). The times must increase.
+pub fn compare_traces<'a>(reference: (&'a [f32], &'a [f32]), actual: (&'a [f32], &'a [f32]),
+    tolerance: CompareTolerance)
+    -> CompareReport {
+    let trim = |(time, values): (&'a [f32], &'a [f32])| {
+        let len = time.len().min(values.len());
+        (&time[..len], &values[..len])
+    };
+    let (reference_time, reference_values) = trim(reference);
+    let (actual_time, actual_values) = trim(actual);
+
+    let shift = best_shift(reference_time, reference_values, (actual_time, actual_values), tolerance.max_time_shift_s);
+    // Compare on the finer time base, measured in reference time
+    let time: Vec<f32> = if actual_time.len() > reference_time.len() {
+        actual_time.iter().map(|&t| t - shift).collect()
+    } else {
+        reference_time.to_vec()
+    };
+    let span = match (actual_time.first(), actual_time.last(), reference_time.first(), reference_time.last()) {
+        (Some(&a0), Some(&a1), Some(&r0), Some(&r1)) => ((a0 - shift).max(r0), (a1 - shift).min(r1)),
+        _ => (0.0, -1.0),
+    };
+    let time = time[overlap(&time, span, 0.0)].to_vec();
+
+    let reference = interpolate(reference_time, reference_values, &time);
+    let shifted: Vec<f32> = time.iter().map(|&t| t + shift).collect();
+    let actual = interpolate(actual_time, actual_values, &shifted);
+    let error: Vec<f32> = reference.iter().zip(&actual).map(|(expected, value)| value - expected).collect();
+    let diff = waveform_diff(&reference, &actual, tolerance.voltage);
+    let regions = violation_regions(&diff.violation_indices).into_iter()
+        .map(|region| DeviationRegion {
+            start_s: time[region.start],
+            end_s: time[region.end - 1],
+            max_deviation: error[region].iter().fold(0.0f32, |max, e| max.max(e.abs())),
+        })
+        .collect();
+
+    info!("Compared {} points with a time shift of {:e} s", time.len(), shift);
+    CompareReport { time_shift_s: shift, time, reference, actual, error, diff, regions }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ChannelScaling;
+    use crate::WaveformMetadata;
+
+    /// A record of a 1 kHz sine with `count` samples over 2 ms, starting
+    /// at `start_time`.
+    fn record(count: u32, start_time: f32) -> WaveformRecord {
+        let time_delta = 2e-3 / count as f32;
+        let metadata = WaveformMetadata {
+            time_delta,
+            start_time,
+            end_time: start_time + (count - 1) as f32 * time_delta,
+            sample_start: 0,
+            sample_length: count,
+            vertical_start: -2.0,
+            vertical_step: 4.0,
+            sample_count: count,
+        };
+        let raw_codes = (0..count)
+            .map(|i| {
+                let t = start_time + i as f32 * time_delta;
+                ((2.0 * std::f32::consts::PI * 1e3 * t).sin() / 4.0 * 65536.0 + 32768.0) as u16
+            })
+            .collect();
+        WaveformRecord { metadata, raw_codes, scaling: ChannelScaling::default() }
+    }
+
+    #[test]
+    fn compares_records_at_different_sample_rates() {
+        let report = compare(&record(2000, 0.0), &record(500, 0.0), CompareTolerance::default());
+        assert!(report.pass(), "{:?}", report.regions);
+        assert_eq!(report.time_shift_s, 0.0);
+        assert_eq!(report.time.len(), 2000 - 3);
+        assert!(report.diff.max_deviation < 0.01);
+        assert!(report.diff.rms_deviation < report.diff.max_deviation);
+        assert!(report.regions.is_empty());
+    }
+
+    #[test]
+    fn aligns_trigger_offsets_before_diffing() {
+        // The same sine captured with the trigger 20 us later
+        let reference = record(2000, 0.0);
+        let mut actual = record(2000, -20e-6);
+        actual.metadata.start_time = 0.0;
+
+        let unaligned = compare(&reference, &actual, CompareTolerance::default());
+        assert!(!unaligned.pass());
+        let tolerance = CompareTolerance { max_time_shift_s: 50e-6, ..CompareTolerance::default() };
+        let aligned = compare(&reference, &actual, tolerance);
+        assert!(aligned.pass(), "{:?}", aligned.regions);
+        assert!((aligned.time_shift_s - 20e-6).abs() < 1e-6, "{}", aligned.time_shift_s);
+    }
+
+    #[test]
+    fn reports_regions_beyond_the_tolerance() {
+        let reference = record(1000, 0.0);
+        let mut actual = reference.clone();
+        actual.raw_codes[300..320].iter_mut().for_each(|code| *code = code.saturating_add(8192));
+        let report = compare(&reference, &actual, CompareTolerance::default());
+        assert!(!report.pass());
+        assert_eq!(report.regions.len(), 1);
+        let region = report.regions[0];
+        assert_eq!((region.start_s, region.end_s), (report.time[300], report.time[319]));
+        assert!((region.max_deviation - 0.5).abs() < 0.01);
+        assert!((report.error[310] - 0.5).abs() < 0.01);
+
+        let disjoint = compare_traces((&[0.0, 1.0], &[0.0, 0.0]), (&[2.0, 3.0], &[0.0, 0.0]),
+            CompareTolerance::default());
+        assert!(disjoint.time.is_empty());
+        assert!(!disjoint.pass());
+    }
+}
@@ -0,0 +1,236 @@
+//! Cross-correlation of waveforms for delay measurements.
+
+use log::{info, warn};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::{OscilloscopeWaveform, ScopeError, TransferFormat};
+
+/// Normalized correlation below which [`channel_skew`] reports no delay.
+pub const MIN_SKEW_CORRELATION: f32 = 0.5;
+
+/// Time offset between two channels found by [`channel_skew`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkewResult {
+    /// Delay of B relative to A in seconds, positive if B lags. `None` if
+    /// the correlation is below [`MIN_SKEW_CORRELATION`], as the peak of
+    /// unrelated signals gives no meaningful delay.
+    pub delay_s: Option<f64>,
+    /// Normalized correlation at the peak, 1 for identical shapes.
+    pub correlation: f32,
+}
+
+/// Circular cross-correlation of two signals, computed with FFTs.
+///
+/// Element `k` is the sum of `a[n] * b[(n + k) mod N]`, so a peak at `k`
+/// means `b` follows `a` by `k` samples. `N` is the length of the longer
+/// signal, and the shorter one is padded with zeros. Because the
+/// correlation wraps around, lags above `N / 2` stand for negative lags,
+/// see [`find_lag_samples`].
+pub fn cross_correlate(signal_a: &[f32], signal_b: &[f32]) -> Vec<f32> {
+    let len = signal_a.len().max(signal_b.len());
+    if len == 0 {
+        return Vec::new();
+    }
+
+    let mut planner = FftPlanner::<f64>::new();
+    let forward = planner.plan_fft_forward(len);
+    let inverse = planner.plan_fft_inverse(len);
+    let spectrum = |signal: &[f32]| {
+        let mut buffer: Vec<Complex<f64>> = signal.iter().map(|&v| Complex::new(v as f64, 0.0)).collect();
+        buffer.resize(len, Complex::new(0.0, 0.0));
+        forward.process(&mut buffer);
+        buffer
+    };
+
+    let spectrum_b = spectrum(signal_b);
+    let mut product = spectrum(signal_a);
+    for (a, b) in product.iter_mut().zip(&spectrum_b) {
+        *a = a.conj() * b;
+    }
+    inverse.process(&mut product);
+    // The inverse transform is not normalized
+    product.iter().map(|value| (value.re / len as f64) as f32).collect()
+}
+
+/// Signed lag of the correlation peak in samples.
+///
+/// Indices in the upper half of `corr` wrap around to negative lags.
+/// Returns 0 for an empty correlation.
+pub fn find_lag_samples(corr: &[f32]) -> i64 {
+    let Some(peak) = (0..corr.len()).max_by(|&a, &b| corr[a].total_cmp(&corr[b])) else {
+        return 0;
+    };
+    if peak > corr.len() / 2 {
+        peak as i64 - corr.len() as i64
+    } else {
+        peak as i64
+    }
+}
+
+/// Fractional offset of a peak from three samples around it, from a
+/// parabola through them. 0 if the samples do not form a peak.
+fn parabolic_offset(before: f32, peak: f32, after: f32) -> f64 {
+    let (before, peak, after) = (before as f64, peak as f64, after as f64);
+    let curvature = before - 2.0 * peak + after;
+    if curvature >= 0.0 {
+        return 0.0;
+    }
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
+/// Skew of waveform `b` relative to waveform `a`, both sampled every
+/// `time_delta` seconds.
+///
+/// The mean-free signals are zero-padded to twice their length, so the
+/// correlation does not wrap around, and normalized by their energies. The
+/// peak lag is refined below one sample with a parabolic fit. Inputs of
+/// different length are truncated to the shorter one.
+pub fn channel_skew(time_delta: f32, a: &[f32], b: &[f32]) -> SkewResult {
+    let len = a.len().min(b.len());
+    if a.len() != b.len() {
+        warn!("Waveforms have {} and {} samples, using the first {}", a.len(), b.len(), len);
+    }
+    let mean_free = |signal: &[f32]| {
+        let mean = signal[..len].iter().map(|&v| v as f64).sum::<f64>() / len.max(1) as f64;
+        let mut samples: Vec<f32> = signal[..len].iter().map(|&v| (v as f64 - mean) as f32).collect();
+        samples.resize(2 * len, 0.0);
+        samples
+    };
+    let (a, b) = (mean_free(a), mean_free(b));
+    let energy = |signal: &[f32]| signal.iter().map(|&v| v as f64 * v as f64).sum::<f64>();
+    let norm = (energy(&a) * energy(&b)).sqrt();
+    if norm <= f64::EPSILON {
+        return SkewResult { delay_s: None, correlation: 0.0 };
+    }
+
+    let corr = cross_correlate(&a, &b);
+    let lag = find_lag_samples(&corr);
+    let peak = lag.rem_euclid(corr.len() as i64) as usize;
+    let before = corr[(peak + corr.len() - 1) % corr.len()];
+    let after = corr[(peak + 1) % corr.len()];
+    let lag = lag as f64 + parabolic_offset(before, corr[peak], after);
+    let correlation = (corr[peak] as f64 / norm) as f32;
+
+    if correlation < MIN_SKEW_CORRELATION {
+        warn!("Channels correlate by only {:.2}, no reliable skew", correlation);
+        return SkewResult { delay_s: None, correlation };
+    }
+    SkewResult { delay_s: Some(lag * time_delta as f64), correlation }
+}
+
+impl OscilloscopeWaveform {
+    /// Skew of `channel_b` relative to `channel_a`, captured from the same
+    /// trigger with data type `dtype`, see [`channel_skew`].
+    pub fn measure_channel_skew(&self, channel_a: u8, channel_b: u8, dtype: TransferFormat)
+        -> Result<SkewResult, ScopeError> {
+        let captures = self.capture_channels(&[channel_a, channel_b], dtype, None)?;
+        let skew = channel_skew(captures[0].0.time_delta, &captures[0].1, &captures[1].1);
+        match skew.delay_s {
+            Some(delay) => info!("CH{} lags CH{} by {:e} s, correlation {:.3}", channel_b, channel_a, delay, skew.correlation),
+            None => info!("No reliable skew between CH{} and CH{}", channel_a, channel_b),
+        }
+        Ok(skew)
+    }
+
+    /// Delay of `channel_b` relative to `channel_a` in seconds, e.g. the
+    /// propagation delay between a clock and a data line.
+    ///
+    /// Both channels are captured from the same trigger with data type
+    /// `dtype` and cross-correlated. The result is positive if B lags A,
+    /// and resolves one sample interval. Delays beyond half the record
+    /// length wrap around, and periodic signals are only unambiguous
+    /// within half a period.
+    pub fn measure_channel_delay(&self, channel_a: u8, channel_b: u8, dtype: TransferFormat)
+        -> Result<f64, ScopeError> {
+        let mut captures = self.capture_channels(&[channel_a, channel_b], dtype, None)?;
+        let (_, mut waveform_b) = captures.pop().expect("two channels captured");
+        let (metadata, mut waveform_a) = captures.pop().expect("two channels captured");
+        if waveform_a.len() != waveform_b.len() {
+            let len = waveform_a.len().min(waveform_b.len());
+            warn!("Channels have {} and {} samples, using the first {}", waveform_a.len(), waveform_b.len(), len);
+            waveform_a.truncate(len);
+            waveform_b.truncate(len);
+        }
+        if waveform_a.is_empty() {
+            return Err(ScopeError::InvalidArgument("No samples to correlate".to_string()));
+        }
+
+        let lag = find_lag_samples(&cross_correlate(&waveform_a, &waveform_b));
+        let delay = lag as f64 * metadata.time_delta as f64;
+        info!("CH{} lags CH{} by {} samples, {:e} s", channel_b, channel_a, lag, delay);
+        Ok(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulse(len: usize, start: usize) -> Vec<f32> {
+        (0..len).map(|n| if (start..start + 5).contains(&n) { 1.0 } else { 0.0 }).collect()
+    }
+
+    #[test]
+    fn matches_direct_circular_correlation() {
+        let a = [1.0, 2.0, -1.0, 0.5, 3.0];
+        let b = [0.0, 1.0, 1.0, -2.0, 0.25];
+        let corr = cross_correlate(&a, &b);
+        for (k, &value) in corr.iter().enumerate() {
+            let direct: f32 = (0..a.len()).map(|n| a[n] * b[(n + k) % a.len()]).sum();
+            assert!((value - direct).abs() < 1e-5, "lag {}: {} vs {}", k, value, direct);
+        }
+    }
+
+    #[test]
+    fn finds_positive_and_negative_lags() {
+        let a = pulse(100, 20);
+        assert_eq!(find_lag_samples(&cross_correlate(&a, &pulse(100, 27))), 7);
+        assert_eq!(find_lag_samples(&cross_correlate(&a, &pulse(100, 11))), -9);
+        assert_eq!(find_lag_samples(&cross_correlate(&a, &a)), 0);
+    }
+
+    #[test]
+    fn handles_empty_and_unequal_signals() {
+        assert!(cross_correlate(&[], &[]).is_empty());
+        assert_eq!(find_lag_samples(&[]), 0);
+        // The shorter signal is zero-padded
+        let corr = cross_correlate(&pulse(64, 10), &pulse(40, 13));
+        assert_eq!(corr.len(), 64);
+        assert_eq!(find_lag_samples(&corr), 3);
+    }
+
+    fn gaussian(len: usize, center: f32) -> Vec<f32> {
+        (0..len).map(|n| (-((n as f32 - center) / 5.0).powi(2)).exp()).collect()
+    }
+
+    #[test]
+    fn measures_sub_sample_skew() {
+        let a = gaussian(200, 80.0);
+        let skew = channel_skew(1e-9, &a, &gaussian(200, 83.4));
+        let delay = skew.delay_s.unwrap();
+        assert!((delay - 3.4e-9).abs() < 0.1e-9, "delay {:e}", delay);
+        assert!(skew.correlation > 0.9);
+
+        let skew = channel_skew(1e-9, &a, &gaussian(150, 71.75));
+        assert!((skew.delay_s.unwrap() + 8.25e-9).abs() < 0.1e-9);
+    }
+
+    #[test]
+    fn reports_low_confidence_without_correlation() {
+        // Two independent pseudo-random sequences
+        let noise = |mut state: u32| -> Vec<f32> {
+            (0..1000).map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            }).collect()
+        };
+        let skew = channel_skew(1e-9, &noise(1), &noise(2));
+        assert_eq!(skew.delay_s, None);
+        assert!(skew.correlation < MIN_SKEW_CORRELATION);
+
+        let flat = channel_skew(1e-9, &[1.0; 100], &gaussian(100, 50.0));
+        assert_eq!(flat, SkewResult { delay_s: None, correlation: 0.0 });
+        assert_eq!(channel_skew(1e-9, &[], &[]).delay_s, None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Threshold crossings with interpolated timestamps.
+
+use std::str::FromStr;
+
+/// Direction of a threshold crossing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    /// Rising and falling, only used to select crossings.
+    Both,
+}
+
+impl FromStr for Edge {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "rising" | "rise" => Ok(Edge::Rising),
+            "falling" | "fall" => Ok(Edge::Falling),
+            "both" | "any" => Ok(Edge::Both),
+            _ => Err(format!("Unknown edge '{}', expected rising, falling or both", value)),
+        }
+    }
+}
+
+/// A crossing of the threshold level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crossing {
+    /// First sample at or past the level.
+    pub index: usize,
+    /// Time of the crossing, interpolated between the bracketing samples.
+    pub time_s: f32,
+    /// `Rising` or `Falling`.
+    pub edge: Edge,
+}
+
+/// Crossings of `level` in the direction `direction`, in time order.
+///
+/// Same as [`find_crossings_with_hysteresis`] without a dead band.
+pub fn find_crossings(time: &[f32], samples: &[f32], level: f32, direction: Edge) -> Vec<Crossing> {
+    find_crossings_with_hysteresis(time, samples, level, 0.0, direction)
+}
+
+/// Crossings of `level` in the direction `direction`, in time order,
+/// ignoring noise within a dead band of `hysteresis` volts centred on the
+/// level.
+///
+/// The signal has to leave the band on one side and then on the other to
+/// count as one crossing, so noise riding on a slow edge is reported once.
+/// The crossing is timed where the signal first passed the level on the
+/// way through the band, interpolated linearly between the two samples
+/// around it.
+pub fn find_crossings_with_hysteresis(time: &[f32], samples: &[f32], level: f32, hysteresis: f32,
+    direction: Edge) -> Vec<Crossing> {
+    let len = time.len().min(samples.len());
+    let upper = level + hysteresis.abs() / 2.0;
+    let lower = level - hysteresis.abs() / 2.0;
+    let interpolate = |j: usize| {
+        let fraction = (level - samples[j - 1]) / (samples[j] - samples[j - 1]);
+        time[j - 1] + fraction * (time[j] - time[j - 1])
+    };
+
+    let mut crossings = Vec::new();
+    // Side of the band the signal was last seen on, true for above, and
+    // the last sample seen there
+    let mut state: Option<bool> = None;
+    let mut last_outside = 0;
+    for i in 0..len {
+        let side = if samples[i] >= upper {
+            Some(true)
+        } else if samples[i] < lower {
+            Some(false)
+        } else {
+            None
+        };
+        let Some(high) = side else { continue };
+
+        if state == Some(!high) {
+            let edge = if high { Edge::Rising } else { Edge::Falling };
+            if direction == Edge::Both || direction == edge {
+                // The signal was on the other side at last_outside, so the
+                // level was passed in between
+                let passed = |j: &usize| if high {
+                    samples[j - 1] < level && samples[*j] >= level
+                } else {
+                    samples[j - 1] >= level && samples[*j] < level
+                };
+                if let Some(j) = (last_outside + 1..=i).find(passed) {
+                    crossings.push(Crossing { index: j, time_s: interpolate(j), edge });
+                }
+            }
+        }
+        state = Some(high);
+        last_outside = i;
+    }
+    crossings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_crossing_times() {
+        let time = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let samples = [0.0, 1.0, 4.0, 4.0, 2.0, 0.0];
+        let rising = find_crossings(&time, &samples, 3.0, Edge::Rising);
+        assert_eq!(rising, [Crossing { index: 2, time_s: 1.0 + 2.0 / 3.0, edge: Edge::Rising }]);
+
+        let falling = find_crossings(&time, &samples, 3.0, Edge::Falling);
+        assert_eq!(falling, [Crossing { index: 4, time_s: 3.5, edge: Edge::Falling }]);
+
+        let both = find_crossings(&time, &samples, 3.0, Edge::Both);
+        assert_eq!(both, [rising[0], falling[0]]);
+        assert!(find_crossings(&time, &samples, 5.0, Edge::Both).is_empty());
+    }
+
+    #[test]
+    fn hysteresis_suppresses_noise_on_slow_edges() {
+        // A slow ramp from 0 to 2 V with ±0.05 V of alternating noise
+        let time: Vec<f32> = (0..200).map(|n| n as f32 * 1e-3).collect();
+        let samples: Vec<f32> = (0..200)
+            .map(|n| n as f32 / 100.0 + if n % 2 == 0 { 0.05 } else { -0.05 })
+            .collect();
+
+        let noisy = find_crossings(&time, &samples, 1.0, Edge::Both);
+        assert!(noisy.len() > 1, "{} crossings", noisy.len());
+
+        let clean = find_crossings_with_hysteresis(&time, &samples, 1.0, 0.2, Edge::Both);
+        assert_eq!(clean.len(), 1);
+        assert_eq!(clean[0].edge, Edge::Rising);
+        assert!((clean[0].time_s - 0.1).abs() < 0.01, "crossing at {}", clean[0].time_s);
+    }
+
+    #[test]
+    fn parses_edges() {
+        assert_eq!("Rising".parse::<Edge>(), Ok(Edge::Rising));
+        assert_eq!(" falling".parse::<Edge>(), Ok(Edge::Falling));
+        assert_eq!("both".parse::<Edge>(), Ok(Edge::Both));
+        assert!("up".parse::<Edge>().is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! Eye diagrams of serial data signals.
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+
+const PHASE_BINS_PER_UI: usize = 100;
+const VOLTAGE_BINS: usize = 200;
+
+/// Density of waveform samples folded onto a window of unit intervals.
+#[derive(Debug, Clone)]
+pub struct EyeDiagram {
+    /// Hit counts indexed as `density[voltage_bin][phase_bin]`.
+    pub density: Vec<Vec<u32>>,
+    /// Voltage covered by the first and past the last voltage bin.
+    pub voltage_range: (f32, f32),
+    /// Length of one unit interval in seconds.
+    pub unit_interval: f64,
+    /// Number of unit intervals along the phase axis.
+    pub ui_count: u32,
+    /// Number of slices folded into the grid.
+    pub slice_count: usize,
+}
+
+impl EyeDiagram {
+    fn voltage_step(&self) -> f32 {
+        (self.voltage_range.1 - self.voltage_range.0) / VOLTAGE_BINS as f32
+    }
+
+    fn voltage_of_bin(&self, bin: usize) -> f32 {
+        self.voltage_range.0 + (bin as f32 + 0.5) * self.voltage_step()
+    }
+
+    fn threshold_bin(&self) -> usize {
+        VOLTAGE_BINS / 2
+    }
+
+    /// Vertical opening at the center of the first unit interval in volts.
+    ///
+    /// This is the gap between the lowest hit above the decision threshold
+    /// and the highest hit below it, or zero if the eye is closed.
+    pub fn eye_height(&self) -> f32 {
+        let column = PHASE_BINS_PER_UI / 2;
+        let threshold = self.threshold_bin();
+        let lowest_high = (threshold..VOLTAGE_BINS).find(|&v| self.density[v][column] > 0);
+        let highest_low = (0..threshold).rev().find(|&v| self.density[v][column] > 0);
+
+        match (lowest_high, highest_low) {
+            (Some(high), Some(low)) if high > low + 1 => {
+                self.voltage_of_bin(high) - self.voltage_of_bin(low) - self.voltage_step()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Horizontal opening at the decision threshold as a fraction of one
+    /// unit interval.
+    pub fn eye_width_fraction(&self) -> f32 {
+        let row = &self.density[self.threshold_bin()];
+        let center = PHASE_BINS_PER_UI / 2;
+        if row[center] > 0 {
+            return 0.0;
+        }
+        let right = (center..PHASE_BINS_PER_UI).find(|&p| row[p] > 0).unwrap_or(PHASE_BINS_PER_UI);
+        let left = (0..center).rev().find(|&p| row[p] > 0).map(|p| p + 1).unwrap_or(0);
+        (right - left) as f32 / PHASE_BINS_PER_UI as f32
+    }
+}
+
+/// Linearly interpolated times at which `waveform` crosses `level`.
+fn level_crossings(time_values: &[f32], waveform: &[f32], level: f32) -> Vec<f64> {
+    time_values.windows(2)
+        .zip(waveform.windows(2))
+        .filter(|(_, v)| (v[0] < level) != (v[1] < level))
+        .map(|(t, v)| {
+            let fraction = (level - v[0]) / (v[1] - v[0]);
+            t[0] as f64 + fraction as f64 * (t[1] - t[0]) as f64
+        })
+        .collect()
+}
+
+/// Fold a serial data waveform onto `ui_count` unit intervals.
+///
+/// Each slice starts at a crossing of the mid level between the waveform's
+/// extremes, so transitions line up at the unit interval boundaries and the
+/// eye opening sits in the middle of each unit interval.
+pub fn build_eye_diagram(time_values: &[f32], waveform: &[f32], bit_rate_hz: f64, ui_count: u32)
+    -> Result<EyeDiagram> {
+    if time_values.len() != waveform.len() {
+        return Err(anyhow!(
+            "Time and waveform lengths differ ({} vs {})", time_values.len(), waveform.len()
+        ));
+    }
+    if !bit_rate_hz.is_finite() || bit_rate_hz <= 0.0 {
+        return Err(anyhow!("Bit rate must be positive, got {}", bit_rate_hz));
+    }
+    if ui_count == 0 {
+        return Err(anyhow!("Eye diagram needs at least one unit interval"));
+    }
+    if waveform.len() < 2 {
+        return Err(anyhow!("Waveform too short for an eye diagram"));
+    }
+
+    let unit_interval = 1.0 / bit_rate_hz;
+    let sample_interval = (time_values[1] - time_values[0]) as f64;
+    if sample_interval >= unit_interval {
+        return Err(anyhow!(
+            "Sample interval {} s is not shorter than the unit interval {} s",
+            sample_interval, unit_interval
+        ));
+    }
+
+    let min = waveform.iter().fold(f32::INFINITY, |a, &b| a.min(b));
+    let max = waveform.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    if max <= min {
+        return Err(anyhow!("Waveform has no transitions"));
+    }
+    let crossings = level_crossings(time_values, waveform, (min + max) / 2.0);
+
+    // Pad the voltage axis so the rails don't sit on the plot edge
+    let padding = (max - min) * 0.1;
+    let voltage_range = (min - padding, max + padding);
+    let voltage_scale = VOLTAGE_BINS as f32 / (voltage_range.1 - voltage_range.0);
+
+    let phase_bins = PHASE_BINS_PER_UI * ui_count as usize;
+    let slice_length = unit_interval * ui_count as f64;
+    let mut density = vec![vec![0u32; phase_bins]; VOLTAGE_BINS];
+    let mut slice_count = 0;
+    let mut sample = 0;
+    let mut slice_end = f64::NEG_INFINITY;
+
+    for &slice_start in &crossings {
+        // Slices must not overlap, otherwise samples get counted twice
+        if slice_start < slice_end {
+            continue;
+        }
+        slice_end = slice_start + slice_length;
+        if slice_end > *time_values.last().unwrap() as f64 {
+            break;
+        }
+
+        while sample < time_values.len() && (time_values[sample] as f64) < slice_start {
+            sample += 1;
+        }
+        while sample < time_values.len() && (time_values[sample] as f64) < slice_end {
+            let phase = (time_values[sample] as f64 - slice_start) / slice_length;
+            let phase_bin = ((phase * phase_bins as f64) as usize).min(phase_bins - 1);
+            let voltage_bin = (((waveform[sample] - voltage_range.0) * voltage_scale) as usize)
+                .min(VOLTAGE_BINS - 1);
+            density[voltage_bin][phase_bin] += 1;
+            sample += 1;
+        }
+        slice_count += 1;
+    }
+
+    if slice_count == 0 {
+        return Err(anyhow!("Waveform does not contain a complete slice of {} unit intervals", ui_count));
+    }
+
+    info!("Eye diagram built from {} slices", slice_count);
+    Ok(EyeDiagram {
+        density,
+        voltage_range,
+        unit_interval,
+        ui_count,
+        slice_count,
+    })
+}
+
+/// Render the eye diagram density as a heat map PNG.
+pub fn plot_eye_diagram(eye: &EyeDiagram, path: &str) -> Result<()> {
+    let max_hits = eye.density.iter().flatten().copied().max().unwrap_or(0);
+    if max_hits == 0 {
+        return Err(anyhow!("Eye diagram is empty"));
+    }
+
+    info!("Creating eye diagram plot");
+    let root = BitMapBackend::new(path, (1200, 600)).into_drawing_area();
+    root.fill(&BLACK)?;
+
+    let (low, high) = eye.voltage_range;
+    let mut chart = ChartBuilder::on(&root)
+        .caption("Eye Diagram", ("sans-serif", 40).into_font().color(&WHITE))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(0f32..eye.ui_count as f32, low..high)?;
+
+    chart
+        .configure_mesh()
+        .disable_mesh()
+        .axis_style(WHITE)
+        .label_style(("sans-serif", 15).into_font().color(&WHITE))
+        .x_desc("Unit Interval")
+        .y_desc("Voltage (V)")
+        .draw()?;
+
+    let phase_step = 1.0 / PHASE_BINS_PER_UI as f32;
+    let voltage_step = eye.voltage_step();
+    let log_max = (max_hits as f64).ln_1p();
+    chart.draw_series(eye.density.iter().enumerate().flat_map(|(v, row)| {
+        row.iter().enumerate().filter(|(_, &hits)| hits > 0).map(move |(p, &hits)| {
+            // Logarithmic scale keeps rarely visited cells visible
+            let intensity = (hits as f64).ln_1p() / log_max;
+            let color = HSLColor(0.66 * (1.0 - intensity), 1.0, 0.5);
+            let x = p as f32 * phase_step;
+            let y = low + v as f32 * voltage_step;
+            Rectangle::new([(x, y), (x + phase_step, y + voltage_step)], color.filled())
+        })
+    }))?;
+
+    root.present()?;
+    info!("Eye diagram saved as {}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_BIT: usize = 200;
+    const SAMPLE_INTERVAL: f32 = 1e-10;
+    const BIT_RATE_HZ: f64 = 1.0 / (SAMPLES_PER_BIT as f64 * SAMPLE_INTERVAL as f64);
+    const BITS: usize = 300;
+
+    /// Pseudo-random bits from the PRBS7 polynomial x^7 + x^6 + 1.
+    fn prbs7(count: usize) -> Vec<bool> {
+        let mut state = 0x7Fu8;
+        (0..count).map(|_| {
+            let bit = (state >> 6 ^ state >> 5) & 1;
+            state = (state << 1 | bit) & 0x7F;
+            bit == 1
+        }).collect()
+    }
+
+    /// Uniform values between -1 and 1 from a fixed seed.
+    fn noise_source() -> impl FnMut() -> f32 {
+        let mut state = 12345u32;
+        move || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16 & 0x7FFF) as f32 / 16383.5 - 1.0
+        }
+    }
+
+    /// NRZ stream between 0 and 1 V that slews across the full swing in
+    /// `slew_samples`. Every bit boundary moves by up to `jitter_samples`,
+    /// and up to `noise_v` is added to every sample.
+    fn nrz(slew_samples: usize, jitter_samples: usize, noise_v: f32) -> (Vec<f32>, Vec<f32>) {
+        let mut random = noise_source();
+        let mut target = Vec::with_capacity(BITS * SAMPLES_PER_BIT);
+        let mut boundary = 0;
+        for (k, &bit) in prbs7(BITS).iter().enumerate() {
+            let next = (k + 1) * SAMPLES_PER_BIT;
+            let shift = (random() * jitter_samples as f32) as isize;
+            let next = (next as isize + shift) as usize;
+            target.extend(std::iter::repeat_n(if bit { 1.0f32 } else { 0.0 }, next - boundary));
+            boundary = next;
+        }
+
+        let step = 1.0 / slew_samples as f32;
+        let mut value = target[0];
+        let waveform = target.iter().map(|&target| {
+            value += (target - value).clamp(-step, step);
+            value + noise_v * random()
+        }).collect::<Vec<f32>>();
+        let time = (0..waveform.len()).map(|i| i as f32 * SAMPLE_INTERVAL).collect();
+        (time, waveform)
+    }
+
+    #[test]
+    fn aligns_transitions_to_unit_interval_boundaries() {
+        let (time, waveform) = nrz(20, 0, 0.0);
+        let eye = build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 2).unwrap();
+        assert_eq!(eye.ui_count, 2);
+        assert!(eye.slice_count > BITS / 4);
+
+        // Threshold hits only occur at the boundaries, including the one
+        // between the two unit intervals
+        let row = &eye.density[eye.threshold_bin()];
+        let hits: Vec<usize> = (0..row.len()).filter(|&p| row[p] > 0).collect();
+        assert!(hits.iter().any(|&p| p.abs_diff(PHASE_BINS_PER_UI) <= 10));
+        for p in hits {
+            let distance = (0..=2).map(|ui| p.abs_diff(ui * PHASE_BINS_PER_UI)).min().unwrap();
+            assert!(distance <= 10, "Threshold hit at phase bin {}", p);
+        }
+    }
+
+    #[test]
+    fn clean_eye_opens_to_the_amplitude() {
+        let (time, waveform) = nrz(20, 0, 0.0);
+        let eye = build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 1).unwrap();
+        let height = eye.eye_height();
+        assert!((height - 1.0).abs() <= 2.0 * eye.voltage_step(), "Eye height {}", height);
+        assert!(eye.eye_width_fraction() > 0.9, "Eye width {}", eye.eye_width_fraction());
+    }
+
+    #[test]
+    fn noise_and_jitter_narrow_the_eye() {
+        let (time, waveform) = nrz(20, 0, 0.0);
+        let clean = build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 1).unwrap();
+        let (time, waveform) = nrz(20, 30, 0.1);
+        let noisy = build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 1).unwrap();
+        assert!(noisy.eye_width_fraction() < clean.eye_width_fraction() - 0.1,
+            "Noisy width {}, clean width {}", noisy.eye_width_fraction(), clean.eye_width_fraction());
+        assert!(noisy.eye_height() < clean.eye_height() - 0.1);
+
+        // Noise beyond half the amplitude closes the eye
+        let (time, waveform) = nrz(20, 0, 0.6);
+        let closed = build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 1).unwrap();
+        assert_eq!(closed.eye_width_fraction(), 0.0);
+        assert_eq!(closed.eye_height(), 0.0);
+    }
+
+    #[test]
+    fn rejects_unusable_input() {
+        let (time, waveform) = nrz(20, 0, 0.0);
+        assert!(build_eye_diagram(&time, &waveform, 0.0, 1).is_err());
+        assert!(build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 0).is_err());
+        // One unit interval shorter than the sample interval
+        assert!(build_eye_diagram(&time, &waveform, 2e10, 1).is_err());
+        assert!(build_eye_diagram(&time, &vec![0.5; time.len()], BIT_RATE_HZ, 1).is_err());
+    }
+
+    #[test]
+    fn plots_the_density() {
+        let (time, waveform) = nrz(20, 0, 0.0);
+        let eye = build_eye_diagram(&time, &waveform, BIT_RATE_HZ, 2).unwrap();
+        let path = std::env::temp_dir().join(format!("eye-{}.png", std::process::id()));
+        let path = path.display().to_string();
+        plot_eye_diagram(&eye, &path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join("no-such-directory").join("eye.png");
+        assert!(plot_eye_diagram(&eye, &path.display().to_string()).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//! Voltage and time histograms.
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+
+use crate::plot::{PlotFormat, PlotOptions};
+use crate::{check_channel, OscilloscopeWaveform, ScopeError};
+
+const MIN_BINS: usize = 2;
+const MAX_BINS: usize = 65536;
+
+/// Amplitude distribution of a waveform.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Lower and upper voltage of each bin.
+    pub bins: Vec<(f32, f32)>,
+    /// Number of samples that fell into each bin.
+    pub counts: Vec<u64>,
+    /// NaN or infinite samples, left out of the bins. A misparsed float
+    /// transfer shows up here.
+    pub non_finite: u64,
+}
+
+/// Which axis the instrument histogram is built over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistogramSource {
+    /// Distribution of sample voltages.
+    Vertical,
+    /// Distribution of sample times.
+    Horizontal,
+}
+
+impl HistogramSource {
+    fn scpi_name(self) -> &'static str {
+        match self {
+            HistogramSource::Vertical => "VERTical",
+            HistogramSource::Horizontal => "HORizontal",
+        }
+    }
+}
+
+impl Histogram {
+    /// Build a histogram with `bin_count` equal bins between `low` and `high`.
+    fn with_range(low: f32, high: f32, counts: Vec<u64>) -> Self {
+        let width = (high - low) / counts.len() as f32;
+        let bins = (0..counts.len())
+            .map(|i| (low + i as f32 * width, low + (i + 1) as f32 * width))
+            .collect();
+        Self { bins, counts, non_finite: 0 }
+    }
+
+    /// The `bins + 1` voltages separating the bins, lowest first.
+    pub fn edges(&self) -> Vec<f32> {
+        self.bins.iter().map(|&(low, _)| low).chain(self.bins.last().map(|&(_, high)| high)).collect()
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    fn centers(&self) -> impl Iterator<Item = f32> + '_ {
+        self.bins.iter().map(|&(low, high)| (low + high) / 2.0)
+    }
+
+    /// Mean voltage, using the bin centers as sample values.
+    pub fn mean(&self) -> f32 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+        let sum: f64 = self.centers()
+            .zip(self.counts.iter())
+            .map(|(center, &count)| center as f64 * count as f64)
+            .sum();
+        (sum / total as f64) as f32
+    }
+
+    /// Standard deviation, using the bin centers as sample values.
+    pub fn std_dev(&self) -> f32 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+        let mean = self.mean() as f64;
+        let sum: f64 = self.centers()
+            .zip(self.counts.iter())
+            .map(|(center, &count)| (center as f64 - mean).powi(2) * count as f64)
+            .sum();
+        (sum / total as f64).sqrt() as f32
+    }
+
+    /// Skewness, using the bin centers as sample values. Positive when the
+    /// distribution has a longer tail towards higher voltages, 0 for a
+    /// constant signal.
+    pub fn skewness(&self) -> f32 {
+        let total = self.total();
+        let std_dev = self.std_dev() as f64;
+        if total == 0 || std_dev == 0.0 {
+            return 0.0;
+        }
+        let mean = self.mean() as f64;
+        let sum: f64 = self.centers()
+            .zip(self.counts.iter())
+            .map(|(center, &count)| ((center as f64 - mean) / std_dev).powi(3) * count as f64)
+            .sum();
+        (sum / total as f64) as f32
+    }
+
+    /// Center voltage of the most populated bin.
+    pub fn mode_voltage(&self) -> f32 {
+        self.counts.iter()
+            .enumerate()
+            .max_by_key(|&(_, &count)| count)
+            .and_then(|(i, _)| self.centers().nth(i))
+            .unwrap_or(0.0)
+    }
+}
+
+fn validate_bin_count(bin_count: usize) -> Result<()> {
+    if !(MIN_BINS..=MAX_BINS).contains(&bin_count) {
+        return Err(anyhow!(
+            "Bin count must be between {} and {}, got {}", MIN_BINS, MAX_BINS, bin_count
+        ));
+    }
+    Ok(())
+}
+
+/// Number of bins by the Freedman-Diaconis rule, which picks the bin width
+/// from the interquartile range so outliers do not stretch it. Falls back to
+/// the square root of the sample count if half the samples or more have the
+/// same value. Non-finite samples are ignored.
+pub fn auto_bin_count(samples: &[f32]) -> usize {
+    let mut sorted: Vec<f32> = samples.iter().copied().filter(|v| v.is_finite()).collect();
+    sorted.sort_unstable_by(f32::total_cmp);
+    let n = sorted.len();
+    let sqrt_rule = (n as f64).sqrt().ceil() as usize;
+    let bins = match (sorted.first(), sorted.last()) {
+        (Some(&min), Some(&max)) if max > min => {
+            let iqr = (sorted[3 * n / 4] - sorted[n / 4]) as f64;
+            let width = 2.0 * iqr / (n as f64).cbrt();
+            if width > 0.0 { ((max - min) as f64 / width).ceil() as usize } else { sqrt_rule }
+        }
+        _ => sqrt_rule,
+    };
+    bins.clamp(MIN_BINS, MAX_BINS)
+}
+
+/// Sort `samples` into `bins` equally wide bins spanning their voltage range,
+/// with [`auto_bin_count`] bins if `bins` is 0.
+///
+/// Bin counts outside 2 to 65536 are clamped. NaN and infinite samples are
+/// counted in [`Histogram::non_finite`] instead of a bin, and without any
+/// finite sample the histogram has no bins.
+pub fn histogram(samples: &[f32], bins: usize) -> Histogram {
+    let finite = || samples.iter().copied().filter(|v| v.is_finite());
+    let min = finite().fold(f32::INFINITY, f32::min);
+    let max = finite().fold(f32::NEG_INFINITY, f32::max);
+    let non_finite = samples.len() as u64 - finite().count() as u64;
+    if !min.is_finite() || !max.is_finite() {
+        return Histogram { bins: Vec::new(), counts: Vec::new(), non_finite };
+    }
+    let bin_count = match bins {
+        0 => auto_bin_count(samples),
+        bins => bins.clamp(MIN_BINS, MAX_BINS),
+    };
+
+    // A constant signal still needs a non-empty range to bin into
+    let (low, high) = if max > min {
+        (min, max)
+    } else {
+        let half_width = (min.abs() * 1e-3).max(1e-6);
+        (min - half_width, max + half_width)
+    };
+
+    let mut counts = vec![0u64; bin_count];
+    let scale = bin_count as f32 / (high - low);
+    for v in finite() {
+        let bin = (((v - low) * scale) as usize).min(bin_count - 1);
+        counts[bin] += 1;
+    }
+
+    Histogram { non_finite, ..Histogram::with_range(low, high, counts) }
+}
+
+/// Sort the samples of `waveform` into `bin_count` equally wide bins spanning
+/// its voltage range. Non-finite samples are ignored.
+pub fn compute_histogram(waveform: &[f32], bin_count: usize) -> Result<Histogram> {
+    validate_bin_count(bin_count)?;
+    let hist = histogram(waveform, bin_count);
+    if hist.bins.is_empty() {
+        return Err(anyhow!("Waveform contains no finite samples"));
+    }
+    Ok(hist)
+}
+
+fn draw_histogram_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str, hist: &Histogram,
+    low: f32, high: f32) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    let max_count = hist.counts.iter().copied().max().unwrap_or(0).max(1);
+    root.fill(&WHITE)?;
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(low..high, 0u64..max_count + max_count / 10)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Voltage (V)")
+        .y_desc("Count")
+        .draw()?;
+
+    chart.draw_series(hist.bins.iter().zip(hist.counts.iter()).map(|(&(bin_low, bin_high), &count)| {
+        Rectangle::new([(bin_low, 0), (bin_high, count)], BLUE.filled())
+    }))?;
+
+    root.present()?;
+    Ok(())
+}
+
+/// Render the histogram as a bar chart. Only PNG and SVG output are
+/// supported.
+pub fn plot_histogram(hist: &Histogram, options: &PlotOptions) -> Result<()> {
+    let (low, high) = match (hist.bins.first(), hist.bins.last()) {
+        (Some(first), Some(last)) => (first.0, last.1),
+        _ => return Err(anyhow!("Histogram has no bins")),
+    };
+
+    info!("Creating histogram plot");
+    let size = (options.width, options.height);
+    match options.format {
+        PlotFormat::Png => {
+            let root = BitMapBackend::new(&options.path, size).into_drawing_area();
+            draw_histogram_chart(root, &options.title, hist, low, high)?;
+        }
+        PlotFormat::Svg => {
+            let root = SVGBackend::new(&options.path, size).into_drawing_area();
+            draw_histogram_chart(root, &options.title, hist, low, high)?;
+        }
+        PlotFormat::Html { .. } => return Err(anyhow!("Histogram plots support PNG and SVG only")),
+    }
+    info!("Histogram saved as {}", options.path);
+    Ok(())
+}
+
+impl OscilloscopeWaveform {
+    /// Read the histogram computed by the instrument for `channel`.
+    ///
+    /// The `HIST?` reply is expected as `<low>,<high>,<count>,<count>,...`.
+    /// Instruments without histogram support answer with an empty line.
+    pub fn query_histogram_from_device(&self, channel: u8, source: HistogramSource)
+        -> Result<Histogram, ScopeError> {
+        check_channel(channel)?;
+        self.send_command(&format!("HIST:SOURce CHAN{}", channel))?;
+        self.send_command(&format!("HIST:TYPE {}", source.scpi_name()))?;
+        let response = self.query("HIST?")?;
+        let unexpected = || ScopeError::UnexpectedResponse {
+            command: "HIST?".to_string(),
+            response: response.clone(),
+        };
+
+        // An empty reply means the instrument has no histogram support
+        let fields: Vec<&str> = response.split(',').map(str::trim).collect();
+        if fields.len() < 2 + MIN_BINS {
+            return Err(unexpected());
+        }
+        let low: f32 = fields[0].parse().map_err(|_| unexpected())?;
+        let high: f32 = fields[1].parse().map_err(|_| unexpected())?;
+        let counts = fields[2..].iter()
+            .map(|field| field.parse::<u64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| unexpected())?;
+        validate_bin_count(counts.len()).map_err(|_| unexpected())?;
+
+        info!("Received {} histogram bins from channel {}", counts.len(), channel);
+        Ok(Histogram::with_range(low, high, counts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_nan_samples_separately() {
+        let samples = [0.0, 1.0, f32::NAN, 2.0, 3.0, f32::INFINITY, f32::NAN];
+        let hist = histogram(&samples, 4);
+        assert_eq!(hist.counts, [1, 1, 1, 1]);
+        assert_eq!(hist.non_finite, 3);
+        assert_eq!(hist.edges(), [0.0, 0.75, 1.5, 2.25, 3.0]);
+        assert!(hist.mean().is_finite());
+
+        let hist = histogram(&[f32::NAN; 3], 0);
+        assert!(hist.bins.is_empty());
+        assert_eq!(hist.non_finite, 3);
+        assert_eq!(hist.skewness(), 0.0);
+        assert!(compute_histogram(&[f32::NAN; 3], 10).is_err());
+    }
+
+    #[test]
+    fn bins_constant_signals_without_dividing_by_zero() {
+        let hist = histogram(&[1.5; 100], 0);
+        assert_eq!(hist.counts.iter().sum::<u64>(), 100);
+        assert!(hist.edges().iter().all(|edge| edge.is_finite()));
+        assert!((hist.mean() - 1.5).abs() < 1e-3);
+        assert_eq!(hist.skewness(), 0.0);
+    }
+
+    #[test]
+    fn picks_bin_count_from_the_spread() {
+        // Uniform samples: IQR is half the range, so 2n^(1/3)/(2 * 0.5) bins
+        let uniform: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+        assert_eq!(auto_bin_count(&uniform), 10);
+        // Mostly constant with one outlier: the IQR is zero, fall back to sqrt(n)
+        let mut spiky = vec![0.0f32; 99];
+        spiky.push(1.0);
+        assert_eq!(auto_bin_count(&spiky), 10);
+        assert_eq!(auto_bin_count(&[]), MIN_BINS);
+    }
+
+    #[test]
+    fn skewness_follows_the_longer_tail() {
+        let mut samples = vec![0.0f32; 90];
+        samples.extend([10.0; 10]);
+        assert!(histogram(&samples, 20).skewness() > 1.0);
+        let mirrored: Vec<f32> = samples.iter().map(|v| -v).collect();
+        assert!(histogram(&mirrored, 20).skewness() < -1.0);
+    }
+
+    #[test]
+    fn plots_histogram_as_svg() {
+        let path = std::env::temp_dir().join(format!("histogram_{}.svg", std::process::id()));
+        let options = PlotOptions {
+            path: path.to_string_lossy().into_owned(),
+            format: PlotFormat::Svg,
+            title: "Noise".to_string(),
+            ..PlotOptions::default()
+        };
+        plot_histogram(&histogram(&[0.0, 1.0, 1.0, 2.0], 3), &options).unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(svg.contains("Noise"));
+        assert!(svg.contains("#0000FF"));
+
+        let html = PlotOptions { format: PlotFormat::html(), ..options };
+        assert!(plot_histogram(&histogram(&[0.0, 1.0], 2), &html).is_err());
+    }
+}
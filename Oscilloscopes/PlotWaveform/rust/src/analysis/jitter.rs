@@ -0,0 +1,166 @@
+//! Period jitter of clock signals.
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+
+use super::crossings::Edge;
+use super::histogram::histogram;
+use super::search::search_edges;
+use crate::units::format_si;
+
+/// Period jitter found by [`measure_jitter`]. Deviations are the intervals
+/// between successive edges minus the reference period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JitterStats {
+    /// Spread between the longest and the shortest interval.
+    pub peak_to_peak_s: f64,
+    /// RMS of the deviations. A clock off its nominal frequency adds its
+    /// period error to every deviation.
+    pub rms_s: f64,
+    /// Largest deviation from the reference period in either direction.
+    pub max_deviation_s: f64,
+    /// Edges found, one more than the intervals measured.
+    pub edge_count: usize,
+    /// The period the intervals were compared with.
+    pub reference_period_s: f64,
+}
+
+/// Deviations of the intervals between successive `edge_times` from
+/// `reference_period_s`.
+pub fn jitter_deviations(edge_times: &[f64], reference_period_s: f64) -> Vec<f64> {
+    edge_times.windows(2).map(|pair| pair[1] - pair[0] - reference_period_s).collect()
+}
+
+/// Measure the period jitter of a clock: the edges of `slope` crossing
+/// `threshold_v` are timed as by [`search_edges`], and the intervals between
+/// successive edges compared with `reference_period_s`.
+///
+/// With [`Edge::Both`] the intervals are half periods, so the reference has
+/// to be half the clock period. Fails if fewer than two edges are found.
+pub fn measure_jitter(time: &[f32], waveform: &[f32], threshold_v: f32, slope: Edge, reference_period_s: f64)
+    -> Result<JitterStats> {
+    if !reference_period_s.is_finite() || reference_period_s <= 0.0 {
+        return Err(anyhow!("Invalid reference period {} s", reference_period_s));
+    }
+    let edges: Vec<f64> = search_edges(time, waveform, threshold_v, slope).into_iter().map(f64::from).collect();
+    if edges.len() < 2 {
+        return Err(anyhow!("Jitter needs at least two edges through {} V, found {}", threshold_v, edges.len()));
+    }
+
+    let deviations = jitter_deviations(&edges, reference_period_s);
+    let min = deviations.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = deviations.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let stats = JitterStats {
+        peak_to_peak_s: max - min,
+        rms_s: (deviations.iter().map(|d| d * d).sum::<f64>() / deviations.len() as f64).sqrt(),
+        max_deviation_s: min.abs().max(max.abs()),
+        edge_count: edges.len(),
+        reference_period_s,
+    };
+    info!("Jitter over {} edges: {:e} s peak-to-peak, {:e} s RMS", stats.edge_count, stats.peak_to_peak_s,
+        stats.rms_s);
+    Ok(stats)
+}
+
+/// Plot the distribution of the deviations of `edge_times`, as measured by
+/// [`measure_jitter`], from `stats.reference_period_s` as a PNG histogram.
+pub fn plot_jitter_histogram(stats: &JitterStats, edge_times: &[f64], output_path: &str) -> Result<()> {
+    let deviations: Vec<f32> = jitter_deviations(edge_times, stats.reference_period_s).into_iter()
+        .map(|d| d as f32)
+        .collect();
+    let hist = histogram(&deviations, 0);
+    let (low, high) = match (hist.bins.first(), hist.bins.last()) {
+        (Some(first), Some(last)) => (first.0, last.1),
+        _ => return Err(anyhow!("Jitter histogram needs at least two edges")),
+    };
+    let max_count = hist.counts.iter().copied().max().unwrap_or(0).max(1);
+
+    info!("Creating jitter histogram of {} intervals", deviations.len());
+    let root = BitMapBackend::new(output_path, (1200, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let caption = format!("Period jitter: {} p-p, {} RMS", format_si(stats.peak_to_peak_s, "s"),
+        format_si(stats.rms_s, "s"));
+    let mut chart = ChartBuilder::on(&root)
+        .caption(caption, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(low..high, 0u64..max_count + max_count / 10)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Deviation from the reference period")
+        .y_desc("Count")
+        .x_label_formatter(&|deviation| format_si(*deviation as f64, "s"))
+        .draw()?;
+
+    chart.draw_series(hist.bins.iter().zip(hist.counts.iter()).map(|(&(bin_low, bin_high), &count)| {
+        Rectangle::new([(bin_low, 0), (bin_high, count)], BLUE.filled())
+    }))?;
+
+    root.present()?;
+    info!("Jitter histogram saved as {}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square clock with 10 ns nominal period whose rising edges are
+    /// moved by the given offsets, sampled every 100 ps. The edges lie
+    /// between samples, so every one is timed at the same point of the
+    /// sample interval.
+    fn clock(offsets_s: &[f64]) -> (Vec<f32>, Vec<f32>) {
+        let edges: Vec<f64> = offsets_s.iter().enumerate()
+            .map(|(n, offset)| (n as f64 + 0.5) * 10e-9 + 25e-12 + offset)
+            .collect();
+        let time: Vec<f32> = (0..offsets_s.len() * 100).map(|i| i as f32 * 100e-12).collect();
+        let waveform = time.iter()
+            .map(|&t| {
+                // High for the first half period after each rising edge
+                let high = edges.iter().any(|&edge| (t as f64) >= edge && (t as f64) < edge + 5e-9);
+                if high { 1.0 } else { 0.0 }
+            })
+            .collect();
+        (time, waveform)
+    }
+
+    #[test]
+    fn measures_period_jitter() {
+        let (time, waveform) = clock(&[0.0, 200e-12, 0.0, -200e-12, 0.0, 0.0]);
+        let stats = measure_jitter(&time, &waveform, 0.5, Edge::Rising, 10e-9).unwrap();
+        assert_eq!(stats.edge_count, 6);
+        // Intervals deviate by +200, -200, -200, +200 and 0 ps
+        assert!((stats.peak_to_peak_s - 400e-12).abs() < 5e-12, "{}", stats.peak_to_peak_s);
+        assert!((stats.max_deviation_s - 200e-12).abs() < 5e-12, "{}", stats.max_deviation_s);
+        assert!((stats.rms_s - (4.0 * 200e-12f64.powi(2) / 5.0).sqrt()).abs() < 5e-12, "{}", stats.rms_s);
+
+        let (ideal_time, ideal_waveform) = clock(&[0.0; 6]);
+        let ideal = measure_jitter(&ideal_time, &ideal_waveform, 0.5, Edge::Falling, 10e-9).unwrap();
+        // The last falling edge lies past the capture
+        assert_eq!(ideal.edge_count, 5);
+        assert!(ideal.peak_to_peak_s < 5e-12, "{}", ideal.peak_to_peak_s);
+
+        assert!(measure_jitter(&time, &waveform, 2.0, Edge::Rising, 10e-9).is_err());
+        assert!(measure_jitter(&time, &waveform, 0.5, Edge::Rising, 0.0).is_err());
+    }
+
+    #[test]
+    fn plots_the_deviation_histogram() {
+        let (time, waveform) = clock(&[0.0, 100e-12, -100e-12, 0.0]);
+        let stats = measure_jitter(&time, &waveform, 0.5, Edge::Rising, 10e-9).unwrap();
+        let edges: Vec<f64> = search_edges(&time, &waveform, 0.5, Edge::Rising).into_iter()
+            .map(f64::from)
+            .collect();
+        let path = std::env::temp_dir().join(format!("jitter-{}.png", std::process::id()));
+        let path = path.display().to_string();
+        plot_jitter_histogram(&stats, &edges, &path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(plot_jitter_histogram(&stats, &edges[..1], &path).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+//! Offline analysis of captured waveforms.
+
+pub mod bandwidth;
+pub mod compare;
+pub mod correlation;
+pub mod crossings;
+pub mod eye;
+pub mod histogram;
+pub mod jitter;
+pub mod peaks;
+pub mod phase;
+pub mod power;
+pub mod psd;
+pub mod pulse;
+pub mod resample;
+pub mod search;
+pub mod spectrogram;
+pub mod stats;
+
+pub use bandwidth::apply_bandwidth_limit;
+pub use compare::{compare, compare_traces, CompareReport, CompareTolerance, DeviationRegion};
+pub use correlation::{channel_skew, SkewResult};
+pub use crossings::{find_crossings, find_crossings_with_hysteresis, Crossing, Edge};
+pub use histogram::{histogram, Histogram};
+pub use jitter::{measure_jitter, plot_jitter_histogram, JitterStats};
+pub use power::{compute_energy, compute_power, differentiate_waveform, integrate_waveform};
+pub use pulse::{measure_pulses, MeasurementSpread, PulseMeasurements};
+pub use stats::{detrend_linear, normalize_waveform, remove_dc_offset};
@@ -0,0 +1,202 @@
+//! Peak and pulse detection.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+
+use crate::plot::{decimate_waveform, padded_voltage_range};
+
+/// A detected peak or trough.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeakInfo {
+    pub index: usize,
+    pub voltage: f32,
+    pub timestamp_s: f32,
+}
+
+/// Look up voltage and time of the given sample indices.
+pub fn peak_info(time: &[f32], waveform: &[f32], peaks: &[usize]) -> Vec<PeakInfo> {
+    peaks.iter()
+        .filter(|&&index| index < time.len() && index < waveform.len())
+        .map(|&index| PeakInfo { index, voltage: waveform[index], timestamp_s: time[index] })
+        .collect()
+}
+
+/// Indices of local maxima above `threshold`, in ascending order.
+///
+/// A flat top counts as one peak at its first sample. When peaks are closer
+/// than `min_separation_samples`, only the highest of them is kept.
+pub fn find_peaks(waveform: &[f32], threshold: f32, min_separation_samples: usize) -> Vec<usize> {
+    let mut candidates: Vec<usize> = Vec::new();
+    let mut i = 1;
+    while i + 1 < waveform.len() {
+        if waveform[i] > threshold && waveform[i] > waveform[i - 1] {
+            // Walk to the end of a plateau before deciding
+            let mut end = i;
+            while end + 1 < waveform.len() && waveform[end + 1] == waveform[i] {
+                end += 1;
+            }
+            if end + 1 < waveform.len() && waveform[end + 1] < waveform[i] {
+                candidates.push(i);
+            }
+            i = end + 1;
+        } else {
+            i += 1;
+        }
+    }
+
+    // Keep the highest peaks first, then drop their close neighbours. Only
+    // the nearest kept peak on either side can be too close.
+    candidates.sort_by(|&a, &b| waveform[b].total_cmp(&waveform[a]).then(a.cmp(&b)));
+    let mut peaks = BTreeSet::new();
+    for candidate in candidates {
+        let too_close = |peak: Option<&usize>| peak.is_some_and(|&peak| peak.abs_diff(candidate) < min_separation_samples);
+        if !too_close(peaks.range(..candidate).next_back()) && !too_close(peaks.range(candidate..).next()) {
+            peaks.insert(candidate);
+        }
+    }
+    peaks.into_iter().collect()
+}
+
+/// Indices of local minima below `threshold`, in ascending order. See
+/// [`find_peaks`].
+pub fn find_troughs(waveform: &[f32], threshold: f32, min_separation_samples: usize) -> Vec<usize> {
+    let inverted: Vec<f32> = waveform.iter().map(|&v| -v).collect();
+    find_peaks(&inverted, -threshold, min_separation_samples)
+}
+
+/// Width in seconds of every pulse above `threshold`.
+///
+/// The crossings are interpolated between samples. Pulses that are already
+/// high at the start or still high at the end of the capture are skipped.
+pub fn measure_pulse_widths(waveform: &[f32], time: &[f32], threshold: f32) -> Vec<f32> {
+    let len = waveform.len().min(time.len());
+    let crossing = |i: usize| {
+        // Threshold crossing between samples i - 1 and i
+        let (v0, v1) = (waveform[i - 1], waveform[i]);
+        let fraction = if v1 != v0 { (threshold - v0) / (v1 - v0) } else { 0.0 };
+        time[i - 1] + fraction * (time[i] - time[i - 1])
+    };
+
+    let mut widths = Vec::new();
+    let mut rise: Option<f32> = None;
+    for i in 1..len {
+        let was_high = waveform[i - 1] > threshold;
+        let is_high = waveform[i] > threshold;
+        if !was_high && is_high {
+            rise = Some(crossing(i));
+        } else if was_high && !is_high {
+            if let Some(start) = rise.take() {
+                widths.push(crossing(i) - start);
+            }
+        }
+    }
+    widths
+}
+
+/// Plot the waveform with a red cross on every peak.
+pub fn annotate_peaks(time: &[f32], waveform: &[f32], peaks: &[usize], output_path: &str) -> Result<()> {
+    if waveform.is_empty() || time.len() != waveform.len() {
+        return Err(anyhow!("Time and waveform must be non-empty and of equal length"));
+    }
+
+    info!("Creating peak plot");
+    let root = BitMapBackend::new(output_path, (1200, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let (min_voltage, max_voltage) = padded_voltage_range(waveform);
+    let mut chart = ChartBuilder::on(&root)
+        .caption("Peaks", ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(time[0]..time[time.len() - 1], min_voltage..max_voltage)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Time (s)")
+        .y_desc("Voltage (V)")
+        .draw()?;
+
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    let (times, values) = decimate_waveform(time, waveform, 2 * columns);
+    chart.draw_series(LineSeries::new(
+        times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+        &BLUE,
+    ))?;
+
+    chart.draw_series(peak_info(time, waveform, peaks).into_iter().map(|peak| {
+        Cross::new((peak.timestamp_s, peak.voltage), 6, RED.stroke_width(2))
+    }))?;
+
+    root.present()?;
+    info!("Peak plot with {} peaks saved as {}", peaks.len(), output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_peaks_above_threshold() {
+        let waveform = [0.0, 1.0, 0.0, 3.0, 0.0, 0.5, 0.0, 2.0, 2.0, 0.0];
+        assert_eq!(find_peaks(&waveform, 0.8, 1), [1, 3, 7]);
+    }
+
+    #[test]
+    fn keeps_highest_of_close_peaks() {
+        let waveform = [0.0, 2.0, 0.0, 3.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.5, 0.0];
+        assert_eq!(find_peaks(&waveform, 0.5, 3), [3, 9]);
+    }
+
+    #[test]
+    fn suppresses_close_peaks_among_many() {
+        // A peak at every odd sample, with pseudo-random heights
+        let mut state = 12345u32;
+        let mut waveform = vec![0.0; 20_001];
+        for sample in waveform.iter_mut().skip(1).step_by(2) {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            *sample = 1.0 + (state >> 8) as f32 / (1 << 24) as f32;
+        }
+        let peaks = find_peaks(&waveform, 0.5, 7);
+
+        // Every candidate, highest first, is kept unless a kept peak is close
+        let mut candidates: Vec<usize> = (1..waveform.len()).step_by(2).collect();
+        candidates.sort_by(|&a, &b| waveform[b].total_cmp(&waveform[a]).then(a.cmp(&b)));
+        let mut expected: Vec<usize> = Vec::new();
+        for candidate in candidates {
+            if expected.iter().all(|&peak| peak.abs_diff(candidate) >= 7) {
+                expected.push(candidate);
+            }
+        }
+        expected.sort_unstable();
+        assert_eq!(peaks, expected);
+        assert!(peaks.len() > 1_000);
+        assert!(peaks.windows(2).all(|pair| pair[1] - pair[0] >= 7));
+    }
+
+    #[test]
+    fn finds_troughs_below_threshold() {
+        let waveform = [0.0, -1.0, 0.0, -3.0, 0.0, -0.2, 0.0];
+        assert_eq!(find_troughs(&waveform, -0.5, 1), [1, 3]);
+    }
+
+    #[test]
+    fn measures_complete_pulses() {
+        let time: Vec<f32> = (0..12).map(|i| i as f32).collect();
+        let waveform = [1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0, 1.0];
+        // Only the pulses from 3.5 to 6.5 and 8.5 to 9.5 are complete
+        assert_eq!(measure_pulse_widths(&waveform, &time, 0.5), [3.0, 1.0]);
+    }
+
+    #[test]
+    fn plot_reports_an_unwritable_path() {
+        let time: Vec<f32> = (0..10).map(|i| i as f32).collect();
+        let waveform = [0.0, 1.0, 0.0, 3.0, 0.0, 0.5, 0.0, 2.0, 2.0, 0.0];
+        let path = std::env::temp_dir().join("no-such-directory").join("peaks.png");
+        assert!(annotate_peaks(&time, &waveform, &[1, 3, 7], &path.display().to_string()).is_err());
+    }
+}
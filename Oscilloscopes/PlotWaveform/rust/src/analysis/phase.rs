@@ -0,0 +1,171 @@
+//! Phase difference between two channels of the same frequency.
+
+use std::f64::consts::PI;
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::decoders::interpolate;
+use crate::measurement::measurement_value;
+use crate::{check_channel, OscilloscopeWaveform, ScopeError, TransferFormat, WaveformMetadata};
+
+/// Wrap an angle in degrees into (-180, 180].
+fn wrap_degrees(degrees: f64) -> f64 {
+    let wrapped = degrees.rem_euclid(360.0);
+    if wrapped > 180.0 { wrapped - 360.0 } else { wrapped }
+}
+
+/// Bring channel B onto the time axis of channel A.
+///
+/// Only the part of A covered by B is kept, so no samples are made up
+/// beyond the ends of B.
+fn align_to_a(time_a: &[f32], waveform_a: &[f32], time_b: &[f32], waveform_b: &[f32])
+    -> (Vec<f32>, Vec<f32>) {
+    if time_a == time_b {
+        return (waveform_a.to_vec(), waveform_b.to_vec());
+    }
+    warn!("Channels are sampled differently, interpolating B onto the time axis of A");
+    let (first, last) = (time_b[0], time_b[time_b.len() - 1]);
+    let (times, samples_a): (Vec<f32>, Vec<f32>) = time_a.iter().zip(waveform_a)
+        .filter(|&(&t, _)| t >= first && t <= last)
+        .map(|(&t, &v)| (t, v))
+        .unzip();
+    let samples_b = interpolate(time_b, waveform_b, &times);
+    (samples_a, samples_b)
+}
+
+/// Phase of channel B relative to channel A in degrees, in (-180, 180].
+///
+/// The fundamental is the strongest non-DC bin of A's spectrum, and the
+/// result is the difference of both phases at that bin. Positive values
+/// mean B lags A. If the channels have different time axes, B is
+/// interpolated onto A's. The captures should span several periods.
+pub fn measure_phase_degrees(time_a: &[f32], waveform_a: &[f32], time_b: &[f32], waveform_b: &[f32])
+    -> Result<f32> {
+    if time_a.len() != waveform_a.len() || time_b.len() != waveform_b.len() {
+        return Err(anyhow!("Time and waveform lengths differ"));
+    }
+    if time_a.len() < 4 || time_b.len() < 4 {
+        return Err(anyhow!("Need at least 4 samples per channel"));
+    }
+
+    let (samples_a, samples_b) = align_to_a(time_a, waveform_a, time_b, waveform_b);
+    let len = samples_a.len();
+    if len < 4 {
+        return Err(anyhow!("Channels overlap by only {} samples", len));
+    }
+
+    // Both channels get the same window, so its effect on the phase
+    // cancels in the difference
+    let spectrum = |samples: &[f32]| {
+        let mean = samples.iter().map(|&v| v as f64).sum::<f64>() / len as f64;
+        let mut buffer: Vec<Complex<f64>> = samples.iter().enumerate()
+            .map(|(n, &v)| {
+                let window = 0.5 * (1.0 - (2.0 * PI * n as f64 / len as f64).cos());
+                Complex::new((v as f64 - mean) * window, 0.0)
+            })
+            .collect();
+        FftPlanner::<f64>::new().plan_fft_forward(len).process(&mut buffer);
+        buffer
+    };
+    let spectrum_a = spectrum(&samples_a);
+    let spectrum_b = spectrum(&samples_b);
+
+    let fundamental = (1..len / 2 + 1)
+        .max_by(|&a, &b| spectrum_a[a].norm_sqr().total_cmp(&spectrum_a[b].norm_sqr()))
+        .filter(|&bin| spectrum_a[bin].norm() > f64::EPSILON)
+        .ok_or_else(|| anyhow!("Channel A has no fundamental"))?;
+    if spectrum_b[fundamental].norm() <= f64::EPSILON {
+        return Err(anyhow!("Channel B has no component at the fundamental of A"));
+    }
+
+    let phase_a = spectrum_a[fundamental].arg().to_degrees();
+    let phase_b = spectrum_b[fundamental].arg().to_degrees();
+    let phase = wrap_degrees(phase_a - phase_b);
+    info!("Phase at bin {} of {}: {:.2} degrees", fundamental, len, phase);
+    Ok(phase as f32)
+}
+
+impl OscilloscopeWaveform {
+    /// Phase of `channel_b` relative to `channel_a` in degrees.
+    ///
+    /// Uses the instrument's phase measurement where supported. Otherwise
+    /// both channels are captured from the same trigger and the phase is
+    /// computed with [`measure_phase_degrees`].
+    pub fn query_phase_degrees(&self, channel_a: u8, channel_b: u8) -> Result<f32, ScopeError> {
+        check_channel(channel_a)?;
+        check_channel(channel_b)?;
+        let command = format!("MEASure:PHASe? CHAN{},CHAN{}", channel_a, channel_b);
+        let onboard = self.query_f64(&command).and_then(|value| {
+            let response = value.to_string();
+            let degrees = measurement_value(value)
+                .ok_or_else(|| ScopeError::UnexpectedResponse { command: command.clone(), response })?;
+            self.verify_no_errors("phase measurement")?;
+            Ok(wrap_degrees(degrees) as f32)
+        });
+        match onboard {
+            Err(ScopeError::Timeout | ScopeError::UnexpectedResponse { .. } | ScopeError::ScpiError { .. }) => {
+                info!("No onboard phase measurement, computing it from a capture");
+                // A query the instrument did not answer may still be pending
+                self.device.clear()?;
+                self.check_errors()?;
+                let captures = self.capture_channels(&[channel_a, channel_b], TransferFormat::Volts, None)?;
+                let time = |(metadata, waveform): &(WaveformMetadata, Vec<f32>)| -> Vec<f32> {
+                    (0..waveform.len()).map(|i| metadata.start_time + i as f32 * metadata.time_delta).collect()
+                };
+                measure_phase_degrees(&time(&captures[0]), &captures[0].1, &time(&captures[1]), &captures[1].1)
+                    .map_err(|e| ScopeError::Analysis(e.to_string()))
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize, sample_interval: f32, frequency: f32, phase_degrees: f32) -> (Vec<f32>, Vec<f32>) {
+        (0..len).map(|n| {
+            let t = n as f32 * sample_interval;
+            (t, (2.0 * std::f32::consts::PI * frequency * t - phase_degrees.to_radians()).sin())
+        }).unzip()
+    }
+
+    #[test]
+    fn measures_lag_and_lead() {
+        let (time, a) = sine(1000, 1e-3, 10.0, 0.0);
+        let (_, lagging) = sine(1000, 1e-3, 10.0, 30.0);
+        let (_, leading) = sine(1000, 1e-3, 10.0, -45.0);
+        assert!((measure_phase_degrees(&time, &a, &time, &lagging).unwrap() - 30.0).abs() < 0.1);
+        assert!((measure_phase_degrees(&time, &a, &time, &leading).unwrap() + 45.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn interpolates_different_sample_rates() {
+        // Frequency between bins and B sampled 3% slower
+        let (time_a, a) = sine(2000, 1e-3, 7.3, 0.0);
+        let (time_b, b) = sine(1940, 1.03e-3, 7.3, 120.0);
+        let phase = measure_phase_degrees(&time_a, &a, &time_b, &b).unwrap();
+        assert!((phase - 120.0).abs() < 0.5, "phase {}", phase);
+    }
+
+    #[test]
+    fn wraps_into_half_open_range() {
+        assert_eq!(wrap_degrees(180.0), 180.0);
+        assert_eq!(wrap_degrees(-180.0), 180.0);
+        assert_eq!(wrap_degrees(270.0), -90.0);
+        assert_eq!(wrap_degrees(-450.0), -90.0);
+    }
+
+    #[test]
+    fn rejects_signals_without_fundamental() {
+        let time: Vec<f32> = (0..100).map(|n| n as f32).collect();
+        let flat = vec![1.0; 100];
+        let (_, b) = sine(100, 1.0, 0.1, 0.0);
+        assert!(measure_phase_degrees(&time, &flat, &time, &b).is_err());
+        assert!(measure_phase_degrees(&time[..3], &flat[..3], &time[..3], &b[..3]).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Integration and differentiation of sampled waveforms, and power and
+//! energy from voltage and current captures.
+//!
+//! All functions take samples `time_delta_s` apart, as in the metadata of
+//! a capture, and return one value per input sample.
+
+use log::warn;
+
+/// Running integral by the trapezoidal rule, starting at 0 at the first
+/// sample.
+///
+/// Sums are kept in `f64`, so long records do not lose the small
+/// increments late in the record.
+pub fn integrate_waveform(waveform: &[f32], time_delta_s: f32) -> Vec<f32> {
+    let half_step = time_delta_s as f64 / 2.0;
+    let mut integral = 0.0;
+    let mut output = Vec::with_capacity(waveform.len());
+    output.extend(waveform.first().map(|_| 0.0));
+    for pair in waveform.windows(2) {
+        integral += (pair[0] as f64 + pair[1] as f64) * half_step;
+        output.push(integral as f32);
+    }
+    output
+}
+
+/// Derivative by central differences, with one-sided differences at the
+/// first and last sample. A single sample has a derivative of 0.
+pub fn differentiate_waveform(waveform: &[f32], time_delta_s: f32) -> Vec<f32> {
+    let len = waveform.len();
+    let step = time_delta_s as f64;
+    (0..len)
+        .map(|i| {
+            let (before, after) = (i.saturating_sub(1), (i + 1).min(len - 1));
+            if before == after {
+                return 0.0;
+            }
+            ((waveform[after] as f64 - waveform[before] as f64) / ((after - before) as f64 * step)) as f32
+        })
+        .collect()
+}
+
+/// Instantaneous power, the product of voltage and current sample by
+/// sample. Captures of different lengths are multiplied over the length
+/// of the shorter one.
+pub fn compute_power(voltage: &[f32], current: &[f32]) -> Vec<f32> {
+    if voltage.len() != current.len() {
+        warn!("Voltage has {} samples and current {}, using the first {}", voltage.len(), current.len(),
+            voltage.len().min(current.len()));
+    }
+    voltage.iter().zip(current).map(|(v, i)| v * i).collect()
+}
+
+/// Energy in joules of a power waveform in watts, the last value of its
+/// [`integrate_waveform`] integral. No samples give 0.
+pub fn compute_energy(power: &[f32], time_delta_s: f32) -> f32 {
+    integrate_waveform(power, time_delta_s).last().copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn integrates_and_differentiates_a_sine() {
+        // Two periods of a 1 kHz sine at 1 MSa/s
+        let dt = 1e-6;
+        let omega = 2.0 * PI * 1e3;
+        let sine: Vec<f32> = (0..2000).map(|i| (omega * i as f64 * dt).sin() as f32).collect();
+
+        let integral = integrate_waveform(&sine, dt as f32);
+        assert_eq!(integral.len(), sine.len());
+        assert_eq!(integral[0], 0.0);
+        for (i, &value) in integral.iter().enumerate() {
+            let expected = (1.0 - (omega * i as f64 * dt).cos()) / omega;
+            assert!((value as f64 - expected).abs() < 1e-4 / omega, "{}: {} vs {}", i, value, expected);
+        }
+
+        let derivative = differentiate_waveform(&sine, dt as f32);
+        assert_eq!(derivative.len(), sine.len());
+        for (i, &value) in derivative.iter().enumerate() {
+            let expected = omega * (omega * i as f64 * dt).cos();
+            // The one-sided ends are less accurate
+            let tolerance = if i == 0 || i == sine.len() - 1 { 1e-2 } else { 1e-3 };
+            assert!((value as f64 - expected).abs() < tolerance * omega, "{}: {} vs {}", i, value, expected);
+        }
+    }
+
+    #[test]
+    fn handles_short_waveforms() {
+        assert!(integrate_waveform(&[], 1.0).is_empty());
+        assert!(differentiate_waveform(&[], 1.0).is_empty());
+        assert_eq!(differentiate_waveform(&[3.0], 1.0), vec![0.0]);
+        assert_eq!(differentiate_waveform(&[1.0, 3.0], 0.5), vec![4.0, 4.0]);
+        assert_eq!(integrate_waveform(&[1.0, 3.0], 0.5), vec![0.0, 1.0]);
+        assert_eq!(compute_energy(&[], 1.0), 0.0);
+    }
+
+    #[test]
+    fn computes_power_and_energy() {
+        // 12 V at 0.5 A for 1 ms deliver 6 mJ
+        let voltage = vec![12.0; 1001];
+        let current = vec![0.5; 1001];
+        let power = compute_power(&voltage, &current);
+        assert_eq!(power, vec![6.0; 1001]);
+        assert!((compute_energy(&power, 1e-6) - 6e-3).abs() < 1e-9);
+        assert_eq!(compute_power(&[1.0, 2.0, 3.0], &[2.0, 2.0]), vec![2.0, 4.0]);
+    }
+}
@@ -0,0 +1,152 @@
+//! Power spectral density estimation.
+
+use std::f64::consts::PI;
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Estimate the one-sided power spectral density with Welch's method.
+///
+/// The waveform is split into segments of `segment_len` samples that
+/// overlap by the fraction `overlap`. Every segment has its mean removed,
+/// is weighted with a Hann window and transformed, and the power spectra
+/// are averaged. Returns the frequencies from 0 to Nyquist and the density
+/// in dB relative to 1 V²/Hz.
+pub fn compute_psd_welch(waveform: &[f32], sample_rate_hz: f64, segment_len: usize, overlap: f32)
+    -> Result<(Vec<f64>, Vec<f64>)> {
+    if !sample_rate_hz.is_finite() || sample_rate_hz <= 0.0 {
+        return Err(anyhow!("Invalid sample rate {} Hz", sample_rate_hz));
+    }
+    if segment_len < 2 || !segment_len.is_power_of_two() {
+        return Err(anyhow!("Segment length {} is not a power of two", segment_len));
+    }
+    if !(0.0..1.0).contains(&overlap) {
+        return Err(anyhow!("Overlap {} is outside of [0, 1)", overlap));
+    }
+    if waveform.len() < segment_len {
+        return Err(anyhow!("{} samples are fewer than one segment of {}", waveform.len(), segment_len));
+    }
+
+    let window: Vec<f64> = (0..segment_len)
+        .map(|n| 0.5 * (1.0 - (2.0 * PI * n as f64 / segment_len as f64).cos()))
+        .collect();
+    let window_power: f64 = window.iter().map(|w| w * w).sum();
+    let step = ((segment_len as f64 * (1.0 - overlap as f64)).round() as usize).max(1);
+    let bins = segment_len / 2 + 1;
+
+    let fft = FftPlanner::<f64>::new().plan_fft_forward(segment_len);
+    let mut power = vec![0.0f64; bins];
+    let mut buffer = vec![Complex::new(0.0, 0.0); segment_len];
+    let mut segments = 0usize;
+    for start in (0..=waveform.len() - segment_len).step_by(step) {
+        let segment = &waveform[start..start + segment_len];
+        let mean = segment.iter().map(|&v| v as f64).sum::<f64>() / segment_len as f64;
+        for ((value, &sample), &w) in buffer.iter_mut().zip(segment).zip(&window) {
+            *value = Complex::new((sample as f64 - mean) * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        for (bin, p) in power.iter_mut().enumerate() {
+            *p += buffer[bin].norm_sqr();
+        }
+        segments += 1;
+    }
+
+    let scale = 1.0 / (sample_rate_hz * window_power * segments as f64);
+    let psd_db = power.iter().enumerate()
+        .map(|(bin, &p)| {
+            // Fold the negative frequencies onto the positive ones
+            let one_sided = if bin == 0 || bin == segment_len / 2 { 1.0 } else { 2.0 };
+            10.0 * (p * scale * one_sided).max(f64::MIN_POSITIVE).log10()
+        })
+        .collect();
+    let frequencies = (0..bins).map(|bin| bin as f64 * sample_rate_hz / segment_len as f64).collect();
+
+    info!("PSD from {} segments of {} samples", segments, segment_len);
+    Ok((frequencies, psd_db))
+}
+
+/// Plot a PSD as PNG with a logarithmic frequency axis.
+///
+/// The DC bin cannot be shown on the log axis and is left out.
+pub fn plot_psd(freq: &[f64], psd: &[f64], path: &str) -> Result<()> {
+    let points: Vec<(f64, f64)> = freq.iter().zip(psd)
+        .filter(|&(&f, &p)| f > 0.0 && p.is_finite())
+        .map(|(&f, &p)| (f, p))
+        .collect();
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return Err(anyhow!("No PSD points above 0 Hz to plot"));
+    };
+    let min_db = points.iter().fold(f64::INFINITY, |a, &(_, p)| a.min(p));
+    let max_db = points.iter().fold(f64::NEG_INFINITY, |a, &(_, p)| a.max(p));
+    let padding = ((max_db - min_db) * 0.1).max(1.0);
+
+    info!("Creating PSD plot");
+    let root = BitMapBackend::new(path, (1200, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption("Power Spectral Density", ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d((first.0..last.0.max(first.0 * 10.0)).log_scale(), min_db - padding..max_db + padding)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Frequency (Hz)")
+        .y_desc("PSD (dB V²/Hz)")
+        .draw()?;
+
+    chart.draw_series(LineSeries::new(points.iter().copied(), &BLUE))?;
+
+    root.present()?;
+    info!("PSD plot saved as {}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preserves_sine_power() {
+        // 1 V amplitude sine centred on bin 32 of 256 at 1 kHz sample rate
+        let sample_rate = 1000.0;
+        let frequency = 32.0 * sample_rate / 256.0;
+        let waveform: Vec<f32> = (0..4096)
+            .map(|n| (2.0 * PI * frequency * n as f64 / sample_rate).sin() as f32)
+            .collect();
+        let (freq, psd) = compute_psd_welch(&waveform, sample_rate, 256, 0.5).unwrap();
+        assert_eq!(freq.len(), 129);
+        assert_eq!(freq[32], frequency);
+
+        let peak = (0..psd.len()).max_by(|&a, &b| psd[a].total_cmp(&psd[b])).unwrap();
+        assert_eq!(peak, 32);
+        // Integrated density equals the mean square of the sine, 0.5 V²
+        let bin_width = freq[1];
+        let total: f64 = psd.iter().map(|db| 10f64.powf(db / 10.0) * bin_width).sum();
+        assert!((total - 0.5).abs() < 0.005, "total power {}", total);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let waveform = vec![0.0f32; 1024];
+        assert!(compute_psd_welch(&waveform, 1e3, 100, 0.5).is_err());
+        assert!(compute_psd_welch(&waveform, 1e3, 256, 1.0).is_err());
+        assert!(compute_psd_welch(&waveform, 1e3, 256, -0.1).is_err());
+        assert!(compute_psd_welch(&waveform, 0.0, 256, 0.5).is_err());
+        assert!(compute_psd_welch(&waveform, 1e3, 2048, 0.5).is_err());
+        assert!(compute_psd_welch(&waveform, 1e3, 256, 0.0).is_ok());
+    }
+
+    #[test]
+    fn plot_reports_an_unwritable_path() {
+        let waveform: Vec<f32> = (0..1024).map(|n| (n as f32 * 0.3).sin()).collect();
+        let (freq, psd) = compute_psd_welch(&waveform, 1e3, 256, 0.5).unwrap();
+        let path = std::env::temp_dir().join("no-such-directory").join("psd.png");
+        assert!(plot_psd(&freq, &psd, &path.display().to_string()).is_err());
+    }
+}
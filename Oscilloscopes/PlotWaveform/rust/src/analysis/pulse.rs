@@ -0,0 +1,185 @@
+//! Pulse measurements of square waves and PWM signals.
+
+use super::crossings::{find_crossings_with_hysteresis, Edge};
+use super::histogram::histogram;
+
+/// Bins of the histogram the base and top levels are taken from.
+const LEVEL_BINS: usize = 256;
+
+/// Samples within this fraction of the amplitude of the base or top level
+/// count as sitting on that level.
+const LEVEL_BAND: f32 = 0.1;
+
+/// Share of samples that have to sit on the base or top level for the
+/// signal to count as a pulse train. A sine only reaches about 40%.
+const MIN_LEVEL_FRACTION: f32 = 0.6;
+
+/// Hysteresis of the 50% reference crossings, as a fraction of the
+/// amplitude, so noise on an edge counts as one crossing.
+const REFERENCE_HYSTERESIS: f32 = 0.1;
+
+/// Mean, smallest and largest value of a measurement over all cycles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeasurementSpread {
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl MeasurementSpread {
+    fn of(values: &[f32]) -> Self {
+        let sum: f64 = values.iter().map(|&v| v as f64).sum();
+        MeasurementSpread {
+            mean: (sum / values.len().max(1) as f64) as f32,
+            min: values.iter().copied().fold(f32::INFINITY, f32::min),
+            max: values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        }
+    }
+}
+
+/// Pulse measurements over a whole record, see [`measure_pulses`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PulseMeasurements {
+    /// Low level in volts.
+    pub base: f32,
+    /// High level in volts.
+    pub top: f32,
+    /// Full cycles from rising edge to rising edge.
+    pub cycles: usize,
+    /// High time of every cycle as a percentage of its period.
+    pub duty_cycle_percent: MeasurementSpread,
+    /// Time from a rising to the next falling reference crossing.
+    pub positive_width_s: MeasurementSpread,
+    /// Time from a falling to the next rising reference crossing.
+    pub negative_width_s: MeasurementSpread,
+    /// Highest voltage above the top level, as a percentage of the
+    /// amplitude.
+    pub overshoot_percent: f32,
+    /// Lowest voltage below the base level, as a percentage of the
+    /// amplitude.
+    pub preshoot_percent: f32,
+}
+
+impl PulseMeasurements {
+    /// Top minus base level.
+    pub fn amplitude(&self) -> f32 {
+        self.top - self.base
+    }
+}
+
+/// Base and top level as the most frequent voltage in the lower and upper
+/// half of the histogram, or `None` if too few samples sit on them for
+/// the signal to be a pulse train.
+fn pulse_levels(waveform: &[f32]) -> Option<(f32, f32)> {
+    let hist = histogram(waveform, LEVEL_BINS);
+    let half = hist.counts.len() / 2;
+    let mode = |range: std::ops::Range<usize>| {
+        let i = range.max_by_key(|&i| hist.counts[i])?;
+        let (low, high) = hist.bins[i];
+        Some((low + high) / 2.0)
+    };
+    let base = mode(0..half)?;
+    let top = mode(half..hist.counts.len())?;
+    let amplitude = top - base;
+    if amplitude <= 0.0 {
+        return None;
+    }
+
+    let band = LEVEL_BAND * amplitude;
+    let finite = waveform.iter().filter(|v| v.is_finite()).count();
+    let on_level = waveform.iter().filter(|&&v| (v - base).abs() <= band || (v - top).abs() <= band).count();
+    (on_level as f32 >= MIN_LEVEL_FRACTION * finite as f32).then_some((base, top))
+}
+
+/// Duty cycle, pulse widths, overshoot and preshoot of a square wave.
+///
+/// Base and top level are found with the histogram mode method, and edges
+/// are timed where they cross the 50% reference level between them.
+/// Returns `None` if the signal does not have two distinct levels or holds
+/// fewer than two full cycles.
+pub fn measure_pulses(time: &[f32], waveform: &[f32]) -> Option<PulseMeasurements> {
+    let len = time.len().min(waveform.len());
+    let (time, waveform) = (&time[..len], &waveform[..len]);
+    let (base, top) = pulse_levels(waveform)?;
+    let amplitude = top - base;
+
+    let crossings = find_crossings_with_hysteresis(time, waveform, (base + top) / 2.0,
+        REFERENCE_HYSTERESIS * amplitude, Edge::Both);
+    let width = |edge: Edge| -> Vec<f32> {
+        crossings.windows(2)
+            .filter(|pair| pair[0].edge == edge)
+            .map(|pair| pair[1].time_s - pair[0].time_s)
+            .collect()
+    };
+    let duty_cycles: Vec<f32> = crossings.windows(3)
+        .filter(|edges| edges[0].edge == Edge::Rising)
+        .map(|edges| 100.0 * (edges[1].time_s - edges[0].time_s) / (edges[2].time_s - edges[0].time_s))
+        .collect();
+    if duty_cycles.len() < 2 {
+        return None;
+    }
+
+    let min = waveform.iter().copied().filter(|v| v.is_finite()).fold(f32::INFINITY, f32::min);
+    let max = waveform.iter().copied().filter(|v| v.is_finite()).fold(f32::NEG_INFINITY, f32::max);
+    Some(PulseMeasurements {
+        base,
+        top,
+        cycles: duty_cycles.len(),
+        duty_cycle_percent: MeasurementSpread::of(&duty_cycles),
+        positive_width_s: MeasurementSpread::of(&width(Edge::Rising)),
+        negative_width_s: MeasurementSpread::of(&width(Edge::Falling)),
+        overshoot_percent: (100.0 * (max - top) / amplitude).max(0.0),
+        preshoot_percent: (100.0 * (base - min) / amplitude).max(0.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `periods` periods of 100 samples of a 0 to 5 V PWM signal with
+    /// `high` samples high per period.
+    fn pwm(periods: usize, high: usize) -> (Vec<f32>, Vec<f32>) {
+        (0..periods * 100)
+            .map(|i| (i as f32 * 1e-6, if i % 100 < high { 5.0 } else { 0.0 }))
+            .unzip()
+    }
+
+    #[test]
+    fn measures_duty_cycle_and_pulse_widths() {
+        let (time, mut waveform) = pwm(5, 25);
+        // Overshoot after one rising edge, preshoot in one low phase
+        waveform[100] = 5.5;
+        waveform[101] = 4.8;
+        waveform[250] = -0.25;
+
+        let pulses = measure_pulses(&time, &waveform).unwrap();
+        assert!((pulses.base - 0.0).abs() < 0.05, "{:?}", pulses);
+        assert!((pulses.top - 5.0).abs() < 0.05, "{:?}", pulses);
+        assert_eq!(pulses.cycles, 3);
+        assert!((pulses.duty_cycle_percent.mean - 25.0).abs() < 1.0);
+        assert!((pulses.positive_width_s.mean - 25e-6).abs() < 1e-6);
+        assert!((pulses.negative_width_s.mean - 75e-6).abs() < 1e-6);
+        assert!(pulses.positive_width_s.min <= pulses.positive_width_s.max);
+        assert!((pulses.overshoot_percent - 10.0).abs() < 1.0);
+        assert!((pulses.preshoot_percent - 5.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn needs_two_full_cycles() {
+        // The first rising edge is at the end of the first period
+        let (time, waveform) = pwm(2, 50);
+        assert_eq!(measure_pulses(&time, &waveform), None);
+        let (time, waveform) = pwm(4, 50);
+        assert!(measure_pulses(&time, &waveform).is_some());
+    }
+
+    #[test]
+    fn rejects_signals_without_two_levels() {
+        let time: Vec<f32> = (0..1000).map(|i| i as f32 * 1e-6).collect();
+        let sine: Vec<f32> = time.iter().map(|t| (2e4 * std::f32::consts::PI * t).sin()).collect();
+        assert_eq!(measure_pulses(&time, &sine), None);
+        assert_eq!(measure_pulses(&time, &vec![1.0; 1000]), None);
+        assert_eq!(measure_pulses(&[], &[]), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Resampling onto a uniform time grid.
+
+use std::f64::consts::PI;
+
+use anyhow::{Result, anyhow};
+
+/// Cutoff of the anti-aliasing filter relative to the output Nyquist
+/// frequency, leaving room for the filter's transition band.
+const CUTOFF_FRACTION: f64 = 0.9;
+
+/// Half length of the filter kernel in output sample periods. Longer
+/// kernels give a steeper transition and flatter passband.
+const KERNEL_HALF_WIDTH: f64 = 16.0;
+
+/// Resample a waveform onto a uniform grid of `new_sample_rate_hz`.
+///
+/// The samples may be irregularly spaced, e.g. after decimation or when
+/// merging channels. They are first linearly interpolated onto a uniform
+/// grid at their mean sample rate. That is then low-pass filtered below
+/// the new Nyquist frequency with a Blackman windowed sinc, evaluated at
+/// the new sample times, so no content above it aliases into the result.
+/// Near both ends the kernel is truncated and renormalized.
+///
+/// The output starts at the first input time. `new_sample_rate_hz` must
+/// not exceed the input's Nyquist frequency, half its mean sample rate,
+/// so the rate is always reduced by at least 2.
+pub fn resample_uniform(time: &[f32], waveform: &[f32], new_sample_rate_hz: f64) -> Result<(Vec<f32>, Vec<f32>)> {
+    if time.len() != waveform.len() {
+        return Err(anyhow!("{} time values for {} samples", time.len(), waveform.len()));
+    }
+    if time.len() < 2 {
+        return Err(anyhow!("At least two samples are needed to resample"));
+    }
+    if time.windows(2).any(|pair| pair[1] <= pair[0]) {
+        return Err(anyhow!("Time values must increase strictly"));
+    }
+    if !new_sample_rate_hz.is_finite() || new_sample_rate_hz <= 0.0 {
+        return Err(anyhow!("Invalid sample rate {} Hz", new_sample_rate_hz));
+    }
+    let start = time[0] as f64;
+    let span = time[time.len() - 1] as f64 - start;
+    let input_rate = (time.len() - 1) as f64 / span;
+    if new_sample_rate_hz > input_rate / 2.0 {
+        return Err(anyhow!("Sample rate {} Hz exceeds the input Nyquist frequency of {} Hz",
+            new_sample_rate_hz, input_rate / 2.0));
+    }
+
+    // Linear interpolation onto the uniform input grid
+    let input_step = 1.0 / input_rate;
+    let mut segment = 0;
+    let uniform: Vec<f64> = (0..time.len())
+        .map(|n| {
+            let t = start + n as f64 * input_step;
+            while segment + 2 < time.len() && time[segment + 1] as f64 <= t {
+                segment += 1;
+            }
+            let (t0, t1) = (time[segment] as f64, time[segment + 1] as f64);
+            let fraction = ((t - t0) / (t1 - t0)).clamp(0.0, 1.0);
+            waveform[segment] as f64 + fraction * (waveform[segment + 1] as f64 - waveform[segment] as f64)
+        })
+        .collect();
+
+    // Windowed sinc low-pass, in units of input samples
+    let cutoff = CUTOFF_FRACTION * new_sample_rate_hz / 2.0 / input_rate;
+    let half_width = KERNEL_HALF_WIDTH * input_rate / new_sample_rate_hz;
+    let kernel = |offset: f64| {
+        let x = offset / half_width;
+        if x.abs() >= 1.0 {
+            return 0.0;
+        }
+        let sinc = if offset == 0.0 { 1.0 } else { (2.0 * PI * cutoff * offset).sin() / (2.0 * PI * cutoff * offset) };
+        let window = 0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos();
+        sinc * window
+    };
+
+    let count = (span * new_sample_rate_hz).floor() as usize + 1;
+    let mut new_time = Vec::with_capacity(count);
+    let mut new_waveform = Vec::with_capacity(count);
+    for m in 0..count {
+        let t = m as f64 / new_sample_rate_hz;
+        // Position on the uniform input grid
+        let position = t * input_rate;
+        let first = (position - half_width).ceil().max(0.0) as usize;
+        let last = ((position + half_width).floor() as usize).min(uniform.len() - 1);
+        let (mut sum, mut weights) = (0.0, 0.0);
+        for (n, &value) in uniform.iter().enumerate().take(last + 1).skip(first) {
+            let weight = kernel(n as f64 - position);
+            sum += weight * value;
+            weights += weight;
+        }
+        new_time.push((start + t) as f32);
+        new_waveform.push((sum / weights) as f32);
+    }
+    Ok((new_time, new_waveform))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_sine_amplitude_when_downsampling() {
+        // 1 s of a 100 Hz sine at 10 kS/s, resampled to 1 kS/s
+        let time: Vec<f32> = (0..10_000).map(|n| n as f32 / 10_000.0).collect();
+        let waveform: Vec<f32> = time.iter().map(|&t| (2.0 * PI * 100.0 * t as f64).sin() as f32).collect();
+        let (new_time, new_waveform) = resample_uniform(&time, &waveform, 1_000.0).unwrap();
+        assert_eq!(new_time.len(), 1_000);
+        assert!((new_time[1] - new_time[0] - 1e-3).abs() < 1e-7);
+
+        // Away from the truncated kernels at both ends
+        let interior = 20..980;
+        for (&t, &v) in new_time[interior.clone()].iter().zip(&new_waveform[interior.clone()]) {
+            let expected = (2.0 * PI * 100.0 * t as f64).sin() as f32;
+            assert!((v - expected).abs() < 1e-3, "{} at {} s, expected {}", v, t, expected);
+        }
+        // Amplitude from the projection onto a sine and cosine over 96
+        // whole periods
+        let (mut in_phase, mut quadrature) = (0.0, 0.0);
+        for (&t, &v) in new_time[interior.clone()].iter().zip(&new_waveform[interior.clone()]) {
+            let phase = 2.0 * PI * 100.0 * t as f64;
+            in_phase += v as f64 * phase.sin();
+            quadrature += v as f64 * phase.cos();
+        }
+        let amplitude = 2.0 * in_phase.hypot(quadrature) / interior.len() as f64;
+        assert!((amplitude - 1.0).abs() < 1e-3, "amplitude {}", amplitude);
+    }
+
+    #[test]
+    fn removes_content_above_the_new_nyquist() {
+        // 100 Hz plus 3 kHz, which would alias to 0 Hz at 1 kS/s
+        let time: Vec<f32> = (0..10_000).map(|n| n as f32 / 10_000.0).collect();
+        let waveform: Vec<f32> = time.iter()
+            .map(|&t| ((2.0 * PI * 100.0 * t as f64).sin() + (2.0 * PI * 3_000.0 * t as f64).cos()) as f32)
+            .collect();
+        let (new_time, new_waveform) = resample_uniform(&time, &waveform, 1_000.0).unwrap();
+        for (&t, &v) in new_time[20..980].iter().zip(&new_waveform[20..980]) {
+            let expected = (2.0 * PI * 100.0 * t as f64).sin() as f32;
+            assert!((v - expected).abs() < 1e-3, "{} at {} s, expected {}", v, t, expected);
+        }
+    }
+
+    #[test]
+    fn handles_irregular_grids_and_rejects_invalid_rates() {
+        // Every other interval twice as long, 15 samples per 2 ms
+        let time: Vec<f32> = (0..3_000).map(|n| (n / 2 * 3 + n % 2) as f32 * 1e-4 / 1.5).collect();
+        let waveform = vec![0.5f32; time.len()];
+        let (_, resampled) = resample_uniform(&time, &waveform, 1_000.0).unwrap();
+        assert!(resampled.iter().all(|&v| (v - 0.5).abs() < 1e-6));
+
+        let time: Vec<f32> = (0..100).map(|n| n as f32 * 1e-4).collect();
+        let waveform = vec![0.0f32; 100];
+        assert!(resample_uniform(&time, &waveform, 6_000.0).is_err());
+        assert!(resample_uniform(&time, &waveform, 0.0).is_err());
+        assert!(resample_uniform(&time, &waveform[..50], 1_000.0).is_err());
+        assert!(resample_uniform(&[0.0, 0.0], &[1.0, 1.0], 1.0).is_err());
+        assert!(resample_uniform(&time, &waveform, 4_000.0).is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+//! Software equivalents of the instrument's edge, pulse and runt search.
+
+use super::crossings::{find_crossings, find_crossings_with_hysteresis, Edge};
+
+/// A complete pulse found by [`search_pulses`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PulseEvent {
+    pub start_s: f32,
+    pub end_s: f32,
+    pub width_s: f32,
+    /// High pulse if true, low pulse otherwise.
+    pub is_positive: bool,
+}
+
+/// A pulse that crossed only one of two thresholds, found by
+/// [`search_runts`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuntEvent {
+    pub start_s: f32,
+    pub end_s: f32,
+    pub width_s: f32,
+    /// Rose above the low threshold if true, fell below the high one
+    /// otherwise.
+    pub is_positive: bool,
+    /// Highest voltage of a positive runt, lowest of a negative one.
+    pub peak_v: f32,
+}
+
+/// Times of all crossings of `threshold` in direction `slope`.
+pub fn search_edges(time: &[f32], waveform: &[f32], threshold: f32, slope: Edge) -> Vec<f32> {
+    find_crossings(time, waveform, threshold, slope).into_iter().map(|crossing| crossing.time_s).collect()
+}
+
+/// Pulses between `min_width_s` and `max_width_s` long, high and low.
+///
+/// An edge has to pass both thresholds, so the band between them works
+/// as hysteresis. Pulses are timed where they pass the middle of the band,
+/// and only pulses with both edges inside the capture are reported. The
+/// thresholds may be given in either order.
+pub fn search_pulses(time: &[f32], waveform: &[f32], high_threshold: f32, low_threshold: f32, min_width_s: f32,
+    max_width_s: f32) -> Vec<PulseEvent> {
+    let (low, high) = (low_threshold.min(high_threshold), low_threshold.max(high_threshold));
+    let crossings = find_crossings_with_hysteresis(time, waveform, (low + high) / 2.0, high - low, Edge::Both);
+    crossings.windows(2)
+        .map(|pair| PulseEvent {
+            start_s: pair[0].time_s,
+            end_s: pair[1].time_s,
+            width_s: pair[1].time_s - pair[0].time_s,
+            is_positive: pair[0].edge == Edge::Rising,
+        })
+        .filter(|pulse| pulse.width_s >= min_width_s && pulse.width_s <= max_width_s)
+        .collect()
+}
+
+/// Runt pulses between `min_s` and `max_s` wide.
+///
+/// A positive runt rises above the low threshold and falls back below it
+/// without reaching the high one. A negative runt does the same downwards
+/// from above the high threshold. Runts are timed where they pass the
+/// threshold they crossed. The thresholds may be given in either order.
+pub fn search_runts(time: &[f32], waveform: &[f32], high_threshold: f32, low_threshold: f32, min_s: f32,
+    max_s: f32) -> Vec<RuntEvent> {
+    let (low, high) = (low_threshold.min(high_threshold), low_threshold.max(high_threshold));
+    let len = time.len().min(waveform.len());
+    // Time where the signal passed `level` between samples i - 1 and i
+    let crossing = |i: usize, level: f32| {
+        let fraction = (level - waveform[i - 1]) / (waveform[i] - waveform[i - 1]);
+        time[i - 1] + fraction * (time[i] - time[i - 1])
+    };
+
+    let mut runts = Vec::new();
+    // Side the signal last settled on, true for above the high threshold
+    let mut settled: Option<bool> = None;
+    // Start time and peak of an excursion into the band
+    let mut excursion: Option<(f32, f32)> = None;
+    for (i, &v) in waveform.iter().enumerate().take(len) {
+        let side = if v > high {
+            Some(true)
+        } else if v < low {
+            Some(false)
+        } else {
+            None
+        };
+        match (side, settled) {
+            (None, Some(above)) => {
+                let (start, peak) = excursion.unwrap_or_else(|| {
+                    (crossing(i, if above { high } else { low }), v)
+                });
+                let peak = if above { peak.min(v) } else { peak.max(v) };
+                excursion = Some((start, peak));
+            }
+            (Some(side), Some(above)) => {
+                if let Some((start_s, peak_v)) = excursion.take() {
+                    // Back on the side it came from without passing the
+                    // other threshold
+                    if side == above {
+                        let end_s = crossing(i, if above { high } else { low });
+                        let width_s = end_s - start_s;
+                        if width_s >= min_s && width_s <= max_s {
+                            runts.push(RuntEvent { start_s, end_s, width_s, is_positive: !above, peak_v });
+                        }
+                    }
+                }
+                settled = Some(side);
+            }
+            (Some(side), None) => settled = Some(side),
+            (None, None) => {}
+        }
+    }
+    runts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(levels: &[f32]) -> (Vec<f32>, Vec<f32>) {
+        ((0..levels.len()).map(|n| n as f32).collect(), levels.to_vec())
+    }
+
+    #[test]
+    fn finds_edges() {
+        let (time, waveform) = signal(&[0.0, 2.0, 2.0, 0.0, 2.0]);
+        assert_eq!(search_edges(&time, &waveform, 1.0, Edge::Rising), [0.5, 3.5]);
+        assert_eq!(search_edges(&time, &waveform, 1.0, Edge::Falling), [2.5]);
+        assert_eq!(search_edges(&time, &waveform, 1.0, Edge::Both), [0.5, 2.5, 3.5]);
+    }
+
+    #[test]
+    fn finds_pulses_within_width_limits() {
+        // High for 2 and 5 samples, low for 3 in between
+        let (time, waveform) = signal(&[0.0, 0.0, 3.0, 3.0, 0.0, 0.0, 0.0, 3.0, 3.0, 3.0, 3.0, 3.0, 0.0]);
+        let pulses = search_pulses(&time, &waveform, 2.0, 1.0, 0.0, 10.0);
+        assert_eq!(pulses.len(), 3);
+        assert_eq!(pulses[0], PulseEvent { start_s: 1.5, end_s: 3.5, width_s: 2.0, is_positive: true });
+        assert_eq!(pulses[1], PulseEvent { start_s: 3.5, end_s: 6.5, width_s: 3.0, is_positive: false });
+        assert_eq!(pulses[2].width_s, 5.0);
+
+        let wide = search_pulses(&time, &waveform, 1.0, 2.0, 2.5, 10.0);
+        assert_eq!(wide.iter().map(|pulse| pulse.width_s).collect::<Vec<_>>(), [3.0, 5.0]);
+    }
+
+    #[test]
+    fn finds_runts_but_not_full_pulses() {
+        // A full pulse, a positive runt up to 1.5 V and a negative runt
+        // down to 1.2 V
+        let (time, waveform) = signal(&[0.0, 3.0, 3.0, 0.0, 1.5, 0.0, 3.0, 1.2, 3.0, 0.0]);
+        let runts = search_runts(&time, &waveform, 2.0, 1.0, 0.0, 10.0);
+        assert_eq!(runts.len(), 2);
+
+        let positive = runts[0];
+        assert!(positive.is_positive);
+        assert_eq!(positive.peak_v, 1.5);
+        assert!((positive.start_s - (3.0 + 1.0 / 1.5)).abs() < 1e-6);
+        assert!((positive.end_s - (4.0 + 0.5 / 1.5)).abs() < 1e-6);
+
+        let negative = runts[1];
+        assert!(!negative.is_positive);
+        assert_eq!(negative.peak_v, 1.2);
+        assert!((negative.width_s - 2.0 * 0.8 / 1.8).abs() < 1e-6);
+
+        assert_eq!(search_runts(&time, &waveform, 2.0, 1.0, 1.0, 10.0).len(), 0);
+    }
+}
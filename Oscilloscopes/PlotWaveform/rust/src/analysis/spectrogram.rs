@@ -0,0 +1,186 @@
+//! Short-time Fourier transform for chirps and transients.
+
+use std::f64::consts::PI;
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::persistence::Colormap;
+
+/// Range below the strongest cell that [`plot_spectrogram`] spreads the
+/// colormap over. Weaker cells are drawn black.
+const PLOT_DYNAMIC_RANGE_DB: f32 = 100.0;
+
+/// Spectra of overlapping frames of a waveform.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spectrogram {
+    /// Level of every frequency bin per frame, `power_db[frame][bin]`, in
+    /// dBFS with a 1 V amplitude sine as full scale.
+    pub power_db: Vec<Vec<f32>>,
+    /// Bin frequencies from 0 to Nyquist in Hz.
+    pub frequencies: Vec<f64>,
+    /// Frame centres in seconds from the first sample.
+    pub times: Vec<f64>,
+}
+
+/// Compute the spectrogram of `waveform`.
+///
+/// Frames of `window_size` samples start every `hop_size` samples. Each is
+/// weighted with a Hann window and transformed. Levels are corrected for
+/// the window's gain, so a sine of amplitude A shows A in dB at its bin.
+pub fn compute_spectrogram(waveform: &[f32], sample_rate: f64, window_size: usize, hop_size: usize)
+    -> Result<Spectrogram> {
+    if !sample_rate.is_finite() || sample_rate <= 0.0 {
+        return Err(anyhow!("Invalid sample rate {} Hz", sample_rate));
+    }
+    if window_size < 2 || !window_size.is_power_of_two() {
+        return Err(anyhow!("Window size {} is not a power of two", window_size));
+    }
+    if hop_size == 0 || hop_size >= window_size {
+        return Err(anyhow!("Hop size {} must be between 1 and the window size {}", hop_size, window_size));
+    }
+    if waveform.len() < window_size {
+        return Err(anyhow!("{} samples are fewer than one window of {}", waveform.len(), window_size));
+    }
+
+    let window: Vec<f64> = (0..window_size)
+        .map(|n| 0.5 * (1.0 - (2.0 * PI * n as f64 / window_size as f64).cos()))
+        .collect();
+    // Amplitude of a sine from its bin magnitude, both sides folded
+    let amplitude_scale = 2.0 / window.iter().sum::<f64>();
+    let bins = window_size / 2 + 1;
+
+    let fft = FftPlanner::<f64>::new().plan_fft_forward(window_size);
+    let mut buffer = vec![Complex::new(0.0, 0.0); window_size];
+    let mut power_db = Vec::new();
+    let mut times = Vec::new();
+    for start in (0..=waveform.len() - window_size).step_by(hop_size) {
+        for ((value, &sample), &w) in buffer.iter_mut().zip(&waveform[start..start + window_size]).zip(&window) {
+            *value = Complex::new(sample as f64 * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        power_db.push(buffer[..bins].iter().enumerate()
+            .map(|(bin, value)| {
+                // DC and Nyquist have no mirrored half to fold in
+                let scale = if bin == 0 || bin == window_size / 2 { amplitude_scale / 2.0 } else { amplitude_scale };
+                (20.0 * (value.norm() * scale).max(f64::MIN_POSITIVE).log10()) as f32
+            })
+            .collect());
+        times.push((start as f64 + window_size as f64 / 2.0) / sample_rate);
+    }
+    let frequencies = (0..bins).map(|bin| bin as f64 * sample_rate / window_size as f64).collect();
+
+    info!("Spectrogram of {} frames with {} bins", times.len(), bins);
+    Ok(Spectrogram { power_db, frequencies, times })
+}
+
+/// Plot a spectrogram as PNG with time on the x axis, frequency on the y
+/// axis and the level in the hot colormap.
+pub fn plot_spectrogram(spec: &Spectrogram, output_path: &str) -> Result<()> {
+    let (Some(&first_time), Some(&last_time)) = (spec.times.first(), spec.times.last()) else {
+        return Err(anyhow!("Spectrogram has no frames"));
+    };
+    let Some(&max_frequency) = spec.frequencies.last() else {
+        return Err(anyhow!("Spectrogram has no frequency bins"));
+    };
+    if spec.power_db.len() != spec.times.len()
+        || spec.power_db.iter().any(|frame| frame.len() != spec.frequencies.len()) {
+        return Err(anyhow!("Spectrogram levels don't match its times and frequencies"));
+    }
+    let max_db = spec.power_db.iter().flatten().copied().fold(f32::NEG_INFINITY, f32::max);
+
+    info!("Creating spectrogram plot");
+    let root = BitMapBackend::new(output_path, (1200, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    // A single frame still gets a time axis of non-zero width
+    let time_range = if last_time > first_time { first_time..last_time } else { first_time - 0.5..first_time + 0.5 };
+    let mut chart = ChartBuilder::on(&root)
+        .caption("Spectrogram", ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(80)
+        .build_cartesian_2d(time_range, 0.0..max_frequency)?;
+
+    chart
+        .configure_mesh()
+        .disable_mesh()
+        .x_desc("Time (s)")
+        .y_desc("Frequency (Hz)")
+        .draw()?;
+
+    // Color every pixel of the plot area from the nearest frame and bin
+    let area = chart.plotting_area().strip_coord_spec();
+    let (width, height) = area.dim_in_pixel();
+    let frames = spec.times.len();
+    let bins = spec.frequencies.len();
+    for x in 0..width {
+        let frame = (x as f64 / (width - 1).max(1) as f64 * (frames - 1) as f64).round() as usize;
+        for y in 0..height {
+            let fraction = 1.0 - y as f64 / (height - 1).max(1) as f64;
+            let bin = (fraction * (bins - 1) as f64).round() as usize;
+            let level = (spec.power_db[frame][bin] - max_db + PLOT_DYNAMIC_RANGE_DB) / PLOT_DYNAMIC_RANGE_DB;
+            area.draw_pixel((x as i32, y as i32), &Colormap::Hot.color(level as f64))?;
+        }
+    }
+
+    root.present()?;
+    info!("Spectrogram plot saved as {}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_a_chirp() {
+        // Linear chirp from 50 to 450 Hz over 1 s at 1 kHz sample rate
+        let sample_rate = 1000.0;
+        let waveform: Vec<f32> = (0..1000)
+            .map(|n| {
+                let t = n as f64 / sample_rate;
+                (2.0 * PI * (50.0 * t + 200.0 * t * t)).sin() as f32
+            })
+            .collect();
+        let spec = compute_spectrogram(&waveform, sample_rate, 64, 32).unwrap();
+        assert_eq!(spec.frequencies.len(), 33);
+        assert_eq!(spec.times.len(), (1000 - 64) / 32 + 1);
+        assert_eq!(spec.times[0], 0.032);
+
+        let peak_frequency = |frame: &[f32]| {
+            let bin = (0..frame.len()).max_by(|&a, &b| frame[a].total_cmp(&frame[b])).unwrap();
+            spec.frequencies[bin]
+        };
+        let peaks: Vec<f64> = spec.power_db.iter().map(|frame| peak_frequency(frame)).collect();
+        assert!(peaks.windows(2).all(|pair| pair[1] >= pair[0]), "{:?}", peaks);
+        for (&t, &peak) in spec.times.iter().zip(&peaks) {
+            let expected = 50.0 + 400.0 * t;
+            assert!((peak - expected).abs() <= 1000.0 / 64.0, "{} Hz at {} s, expected {}", peak, t, expected);
+        }
+    }
+
+    #[test]
+    fn measures_sine_amplitude() {
+        // 0.5 V amplitude sine centred on bin 8 of 64
+        let waveform: Vec<f32> = (0..256).map(|n| 0.5 * (2.0 * PI * 8.0 * n as f64 / 64.0).sin() as f32).collect();
+        let spec = compute_spectrogram(&waveform, 1e3, 64, 16).unwrap();
+        for frame in &spec.power_db {
+            assert!((frame[8] - 20.0 * 0.5f32.log10()).abs() < 0.01, "{} dB", frame[8]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let waveform = vec![0.0f32; 1024];
+        assert!(compute_spectrogram(&waveform, 1e3, 100, 10).is_err());
+        assert!(compute_spectrogram(&waveform, 1e3, 64, 64).is_err());
+        assert!(compute_spectrogram(&waveform, 1e3, 64, 0).is_err());
+        assert!(compute_spectrogram(&waveform, 0.0, 64, 16).is_err());
+        assert!(compute_spectrogram(&waveform, 1e3, 2048, 16).is_err());
+        assert!(compute_spectrogram(&waveform, 1e3, 64, 63).is_ok());
+    }
+}
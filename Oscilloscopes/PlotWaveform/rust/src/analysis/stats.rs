@@ -0,0 +1,211 @@
+//! Amplitude statistics of waveforms.
+
+use anyhow::{Result, anyhow};
+use log::info;
+
+use crate::measurement::measurement_value;
+use crate::{check_channel, DataRange, OscilloscopeWaveform, ScopeError, TransferFormat};
+
+/// Amplitude statistics in volts.
+///
+/// All values are NaN for an empty waveform, and the crest factor is NaN
+/// when the RMS value is zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaveformStats {
+    pub mean: f32,
+    pub rms: f32,
+    /// Population standard deviation, i.e. the AC RMS value.
+    pub std_dev: f32,
+    pub peak_to_peak: f32,
+    pub min: f32,
+    pub max: f32,
+    /// Largest absolute value divided by the RMS value.
+    pub crest_factor: f32,
+}
+
+impl WaveformStats {
+    /// Compute the statistics of a whole waveform.
+    pub fn compute(waveform: &[f32]) -> WaveformStats {
+        if waveform.is_empty() {
+            return Self::from_measurements(f32::NAN, f32::NAN, f32::NAN, f32::NAN);
+        }
+
+        let count = waveform.len() as f64;
+        let mut sum = 0.0f64;
+        let mut sum_squares = 0.0f64;
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        for &v in waveform {
+            sum += v as f64;
+            sum_squares += v as f64 * v as f64;
+            min = min.min(v);
+            max = max.max(v);
+        }
+        Self::from_measurements((sum / count) as f32, (sum_squares / count).sqrt() as f32, min, max)
+    }
+
+    /// Compute the statistics of `len` samples starting at `start`.
+    pub fn compute_window(waveform: &[f32], start: usize, len: usize) -> Result<WaveformStats> {
+        let window = start.checked_add(len)
+            .and_then(|end| waveform.get(start..end))
+            .ok_or_else(|| anyhow!(
+                "Window of {} samples at {} exceeds the {} samples of the waveform", len, start, waveform.len()
+            ))?;
+        Ok(Self::compute(window))
+    }
+
+    /// Derive the remaining values from mean, RMS and extremes.
+    fn from_measurements(mean: f32, rms: f32, min: f32, max: f32) -> WaveformStats {
+        let peak = min.abs().max(max.abs());
+        WaveformStats {
+            mean,
+            rms,
+            // Rounding can make the difference slightly negative
+            std_dev: (rms * rms - mean * mean).max(0.0).sqrt(),
+            peak_to_peak: max - min,
+            min,
+            max,
+            crest_factor: if rms > 0.0 { peak / rms } else { f32::NAN },
+        }
+    }
+}
+
+/// Scale a waveform so its minimum becomes 0.0 and its maximum 1.0. A flat
+/// waveform maps to all zeros.
+pub fn normalize_waveform(waveform: &[f32]) -> Vec<f32> {
+    let (min, max) = waveform.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| {
+        (min.min(v), max.max(v))
+    });
+    let range = max as f64 - min as f64;
+    waveform.iter()
+        .map(|&v| if range > 0.0 { ((v as f64 - min as f64) / range) as f32 } else { 0.0 })
+        .collect()
+}
+
+/// Subtract the mean, leaving only the AC part of a waveform.
+pub fn remove_dc_offset(waveform: &[f32]) -> Vec<f32> {
+    let mean = waveform.iter().map(|&v| v as f64).sum::<f64>() / waveform.len().max(1) as f64;
+    waveform.iter().map(|&v| (v as f64 - mean) as f32).collect()
+}
+
+/// Subtract the least-squares line through a waveform, so a drifting
+/// baseline does not add to the RMS value of an AC measurement.
+///
+/// Only as many samples as both slices hold are used. Without a time
+/// span to fit a slope to, e.g. for a single sample, only the mean is
+/// subtracted.
+pub fn detrend_linear(time: &[f32], waveform: &[f32]) -> Vec<f32> {
+    let count = time.len().min(waveform.len());
+    let (time, waveform) = (&time[..count], &waveform[..count]);
+    let n = count.max(1) as f64;
+    let mean_time = time.iter().map(|&t| t as f64).sum::<f64>() / n;
+    let mean_value = waveform.iter().map(|&v| v as f64).sum::<f64>() / n;
+
+    // Centred on the mean time, so large time offsets don't cost precision
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (&t, &v) in time.iter().zip(waveform) {
+        let dt = t as f64 - mean_time;
+        covariance += dt * (v as f64 - mean_value);
+        variance += dt * dt;
+    }
+    let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
+    time.iter().zip(waveform)
+        .map(|(&t, &v)| (v as f64 - mean_value - slope * (t as f64 - mean_time)) as f32)
+        .collect()
+}
+
+impl OscilloscopeWaveform {
+    /// Statistics of a channel from the instrument's measurements.
+    ///
+    /// If the instrument does not support the `MEASure` queries, the
+    /// channel is captured in volts and the statistics are computed from
+    /// the samples instead.
+    pub fn query_channel_stats(&self, channel: u8) -> Result<WaveformStats, ScopeError> {
+        check_channel(channel)?;
+        match self.query_onboard_stats(channel) {
+            Err(ScopeError::Timeout | ScopeError::UnexpectedResponse { .. } | ScopeError::ScpiError { .. }) => {
+                info!("No onboard measurements, computing statistics of channel {} from a capture", channel);
+                // A query the instrument did not answer may still be pending
+                self.device.clear()?;
+                self.check_errors()?;
+                let (_, waveform) = self.get_waveform_data(channel, DataRange::All, TransferFormat::Volts, None)?;
+                Ok(WaveformStats::compute(&waveform))
+            }
+            result => result,
+        }
+    }
+
+    fn query_onboard_stats(&self, channel: u8) -> Result<WaveformStats, ScopeError> {
+        let measure = |name: &str| {
+            let command = format!("MEASure:{}? CHAN{}", name, channel);
+            let value = self.query_f64(&command)?;
+            measurement_value(value)
+                .map(|value| value as f32)
+                .ok_or_else(|| ScopeError::UnexpectedResponse { command, response: value.to_string() })
+        };
+        let mean = measure("MEAN")?;
+        let rms = measure("RMS")?;
+        let min = measure("MINimum")?;
+        let max = measure("MAXimum")?;
+        self.verify_no_errors("statistics measurement")?;
+        Ok(WaveformStats::from_measurements(mean, rms, min, max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_basic_statistics() {
+        let stats = WaveformStats::compute(&[1.0, 3.0, -1.0, 1.0]);
+        assert_eq!(stats.mean, 1.0);
+        assert_eq!(stats.rms, 3.0f32.sqrt());
+        assert!((stats.std_dev - 2.0f32.sqrt()).abs() < 1e-6);
+        assert_eq!((stats.min, stats.max, stats.peak_to_peak), (-1.0, 3.0, 4.0));
+        assert!((stats.crest_factor - 3.0 / 3.0f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sine_has_crest_factor_sqrt_two() {
+        let sine: Vec<f32> = (0..1000)
+            .map(|n| (2.0 * std::f32::consts::PI * n as f32 / 100.0).sin())
+            .collect();
+        let stats = WaveformStats::compute(&sine);
+        assert!((stats.rms - 0.5f32.sqrt()).abs() < 1e-4);
+        assert!((stats.crest_factor - 2.0f32.sqrt()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn normalizes_and_removes_baselines() {
+        assert_eq!(normalize_waveform(&[-2.0, 0.0, 2.0, 1.0]), [0.0, 0.5, 1.0, 0.75]);
+        assert_eq!(normalize_waveform(&[3.0, 3.0]), [0.0, 0.0]);
+        assert_eq!(remove_dc_offset(&[1.0, 2.0, 3.0]), [-1.0, 0.0, 1.0]);
+        for empty in [normalize_waveform(&[]), remove_dc_offset(&[]), detrend_linear(&[], &[])] {
+            assert!(empty.is_empty());
+        }
+
+        // A 1 V cosine on a baseline drifting from 5 to 7 V over 1 ms. A sine
+        // would correlate with the ramp and tilt the fit
+        let time: Vec<f32> = (0..1000).map(|n| 1.0 + n as f32 * 1e-6).collect();
+        let cosine: Vec<f32> = (0..1000).map(|n| (2.0 * std::f32::consts::PI * n as f32 / 100.0).cos()).collect();
+        let drifting: Vec<f32> = cosine.iter().enumerate().map(|(n, &v)| v + 5.0 + 2.0 * n as f32 / 1000.0).collect();
+        let detrended = detrend_linear(&time, &drifting);
+        let rms = WaveformStats::compute(&detrended).rms;
+        assert!((rms - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3, "RMS {}", rms);
+        assert!(detrended.iter().zip(&cosine).all(|(a, b)| (a - b).abs() < 0.02));
+        assert_eq!(detrend_linear(&[0.0], &[4.0]), [0.0]);
+    }
+
+    #[test]
+    fn computes_windows_and_rejects_bad_ranges() {
+        let waveform = [10.0, 1.0, 2.0, 3.0, 10.0];
+        let stats = WaveformStats::compute_window(&waveform, 1, 3).unwrap();
+        assert_eq!((stats.mean, stats.min, stats.max), (2.0, 1.0, 3.0));
+        assert!(WaveformStats::compute_window(&waveform, 3, 3).is_err());
+        assert!(WaveformStats::compute_window(&waveform, usize::MAX, 2).is_err());
+
+        let empty = WaveformStats::compute(&[]);
+        assert!(empty.mean.is_nan() && empty.crest_factor.is_nan());
+    }
+}
@@ -0,0 +1,148 @@
+//! Async facade over the blocking API, enabled with the `async` feature.
+//!
+//! VISA calls block, so every call runs on tokio's blocking thread pool
+//! while the executor keeps serving other tasks. Calls are serialized on
+//! the session in the order they start.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::warn;
+use tokio::task;
+
+use crate::settings::AcquisitionMode;
+use crate::{DataRange, DeviceSelector, OscilloscopeWaveform, Result, ScopeError, TransferFormat, WaveformRecord};
+
+/// Marks the session as interrupted unless disarmed, i.e. when the future
+/// owning it is dropped before its call completed.
+struct CancelGuard {
+    interrupted: Arc<AtomicBool>,
+    armed: bool,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            self.interrupted.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Async wrapper of an [`OscilloscopeWaveform`] for tokio applications.
+///
+/// Dropping a future cancels the wait, not the VISA call: the call runs
+/// to completion or timeout in the background. The session is then
+/// marked as interrupted and the next call starts with a device clear,
+/// which discards any response still pending from the abandoned call.
+#[derive(Clone)]
+pub struct AsyncOscilloscopeWaveform {
+    scope: Arc<Mutex<OscilloscopeWaveform>>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl AsyncOscilloscopeWaveform {
+    /// Open an instrument without blocking the executor.
+    pub async fn open(selector: DeviceSelector) -> Result<Self> {
+        let scope = join(task::spawn_blocking(move || OscilloscopeWaveform::open(&selector)).await)?;
+        Ok(Self::from_blocking(scope))
+    }
+
+    /// Wrap an already opened instrument.
+    pub fn from_blocking(scope: OscilloscopeWaveform) -> Self {
+        Self { scope: Arc::new(Mutex::new(scope)), interrupted: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Run `call` with exclusive access to the blocking instrument.
+    ///
+    /// Methods without an async counterpart can be reached this way.
+    pub async fn call<T, F>(&self, call: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut OscilloscopeWaveform) -> Result<T> + Send + 'static,
+    {
+        let scope = Arc::clone(&self.scope);
+        let interrupted = Arc::clone(&self.interrupted);
+        let mut guard = CancelGuard { interrupted: Arc::clone(&self.interrupted), armed: true };
+        let result = task::spawn_blocking(move || {
+            let mut scope = scope.lock().unwrap_or_else(PoisonError::into_inner);
+            if interrupted.swap(false, Ordering::SeqCst) {
+                warn!("Previous call was cancelled, clearing the device");
+                scope.device.clear()?;
+                scope.check_errors()?;
+            }
+            let result = call(&mut scope);
+            // A timeout can leave part of a response in the device
+            if matches!(result, Err(ScopeError::Timeout)) {
+                interrupted.store(true, Ordering::SeqCst);
+            }
+            result
+        }).await;
+        guard.armed = false;
+        join(result)
+    }
+
+    /// Async version of [`OscilloscopeWaveform::get_waveform_data`].
+    pub async fn get_waveform_data(&self, channel: u8, range: DataRange, data_transfer_type: TransferFormat,
+        memory_depth: Option<u32>) -> Result<(Vec<f32>, Vec<f32>)> {
+        self.call(move |scope| scope.get_waveform_data(channel, range, data_transfer_type, memory_depth)).await
+    }
+
+    /// Async version of [`OscilloscopeWaveform::get_waveform_record`].
+    pub async fn get_waveform_record(&self, channel: u8, range: DataRange, memory_depth: Option<u32>)
+        -> Result<WaveformRecord> {
+        self.call(move |scope| scope.get_waveform_record(channel, range, memory_depth)).await
+    }
+
+    /// Async version of [`OscilloscopeWaveform::set_timebase`].
+    pub async fn set_timebase(&self, secs_per_div: f32, delay_s: f32) -> Result<()> {
+        self.call(move |scope| scope.set_timebase(secs_per_div, delay_s)).await
+    }
+
+    /// Async version of [`OscilloscopeWaveform::set_vertical`].
+    pub async fn set_vertical(&self, channel: u8, volts_per_div: f64, offset_v: f64) -> Result<()> {
+        self.call(move |scope| scope.set_vertical(channel, volts_per_div, offset_v)).await
+    }
+
+    /// Async version of [`OscilloscopeWaveform::set_acquisition_mode`].
+    pub async fn set_acquisition_mode(&self, mode: AcquisitionMode) -> Result<AcquisitionMode> {
+        self.call(move |scope| scope.set_acquisition_mode(mode)).await
+    }
+
+    /// Async version of [`OscilloscopeWaveform::capture_screenshot`].
+    pub async fn capture_screenshot(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        self.call(move |scope| scope.capture_screenshot(path)).await
+    }
+
+    /// Async version of [`OscilloscopeWaveform::idn`].
+    pub async fn idn(&self) -> Result<String> {
+        self.call(|scope| scope.idn()).await
+    }
+}
+
+/// Unwrap the result of a blocking task, passing on its panics.
+fn join<T>(result: std::result::Result<Result<T>, task::JoinError>) -> Result<T> {
+    match result {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        Err(error) => Err(ScopeError::Io(std::io::Error::other(error))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_guard_marks_session_interrupted() {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let mut guard = CancelGuard { interrupted: Arc::clone(&interrupted), armed: true };
+        guard.armed = false;
+        drop(guard);
+        assert!(!interrupted.load(Ordering::SeqCst));
+
+        drop(CancelGuard { interrupted: Arc::clone(&interrupted), armed: true });
+        assert!(interrupted.load(Ordering::SeqCst));
+    }
+}
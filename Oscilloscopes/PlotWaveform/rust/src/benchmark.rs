@@ -0,0 +1,148 @@
+//! Timing of waveform transfers for comparing data types and memory depths.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::settings::ChannelScaling;
+use crate::waveform::{decode_metadata, extract_waveform_into, pack_query};
+use crate::{check_channel, DataRange, OscilloscopeWaveform, Result, ScopeError, TransferFormat};
+
+/// Timing of one waveform read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferTiming {
+    /// From sending the data query until the block header arrived.
+    pub round_trip: Duration,
+    /// Reading the block data after the header.
+    pub transfer: Duration,
+    /// Decoding the block into voltages.
+    pub decode: Duration,
+    /// Size of the block data.
+    pub bytes: usize,
+}
+
+impl TransferTiming {
+    /// Round trip, transfer and decode together.
+    pub fn total(&self) -> Duration {
+        self.round_trip + self.transfer + self.decode
+    }
+}
+
+/// Repeated reads of one data type and memory depth.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkResult {
+    pub data_transfer_type: TransferFormat,
+    /// Memory depth the instrument applied, which may differ from the
+    /// requested one.
+    pub memory_depth: u32,
+    pub runs: Vec<TransferTiming>,
+}
+
+impl BenchmarkResult {
+    /// Mean timing over all runs, or all zero without runs.
+    pub fn mean(&self) -> TransferTiming {
+        let count = self.runs.len().max(1) as u32;
+        let sum = self.runs.iter().fold(TransferTiming::default(), |sum, run| TransferTiming {
+            round_trip: sum.round_trip + run.round_trip,
+            transfer: sum.transfer + run.transfer,
+            decode: sum.decode + run.decode,
+            bytes: sum.bytes + run.bytes,
+        });
+        TransferTiming {
+            round_trip: sum.round_trip / count,
+            transfer: sum.transfer / count,
+            decode: sum.decode / count,
+            bytes: sum.bytes / count as usize,
+        }
+    }
+
+    /// Mean block data rate in megabytes per second.
+    pub fn throughput_mb_s(&self) -> f64 {
+        let mean = self.mean();
+        let seconds = mean.transfer.as_secs_f64();
+        if seconds > 0.0 { mean.bytes as f64 / seconds / 1e6 } else { 0.0 }
+    }
+}
+
+/// Write every run of the results as one CSV row, with times in seconds.
+pub fn write_benchmark_csv(path: &str, results: &[BenchmarkResult]) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "data_transfer_type,memory_depth,run,bytes,round_trip_s,transfer_s,decode_s")?;
+    for result in results {
+        for (run, timing) in result.runs.iter().enumerate() {
+            writeln!(writer, "{},{},{},{},{},{},{}",
+                result.data_transfer_type, result.memory_depth, run + 1, timing.bytes,
+                timing.round_trip.as_secs_f64(), timing.transfer.as_secs_f64(), timing.decode.as_secs_f64())?;
+        }
+    }
+    writer.flush()?;
+    info!("Benchmark results saved as {}", path);
+    Ok(())
+}
+
+impl OscilloscopeWaveform {
+    /// Acquire once at `memory_depth` and read the channel `runs` times
+    /// as `data_transfer_type`, timing every read.
+    ///
+    /// The block and sample buffers are reused between runs, so only the
+    /// first run pays for their allocation. Progress callbacks are still
+    /// called and should be unset for clean numbers.
+    pub fn benchmark_transfer(&self, channel: u8, data_transfer_type: TransferFormat, memory_depth: u32, runs: usize)
+        -> Result<BenchmarkResult> {
+        check_channel(channel)?;
+        if runs == 0 {
+            return Err(ScopeError::InvalidArgument("Benchmark needs at least one run".to_string()));
+        }
+
+        let memory_depth = self.start_capture(&[channel], data_transfer_type, Some(memory_depth), 1)?;
+        info!("Benchmarking {} runs of {} at memory depth {}", runs, data_transfer_type, memory_depth);
+        let command = pack_query(channel, DataRange::All, data_transfer_type);
+        let mut block = Vec::new();
+        let mut samples = Vec::new();
+        let mut timings = Vec::with_capacity(runs);
+        for _ in 0..runs {
+            let start = Instant::now();
+            self.send_command(&command)?;
+            let transfer = self.read_binary_block_into(&mut block)?;
+            let round_trip = start.elapsed().saturating_sub(transfer);
+
+            let start = Instant::now();
+            let metadata = decode_metadata(&block, data_transfer_type, self.byte_order)?;
+            metadata.validate(data_transfer_type)?;
+            extract_waveform_into(&block, &metadata, data_transfer_type, ChannelScaling::default(), self.byte_order,
+                &mut samples)?;
+            let decode = start.elapsed();
+
+            timings.push(TransferTiming { round_trip, transfer, decode, bytes: block.len() });
+        }
+
+        Ok(BenchmarkResult { data_transfer_type, memory_depth, runs: timings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_runs() {
+        let run = |ms: u64, bytes| TransferTiming {
+            round_trip: Duration::from_millis(ms),
+            transfer: Duration::from_millis(2 * ms),
+            decode: Duration::from_millis(ms / 2),
+            bytes,
+        };
+        let result = BenchmarkResult {
+            data_transfer_type: TransferFormat::Raw,
+            memory_depth: 1000,
+            runs: vec![run(10, 2000), run(30, 2000)],
+        };
+        let mean = result.mean();
+        assert_eq!(mean, run(20, 2000));
+        assert_eq!(mean.total(), Duration::from_millis(70));
+        // 2000 bytes in 40 ms
+        assert!((result.throughput_mb_s() - 0.05).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,263 @@
+//! Live view of one channel in the terminal.
+//!
+//! A background thread owns the instrument and captures in a loop, sending
+//! every result over a channel to the UI thread, which redraws at up to 10
+//! frames per second. Keys 1-4 select the channel and q quits, returning
+//! the instrument to the run state it was found in.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::Line;
+use ratatui::widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+
+use oscilloscope_waveform::acquisition::TriggerStatus;
+use oscilloscope_waveform::plot::decimate_waveform;
+use oscilloscope_waveform::simulator::{SimulatedScope, SimulationConfig};
+use oscilloscope_waveform::{ConnectionTarget, DataRange, OscilloscopeWaveform, Timeouts, TransferFormat};
+
+/// Shortest time between two redraws, for at most 10 frames per second.
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Points per capture passed to the UI, plenty for a Braille chart.
+const CHART_POINTS: usize = 2_000;
+
+/// Pause after a failed capture before trying again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Trace colors of channels 1 to 4.
+const CHANNEL_COLORS: [Color; 4] = [Color::Yellow, Color::Cyan, Color::Magenta, Color::Blue];
+
+/// Watch a channel of a Batronix oscilloscope live in the terminal.
+#[derive(Parser, Debug)]
+struct Args {
+    /// IP address or host name of the instrument. Without it the first
+    /// Batronix instrument found is used
+    #[arg(long, conflicts_with = "simulate")]
+    url: Option<String>,
+
+    /// Channel shown at start, switched with the keys 1-4
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=4))]
+    channel: u8,
+
+    /// Watch a simulated instrument, to try the tool without hardware
+    #[arg(long)]
+    simulate: bool,
+}
+
+/// Requests from the UI thread to the capture thread.
+enum Control {
+    Channel(u8),
+    Quit,
+}
+
+/// A capture with the instrument state it was taken in.
+struct Update {
+    channel: u8,
+    time_values: Vec<f32>,
+    waveform: Vec<f32>,
+    trigger_status: TriggerStatus,
+    secs_per_div: f64,
+}
+
+fn capture(scope: &OscilloscopeWaveform, channel: u8) -> oscilloscope_waveform::Result<Update> {
+    let (time_values, waveform) = scope.get_waveform_data(channel, DataRange::All, TransferFormat::Raw, None)?;
+    let (time_values, waveform) = decimate_waveform(&time_values, &waveform, CHART_POINTS);
+    Ok(Update {
+        channel,
+        time_values,
+        waveform,
+        trigger_status: scope.trigger_status()?,
+        secs_per_div: scope.timebase()?,
+    })
+}
+
+/// Capture `channel` until told to quit, then restore the run state found
+/// at the start. Failed captures are reported and retried.
+fn poll_captures(scope: OscilloscopeWaveform, mut channel: u8, control: Receiver<Control>,
+    updates: Sender<std::result::Result<Update, String>>) -> oscilloscope_waveform::Result<()> {
+    // Captures start the acquisition, so the state has to be read first
+    let was_running = scope.trigger_status()?.is_running();
+    let mut pause = Duration::ZERO;
+    loop {
+        match control.recv_timeout(pause) {
+            Ok(Control::Channel(selected)) => channel = selected,
+            Ok(Control::Quit) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        let update = capture(&scope, channel).map_err(|e| format!("CH{}: {}", channel, e));
+        pause = if update.is_ok() { Duration::ZERO } else { RETRY_INTERVAL };
+        if updates.send(update).is_err() {
+            break;
+        }
+    }
+    scope.set_running(was_running)
+}
+
+/// What the UI shows.
+struct Monitor {
+    channel: u8,
+    latest: Option<Update>,
+    error: Option<String>,
+}
+
+/// Bounds of `values` for an axis, widened if they are all equal.
+fn bounds(values: impl Iterator<Item = f32>) -> [f64; 2] {
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+        (min.min(v as f64), max.max(v as f64))
+    });
+    match (min.is_finite(), max > min) {
+        (true, true) => [min, max],
+        (true, false) => [min - 1.0, min + 1.0],
+        _ => [-1.0, 1.0],
+    }
+}
+
+/// Column means of `waveform` over `width` columns, scaled to 0-100 from
+/// its minimum to its maximum.
+fn sparkline_bars(waveform: &[f32], width: usize) -> Vec<u64> {
+    if waveform.is_empty() || width == 0 {
+        return Vec::new();
+    }
+    let columns = width.min(waveform.len());
+    let means: Vec<f64> = (0..columns)
+        .map(|column| {
+            let part = &waveform[column * waveform.len() / columns..(column + 1) * waveform.len() / columns];
+            part.iter().map(|&v| v as f64).sum::<f64>() / part.len() as f64
+        })
+        .collect();
+    let [min, max] = bounds(means.iter().map(|&mean| mean as f32));
+    means.iter().map(|&mean| ((mean - min) / (max - min) * 100.0).round() as u64).collect()
+}
+
+fn draw(frame: &mut Frame, monitor: &Monitor) {
+    let [status_area, chart_area, sparkline_area] =
+        Layout::vertical([Constraint::Length(4), Constraint::Min(8), Constraint::Length(6)]).areas(frame.area());
+
+    let (trigger, timebase) = match &monitor.latest {
+        Some(update) => (format!("{:?}", update.trigger_status), format!("{:.3e} s/div", update.secs_per_div)),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    let mut status = vec![Line::from(format!(
+        "Channel {}   Trigger: {}   Timebase: {}   [1-4] channel   [q] quit",
+        monitor.channel, trigger, timebase
+    ))];
+    if let Some(error) = &monitor.error {
+        status.push(Line::styled(error.as_str(), Style::default().fg(Color::Red)));
+    }
+    frame.render_widget(Paragraph::new(status).block(Block::bordered().title("Magnova")), status_area);
+
+    let Some(update) = &monitor.latest else {
+        frame.render_widget(Paragraph::new("Waiting for the first capture").block(Block::bordered()), chart_area);
+        return;
+    };
+    let color = CHANNEL_COLORS[update.channel as usize - 1];
+    let points: Vec<(f64, f64)> = update.time_values.iter().zip(&update.waveform)
+        .map(|(&t, &v)| (t as f64, v as f64))
+        .collect();
+    let [t0, t1] = bounds(update.time_values.iter().copied());
+    let [v0, v1] = bounds(update.waveform.iter().copied());
+    let dataset = Dataset::default()
+        .marker(Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(color))
+        .data(&points);
+    let chart = Chart::new(vec![dataset])
+        .block(Block::bordered().title(format!("CH{}", update.channel)))
+        .x_axis(Axis::default().title("s").bounds([t0, t1])
+            .labels([format!("{:.3e}", t0), format!("{:.3e}", t1)]))
+        .y_axis(Axis::default().title("V").bounds([v0, v1])
+            .labels([format!("{:.3}", v0), format!("{:.3}", v1)]));
+    frame.render_widget(chart, chart_area);
+
+    let bars = sparkline_bars(&update.waveform, sparkline_area.width.saturating_sub(2) as usize);
+    let sparkline = Sparkline::default()
+        .block(Block::bordered().title("Last capture"))
+        .style(Style::default().fg(color))
+        .max(100)
+        .data(&bars);
+    frame.render_widget(sparkline, sparkline_area);
+}
+
+/// Show updates until q is pressed.
+fn run(terminal: &mut DefaultTerminal, channel: u8, control: &Sender<Control>,
+    updates: &Receiver<std::result::Result<Update, String>>) -> Result<()> {
+    let mut monitor = Monitor { channel, latest: None, error: None };
+    let mut dirty = true;
+    let mut last_draw = Instant::now() - FRAME_INTERVAL;
+    loop {
+        loop {
+            match updates.try_recv() {
+                Ok(Ok(update)) => {
+                    monitor.latest = Some(update);
+                    monitor.error = None;
+                }
+                Ok(Err(error)) => monitor.error = Some(error),
+                Err(TryRecvError::Empty) => break,
+                // The capture thread stopped, its error is reported by main
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+            dirty = true;
+        }
+        if dirty && last_draw.elapsed() >= FRAME_INTERVAL {
+            terminal.draw(|frame| draw(frame, &monitor))?;
+            dirty = false;
+            last_draw = Instant::now();
+        }
+
+        // Wait for keys until the next frame is due
+        if !event::poll(FRAME_INTERVAL.saturating_sub(last_draw.elapsed()).max(Duration::from_millis(10)))? {
+            continue;
+        }
+        match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Char(digit @ '1'..='4') => {
+                    monitor.channel = digit as u8 - b'0';
+                    control.send(Control::Channel(monitor.channel))
+                        .map_err(|_| anyhow!("The capture thread stopped"))?;
+                    dirty = true;
+                }
+                _ => {}
+            },
+            Event::Resize(..) => dirty = true,
+            _ => {}
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let scope = if args.simulate {
+        OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())))
+    } else {
+        let target = match args.url {
+            Some(host) => ConnectionTarget::Tcp { host, port: None },
+            None => ConnectionTarget::Auto,
+        };
+        OscilloscopeWaveform::new(&target, Timeouts::default())?
+    };
+
+    let (control_sender, control_receiver) = mpsc::channel();
+    let (update_sender, update_receiver) = mpsc::channel();
+    let capture_thread = thread::spawn(move || poll_captures(scope, args.channel, control_receiver, update_sender));
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, args.channel, &control_sender, &update_receiver);
+    ratatui::restore();
+
+    // The capture thread finishes its current capture before it stops
+    let _ = control_sender.send(Control::Quit);
+    let restored = capture_thread.join().map_err(|_| anyhow!("The capture thread panicked"))?;
+    result?;
+    restored?;
+    Ok(())
+}
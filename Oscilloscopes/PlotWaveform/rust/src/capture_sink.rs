@@ -0,0 +1,267 @@
+//! Automatic file naming and retention for repeated captures.
+//!
+//! Files are named `<timestamp>_ch<channel>_<sequence>.<extension>`, e.g.
+//! `2024-06-01T12-30-00_ch1_0001.png`, with the timestamp in UTC. All files
+//! of one capture share the name up to the extension, so a plot and its CSV
+//! are kept or pruned together.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::{info, warn};
+
+/// Which captures [`CaptureSink::prune`] keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Retention {
+    /// Never delete anything.
+    #[default]
+    KeepAll,
+    /// Keep the files of the newest N captures.
+    KeepLast(usize),
+    /// Keep the newest captures that fit into this many bytes together.
+    /// The newest capture is kept even if it is larger.
+    MaxBytes(u64),
+}
+
+/// Base name of one capture, to which each output adds its extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureName {
+    stem: PathBuf,
+}
+
+impl CaptureName {
+    /// Path of the output with the given extension, e.g. `"png"`.
+    pub fn path(&self, extension: &str) -> PathBuf {
+        self.stem.with_extension(extension)
+    }
+}
+
+/// A file that follows the naming pattern of [`CaptureSink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CaptureFile {
+    path: PathBuf,
+    stem: String,
+    timestamp: String,
+    sequence: u64,
+    bytes: u64,
+}
+
+/// Writes captures into a directory under unique names and prunes old ones.
+#[derive(Debug)]
+pub struct CaptureSink {
+    dir: PathBuf,
+    retention: Retention,
+    /// Sequence number of the last capture.
+    sequence: u64,
+}
+
+/// Format UNIX seconds as `YYYY-MM-DDTHH-MM-SS` in UTC.
+pub(crate) fn format_timestamp(unix_s: u64) -> String {
+    let (days, seconds) = (unix_s / 86_400, unix_s % 86_400);
+    // Civil date from days since 1970-01-01, with years starting in March
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}-{:02}-{:02}",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Split a file name into stem, timestamp and sequence number if it follows
+/// the capture naming pattern. Qualified extensions such as
+/// `histogram.png` belong to the same capture.
+fn parse_capture_name(file_name: &str) -> Option<(&str, &str, u64)> {
+    let (stem, extension) = file_name.split_once('.')?;
+    if !extension.split('.').all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric())) {
+        return None;
+    }
+
+    let digits = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+    let timestamp = stem.get(..19)?;
+    let timestamp_ok = timestamp.bytes().enumerate().all(|(i, b)| match i {
+        4 | 7 | 13 | 16 => b == b'-',
+        10 => b == b'T',
+        _ => b.is_ascii_digit(),
+    });
+    let (channel, sequence) = stem[19..].strip_prefix("_ch")?.split_once('_')?;
+    if !timestamp_ok || !digits(channel) || !digits(sequence) || sequence.len() < 4 {
+        return None;
+    }
+    Some((stem, timestamp, sequence.parse().ok()?))
+}
+
+impl CaptureSink {
+    /// Write captures into `dir`, creating it if needed.
+    ///
+    /// Numbering continues after the highest sequence number already in
+    /// the directory, so a restarted capture run does not reuse names.
+    pub fn new(dir: impl Into<PathBuf>, retention: Retention) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut sink = Self { dir, retention, sequence: 0 };
+        sink.sequence = sink.capture_files()?.iter().map(|file| file.sequence).max().unwrap_or(0);
+        Ok(sink)
+    }
+
+    /// Directory the captures are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Name the next capture of `channel`.
+    ///
+    /// The sequence number grows with every capture, so captures within
+    /// the same second get different names.
+    pub fn next_capture(&mut self, channel: u8) -> CaptureName {
+        self.sequence += 1;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let name = format!("{}_ch{}_{:04}", format_timestamp(now), channel, self.sequence);
+        CaptureName { stem: self.dir.join(name) }
+    }
+
+    /// All files in the directory that follow the naming pattern, oldest
+    /// capture first.
+    fn capture_files(&self) -> Result<Vec<CaptureFile>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let file_name = entry.file_name();
+            let Some((stem, timestamp, sequence)) = file_name.to_str().and_then(parse_capture_name) else {
+                continue;
+            };
+            files.push(CaptureFile {
+                path: entry.path(),
+                stem: stem.to_string(),
+                timestamp: timestamp.to_string(),
+                sequence,
+                bytes: metadata.len(),
+            });
+        }
+        files.sort_by(|a, b| (a.sequence, &a.timestamp, &a.path).cmp(&(b.sequence, &b.timestamp, &b.path)));
+        Ok(files)
+    }
+
+    /// Delete the captures the retention policy does not keep and return
+    /// the deleted paths.
+    ///
+    /// Only files following the naming pattern are considered, anything
+    /// else in the directory is left alone.
+    pub fn prune(&self) -> Result<Vec<PathBuf>> {
+        if self.retention == Retention::KeepAll {
+            return Ok(Vec::new());
+        }
+
+        // Files of one capture, newest capture first
+        let mut captures: Vec<Vec<CaptureFile>> = Vec::new();
+        for file in self.capture_files()?.into_iter().rev() {
+            match captures.last_mut() {
+                Some(capture) if capture[0].stem == file.stem => capture.push(file),
+                _ => captures.push(vec![file]),
+            }
+        }
+
+        let keep = match self.retention {
+            Retention::KeepAll => captures.len(),
+            Retention::KeepLast(count) => count,
+            Retention::MaxBytes(max_bytes) => {
+                let mut total = 0;
+                captures.iter()
+                    .take_while(|capture| {
+                        total += capture.iter().map(|file| file.bytes).sum::<u64>();
+                        total <= max_bytes
+                    })
+                    .count()
+                    .max(1)
+            }
+        };
+
+        let mut deleted = Vec::new();
+        for file in captures.into_iter().skip(keep).flatten() {
+            match fs::remove_file(&file.path) {
+                Ok(()) => deleted.push(file.path),
+                Err(e) => warn!("Could not delete {}: {}", file.path.display(), e),
+            }
+        }
+        if !deleted.is_empty() {
+            info!("Pruned {} old capture files from {}", deleted.len(), self.dir.display());
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00-00-00");
+        assert_eq!(format_timestamp(1_717_245_000), "2024-06-01T12-30-00");
+        assert_eq!(format_timestamp(951_868_799), "2000-02-29T23-59-59");
+    }
+
+    #[test]
+    fn matches_only_the_naming_pattern() {
+        assert_eq!(parse_capture_name("2024-06-01T12-30-00_ch1_0001.png"),
+            Some(("2024-06-01T12-30-00_ch1_0001", "2024-06-01T12-30-00", 1)));
+        assert_eq!(parse_capture_name("2024-06-01T12-30-00_ch4_12345.csv").map(|name| name.2), Some(12345));
+        assert_eq!(parse_capture_name("2024-06-01T12-30-00_ch1_0001.histogram.png").map(|name| name.0),
+            Some("2024-06-01T12-30-00_ch1_0001"));
+        for name in ["waveform.png", "2024-06-01T12-30-00_ch1_01.png", "2024-06-01T12-30-00_ch1_0001",
+            "2024-06-01 12-30-00_ch1_0001.png", "2024-06-01T12-30-00_chA_0001.png", "2024-06-01T12-30-00_ch1_0001.png.bak~",
+            "2024-06-01T12-30-00_ch1_0001..png"] {
+            assert_eq!(parse_capture_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn prunes_only_its_own_captures() {
+        let dir = temp_dir("capture-sink");
+        let mut sink = CaptureSink::new(&dir, Retention::KeepLast(2)).unwrap();
+        let mut names = Vec::new();
+        for _ in 0..4 {
+            let name = sink.next_capture(1);
+            fs::write(name.path("png"), b"png").unwrap();
+            fs::write(name.path("csv"), b"csv").unwrap();
+            names.push(name);
+        }
+        fs::write(dir.join("notes.txt"), b"keep").unwrap();
+        fs::write(dir.join("2024-06-01T12-30-00_ch1_01.png"), b"keep").unwrap();
+
+        let deleted = sink.prune().unwrap();
+        assert_eq!(deleted.len(), 4);
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(name.path("png").exists(), i >= 2);
+            assert_eq!(name.path("csv").exists(), i >= 2);
+        }
+        assert!(dir.join("notes.txt").exists());
+        assert!(dir.join("2024-06-01T12-30-00_ch1_01.png").exists());
+
+        // Numbering continues, and the byte limit keeps at least the newest
+        let mut sink = CaptureSink::new(&dir, Retention::MaxBytes(1)).unwrap();
+        let name = sink.next_capture(2);
+        assert!(name.path("png").to_str().unwrap().ends_with("_ch2_0005.png"));
+        fs::write(name.path("png"), b"png").unwrap();
+        sink.prune().unwrap();
+        assert!(name.path("png").exists());
+        assert!(!names[3].path("png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
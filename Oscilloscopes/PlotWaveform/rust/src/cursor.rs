@@ -0,0 +1,135 @@
+//! Cursor measurements on the instrument and on captured data.
+
+use log::info;
+
+use crate::settings::{HorizRef, TimebaseSettings, HORIZONTAL_DIVISIONS, VERTICAL_DIVISIONS};
+use crate::{check_channel, OscilloscopeWaveform, Result, ScopeError};
+
+/// Axis a cursor pair measures along.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorType {
+    /// Vertical lines, positioned in seconds.
+    Time,
+    /// Horizontal lines, positioned in volts.
+    Voltage,
+}
+
+impl CursorType {
+    /// Axis letter used in the `CURSor` commands.
+    fn axis(self) -> char {
+        match self {
+            CursorType::Time => 'X',
+            CursorType::Voltage => 'Y',
+        }
+    }
+}
+
+/// Difference `values[idx_b] - values[idx_a]`, or NaN if an index is out of
+/// range.
+///
+/// Pass the time axis for a delta-time and the waveform for a delta-voltage
+/// readout, like the instrument's cursors but on captured data.
+pub fn software_cursor_delta(values: &[f32], idx_a: usize, idx_b: usize) -> f32 {
+    match (values.get(idx_a), values.get(idx_b)) {
+        (Some(a), Some(b)) => b - a,
+        _ => f32::NAN,
+    }
+}
+
+/// Time range visible on screen.
+pub(crate) fn visible_time_range(timebase: &TimebaseSettings) -> (f32, f32) {
+    let span = timebase.secs_per_div * HORIZONTAL_DIVISIONS as f32;
+    let delay = timebase.delay_s;
+    match timebase.reference {
+        HorizRef::Left => (delay, delay + span),
+        HorizRef::Center => (delay - span / 2.0, delay + span / 2.0),
+        HorizRef::Right => (delay - span, delay),
+    }
+}
+
+/// Voltage range visible on screen. The offset is the voltage at the
+/// center line.
+fn visible_voltage_range(volts_per_div: f32, offset_v: f32) -> (f32, f32) {
+    let half_span = volts_per_div * VERTICAL_DIVISIONS as f32 / 2.0;
+    (offset_v - half_span, offset_v + half_span)
+}
+
+impl OscilloscopeWaveform {
+    /// Move cursor 1 or 2 of a pair to `position`, in seconds for time
+    /// cursors and volts for voltage cursors.
+    ///
+    /// The position must lie within the range currently on screen. Voltage
+    /// cursors are checked against the channel selected as cursor source.
+    pub fn place_cursor(&self, cursor_type: CursorType, cursor_id: u8, position: f32) -> Result<()> {
+        if !(1..=2).contains(&cursor_id) {
+            return Err(ScopeError::InvalidArgument(format!("Invalid cursor {}, expected 1 or 2", cursor_id)));
+        }
+        if !position.is_finite() {
+            return Err(ScopeError::InvalidArgument(format!("Invalid cursor position {}", position)));
+        }
+
+        let (min, max) = match cursor_type {
+            CursorType::Time => visible_time_range(&self.get_timebase()?),
+            CursorType::Voltage => {
+                let channel = self.cursor_source()?;
+                let vertical = self.get_vertical(channel)?;
+                visible_voltage_range(vertical.volts_per_div, vertical.offset_v)
+            }
+        };
+        if !(min..=max).contains(&position) {
+            return Err(ScopeError::InvalidArgument(format!(
+                "{:?} cursor position {} outside of the visible range {} to {}", cursor_type, position, min, max
+            )));
+        }
+
+        self.send_command("CURSor:STATe 1")?;
+        self.send_command(&format!("CURSor:{}{}:POSition {}", cursor_type.axis(), cursor_id, position))?;
+        self.verify_no_errors("cursor placement")?;
+        info!("{:?} cursor {} at {}", cursor_type, cursor_id, position);
+        Ok(())
+    }
+
+    /// Difference between cursor 2 and cursor 1, in seconds or volts.
+    pub fn get_cursor_delta(&self, cursor_type: CursorType) -> Result<f32> {
+        Ok(self.query_f64(&format!("CURSor:{}DELta?", cursor_type.axis()))? as f32)
+    }
+
+    /// Channel the cursors measure.
+    fn cursor_source(&self) -> Result<u8> {
+        let response = self.query("CURSor:SOURce?")?;
+        let channel = response.to_ascii_uppercase()
+            .trim_start_matches("CHAN")
+            .trim_start_matches("CH")
+            .parse::<u8>()
+            .map_err(|_| ScopeError::UnexpectedResponse {
+                command: "CURSor:SOURce?".to_string(),
+                response: response.clone(),
+            })?;
+        check_channel(channel)?;
+        Ok(channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_visible_ranges() {
+        let mut timebase = TimebaseSettings { secs_per_div: 0.5, delay_s: 1.0, reference: HorizRef::Center };
+        assert_eq!(visible_time_range(&timebase), (-1.5, 3.5));
+        timebase.reference = HorizRef::Left;
+        assert_eq!(visible_time_range(&timebase), (1.0, 6.0));
+        timebase.reference = HorizRef::Right;
+        assert_eq!(visible_time_range(&timebase), (-4.0, 1.0));
+        assert_eq!(visible_voltage_range(0.5, 1.0), (-1.0, 3.0));
+    }
+
+    #[test]
+    fn computes_software_delta() {
+        let values = [0.0, 0.5, 2.0, -1.0];
+        assert_eq!(software_cursor_delta(&values, 1, 2), 1.5);
+        assert_eq!(software_cursor_delta(&values, 2, 3), -3.0);
+        assert!(software_cursor_delta(&values, 0, 4).is_nan());
+    }
+}
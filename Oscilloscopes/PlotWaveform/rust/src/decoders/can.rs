@@ -0,0 +1,416 @@
+//! CAN bus decoder for classic CAN frames.
+
+use anyhow::{Result, anyhow};
+use log::info;
+
+use super::LogicTrace;
+
+/// Generator polynomial of the CAN CRC-15.
+const CRC15_POLY: u16 = 0x4599;
+
+/// Number of equal bits after which the transmitter inserts a stuff bit.
+const STUFF_RUN: u32 = 5;
+
+/// Position of the sample point within a bit.
+const SAMPLE_POINT: f64 = 0.75;
+
+/// Recessive bits that mark the end of a frame: end of frame and
+/// intermission. Error frames end with at least as many.
+const IDLE_BITS: f64 = 10.0;
+
+/// Fewer samples per bit leave too little room for the sample point.
+const MIN_SAMPLES_PER_BIT: f64 = 4.0;
+
+/// One decoded CAN frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CanFrame {
+    pub id: u32,
+    pub is_extended: bool,
+    /// Remote frame, which carries no data.
+    pub is_rtr: bool,
+    pub dlc: u8,
+    /// Payload, see [`CanFrame::payload`] for the valid bytes.
+    pub data: [u8; 8],
+    pub crc_ok: bool,
+    /// The frame was aborted by an error flag or a stuff or form error.
+    /// The other fields hold what was decoded until then.
+    pub is_error: bool,
+    /// Time of the start of frame edge.
+    pub timestamp_s: f32,
+}
+
+impl CanFrame {
+    /// The payload bytes the DLC announces, empty for remote frames.
+    pub fn payload(&self) -> &[u8] {
+        if self.is_rtr {
+            return &[];
+        }
+        &self.data[..self.dlc.min(8) as usize]
+    }
+}
+
+/// CRC-15 of a bit sequence as computed by CAN controllers.
+fn crc15(bits: &[bool]) -> u16 {
+    bits.iter().fold(0u16, |crc, &bit| {
+        let feedback = bit != (crc & 0x4000 != 0);
+        let crc = (crc << 1) & 0x7FFF;
+        if feedback { crc ^ CRC15_POLY } else { crc }
+    })
+}
+
+/// Reason a frame could not be read to its end.
+enum Abort {
+    /// Six equal bits, i.e. an error flag or a corrupted frame.
+    StuffError,
+    /// A fixed-form bit had the wrong level.
+    FormError,
+    EndOfCapture,
+}
+
+/// Reads the bits of one frame, removing stuff bits.
+struct BitReader<'a> {
+    trace: &'a LogicTrace,
+    bit_time: f64,
+    /// Start time of the next bit.
+    bit_start: f64,
+    run_level: bool,
+    run_len: u32,
+    /// Destuffed bits since the start of frame.
+    bits: Vec<bool>,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(trace: &'a LogicTrace, bit_time: f64, start: f64) -> Self {
+        Self { trace, bit_time, bit_start: start, run_level: false, run_len: 0, bits: Vec::new() }
+    }
+
+    /// Read one bit as it is on the bus. True is recessive.
+    fn raw_bit(&mut self) -> Result<bool, Abort> {
+        let level = self.trace.level_at(self.bit_start + SAMPLE_POINT * self.bit_time)
+            .ok_or(Abort::EndOfCapture)?;
+        self.bit_start = self.next_bit_start();
+        Ok(level)
+    }
+
+    /// Start of the following bit. Edges within a quarter bit of the
+    /// nominal boundary resynchronize the bit timing, so clock differences
+    /// don't add up over a long frame.
+    fn next_bit_start(&self) -> f64 {
+        let nominal = self.bit_start + self.bit_time;
+        let window = self.bit_time / 4.0;
+        let (Some(from), Some(to)) = (self.trace.index_at(nominal - window), self.trace.index_at(nominal + window))
+        else {
+            return nominal;
+        };
+        (from.max(1)..=to)
+            .find(|&i| self.trace.level(i) != self.trace.level(i - 1))
+            .map_or(nominal, |i| self.trace.time_of(i))
+    }
+
+    /// Read one bit of the stuffed part of the frame.
+    fn bit(&mut self) -> Result<bool, Abort> {
+        if self.run_len == STUFF_RUN {
+            self.skip_stuff_bit()?;
+        }
+        let level = self.raw_bit()?;
+        if level == self.run_level {
+            self.run_len += 1;
+        } else {
+            self.run_level = level;
+            self.run_len = 1;
+        }
+        self.bits.push(level);
+        Ok(level)
+    }
+
+    /// Consume the stuff bit after a run of equal bits.
+    fn skip_stuff_bit(&mut self) -> Result<(), Abort> {
+        let level = self.raw_bit()?;
+        if level == self.run_level {
+            return Err(Abort::StuffError);
+        }
+        self.run_level = level;
+        self.run_len = 1;
+        Ok(())
+    }
+
+    /// Read a field of `width` bits, most significant bit first.
+    fn field(&mut self, width: u32) -> Result<u32, Abort> {
+        (0..width).try_fold(0u32, |value, _| Ok(value << 1 | self.bit()? as u32))
+    }
+}
+
+/// Read a frame starting at the start of frame bit into `frame`.
+fn read_frame(reader: &mut BitReader, frame: &mut CanFrame) -> Result<(), Abort> {
+    if reader.bit()? {
+        return Err(Abort::FormError);
+    }
+    let base_id = reader.field(11)?;
+    // RTR in standard frames, SRR in extended ones
+    let rtr_or_srr = reader.bit()?;
+    frame.is_extended = reader.bit()?;
+    if frame.is_extended {
+        frame.id = base_id << 18 | reader.field(18)?;
+        frame.is_rtr = reader.bit()?;
+        // Reserved bits r1 and r0
+        reader.field(2)?;
+    } else {
+        frame.id = base_id;
+        frame.is_rtr = rtr_or_srr;
+        // Reserved bit r0
+        reader.bit()?;
+    }
+    frame.dlc = reader.field(4)? as u8;
+    if !frame.is_rtr {
+        for byte in frame.data.iter_mut().take(frame.dlc.min(8) as usize) {
+            *byte = reader.field(8)? as u8;
+        }
+    }
+
+    let crc = crc15(&reader.bits);
+    frame.crc_ok = reader.field(15)? as u16 == crc;
+    // Stuffing still applies after the last CRC bit
+    if reader.run_len == STUFF_RUN {
+        reader.skip_stuff_bit()?;
+    }
+
+    // CRC delimiter, ACK slot and ACK delimiter
+    let crc_delimiter = reader.raw_bit()?;
+    reader.raw_bit()?;
+    let ack_delimiter = reader.raw_bit()?;
+    if !crc_delimiter || !ack_delimiter {
+        return Err(Abort::FormError);
+    }
+    Ok(())
+}
+
+/// Index of the next start of frame edge at or after `from` that follows
+/// at least `idle_samples` recessive samples.
+fn find_start_of_frame(trace: &LogicTrace, from: usize, idle_samples: usize) -> Option<usize> {
+    let mut recessive = 0;
+    for index in from..trace.len() {
+        if trace.level(index) {
+            recessive += 1;
+        } else if recessive >= idle_samples {
+            return Some(index);
+        } else {
+            recessive = 0;
+        }
+    }
+    None
+}
+
+/// Decode classic CAN frames from a differential capture (CANH - CANL).
+///
+/// The bus is dominant above `threshold_high` and recessive below
+/// `threshold_low`, with hysteresis in between. A frame is only recognized
+/// after the bus has been idle for ten bit times, so a capture should start
+/// between frames. Frames aborted by an error flag or a stuff or form error
+/// are returned with `is_error` set. Bits are sampled at 75% of the bit
+/// time.
+pub fn decode_can(time: &[f32], waveform: &[f32], bit_rate: u32, threshold_high: f32, threshold_low: f32)
+    -> Result<Vec<CanFrame>> {
+    if bit_rate == 0 {
+        return Err(anyhow!("Bit rate must be positive"));
+    }
+    let mut trace = LogicTrace::with_hysteresis(time, waveform, threshold_high, threshold_low)?;
+    // Work with recessive = true, the logic level of a 1 bit
+    trace.invert();
+
+    let bit_time = 1.0 / bit_rate as f64;
+    let samples_per_bit = bit_time / trace.sample_interval;
+    if samples_per_bit < MIN_SAMPLES_PER_BIT {
+        return Err(anyhow!(
+            "{:.1} samples per bit are too few, at least {} are needed", samples_per_bit, MIN_SAMPLES_PER_BIT
+        ));
+    }
+    let idle_samples = (IDLE_BITS * samples_per_bit) as usize;
+
+    let mut frames = Vec::new();
+    let mut index = 0;
+    while let Some(start) = find_start_of_frame(&trace, index, idle_samples) {
+        let timestamp = trace.time_of(start);
+        let mut reader = BitReader::new(&trace, bit_time, timestamp);
+        let mut frame = CanFrame {
+            id: 0,
+            is_extended: false,
+            is_rtr: false,
+            dlc: 0,
+            data: [0; 8],
+            crc_ok: false,
+            is_error: false,
+            timestamp_s: timestamp as f32,
+        };
+
+        match read_frame(&mut reader, &mut frame) {
+            Ok(()) => {}
+            Err(Abort::StuffError | Abort::FormError) => frame.is_error = true,
+            // A frame cut off by the end of the capture is dropped
+            Err(Abort::EndOfCapture) => break,
+        }
+        frames.push(frame);
+
+        // Continue after the frame, the idle detection skips the rest
+        index = trace.index_at(reader.bit_start).unwrap_or(trace.len());
+    }
+
+    info!("Decoded {} CAN frames", frames.len());
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_BIT: usize = 10;
+    const BIT_RATE: u32 = 500_000;
+
+    fn push_field(bits: &mut Vec<bool>, value: u32, width: u32) {
+        bits.extend((0..width).rev().map(|bit| value >> bit & 1 == 1));
+    }
+
+    /// Unstuffed bits from start of frame to the end of the data field.
+    fn frame_bits(id: u32, extended: bool, rtr: bool, dlc: u8, data: &[u8]) -> Vec<bool> {
+        let mut bits = vec![false];
+        if extended {
+            push_field(&mut bits, id >> 18, 11);
+            bits.extend([true, true]);
+            push_field(&mut bits, id & 0x3FFFF, 18);
+            bits.extend([rtr, false, false]);
+        } else {
+            push_field(&mut bits, id, 11);
+            bits.extend([rtr, false, false]);
+        }
+        push_field(&mut bits, dlc as u32, 4);
+        for &byte in data {
+            push_field(&mut bits, byte as u32, 8);
+        }
+        bits
+    }
+
+    /// Insert a complementary bit after every five equal bits.
+    fn stuff(bits: &[bool]) -> Vec<bool> {
+        let mut stuffed = Vec::new();
+        let mut run = 0;
+        for &bit in bits {
+            if stuffed.last() == Some(&bit) {
+                run += 1;
+            } else {
+                run = 1;
+            }
+            stuffed.push(bit);
+            if run == 5 {
+                stuffed.push(!bit);
+                run = 1;
+            }
+        }
+        stuffed
+    }
+
+    /// Bus bits of a complete acknowledged frame, CRC computed from `bits`.
+    fn bus_bits(bits: &[bool]) -> Vec<bool> {
+        let mut with_crc = bits.to_vec();
+        push_field(&mut with_crc, crc15(bits) as u32, 15);
+        let mut bus = stuff(&with_crc);
+        // CRC delimiter, ACK, ACK delimiter, end of frame, intermission
+        bus.extend([true, false, true]);
+        bus.extend([true; 10]);
+        bus
+    }
+
+    /// Differential waveform of idle time followed by `bus` bits.
+    fn render(bus: &[bool]) -> (Vec<f32>, Vec<f32>) {
+        let mut levels = vec![true; 12];
+        levels.extend_from_slice(bus);
+        let waveform: Vec<f32> = levels.iter()
+            .flat_map(|&recessive| std::iter::repeat_n(if recessive { 0.0 } else { 2.0 }, SAMPLES_PER_BIT))
+            .collect();
+        let sample_interval = 1.0 / (BIT_RATE as f32 * SAMPLES_PER_BIT as f32);
+        let time = (0..waveform.len()).map(|i| i as f32 * sample_interval).collect();
+        (time, waveform)
+    }
+
+    fn decode(bus: &[bool]) -> Vec<CanFrame> {
+        let (time, waveform) = render(bus);
+        decode_can(&time, &waveform, BIT_RATE, 1.2, 0.8).unwrap()
+    }
+
+    #[test]
+    fn computes_reference_crc() {
+        // CRC-15/CAN check value over the ASCII digits 1 to 9
+        let mut bits = Vec::new();
+        for byte in b"123456789" {
+            push_field(&mut bits, *byte as u32, 8);
+        }
+        assert_eq!(crc15(&bits), 0x059E);
+    }
+
+    #[test]
+    fn decodes_standard_data_frame() {
+        // Zero and all-ones bytes force stuff bits
+        let data = [0x00, 0xFF, 0x12, 0x34];
+        let frames = decode(&bus_bits(&frame_bits(0x000, false, false, 4, &data)));
+        assert_eq!(frames.len(), 1);
+        let frame = frames[0];
+        assert_eq!((frame.id, frame.is_extended, frame.is_rtr, frame.dlc), (0x000, false, false, 4));
+        assert_eq!(frame.payload(), data);
+        assert!(frame.crc_ok && !frame.is_error);
+        assert!((frame.timestamp_s - 12.0 / BIT_RATE as f32).abs() < 1e-9);
+    }
+
+    #[test]
+    fn decodes_consecutive_extended_and_remote_frames() {
+        let mut bus = bus_bits(&frame_bits(0x1ABC_DEF0, true, false, 8, &[1, 2, 3, 4, 5, 6, 7, 8]));
+        bus.extend(bus_bits(&frame_bits(0x7FF, false, true, 2, &[])));
+        let frames = decode(&bus);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].id, frames[0].is_extended), (0x1ABC_DEF0, true));
+        assert_eq!(frames[0].payload(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!((frames[1].id, frames[1].is_rtr, frames[1].dlc), (0x7FF, true, 2));
+        assert!(frames[1].payload().is_empty());
+        assert!(frames.iter().all(|frame| frame.crc_ok && !frame.is_error));
+    }
+
+    #[test]
+    fn detects_crc_mismatch() {
+        let bits = frame_bits(0x123, false, false, 1, &[0x5A]);
+        let mut corrupted = bits.clone();
+        // Flip a data bit but keep the CRC of the original frame
+        let last = corrupted.len() - 1;
+        corrupted[last] = !corrupted[last];
+        let mut with_crc = corrupted;
+        push_field(&mut with_crc, crc15(&bits) as u32, 15);
+        let mut bus = stuff(&with_crc);
+        bus.extend([true, false, true]);
+        bus.extend([true; 10]);
+
+        let frames = decode(&bus);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].payload(), [0x5B]);
+        assert!(!frames[0].crc_ok);
+    }
+
+    #[test]
+    fn reports_error_frames() {
+        let mut bus = stuff(&frame_bits(0x321, false, false, 2, &[0xAA, 0x55]))[..20].to_vec();
+        // Error flag, error delimiter and intermission
+        bus.extend([false; 6]);
+        bus.extend([true; 11]);
+        bus.extend(bus_bits(&frame_bits(0x042, false, false, 1, &[0x99])));
+
+        let frames = decode(&bus);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].is_error);
+        assert_eq!((frames[1].id, frames[1].payload()), (0x042, &[0x99][..]));
+        assert!(frames[1].crc_ok && !frames[1].is_error);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let (time, waveform) = render(&bus_bits(&frame_bits(0x1, false, false, 0, &[])));
+        assert!(decode_can(&time, &waveform, 0, 1.2, 0.8).is_err());
+        assert!(decode_can(&time, &waveform, BIT_RATE, 0.8, 1.2).is_err());
+        // Two samples per bit
+        assert!(decode_can(&time, &waveform, BIT_RATE * 5, 1.2, 0.8).is_err());
+    }
+}
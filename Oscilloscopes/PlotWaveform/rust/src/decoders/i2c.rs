@@ -0,0 +1,440 @@
+//! I2C decoder.
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use plotters::prelude::*;
+
+use super::{interpolate, LogicTrace};
+use crate::plot::{decimate_waveform, padded_voltage_range};
+
+/// Transfer direction from the controller's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cDirection {
+    Write,
+    Read,
+}
+
+/// Everything between a START (or repeated START) and the next STOP or
+/// repeated START.
+#[derive(Debug, Clone, PartialEq)]
+pub struct I2cTransaction {
+    /// 7-bit or 10-bit target address.
+    pub address: u16,
+    pub direction: I2cDirection,
+    /// Bytes following the address.
+    pub data: Vec<u8>,
+    /// Acknowledge bit of every byte, address bytes included. `true` means
+    /// the byte was acknowledged (SDA pulled low).
+    pub ack_bits: Vec<bool>,
+    pub start_time_s: f32,
+    pub stop_time_s: f32,
+}
+
+impl I2cTransaction {
+    fn is_ten_bit_header(byte: u8) -> bool {
+        byte >> 3 == 0b11110
+    }
+}
+
+/// Bring both signals onto the time axis of the one with more samples.
+fn align_channels(scl_time: &[f32], scl: &[f32], sda_time: &[f32], sda: &[f32])
+    -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    if scl.len() == sda.len() {
+        return (scl_time.to_vec(), scl.to_vec(), sda.to_vec());
+    }
+    warn!("SCL has {} samples and SDA has {}, interpolating to the higher rate", scl.len(), sda.len());
+    if scl.len() > sda.len() {
+        (scl_time.to_vec(), scl.to_vec(), interpolate(sda_time, sda, scl_time))
+    } else {
+        (sda_time.to_vec(), interpolate(scl_time, scl, sda_time), sda.to_vec())
+    }
+}
+
+/// Decoder state for the transaction currently on the bus.
+struct OpenTransaction {
+    start_time: f64,
+    bytes: Vec<u8>,
+    ack_bits: Vec<bool>,
+    bits: Vec<bool>,
+}
+
+impl OpenTransaction {
+    fn new(start_time: f64) -> Self {
+        Self { start_time, bytes: Vec::new(), ack_bits: Vec::new(), bits: Vec::new() }
+    }
+
+    /// Store a bit sampled on a rising SCL edge. Every ninth bit is the
+    /// acknowledge of the preceding byte.
+    fn push_bit(&mut self, bit: bool) {
+        self.bits.push(bit);
+        if self.bits.len() == 9 {
+            let byte = self.bits[..8].iter().fold(0u8, |acc, &b| (acc << 1) | b as u8);
+            self.bytes.push(byte);
+            self.ack_bits.push(!self.bits[8]);
+            self.bits.clear();
+        }
+    }
+
+    /// Turn the collected bytes into a transaction. `previous_ten_bit` is the
+    /// 10-bit address of a write that ended in a repeated START, which a
+    /// following 10-bit read header refers to.
+    fn finish(self, stop_time: f64, previous_ten_bit: Option<u16>) -> Option<I2cTransaction> {
+        let (&first, rest) = self.bytes.split_first()?;
+        let direction = if first & 1 == 1 { I2cDirection::Read } else { I2cDirection::Write };
+
+        let (address, data) = if I2cTransaction::is_ten_bit_header(first) {
+            let high = ((first >> 1) & 0b11) as u16;
+            match (direction, previous_ten_bit) {
+                // A read header after a repeated START reuses the full address
+                (I2cDirection::Read, Some(address)) if address >> 8 == high => (address, rest),
+                _ => {
+                    let (&low, data) = rest.split_first()?;
+                    ((high << 8) | low as u16, data)
+                }
+            }
+        } else {
+            ((first >> 1) as u16, rest)
+        };
+
+        Some(I2cTransaction {
+            address,
+            direction,
+            data: data.to_vec(),
+            ack_bits: self.ack_bits,
+            start_time_s: self.start_time as f32,
+            stop_time_s: stop_time as f32,
+        })
+    }
+}
+
+/// Decode I2C transactions from SCL and SDA captures.
+///
+/// Both lines are sliced at `threshold_v`. Data bits are sampled on rising
+/// SCL edges, so clock stretching needs no special handling. If the two
+/// captures have different sample counts, the slower one is interpolated
+/// onto the time axis of the faster one first.
+pub fn decode_i2c(scl_time: &[f32], scl: &[f32], sda_time: &[f32], sda: &[f32], threshold_v: f32)
+    -> Result<Vec<I2cTransaction>> {
+    if scl_time.len() != scl.len() || sda_time.len() != sda.len() {
+        return Err(anyhow!("Each channel needs as many time values as samples"));
+    }
+    let (time_values, scl, sda) = align_channels(scl_time, scl, sda_time, sda);
+    let scl = LogicTrace::new(&time_values, &scl, threshold_v)?;
+    let sda = LogicTrace::new(&time_values, &sda, threshold_v)?;
+
+    let mut transactions: Vec<I2cTransaction> = Vec::new();
+    let mut open: Option<OpenTransaction> = None;
+    // 10-bit write address that a repeated START may continue from
+    let mut ten_bit_address: Option<u16> = None;
+
+    for i in 1..scl.len() {
+        let clock_high = scl.level(i - 1) && scl.level(i);
+        let sda_fell = sda.level(i - 1) && !sda.level(i);
+        let sda_rose = !sda.level(i - 1) && sda.level(i);
+        let time = scl.time_of(i);
+
+        if clock_high && sda_fell {
+            // START, or repeated START if a transaction is still open
+            if let Some(transaction) = open.take() {
+                let ten_bit = transaction.bytes.first()
+                    .is_some_and(|&b| I2cTransaction::is_ten_bit_header(b));
+                let finished = transaction.finish(time, ten_bit_address);
+                ten_bit_address = finished.as_ref()
+                    .filter(|t| ten_bit && t.direction == I2cDirection::Write)
+                    .map(|t| t.address);
+                transactions.extend(finished);
+            } else {
+                ten_bit_address = None;
+            }
+            open = Some(OpenTransaction::new(time));
+        } else if clock_high && sda_rose {
+            // STOP
+            if let Some(transaction) = open.take() {
+                transactions.extend(transaction.finish(time, ten_bit_address));
+            }
+            ten_bit_address = None;
+        } else if !scl.level(i - 1) && scl.level(i) {
+            if let Some(transaction) = open.as_mut() {
+                transaction.push_bit(sda.level(i));
+            }
+        }
+    }
+
+    if open.is_some() {
+        warn!("Capture ended inside an I2C transaction, dropping it");
+    }
+
+    info!("Decoded {} I2C transactions", transactions.len());
+    Ok(transactions)
+}
+
+fn describe(transaction: &I2cTransaction) -> String {
+    let direction = match transaction.direction {
+        I2cDirection::Write => "W",
+        I2cDirection::Read => "R",
+    };
+    let data: Vec<String> = transaction.data.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("{} 0x{:02X}: {}", direction, transaction.address, data.join(" "))
+}
+
+/// Plot SCL and SDA as two traces with the decoded transactions listed below.
+/// Transactions whose address was not acknowledged are labeled in red.
+pub fn plot_i2c_decode(time_values: &[f32], scl: &[f32], sda: &[f32], transactions: &[I2cTransaction],
+    output_path: &str) -> Result<()> {
+    if time_values.is_empty() || scl.len() != time_values.len() || sda.len() != time_values.len() {
+        return Err(anyhow!("SCL and SDA must share the time axis"));
+    }
+
+    info!("Creating I2C decode plot");
+    let root = BitMapBackend::new(output_path, (1200, 800)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let root = root.titled("I2C Decode", ("sans-serif", 40))?;
+    let (traces, annotations) = root.split_vertically(640);
+    let (scl_area, sda_area) = traces.split_vertically(320);
+
+    let min_time = time_values[0];
+    let max_time = time_values[time_values.len() - 1];
+
+    for (area, name, samples) in [(&scl_area, "SCL (V)", scl), (&sda_area, "SDA (V)", sda)] {
+        let (low, high) = padded_voltage_range(samples);
+        let mut chart = ChartBuilder::on(area)
+            .margin(10)
+            .x_label_area_size(30)
+            .y_label_area_size(60)
+            .build_cartesian_2d(min_time..max_time, low..high)?;
+        chart.configure_mesh().y_desc(name).draw()?;
+
+        let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+        let (times, values) = decimate_waveform(time_values, samples, 2 * columns);
+        chart.draw_series(LineSeries::new(
+            times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+            &BLUE,
+        ))?;
+    }
+
+    let mut chart = ChartBuilder::on(&annotations)
+        .margin(10)
+        .x_label_area_size(30)
+        .y_label_area_size(60)
+        .build_cartesian_2d(min_time..max_time, 0f32..4.0)?;
+    chart.configure_mesh().disable_y_axis().disable_mesh().x_desc("Time (s)").draw()?;
+
+    // Stagger the labels over four rows so neighbours don't overlap
+    chart.draw_series(transactions.iter().enumerate().map(|(i, transaction)| {
+        let color = if transaction.ack_bits.first() == Some(&true) { BLACK } else { RED };
+        Text::new(
+            describe(transaction),
+            (transaction.start_time_s, 3.5 - (i % 4) as f32),
+            ("sans-serif", 14).into_font().color(&color),
+        )
+    }))?;
+
+    root.present()?;
+    info!("I2C decode plot saved as {}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples per quarter of an SCL period.
+    const QUARTER: usize = 4;
+    const SAMPLE_INTERVAL: f32 = 1e-7;
+    const THRESHOLD: f32 = 1.5;
+
+    struct Capture {
+        time: Vec<f32>,
+        scl: Vec<f32>,
+        sda: Vec<f32>,
+    }
+
+    impl Capture {
+        /// Idle bus with both lines high.
+        fn new() -> Self {
+            let mut capture = Self { time: vec![], scl: vec![], sda: vec![] };
+            capture.push(2 * QUARTER, true, true);
+            capture
+        }
+
+        fn push(&mut self, samples: usize, scl: bool, sda: bool) {
+            let level = |high: bool| if high { 3.3 } else { 0.0 };
+            for _ in 0..samples {
+                self.time.push(self.time.len() as f32 * SAMPLE_INTERVAL);
+                self.scl.push(level(scl));
+                self.sda.push(level(sda));
+            }
+        }
+
+        /// START from the idle bus.
+        fn start(&mut self) {
+            self.push(QUARTER, true, false);
+            self.push(QUARTER, false, false);
+        }
+
+        /// Repeated START after a byte.
+        fn repeated_start(&mut self) {
+            self.push(QUARTER, false, true);
+            self.push(QUARTER, true, true);
+            self.start();
+        }
+
+        fn stop(&mut self) {
+            self.push(QUARTER, false, false);
+            self.push(QUARTER, true, false);
+            self.push(2 * QUARTER, true, true);
+        }
+
+        /// One bit, with SDA changing in the middle of the low clock phase.
+        /// `stretch` holds the clock low for that many extra samples.
+        fn bit(&mut self, bit: bool, stretch: usize) {
+            self.push(QUARTER + stretch, false, bit);
+            self.push(2 * QUARTER, true, bit);
+            self.push(QUARTER, false, bit);
+        }
+
+        /// A byte MSB first, followed by its acknowledge bit.
+        fn byte(&mut self, byte: u8, ack: bool) {
+            self.byte_stretched(byte, ack, 0);
+        }
+
+        fn byte_stretched(&mut self, byte: u8, ack: bool, stretch: usize) {
+            for bit in (0..8).rev() {
+                self.bit(byte >> bit & 1 == 1, stretch);
+            }
+            self.bit(!ack, 0);
+        }
+
+        fn decode(&self) -> Vec<I2cTransaction> {
+            decode_i2c(&self.time, &self.scl, &self.time, &self.sda, THRESHOLD).unwrap()
+        }
+    }
+
+    fn summary(transactions: &[I2cTransaction]) -> Vec<(u16, I2cDirection, Vec<u8>)> {
+        transactions.iter().map(|t| (t.address, t.direction, t.data.clone())).collect()
+    }
+
+    /// Register write to 0x50, then a separate read of two bytes.
+    fn register_access() -> Capture {
+        let mut capture = Capture::new();
+        capture.start();
+        capture.byte(0x50 << 1, true);
+        capture.byte(0x00, true);
+        capture.byte(0x10, true);
+        capture.stop();
+        capture.start();
+        capture.byte(0x50 << 1 | 1, true);
+        capture.byte(0xAB, true);
+        capture.byte(0xCD, false);
+        capture.stop();
+        capture
+    }
+
+    #[test]
+    fn decodes_write_and_read() {
+        let transactions = register_access().decode();
+        assert_eq!(summary(&transactions), [
+            (0x50, I2cDirection::Write, vec![0x00, 0x10]),
+            (0x50, I2cDirection::Read, vec![0xAB, 0xCD]),
+        ]);
+        assert_eq!(transactions[0].ack_bits, [true, true, true]);
+        // The controller ends a read by not acknowledging the last byte
+        assert_eq!(transactions[1].ack_bits, [true, true, false]);
+        assert!(transactions[0].stop_time_s < transactions[1].start_time_s);
+    }
+
+    #[test]
+    fn splits_at_repeated_start() {
+        let mut capture = Capture::new();
+        capture.start();
+        capture.byte(0x68 << 1, true);
+        capture.byte(0x3B, true);
+        capture.repeated_start();
+        capture.byte(0x68 << 1 | 1, true);
+        capture.byte(0x42, false);
+        capture.stop();
+
+        let transactions = capture.decode();
+        assert_eq!(summary(&transactions), [
+            (0x68, I2cDirection::Write, vec![0x3B]),
+            (0x68, I2cDirection::Read, vec![0x42]),
+        ]);
+        assert_eq!(transactions[0].stop_time_s, transactions[1].start_time_s);
+    }
+
+    #[test]
+    fn decodes_ten_bit_addresses() {
+        let address: u16 = 0x2A5;
+        let header = 0b1111_0000 | ((address >> 8) as u8) << 1;
+        let mut capture = Capture::new();
+        capture.start();
+        capture.byte(header, true);
+        capture.byte(address as u8, true);
+        capture.byte(0x11, true);
+        // The read header after a repeated START refers back to the write
+        capture.repeated_start();
+        capture.byte(header | 1, true);
+        capture.byte(0x77, false);
+        capture.stop();
+        // After a STOP the next 10-bit transfer sends the full address again
+        capture.start();
+        capture.byte(header, true);
+        capture.byte(address as u8, true);
+        capture.byte(0x22, true);
+        capture.stop();
+
+        assert_eq!(summary(&capture.decode()), [
+            (address, I2cDirection::Write, vec![0x11]),
+            (address, I2cDirection::Read, vec![0x77]),
+            (address, I2cDirection::Write, vec![0x22]),
+        ]);
+    }
+
+    #[test]
+    fn tolerates_clock_stretching() {
+        let mut capture = Capture::new();
+        capture.start();
+        capture.byte(0x50 << 1, true);
+        capture.byte_stretched(0x5A, true, 20 * QUARTER);
+        capture.byte_stretched(0xC3, true, 3);
+        capture.stop();
+
+        assert_eq!(summary(&capture.decode()), [(0x50, I2cDirection::Write, vec![0x5A, 0xC3])]);
+    }
+
+    #[test]
+    fn interpolates_channels_with_different_sample_counts() {
+        let capture = register_access();
+        let sda_time: Vec<f32> = capture.time.iter().step_by(2).copied().collect();
+        let sda: Vec<f32> = capture.sda.iter().step_by(2).copied().collect();
+        let expected = summary(&capture.decode());
+
+        let transactions = decode_i2c(&capture.time, &capture.scl, &sda_time, &sda, THRESHOLD).unwrap();
+        assert_eq!(summary(&transactions), expected);
+        // Either channel may be the slower one
+        let scl_time = sda_time;
+        let scl: Vec<f32> = capture.scl.iter().step_by(2).copied().collect();
+        let transactions = decode_i2c(&scl_time, &scl, &capture.time, &capture.sda, THRESHOLD).unwrap();
+        assert_eq!(summary(&transactions), expected);
+
+        assert!(decode_i2c(&capture.time, &capture.scl, &capture.time[1..], &capture.sda, THRESHOLD).is_err());
+    }
+
+    #[test]
+    fn drops_a_transaction_cut_off_by_the_capture() {
+        let mut capture = register_access();
+        capture.start();
+        capture.byte(0x50 << 1, true);
+        assert_eq!(capture.decode().len(), 2);
+    }
+
+    #[test]
+    fn plot_reports_an_unwritable_path() {
+        let capture = register_access();
+        let transactions = capture.decode();
+        let path = std::env::temp_dir().join("no-such-directory").join("i2c.png");
+        let result = plot_i2c_decode(&capture.time, &capture.scl, &capture.sda, &transactions,
+            &path.display().to_string());
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! LIN bus decoder.
+
+use anyhow::{Result, anyhow};
+use log::info;
+
+use super::LogicTrace;
+
+/// Minimum length of the break field in bit times.
+const BREAK_BITS: f64 = 13.0;
+
+/// Byte following the break that lets slaves measure the bit rate.
+pub const SYNC_BYTE: u8 = 0x55;
+
+/// Start bit, 8 data bits and stop bit of every byte on the bus.
+const BYTE_BITS: f64 = 10.0;
+
+/// Most data bytes a frame can carry.
+const MAX_DATA_BYTES: usize = 8;
+
+/// Frame identifiers of the diagnostic frames, which use the classic
+/// checksum in every LIN version.
+const DIAGNOSTIC_IDS: [u8; 2] = [0x3C, 0x3D];
+
+/// Protocol version, which selects the checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinVersion {
+    /// LIN 1.x, classic checksum over the data bytes.
+    V1,
+    /// LIN 2.x, enhanced checksum over the protected identifier and the
+    /// data bytes, except for diagnostic frames.
+    V2,
+}
+
+impl LinVersion {
+    /// Checksum a node of this version sends after `data` in the frame
+    /// with protected identifier `pid`.
+    pub fn checksum(self, pid: u8, data: &[u8]) -> u8 {
+        let enhanced = self == LinVersion::V2 && !DIAGNOSTIC_IDS.contains(&(pid & 0x3F));
+        let first = if enhanced { Some(pid) } else { None };
+        // Sum with the carry added back in, then inverted
+        let sum = first.iter().chain(data).fold(0u16, |sum, &byte| {
+            let sum = sum + byte as u16;
+            if sum > 0xFF { sum - 0xFF } else { sum }
+        });
+        !(sum as u8)
+    }
+}
+
+/// Protected identifier of frame identifier `id`, with the parity bits
+/// P0 and P1 in bits 6 and 7.
+pub fn protected_id(id: u8) -> u8 {
+    let bit = |n: u8| id >> n & 1;
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+    id & 0x3F | p0 << 6 | p1 << 7
+}
+
+/// One decoded LIN frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinFrame {
+    /// The frame started with a complete break field. False for a frame
+    /// whose break began before the capture.
+    pub break_detected: bool,
+    /// Should be [`SYNC_BYTE`].
+    pub sync_byte: u8,
+    /// Protected identifier, see [`LinFrame::id`].
+    pub pid: u8,
+    /// The parity bits of `pid` match its identifier.
+    pub pid_parity_ok: bool,
+    /// Response bytes before the checksum, empty if no node responded.
+    pub data: Vec<u8>,
+    /// Last byte of the response, 0 if there was none.
+    pub checksum: u8,
+    pub checksum_ok: bool,
+    /// Time of the start of the break.
+    pub timestamp_s: f32,
+}
+
+impl LinFrame {
+    /// Frame identifier without the parity bits.
+    pub fn id(&self) -> u8 {
+        self.pid & 0x3F
+    }
+}
+
+/// Index of the next recessive-to-dominant edge at or after `from`.
+fn next_falling_edge(trace: &LogicTrace, from: usize) -> Option<usize> {
+    (from.max(1)..trace.len()).find(|&index| trace.level(index - 1) && !trace.level(index))
+}
+
+/// Whether the dominant level starting at `start` lasts long enough for a
+/// break. Half a bit is allowed for edges moved by the slicing threshold.
+fn is_break(trace: &LogicTrace, start: usize, bit_time: f64) -> bool {
+    let end = (start..trace.len()).find(|&index| trace.level(index)).unwrap_or(trace.len());
+    trace.time_of(end) - trace.time_of(start) >= (BREAK_BITS - 0.5) * bit_time
+}
+
+/// Read the byte whose start bit begins at `start`, sampling every bit in
+/// its middle. Returns the byte and the index of the middle of the stop
+/// bit, or `None` if the byte runs past the end of the capture.
+fn read_byte(trace: &LogicTrace, start: usize, bit_time: f64) -> Option<(u8, usize)> {
+    let start_time = trace.time_of(start);
+    let stop = trace.index_at(start_time + (BYTE_BITS - 0.5) * bit_time)?;
+    let byte = (0..8).fold(0u8, |byte, bit| {
+        let high = trace.level_at(start_time + (1.5 + bit as f64) * bit_time) == Some(true);
+        byte | (high as u8) << bit
+    });
+    Some((byte, stop))
+}
+
+/// Decode LIN frames from a capture of the bus line.
+///
+/// The line is sliced at `threshold_v`, and the bytes are read at the
+/// nominal `baud_rate`. A frame starts with a break of at least 13 bit
+/// times, followed by the sync byte and the protected identifier. The
+/// response runs until the next break, the end of the capture or 8 data
+/// bytes plus the checksum, whose last byte is taken as the checksum.
+/// `version` selects the classic or the enhanced checksum.
+///
+/// A capture that starts in the dominant level is taken as a break cut
+/// off by the trigger if a sync byte follows.
+pub fn decode_lin(time: &[f32], waveform: &[f32], baud_rate: u32, threshold_v: f32, version: LinVersion)
+    -> Result<Vec<LinFrame>> {
+    if baud_rate == 0 {
+        return Err(anyhow!("Baud rate must be positive"));
+    }
+    let trace = LogicTrace::new(time, waveform, threshold_v)?;
+    let bit_time = 1.0 / baud_rate as f64;
+
+    let mut frames = Vec::new();
+    let mut index = 0;
+    loop {
+        let start = if index == 0 && !trace.level(0) { Some(0) } else { next_falling_edge(&trace, index) };
+        let Some(start) = start else { break };
+        let break_detected = start > 0 && is_break(&trace, start, bit_time);
+        let Some(delimiter) = (start..trace.len()).find(|&index| trace.level(index)) else { break };
+        if start > 0 && !break_detected {
+            // A byte outside a frame header
+            index = delimiter;
+            continue;
+        }
+
+        let Some(sync_start) = next_falling_edge(&trace, delimiter) else { break };
+        let Some((sync_byte, after_sync)) = read_byte(&trace, sync_start, bit_time) else { break };
+        if !break_detected && sync_byte != SYNC_BYTE {
+            index = after_sync;
+            continue;
+        }
+        let Some(pid_start) = next_falling_edge(&trace, after_sync) else { break };
+        let Some((pid, mut next)) = read_byte(&trace, pid_start, bit_time) else { break };
+
+        let mut response = Vec::new();
+        while response.len() <= MAX_DATA_BYTES {
+            let Some(byte_start) = next_falling_edge(&trace, next) else {
+                next = trace.len();
+                break;
+            };
+            if is_break(&trace, byte_start, bit_time) {
+                next = byte_start;
+                break;
+            }
+            let Some((byte, after)) = read_byte(&trace, byte_start, bit_time) else {
+                next = trace.len();
+                break;
+            };
+            response.push(byte);
+            next = after;
+        }
+
+        let checksum = response.pop();
+        let checksum_ok = checksum == Some(version.checksum(pid, &response));
+        frames.push(LinFrame {
+            break_detected,
+            sync_byte,
+            pid,
+            pid_parity_ok: protected_id(pid) == pid,
+            data: response,
+            checksum: checksum.unwrap_or(0),
+            checksum_ok,
+            timestamp_s: trace.time_of(start) as f32,
+        });
+        index = next;
+    }
+
+    info!("Decoded {} LIN frames", frames.len());
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_BIT: usize = 10;
+    const BAUD_RATE: u32 = 19_200;
+    const THRESHOLD: f32 = 6.0;
+
+    /// Bus levels of a byte with start and stop bit, LSB first.
+    fn push_byte(levels: &mut Vec<bool>, byte: u8) {
+        levels.push(false);
+        levels.extend((0..8).map(|bit| byte >> bit & 1 == 1));
+        levels.push(true);
+    }
+
+    /// Bus levels of a frame with a 13-bit break, followed by idle time.
+    fn frame_levels(pid: u8, response: &[u8]) -> Vec<bool> {
+        let mut levels = vec![false; 13];
+        levels.push(true);
+        push_byte(&mut levels, SYNC_BYTE);
+        push_byte(&mut levels, pid);
+        // Response space
+        levels.extend([true; 2]);
+        for &byte in response {
+            push_byte(&mut levels, byte);
+        }
+        levels.extend([true; 5]);
+        levels
+    }
+
+    /// A 12 V bus waveform of `levels`, after some idle time if `idle`.
+    fn render(levels: &[bool], idle: bool) -> (Vec<f32>, Vec<f32>) {
+        let mut all = if idle { vec![true; 8] } else { Vec::new() };
+        all.extend_from_slice(levels);
+        let waveform: Vec<f32> = all.iter()
+            .flat_map(|&recessive| std::iter::repeat_n(if recessive { 12.0 } else { 0.2 }, SAMPLES_PER_BIT))
+            .collect();
+        let sample_interval = 1.0 / (BAUD_RATE as f32 * SAMPLES_PER_BIT as f32);
+        let time = (0..waveform.len()).map(|i| i as f32 * sample_interval).collect();
+        (time, waveform)
+    }
+
+    fn decode(levels: &[bool], version: LinVersion) -> Vec<LinFrame> {
+        let (time, waveform) = render(levels, true);
+        decode_lin(&time, &waveform, BAUD_RATE, THRESHOLD, version).unwrap()
+    }
+
+    #[test]
+    fn computes_reference_values() {
+        // Protected identifiers from the LIN specification's ID table
+        let pids: Vec<u8> = [0x00, 0x01, 0x10, 0x20, 0x3C, 0x3D, 0x3F].into_iter().map(protected_id).collect();
+        assert_eq!(pids, [0x80, 0xC1, 0x50, 0x20, 0x3C, 0x7D, 0xBF]);
+        // Checksum example of the LIN specification
+        assert_eq!(LinVersion::V1.checksum(0x4A, &[0x4A, 0x55, 0x93, 0xE5]), 0xE6);
+        assert_eq!(LinVersion::V2.checksum(0x4A, &[0x4A, 0x55, 0x93, 0xE5]), 0x9C);
+        // Diagnostic frames keep the classic checksum
+        assert_eq!(LinVersion::V2.checksum(0x3C, &[0x4A, 0x55, 0x93, 0xE5]), 0xE6);
+    }
+
+    #[test]
+    fn decodes_enhanced_checksum_frame() {
+        let pid = protected_id(0x10);
+        let data = [0x01, 0x80, 0xFF, 0x00];
+        let mut response = data.to_vec();
+        response.push(LinVersion::V2.checksum(pid, &data));
+        let frames = decode(&frame_levels(pid, &response), LinVersion::V2);
+
+        assert_eq!(frames, [LinFrame {
+            break_detected: true,
+            sync_byte: SYNC_BYTE,
+            pid: 0x50,
+            pid_parity_ok: true,
+            data: data.to_vec(),
+            checksum: 0x2E,
+            checksum_ok: true,
+            timestamp_s: frames[0].timestamp_s,
+        }]);
+        assert!((frames[0].timestamp_s - 8.0 / BAUD_RATE as f32).abs() < 1e-9);
+        assert_eq!(frames[0].id(), 0x10);
+    }
+
+    #[test]
+    fn selects_checksum_by_version() {
+        let pid = protected_id(0x22);
+        let data = [0x4A, 0x55, 0x93, 0xE5];
+        let mut classic = data.to_vec();
+        classic.push(0xE6);
+        let levels = frame_levels(pid, &classic);
+
+        assert!(decode(&levels, LinVersion::V1)[0].checksum_ok);
+        let frame = &decode(&levels, LinVersion::V2)[0];
+        assert_eq!((frame.data.as_slice(), frame.checksum), (&data[..], 0xE6));
+        assert!(!frame.checksum_ok);
+
+        // A master request is checked with the classic checksum in LIN 2.x
+        let frames = decode(&frame_levels(0x3C, &classic), LinVersion::V2);
+        assert!(frames[0].checksum_ok);
+    }
+
+    #[test]
+    fn decodes_consecutive_frames_and_headers_without_response() {
+        let pid = protected_id(0x3F);
+        let mut levels = frame_levels(protected_id(0x05), &[]);
+        levels.extend(frame_levels(pid, &[0x12, 0x34, LinVersion::V2.checksum(pid, &[0x12, 0x34])]));
+        let frames = decode(&levels, LinVersion::V2);
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].id(), frames[0].data.len(), frames[0].checksum_ok), (0x05, 0, false));
+        assert_eq!((frames[1].id(), frames[1].data.as_slice()), (0x3F, &[0x12, 0x34][..]));
+        assert!(frames[1].checksum_ok);
+    }
+
+    #[test]
+    fn reports_parity_errors_and_ignores_short_breaks() {
+        // A 0x00 byte is 9 bits dominant, too short for a break
+        let mut levels = Vec::new();
+        push_byte(&mut levels, 0x00);
+        levels.extend([true; 4]);
+        // Identifier 0x10 with P1 set
+        levels.extend(frame_levels(0xD0, &[0x00, 0xFF]));
+        let frames = decode(&levels, LinVersion::V2);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].id(), 0x10);
+        assert!(!frames[0].pid_parity_ok);
+    }
+
+    #[test]
+    fn accepts_break_cut_off_by_the_capture() {
+        let levels = frame_levels(protected_id(0x01), &[0x07, LinVersion::V1.checksum(0xC1, &[0x07])]);
+        let (time, waveform) = render(&levels[5..], false);
+        let frames = decode_lin(&time, &waveform, BAUD_RATE, THRESHOLD, LinVersion::V1).unwrap();
+
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].break_detected);
+        assert_eq!((frames[0].sync_byte, frames[0].pid, frames[0].data.as_slice()), (0x55, 0xC1, &[0x07][..]));
+        assert!(frames[0].checksum_ok);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let (time, waveform) = render(&frame_levels(0x80, &[]), true);
+        assert!(decode_lin(&time, &waveform, 0, THRESHOLD, LinVersion::V2).is_err());
+        assert!(decode_lin(&time[1..], &waveform, BAUD_RATE, THRESHOLD, LinVersion::V2).is_err());
+    }
+}
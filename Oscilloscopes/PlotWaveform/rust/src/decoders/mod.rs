@@ -0,0 +1,126 @@
+//! Protocol decoders working on captured waveforms.
+
+pub mod can;
+pub mod i2c;
+pub mod lin;
+pub mod spi;
+pub mod uart;
+
+use anyhow::{Result, anyhow};
+
+/// A waveform converted to logic levels on a uniform time grid.
+pub(crate) struct LogicTrace {
+    start_time: f64,
+    sample_interval: f64,
+    levels: Vec<bool>,
+}
+
+impl LogicTrace {
+    /// Slice `waveform` at `threshold`. Samples above it are high.
+    pub(crate) fn new(time_values: &[f32], waveform: &[f32], threshold: f32) -> Result<Self> {
+        if time_values.len() != waveform.len() {
+            return Err(anyhow!(
+                "Time and waveform lengths differ ({} vs {})", time_values.len(), waveform.len()
+            ));
+        }
+        if waveform.len() < 2 {
+            return Err(anyhow!("Waveform too short to decode"));
+        }
+        let sample_interval = (time_values[1] - time_values[0]) as f64;
+        if sample_interval <= 0.0 {
+            return Err(anyhow!("Time values must be increasing"));
+        }
+
+        Ok(Self {
+            start_time: time_values[0] as f64,
+            sample_interval,
+            levels: waveform.iter().map(|&v| v > threshold).collect(),
+        })
+    }
+
+    /// Slice `waveform` with hysteresis. The level turns high above `high`
+    /// and low below `low`, and keeps its previous value in between,
+    /// starting low.
+    pub(crate) fn with_hysteresis(time_values: &[f32], waveform: &[f32], high: f32, low: f32) -> Result<Self> {
+        if low > high {
+            return Err(anyhow!("Low threshold {} is above high threshold {}", low, high));
+        }
+        let mut trace = Self::new(time_values, waveform, high)?;
+        let mut level = false;
+        for (slot, &v) in trace.levels.iter_mut().zip(waveform) {
+            if v > high {
+                level = true;
+            } else if v < low {
+                level = false;
+            }
+            *slot = level;
+        }
+        Ok(trace)
+    }
+
+    /// Threshold halfway between the waveform's extremes.
+    pub(crate) fn midpoint_threshold(waveform: &[f32]) -> f32 {
+        let min = waveform.iter().fold(f32::INFINITY, |a, &b| a.min(b));
+        let max = waveform.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        (min + max) / 2.0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub(crate) fn level(&self, index: usize) -> bool {
+        self.levels[index]
+    }
+
+    pub(crate) fn time_of(&self, index: usize) -> f64 {
+        self.start_time + index as f64 * self.sample_interval
+    }
+
+    /// Index of the sample closest to `time`, if it lies inside the trace.
+    pub(crate) fn index_at(&self, time: f64) -> Option<usize> {
+        let index = ((time - self.start_time) / self.sample_interval).round();
+        if index < 0.0 || index >= self.levels.len() as f64 {
+            return None;
+        }
+        Some(index as usize)
+    }
+
+    /// Logic level at `time`, if it lies inside the trace.
+    pub(crate) fn level_at(&self, time: f64) -> Option<bool> {
+        self.index_at(time).map(|index| self.levels[index])
+    }
+
+    /// Invert every level, for signals that idle low.
+    pub(crate) fn invert(&mut self) {
+        for level in &mut self.levels {
+            *level = !*level;
+        }
+    }
+}
+
+/// Linearly interpolate `values`, sampled at `times`, onto `target_times`.
+/// Points outside the source range take the nearest edge value.
+pub(crate) fn interpolate(times: &[f32], values: &[f32], target_times: &[f32]) -> Vec<f32> {
+    let len = times.len().min(values.len());
+    if len == 0 {
+        return vec![0.0; target_times.len()];
+    }
+
+    let mut segment = 0;
+    target_times.iter().map(|&t| {
+        if t <= times[0] {
+            return values[0];
+        }
+        if t >= times[len - 1] {
+            return values[len - 1];
+        }
+        // Target times are increasing, so the segment only moves forward
+        while segment + 1 < len && times[segment + 1] < t {
+            segment += 1;
+        }
+        let (t0, t1) = (times[segment], times[segment + 1]);
+        let fraction = if t1 > t0 { (t - t0) / (t1 - t0) } else { 0.0 };
+        values[segment] + fraction * (values[segment + 1] - values[segment])
+    }).collect()
+}
@@ -0,0 +1,276 @@
+//! SPI decoder.
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+
+use super::LogicTrace;
+
+/// Bus mode and framing of an SPI capture.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpiConfig {
+    /// Clock idle level, `true` for idle high.
+    pub cpol: bool,
+    /// `false` samples on the leading clock edge, `true` on the trailing one.
+    pub cpha: bool,
+    /// Bits per word, 1 to 32. Words are sent MSB first.
+    pub word_size: u8,
+    pub cs_active_low: bool,
+    /// Without a chip select line, a pause between sampling edges longer
+    /// than this starts a new frame.
+    pub frame_gap_s: f32,
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            cpol: false,
+            cpha: false,
+            word_size: 8,
+            cs_active_low: true,
+            frame_gap_s: 1e-6,
+        }
+    }
+}
+
+/// One word exchanged on the bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpiFrame {
+    /// Time of the first sampling edge of the word.
+    pub start_s: f32,
+    /// Time of the last sampling edge of the word.
+    pub end_s: f32,
+    pub mosi_word: u32,
+    pub miso_word: u32,
+}
+
+fn logic_trace((time_values, waveform): (&[f32], &[f32])) -> Result<LogicTrace> {
+    LogicTrace::new(time_values, waveform, LogicTrace::midpoint_threshold(waveform))
+}
+
+/// Partially received word.
+struct Word {
+    start: f64,
+    bits: u8,
+    mosi: u32,
+    miso: u32,
+}
+
+/// Decode SPI words from clock, data, and optional chip select captures.
+///
+/// Each signal is given as `(time, samples)` and sliced halfway between
+/// its extremes. Data is sampled on the rising clock edge when `cpol` and
+/// `cpha` are equal and on the falling edge otherwise. A word that is cut
+/// short by the end of a frame is dropped.
+pub fn decode_spi(clk: (&[f32], &[f32]), mosi: (&[f32], &[f32]), miso: (&[f32], &[f32]),
+    cs: Option<(&[f32], &[f32])>, config: SpiConfig) -> Result<Vec<SpiFrame>> {
+    if !(1..=32).contains(&config.word_size) {
+        return Err(anyhow!("Word size must be between 1 and 32, got {}", config.word_size));
+    }
+
+    let clk = logic_trace(clk)?;
+    let mosi = logic_trace(mosi)?;
+    let miso = logic_trace(miso)?;
+    let cs = cs.map(logic_trace).transpose()?;
+    let sample_on_rising = config.cpol == config.cpha;
+
+    let mut frames = Vec::new();
+    let mut word: Option<Word> = None;
+    let mut last_edge = f64::NEG_INFINITY;
+    let mut dropped_words = 0;
+
+    for i in 1..clk.len() {
+        let time = clk.time_of(i);
+
+        let selected = match &cs {
+            Some(cs) => cs.level_at(time).map(|level| level != config.cs_active_low),
+            None => Some(true),
+        };
+        if selected != Some(true) {
+            // Chip select released, end of frame
+            dropped_words += word.take().is_some() as usize;
+            continue;
+        }
+
+        let rising = !clk.level(i - 1) && clk.level(i);
+        let falling = clk.level(i - 1) && !clk.level(i);
+        let sampling_edge = if sample_on_rising { rising } else { falling };
+        if !sampling_edge {
+            continue;
+        }
+
+        if cs.is_none() && time - last_edge > config.frame_gap_s as f64 {
+            dropped_words += word.take().is_some() as usize;
+        }
+        last_edge = time;
+
+        let (Some(mosi_bit), Some(miso_bit)) = (mosi.level_at(time), miso.level_at(time)) else {
+            continue;
+        };
+        let current = word.get_or_insert(Word { start: time, bits: 0, mosi: 0, miso: 0 });
+        current.mosi = (current.mosi << 1) | mosi_bit as u32;
+        current.miso = (current.miso << 1) | miso_bit as u32;
+        current.bits += 1;
+
+        if current.bits == config.word_size {
+            frames.push(SpiFrame {
+                start_s: current.start as f32,
+                end_s: time as f32,
+                mosi_word: current.mosi,
+                miso_word: current.miso,
+            });
+            word = None;
+        }
+    }
+
+    if dropped_words > 0 {
+        warn!("Dropped {} incomplete SPI words", dropped_words);
+    }
+    info!("Decoded {} SPI words", frames.len());
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_HALF_BIT: usize = 5;
+    const SAMPLE_INTERVAL: f32 = 1e-8;
+
+    struct Capture {
+        time: Vec<f32>,
+        clk: Vec<f32>,
+        mosi: Vec<f32>,
+        miso: Vec<f32>,
+        cs: Vec<f32>,
+    }
+
+    impl Capture {
+        fn new() -> Self {
+            Self { time: vec![], clk: vec![], mosi: vec![], miso: vec![], cs: vec![] }
+        }
+
+        fn push(&mut self, samples: usize, clk: bool, mosi: bool, miso: bool, cs: bool) {
+            let level = |high: bool| if high { 3.3 } else { 0.0 };
+            for _ in 0..samples {
+                self.time.push(self.time.len() as f32 * SAMPLE_INTERVAL);
+                self.clk.push(level(clk));
+                self.mosi.push(level(mosi));
+                self.miso.push(level(miso));
+                self.cs.push(level(cs));
+            }
+        }
+    }
+
+    /// Generate a transfer of `words` (MOSI, MISO) framed by an active-low
+    /// chip select, followed by `gap` idle samples.
+    fn generate(capture: &mut Capture, cpol: bool, cpha: bool, word_size: u8, words: &[(u32, u32)], gap: usize) {
+        capture.push(4 * SAMPLES_PER_HALF_BIT, cpol, false, false, true);
+        capture.push(2 * SAMPLES_PER_HALF_BIT, cpol, false, false, false);
+        for &(mosi, miso) in words {
+            for bit in (0..word_size).rev() {
+                let mosi_bit = (mosi >> bit) & 1 == 1;
+                let miso_bit = (miso >> bit) & 1 == 1;
+                // With CPHA=0 data leads the first clock edge, with CPHA=1
+                // it changes on the first edge and is sampled on the second
+                let first_half_clk = if cpha { !cpol } else { cpol };
+                capture.push(SAMPLES_PER_HALF_BIT, first_half_clk, mosi_bit, miso_bit, false);
+                capture.push(SAMPLES_PER_HALF_BIT, !first_half_clk, mosi_bit, miso_bit, false);
+            }
+        }
+        capture.push(2 * SAMPLES_PER_HALF_BIT, cpol, false, false, false);
+        capture.push(gap.max(1), cpol, false, false, true);
+    }
+
+    fn decode(capture: &Capture, use_cs: bool, config: SpiConfig) -> Vec<SpiFrame> {
+        let cs = use_cs.then_some((capture.time.as_slice(), capture.cs.as_slice()));
+        decode_spi(
+            (&capture.time, &capture.clk),
+            (&capture.time, &capture.mosi),
+            (&capture.time, &capture.miso),
+            cs,
+            config,
+        ).unwrap()
+    }
+
+    fn words(frames: &[SpiFrame]) -> Vec<(u32, u32)> {
+        frames.iter().map(|f| (f.mosi_word, f.miso_word)).collect()
+    }
+
+    const GOLDEN: [(u32, u32); 4] = [(0xA5, 0x3C), (0x01, 0x80), (0xFF, 0x00), (0x5A, 0xC3)];
+
+    fn check_mode(cpol: bool, cpha: bool) {
+        let mut capture = Capture::new();
+        generate(&mut capture, cpol, cpha, 8, &GOLDEN, 10);
+        let config = SpiConfig { cpol, cpha, ..SpiConfig::default() };
+        let frames = decode(&capture, true, config);
+        assert_eq!(words(&frames), GOLDEN, "CPOL={} CPHA={}", cpol, cpha);
+        assert!(frames.iter().all(|f| f.start_s < f.end_s));
+    }
+
+    #[test]
+    fn decodes_mode_0() {
+        check_mode(false, false);
+    }
+
+    #[test]
+    fn decodes_mode_1() {
+        check_mode(false, true);
+    }
+
+    #[test]
+    fn decodes_mode_2() {
+        check_mode(true, false);
+    }
+
+    #[test]
+    fn decodes_mode_3() {
+        check_mode(true, true);
+    }
+
+    #[test]
+    fn wrong_mode_misreads_data() {
+        let mut capture = Capture::new();
+        generate(&mut capture, false, false, 8, &GOLDEN, 10);
+        let config = SpiConfig { cpol: false, cpha: true, ..SpiConfig::default() };
+        assert_ne!(words(&decode(&capture, true, config)), GOLDEN);
+    }
+
+    #[test]
+    fn decodes_odd_word_size() {
+        let golden = [(0x1FFF, 0x0001), (0x0ABC, 0x1234)];
+        let mut capture = Capture::new();
+        generate(&mut capture, false, false, 13, &golden, 10);
+        let config = SpiConfig { word_size: 13, ..SpiConfig::default() };
+        assert_eq!(words(&decode(&capture, true, config)), golden);
+    }
+
+    #[test]
+    fn segments_frames_by_gap_without_chip_select() {
+        let mut capture = Capture::new();
+        // A truncated 4-bit burst, then a full word after a long pause
+        generate(&mut capture, false, false, 4, &[(0xF, 0x0)], 200);
+        generate(&mut capture, false, false, 8, &[(0x96, 0x69)], 10);
+        let config = SpiConfig {
+            frame_gap_s: 100.0 * SAMPLE_INTERVAL,
+            ..SpiConfig::default()
+        };
+        assert_eq!(words(&decode(&capture, false, config)), [(0x96, 0x69)]);
+    }
+
+    #[test]
+    fn rejects_invalid_word_size() {
+        let mut capture = Capture::new();
+        generate(&mut capture, false, false, 8, &GOLDEN, 10);
+        for word_size in [0, 33] {
+            let config = SpiConfig { word_size, ..SpiConfig::default() };
+            let result = decode_spi(
+                (&capture.time, &capture.clk),
+                (&capture.time, &capture.mosi),
+                (&capture.time, &capture.miso),
+                None,
+                config,
+            );
+            assert!(result.is_err());
+        }
+    }
+}
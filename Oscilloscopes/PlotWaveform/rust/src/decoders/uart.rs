@@ -0,0 +1,416 @@
+//! UART decoder.
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+
+use super::LogicTrace;
+use crate::plot::{decimate_waveform, padded_voltage_range};
+
+/// Parity bit following the data bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Length of the stop condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+impl StopBits {
+    fn bit_times(self) -> f64 {
+        match self {
+            StopBits::One => 1.0,
+            StopBits::OneAndHalf => 1.5,
+            StopBits::Two => 2.0,
+        }
+    }
+}
+
+/// Frame format of the UART line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    /// Number of data bits, 5 to 9.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// True for a standard line that idles high, false for an inverted one.
+    pub idle_high: bool,
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        Self {
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            idle_high: true,
+        }
+    }
+}
+
+/// One decoded character.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UartFrame {
+    /// Time of the falling edge that starts the frame.
+    pub timestamp_s: f32,
+    pub data: u16,
+    pub parity_error: bool,
+    /// A stop bit was not at the idle level.
+    pub framing_error: bool,
+}
+
+/// Decode UART frames from an analog or digital capture.
+///
+/// The signal is sliced halfway between its extremes. Each frame starts at
+/// an idle-to-start-bit edge, and every bit is sampled in its middle, half
+/// a bit time after the bit boundary.
+pub fn decode_uart(time_values: &[f32], waveform: &[f32], baud_rate: u32, config: UartConfig)
+    -> Result<Vec<UartFrame>> {
+    if baud_rate == 0 {
+        return Err(anyhow!("Baud rate must be positive"));
+    }
+    if !(5..=9).contains(&config.data_bits) {
+        return Err(anyhow!("Data bits must be between 5 and 9, got {}", config.data_bits));
+    }
+
+    let mut trace = LogicTrace::new(time_values, waveform, LogicTrace::midpoint_threshold(waveform))?;
+    // Work with idle = true regardless of the line polarity
+    if !config.idle_high {
+        trace.invert();
+    }
+
+    let bit_time = 1.0 / baud_rate as f64;
+    let data_bits = config.data_bits as usize;
+    let parity_bits = if config.parity == Parity::None { 0 } else { 1 };
+    let first_stop_bit = 1 + data_bits + parity_bits;
+    let frame_bits = first_stop_bit as f64 + config.stop_bits.bit_times();
+
+    let mut frames = Vec::new();
+    let mut index = 1;
+    while index < trace.len() {
+        // Look for the idle-to-start-bit edge
+        let start_edge = trace.level(index - 1) && !trace.level(index);
+        if !start_edge {
+            index += 1;
+            continue;
+        }
+        let start = trace.time_of(index);
+        let bit_at = |bit: f64| trace.level_at(start + (bit + 0.5) * bit_time);
+
+        // A start bit that is gone by its middle was a glitch
+        match bit_at(0.0) {
+            Some(false) => {}
+            Some(true) => {
+                index += 1;
+                continue;
+            }
+            None => break,
+        }
+        if trace.index_at(start + frame_bits * bit_time).is_none() {
+            // Frame runs past the end of the capture
+            break;
+        }
+
+        let mut data = 0u16;
+        let mut ones = 0;
+        for bit in 0..data_bits {
+            if bit_at(1.0 + bit as f64) == Some(true) {
+                data |= 1 << bit;
+                ones += 1;
+            }
+        }
+
+        let parity_error = match config.parity {
+            Parity::None => false,
+            parity => {
+                if bit_at((1 + data_bits) as f64) == Some(true) {
+                    ones += 1;
+                }
+                let even = ones % 2 == 0;
+                (parity == Parity::Even) != even
+            }
+        };
+
+        let mut framing_error = bit_at(first_stop_bit as f64) != Some(true);
+        if config.stop_bits == StopBits::Two {
+            framing_error |= bit_at(first_stop_bit as f64 + 1.0) != Some(true);
+        }
+
+        frames.push(UartFrame {
+            timestamp_s: start as f32,
+            data,
+            parity_error,
+            framing_error,
+        });
+
+        // Continue searching from the middle of the first stop bit
+        index = trace.index_at(start + (first_stop_bit as f64 + 0.5) * bit_time)
+            .unwrap_or(trace.len());
+    }
+
+    info!("Decoded {} UART frames", frames.len());
+    Ok(frames)
+}
+
+/// Plot the waveform with the decoded characters written above each frame.
+/// Frames with parity or framing errors are labeled in red.
+pub fn plot_uart_decode(time_values: &[f32], waveform: &[f32], frames: &[UartFrame], output_path: &str)
+    -> Result<()> {
+    if waveform.is_empty() {
+        return Err(anyhow!("Nothing to plot"));
+    }
+
+    info!("Creating UART decode plot");
+    let root = BitMapBackend::new(output_path, (1200, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let min_time = *time_values.first().unwrap_or(&0.0);
+    let max_time = *time_values.last().unwrap_or(&1.0);
+    let (min_voltage, max_voltage) = padded_voltage_range(waveform);
+    let label_voltage = max_voltage - (max_voltage - min_voltage) * 0.05;
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption("UART Decode", ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(min_time..max_time, min_voltage..max_voltage)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Time (s)")
+        .y_desc("Voltage (V)")
+        .draw()?;
+
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    let (times, values) = decimate_waveform(time_values, waveform, 2 * columns);
+    chart.draw_series(LineSeries::new(
+        times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+        &BLUE,
+    ))?;
+
+    chart.draw_series(frames.iter().map(|frame| {
+        let color = if frame.parity_error || frame.framing_error { RED } else { BLACK };
+        Text::new(
+            format!("0x{:02X}", frame.data),
+            (frame.timestamp_s, label_voltage),
+            ("sans-serif", 14).into_font().color(&color),
+        )
+    }))?;
+
+    root.present()?;
+    info!("UART decode plot saved as {}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_BIT: usize = 16;
+    const BAUD_RATE: u32 = 9600;
+
+    /// Logic levels of a line that idles high.
+    struct Line {
+        levels: Vec<bool>,
+    }
+
+    impl Line {
+        fn new() -> Self {
+            let mut line = Self { levels: Vec::new() };
+            line.push(true, 2.0);
+            line
+        }
+
+        fn push(&mut self, level: bool, bit_times: f64) {
+            let samples = (bit_times * SAMPLES_PER_BIT as f64).round() as usize;
+            self.levels.extend(std::iter::repeat_n(level, samples));
+        }
+
+        /// Start bit, data bits LSB first and parity bit, without the stop
+        /// bits. `flip_parity` sends the wrong parity.
+        fn frame_without_stop(&mut self, data: u16, config: &UartConfig, flip_parity: bool) {
+            self.push(false, 1.0);
+            for bit in 0..config.data_bits {
+                self.push(data >> bit & 1 == 1, 1.0);
+            }
+            let odd_ones = data.count_ones() % 2 == 1;
+            match config.parity {
+                Parity::None => {}
+                Parity::Even => self.push(odd_ones != flip_parity, 1.0),
+                Parity::Odd => self.push(odd_ones == flip_parity, 1.0),
+            }
+        }
+
+        fn frame(&mut self, data: u16, config: &UartConfig) {
+            self.frame_without_stop(data, config, false);
+            self.push(true, config.stop_bits.bit_times());
+        }
+
+        /// Waveform in volts, inverted for a line that idles low.
+        fn capture(&self, idle_high: bool) -> (Vec<f32>, Vec<f32>) {
+            let sample_interval = 1.0 / (BAUD_RATE as f32 * SAMPLES_PER_BIT as f32);
+            let time = (0..self.levels.len()).map(|i| i as f32 * sample_interval).collect();
+            let waveform = self.levels.iter()
+                .map(|&level| if level == idle_high { 3.3 } else { 0.0 })
+                .collect();
+            (time, waveform)
+        }
+
+        fn decode(&self, config: UartConfig) -> Vec<UartFrame> {
+            let (time, waveform) = self.capture(config.idle_high);
+            decode_uart(&time, &waveform, BAUD_RATE, config).unwrap()
+        }
+    }
+
+    /// Data, parity error and framing error of every frame.
+    fn summary(frames: &[UartFrame]) -> Vec<(u16, bool, bool)> {
+        frames.iter().map(|f| (f.data, f.parity_error, f.framing_error)).collect()
+    }
+
+    fn clean(data: &[u16]) -> Vec<(u16, bool, bool)> {
+        data.iter().map(|&d| (d, false, false)).collect()
+    }
+
+    const GOLDEN: [u16; 5] = [0x48, 0x65, 0x00, 0xFF, 0xA5];
+
+    #[test]
+    fn decodes_8n1() {
+        let config = UartConfig::default();
+        let mut line = Line::new();
+        for data in GOLDEN {
+            line.frame(data, &config);
+        }
+        line.push(true, 2.0);
+
+        let frames = line.decode(config);
+        assert_eq!(summary(&frames), clean(&GOLDEN));
+        let bit_time = 1.0 / BAUD_RATE as f32;
+        assert!((frames[1].timestamp_s - 12.0 * bit_time).abs() < 1e-9);
+    }
+
+    #[test]
+    fn checks_even_and_odd_parity() {
+        for parity in [Parity::Even, Parity::Odd] {
+            let config = UartConfig { data_bits: 7, parity, ..UartConfig::default() };
+            let mut line = Line::new();
+            line.frame(0x41, &config);
+            line.frame(0x43, &config);
+            line.frame_without_stop(0x41, &config, true);
+            line.push(true, 1.0);
+            line.frame_without_stop(0x7E, &config, true);
+            line.push(true, 2.0);
+
+            assert_eq!(summary(&line.decode(config)),
+                [(0x41, false, false), (0x43, false, false), (0x41, true, false), (0x7E, true, false)],
+                "{:?}", parity);
+        }
+    }
+
+    #[test]
+    fn flags_a_low_stop_bit() {
+        let config = UartConfig::default();
+        let mut line = Line::new();
+        line.frame_without_stop(0x55, &config, false);
+        line.push(false, 1.0);
+        line.push(true, 2.0);
+        line.frame(0x33, &config);
+        line.push(true, 2.0);
+
+        assert_eq!(summary(&line.decode(config)), [(0x55, false, true), (0x33, false, false)]);
+    }
+
+    #[test]
+    fn handles_longer_stop_conditions() {
+        // Frames back to back, each next start bit right after the stop bits
+        let config = UartConfig { stop_bits: StopBits::OneAndHalf, ..UartConfig::default() };
+        let mut line = Line::new();
+        for data in GOLDEN {
+            line.frame(data, &config);
+        }
+        line.push(true, 1.0);
+        assert_eq!(summary(&line.decode(config)), clean(&GOLDEN));
+
+        // With two stop bits the second one is checked as well, here with a
+        // dip in its middle that is too short to start a frame
+        let config = UartConfig { stop_bits: StopBits::Two, ..UartConfig::default() };
+        let mut line = Line::new();
+        line.frame(0x12, &config);
+        line.frame_without_stop(0x34, &config, false);
+        line.push(true, 1.375);
+        line.push(false, 0.25);
+        line.push(true, 2.375);
+        line.frame(0x56, &config);
+        line.push(true, 1.0);
+        assert_eq!(summary(&line.decode(config)), [(0x12, false, false), (0x34, false, true), (0x56, false, false)]);
+    }
+
+    #[test]
+    fn decodes_an_inverted_line() {
+        let config = UartConfig { idle_high: false, ..UartConfig::default() };
+        let mut line = Line::new();
+        for data in GOLDEN {
+            line.frame(data, &config);
+        }
+        line.push(true, 2.0);
+        assert_eq!(summary(&line.decode(config)), clean(&GOLDEN));
+
+        // Read as a normal line, the idle level looks like a start bit
+        let (time, waveform) = line.capture(false);
+        let frames = decode_uart(&time, &waveform, BAUD_RATE, UartConfig::default()).unwrap();
+        assert_ne!(summary(&frames), clean(&GOLDEN));
+    }
+
+    #[test]
+    fn ignores_start_bit_glitches() {
+        let config = UartConfig::default();
+        let mut line = Line::new();
+        line.push(false, 0.25);
+        line.push(true, 2.0);
+        line.frame(0x5A, &config);
+        line.push(true, 2.0);
+
+        let frames = line.decode(config);
+        assert_eq!(summary(&frames), clean(&[0x5A]));
+        assert!(frames[0].timestamp_s > 2.0 / BAUD_RATE as f32);
+    }
+
+    #[test]
+    fn drops_a_frame_cut_off_by_the_capture() {
+        let config = UartConfig::default();
+        let mut line = Line::new();
+        line.frame(0x31, &config);
+        line.frame_without_stop(0x32, &config, false);
+        assert_eq!(summary(&line.decode(config)), clean(&[0x31]));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let mut line = Line::new();
+        line.frame(0x31, &UartConfig::default());
+        let (time, waveform) = line.capture(true);
+        for data_bits in [4, 10] {
+            let config = UartConfig { data_bits, ..UartConfig::default() };
+            assert!(decode_uart(&time, &waveform, BAUD_RATE, config).is_err());
+        }
+        assert!(decode_uart(&time, &waveform, 0, UartConfig::default()).is_err());
+    }
+
+    #[test]
+    fn plot_reports_an_unwritable_path() {
+        let config = UartConfig::default();
+        let mut line = Line::new();
+        line.frame(0x31, &config);
+        let (time, waveform) = line.capture(true);
+        let frames = line.decode(config);
+        let path = std::env::temp_dir().join("no-such-directory").join("uart.png");
+        assert!(plot_uart_decode(&time, &waveform, &frames, &path.display().to_string()).is_err());
+    }
+}
@@ -0,0 +1,1146 @@
+//! Connection to the instrument and low-level SCPI I/O.
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{info, error, warn};
+use visa_rs::enums::attribute::{AttrTermcharEn, HasAttribute};
+use visa_rs::prelude::*;
+use visa_rs::VisaString;
+
+use crate::acquisition::DEFAULT_WAIT_TIMEOUT;
+use crate::scpi::ErrorCheck;
+use crate::settings::ChannelScaling;
+use crate::trace::{TraceFile, TracingTransport};
+use crate::transport::{set_visa_timeout, Transport, VisaTransport};
+use crate::waveform::{ByteOrderMode, TimeReference};
+use crate::{Result, ScopeError, VisaError};
+
+/// Number of analog input channels.
+pub(crate) const CHANNEL_COUNT: u8 = 4;
+
+/// Reject channel numbers outside of 1..=CHANNEL_COUNT.
+pub(crate) fn check_channel(channel: u8) -> Result<()> {
+    if !(1..=CHANNEL_COUNT).contains(&channel) {
+        return Err(ScopeError::InvalidChannel(channel));
+    }
+    Ok(())
+}
+
+/// Connection to a Batronix oscilloscope.
+pub struct OscilloscopeWaveform {
+    pub(crate) device: Box<dyn Transport>,
+    pub(crate) error_check: ErrorCheck,
+    /// How long to wait for an acquisition to complete
+    pub(crate) wait_timeout: Duration,
+    timeouts: Timeouts,
+    progress_callback: Option<Box<dyn Fn(TransferProgress) + Send>>,
+    /// Unit conversion of every channel, applied when samples are decoded
+    pub(crate) channel_scaling: [Cell<ChannelScaling>; CHANNEL_COUNT as usize],
+    /// File the SCPI trace is mirrored to, shared with the transport
+    pub(crate) scpi_log: TraceFile,
+    /// Byte order of data blocks
+    pub(crate) byte_order: ByteOrderMode,
+    /// Zero point of the time axis of captures
+    pub(crate) time_reference: TimeReference,
+    /// Restart acquisition when the connection is closed
+    run_on_close: bool,
+    /// `close` already returned the instrument to local control
+    closed: bool,
+}
+
+/// I/O timeout for the commands sent when the connection is closed, so a
+/// dead connection doesn't hold up the exit.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Amount of block data read between two progress reports.
+const PROGRESS_INTERVAL: usize = 64 * 1024;
+
+/// State of a running binary block transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub bytes_received: usize,
+    /// Size of the block, or 0 if the instrument sent an indefinite-length
+    /// block whose size is only known at its end.
+    pub bytes_total: usize,
+    /// Time since the block header was read.
+    pub elapsed: Duration,
+}
+
+impl TransferProgress {
+    /// Average transfer rate so far in megabytes per second.
+    pub fn throughput_mb_s(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 { self.bytes_received as f64 / seconds / 1e6 } else { 0.0 }
+    }
+}
+
+/// Progress callback for reads nobody watches.
+pub(crate) fn no_progress(_bytes_received: usize, _bytes_total: usize) {}
+
+/// Longest text line kept when a response is not a block.
+const MAX_TEXT_RESPONSE: usize = 1024;
+
+/// Most bytes discarded after a definite-length block while looking for
+/// its newline. Anything longer means the stream is out of step.
+const MAX_TERMINATOR_BYTES: usize = 16;
+
+/// I/O timeout for the terminator after a block. It is sent together with
+/// the block, so if it is not there right away there is none.
+const TERMINATOR_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Outcome of [`read_ieee_block`].
+#[derive(Debug)]
+pub(crate) struct BlockTransfer {
+    /// Time spent on the data after the header.
+    pub elapsed: Duration,
+    /// The block had a definite length, so whatever terminates it is
+    /// still to be read with [`skip_terminator`].
+    pub terminator_pending: bool,
+}
+
+/// Read an IEEE-488.2 block into `data` and call `report` with the bytes
+/// read so far, the block size and the time since the header after every
+/// chunk.
+///
+/// Definite-length (`#<n><length><data>`) and indefinite-length
+/// (`#0<data>`) blocks are accepted. An indefinite-length block is read up
+/// to the END indicator, a definite-length one up to its last data byte. A
+/// response not starting with `#` is read to the end of its line and
+/// returned as [`ScopeError::NotABlock`].
+pub(crate) fn read_ieee_block(mut reader: impl Read, data: &mut Vec<u8>,
+    report: impl Fn(usize, usize, Duration)) -> Result<BlockTransfer> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    if byte[0] != b'#' {
+        return Err(ScopeError::NotABlock(read_text_line(&mut reader, byte[0])?));
+    }
+    reader.read_exact(&mut byte)?;
+    if !byte[0].is_ascii_digit() {
+        return Err(ScopeError::InvalidHeader { got: byte[0] });
+    }
+    if byte[0] == b'0' {
+        let elapsed = read_indefinite_block(reader, data, report)?;
+        return Ok(BlockTransfer { elapsed, terminator_pending: false });
+    }
+
+    let size_len = (byte[0] - b'0') as usize;
+    let mut size_str = vec![0u8; size_len];
+    reader.read_exact(&mut size_str)?;
+    let size_str = String::from_utf8_lossy(&size_str);
+    let data_size = size_str.parse::<usize>()
+        .map_err(|_| ScopeError::InvalidBlockLength(size_str.to_string()))?;
+
+    // Now read the actual data, in pieces so progress can be reported
+    data.resize(data_size, 0);
+    let start_time = Instant::now();
+    let mut bytes_received = 0;
+    while bytes_received < data_size {
+        let end = (bytes_received + PROGRESS_INTERVAL).min(data_size);
+        reader.read_exact(&mut data[bytes_received..end])?;
+        bytes_received = end;
+        report(bytes_received, data_size, start_time.elapsed());
+    }
+    Ok(BlockTransfer { elapsed: start_time.elapsed(), terminator_pending: true })
+}
+
+/// Discard what follows a definite-length block up to and including its
+/// newline. Depending on the termination settings the instrument sends
+/// `\n`, `\r\n` or nothing, so a read timing out or hitting the end just
+/// means there is no more. Returns the number of bytes discarded, or `None`
+/// if there was no newline within [`MAX_TERMINATOR_BYTES`].
+pub(crate) fn skip_terminator(mut reader: impl Read) -> Result<Option<usize>> {
+    let mut byte = [0u8; 1];
+    for count in 1..=MAX_TERMINATOR_BYTES {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(Some(count - 1)),
+            Ok(_) if byte[0] == b'\n' => return Ok(Some(count)),
+            Ok(_) => {}
+            Err(e) => match ScopeError::from(e) {
+                ScopeError::Timeout => return Ok(Some(count - 1)),
+                e => return Err(e),
+            },
+        }
+    }
+    Ok(None)
+}
+
+/// Read the data of an indefinite-length block, which ends with a newline
+/// sent with the END indicator. A read returning less than requested has
+/// hit the END. Once the size is known, a last report with the final size
+/// marks the block complete.
+fn read_indefinite_block(mut reader: impl Read, data: &mut Vec<u8>, report: impl Fn(usize, usize, Duration))
+    -> Result<Duration> {
+    let start_time = Instant::now();
+    data.clear();
+    loop {
+        let start = data.len();
+        data.resize(start + PROGRESS_INTERVAL, 0);
+        let count = reader.read(&mut data[start..])?;
+        data.truncate(start + count);
+        report(data.len(), 0, start_time.elapsed());
+        if count < PROGRESS_INTERVAL {
+            break;
+        }
+    }
+    if data.last() == Some(&b'\n') {
+        data.pop();
+    }
+    report(data.len(), data.len(), start_time.elapsed());
+    Ok(start_time.elapsed())
+}
+
+/// Read the rest of a text line that started with `first`, without
+/// reading past its newline.
+fn read_text_line(mut reader: impl Read, first: u8) -> Result<String> {
+    let mut line = vec![first];
+    let mut byte = [0u8; 1];
+    while line.last() != Some(&b'\n') && line.len() < MAX_TEXT_RESPONSE {
+        match reader.read(&mut byte)? {
+            0 => break,
+            _ => line.push(byte[0]),
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).trim().to_string())
+}
+
+/// Resource patterns searched during discovery.
+const DISCOVERY_PATTERNS: [&str; 3] = ["?*::INSTR", "USB?*INSTR", "TCPIP?*INSTR"];
+
+/// VISA I/O timeouts for the different kinds of operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long a resource gets to answer `*IDN?` during discovery.
+    pub discovery: Duration,
+    /// How long to wait for access to the instrument and for the answer
+    /// to a command or query.
+    pub command: Duration,
+    /// How long a read of block data such as a waveform may take. The
+    /// I/O timeout is raised to this for the read and restored afterwards.
+    pub transfer: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            discovery: Duration::from_secs(1),
+            command: Duration::from_secs(10),
+            transfer: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Fields of an `*IDN?` reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub manufacturer: String,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+}
+
+impl Identity {
+    /// Parse a `<manufacturer>,<model>,<serial>,<firmware>` reply. Missing
+    /// fields are left empty.
+    pub fn parse(idn: &str) -> Self {
+        let mut fields = idn.trim().splitn(4, ',').map(|field| field.trim().to_string());
+        Self {
+            manufacturer: fields.next().unwrap_or_default(),
+            model: fields.next().unwrap_or_default(),
+            serial: fields.next().unwrap_or_default(),
+            firmware: fields.next().unwrap_or_default(),
+        }
+    }
+
+    /// Whether the instrument was made by Batronix.
+    pub fn is_batronix(&self) -> bool {
+        self.manufacturer.contains("Batronix")
+    }
+}
+
+/// A VISA resource found by [`discover_devices`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    /// VISA resource string, usable with [`DeviceSelector::Resource`].
+    pub resource: String,
+    /// `None` if the resource could not be opened or did not answer
+    /// `*IDN?` in time.
+    pub identity: Option<Identity>,
+}
+
+impl DiscoveredDevice {
+    /// Whether the resource answered `*IDN?`.
+    pub fn is_responsive(&self) -> bool {
+        self.identity.is_some()
+    }
+
+    /// Whether the resource identified itself as a Batronix instrument.
+    pub fn is_batronix(&self) -> bool {
+        self.identity.as_ref().is_some_and(Identity::is_batronix)
+    }
+}
+
+/// Which instrument [`OscilloscopeWaveform::open`] connects to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The first Batronix instrument found.
+    #[default]
+    Auto,
+    /// A network instrument by IP address or host name.
+    Address(String),
+    /// The Batronix instrument with this serial number.
+    Serial(String),
+    /// A VISA resource string such as `USB0::0x1234::0x5678::SN::INSTR`.
+    Resource(String),
+}
+
+/// Default baud rate of [`ConnectionTarget::Serial`]. The virtual COM port
+/// of the instrument ignores it, real serial lines need the configured one.
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// How [`OscilloscopeWaveform::new`] reaches the instrument.
+///
+/// Parsed from `auto`, `usb::SERIALNUMBER`, `tcpip::HOST[::PORT]`,
+/// `visa::RESOURCE` and `serial::PORT[::BAUD]`, the syntax of the
+/// `--connect` option.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ConnectionTarget {
+    /// The first Batronix instrument found by [`discover_devices`].
+    #[default]
+    Auto,
+    /// A USB instrument by serial number, found with a VISA search instead
+    /// of querying every resource.
+    Usb { serial: String },
+    /// A network instrument by IP address or host name. Without a port VISA
+    /// picks the instrument protocol, with one a raw SCPI socket is used,
+    /// usually on port 5025.
+    Tcp { host: String, port: Option<u16> },
+    /// A VISA resource string passed through as is, e.g.
+    /// `USB0::0x1234::0x5678::SN::INSTR`.
+    VisaResource(String),
+    /// The virtual COM port of the instrument or another serial line, e.g.
+    /// `COM3` or `/dev/ttyACM0`. Needs the `serial` feature.
+    Serial { port: String, baud: u32 },
+}
+
+impl FromStr for ConnectionTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("auto") {
+            return Ok(ConnectionTarget::Auto);
+        }
+        let expected = || format!(
+            "Invalid connection '{}', expected auto, usb::SERIAL, tcpip::HOST[::PORT], visa::RESOURCE or \
+             serial::PORT[::BAUD]", value
+        );
+        let (kind, rest) = value.split_once("::").ok_or_else(expected)?;
+        if rest.is_empty() {
+            return Err(expected());
+        }
+        match kind.to_ascii_lowercase().as_str() {
+            "usb" => Ok(ConnectionTarget::Usb { serial: rest.to_string() }),
+            "tcpip" | "tcp" => match rest.split_once("::") {
+                Some((host, port)) => {
+                    let port = port.parse().map_err(|_| format!("Invalid TCP port '{}'", port))?;
+                    Ok(ConnectionTarget::Tcp { host: host.to_string(), port: Some(port) })
+                }
+                None => Ok(ConnectionTarget::Tcp { host: rest.to_string(), port: None }),
+            },
+            "visa" => Ok(ConnectionTarget::VisaResource(rest.to_string())),
+            "serial" => match rest.rsplit_once("::") {
+                Some((port, baud)) => {
+                    let baud = baud.parse().map_err(|_| format!("Invalid baud rate '{}'", baud))?;
+                    Ok(ConnectionTarget::Serial { port: port.to_string(), baud })
+                }
+                None => Ok(ConnectionTarget::Serial { port: rest.to_string(), baud: DEFAULT_BAUD_RATE }),
+            },
+            _ => Err(expected()),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionTarget::Auto => write!(f, "auto"),
+            ConnectionTarget::Usb { serial } => write!(f, "usb::{}", serial),
+            ConnectionTarget::Tcp { host, port: None } => write!(f, "tcpip::{}", host),
+            ConnectionTarget::Tcp { host, port: Some(port) } => write!(f, "tcpip::{}::{}", host, port),
+            ConnectionTarget::VisaResource(resource) => write!(f, "visa::{}", resource),
+            ConnectionTarget::Serial { port, baud } => write!(f, "serial::{}::{}", port, baud),
+        }
+    }
+}
+
+pub(crate) fn visa_string(value: &str) -> Result<VisaString> {
+    CString::new(value)
+        .map(VisaString::from)
+        .map_err(|_| ScopeError::InvalidArgument(format!("Invalid VISA string {:?}", value)))
+}
+
+/// The VISA resource of the USB instrument with serial number `serial`.
+fn find_usb_resource(serial: &str) -> Result<String> {
+    if serial.contains(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_') {
+        return Err(ScopeError::InvalidArgument(format!("Invalid serial number '{}'", serial)));
+    }
+    let rm = DefaultRM::new()?;
+    // With or without the interface number in front of INSTR
+    let pattern = format!("USB?*::?*::?*::{}::?*INSTR", serial);
+    let mut resources = rm.find_res_list(&visa_string(&pattern)?).map_err(|e| match VisaError::from(e) {
+        VisaError::ResourceNotFound => {
+            error!("No USB instrument with serial number {}", serial);
+            ScopeError::NoDeviceFound
+        }
+        e => ScopeError::Visa(e),
+    })?;
+    let resource = resources.find_next()?.ok_or(ScopeError::NoDeviceFound)?;
+    Ok(resource.to_string())
+}
+
+/// Open a resource briefly and ask for its identity.
+fn query_identity(rm: &DefaultRM, resource: &VisaString, timeout: Duration) -> Result<Identity> {
+    let device = rm.open(resource, AccessMode::NO_LOCK, timeout)?;
+    set_visa_timeout(&device, timeout)?;
+    (&device).write_all(b"*IDN?\n")?;
+    let mut idn = String::new();
+    BufReader::new(&device).read_line(&mut idn)?;
+    Ok(Identity::parse(&idn))
+}
+
+/// List all VISA instruments with their identity.
+///
+/// Every resource matching the search patterns is opened just long enough
+/// to query `*IDN?`, waiting up to `timeout` for each. Resources that
+/// cannot be opened or don't answer are still listed, without an identity.
+pub fn discover_devices(rm: &DefaultRM, timeout: Duration) -> Result<Vec<DiscoveredDevice>> {
+    info!("Searching for VISA devices");
+
+    let mut resources: Vec<VisaString> = Vec::new();
+    for pattern in DISCOVERY_PATTERNS {
+        info!("Trying pattern: {}", pattern);
+        match rm.find_res_list(&visa_string(pattern)?) {
+            Ok(list) => {
+                for resource in list.flatten() {
+                    // The patterns overlap, list every resource once
+                    if !resources.contains(&resource) {
+                        resources.push(resource);
+                    }
+                }
+            }
+            Err(e) => error!("Error listing resources for pattern {}: {}", pattern, e),
+        }
+    }
+
+    let devices = resources.iter().map(|resource| {
+        let identity = match query_identity(rm, resource, timeout) {
+            Ok(identity) => {
+                info!("{} responded: {} {}", resource, identity.manufacturer, identity.model);
+                Some(identity)
+            }
+            Err(e) => {
+                warn!("{} did not respond: {}", resource, e);
+                None
+            }
+        };
+        DiscoveredDevice { resource: resource.to_string(), identity }
+    }).collect();
+    Ok(devices)
+}
+
+impl OscilloscopeWaveform {
+    /// Connect to the instrument at `target`.
+    ///
+    /// A serial target fails with [`ScopeError::TransportUnavailable`] if
+    /// the library was built without the `serial` feature.
+    pub fn new(target: &ConnectionTarget, timeouts: Timeouts) -> Result<Self> {
+        info!("Connecting to {}", target);
+        let selector = match target {
+            ConnectionTarget::Auto => DeviceSelector::Auto,
+            ConnectionTarget::Usb { serial } => DeviceSelector::Resource(find_usb_resource(serial)?),
+            ConnectionTarget::Tcp { host, port: None } => DeviceSelector::Address(host.clone()),
+            ConnectionTarget::Tcp { host, port: Some(port) } => {
+                DeviceSelector::Resource(format!("TCPIP::{}::{}::SOCKET", host, port))
+            }
+            ConnectionTarget::VisaResource(resource) => DeviceSelector::Resource(resource.clone()),
+            #[cfg(feature = "serial")]
+            ConnectionTarget::Serial { port, baud } => {
+                let transport = crate::transport::SerialTransport::open(port, *baud, timeouts.command)?;
+                return Ok(Self::connected(Box::new(transport), timeouts));
+            }
+            #[cfg(not(feature = "serial"))]
+            ConnectionTarget::Serial { .. } => {
+                return Err(ScopeError::TransportUnavailable {
+                    transport: "Serial".to_string(),
+                    feature: "serial".to_string(),
+                });
+            }
+        };
+        Self::open_with_timeouts(&selector, timeouts)
+    }
+
+    /// Connect to the instrument picked by `selector`, with the default
+    /// timeouts.
+    pub fn open(selector: &DeviceSelector) -> Result<Self> {
+        Self::open_with_timeouts(selector, Timeouts::default())
+    }
+
+    /// Connect to the instrument picked by `selector`.
+    pub fn open_with_timeouts(selector: &DeviceSelector, timeouts: Timeouts) -> Result<Self> {
+        info!("Initializing VISA");
+        let rm = DefaultRM::new()?;
+
+        let resource = match selector {
+            DeviceSelector::Auto => discover_devices(&rm, timeouts.discovery)?.into_iter()
+                .find(DiscoveredDevice::is_batronix)
+                .ok_or(ScopeError::NoDeviceFound)?
+                .resource,
+            DeviceSelector::Serial(serial) => discover_devices(&rm, timeouts.discovery)?.into_iter()
+                .find(|device| device.identity.as_ref()
+                    .is_some_and(|identity| identity.is_batronix() && identity.serial == *serial))
+                .ok_or_else(|| {
+                    error!("No Batronix device with serial number {}", serial);
+                    ScopeError::NoDeviceFound
+                })?
+                .resource,
+            DeviceSelector::Address(address) => format!("TCPIP::{}::INSTR", address),
+            DeviceSelector::Resource(resource) => resource.clone(),
+        };
+
+        info!("Opening {}", resource);
+        let device = rm.open(&visa_string(&resource)?, AccessMode::NO_LOCK, timeouts.command)?;
+        if resource.to_ascii_uppercase().ends_with("::SOCKET") {
+            // Raw sockets have no END indicator, responses end at the newline
+            device.set_attr(AttrTermcharEn::VI_TRUE)?;
+        }
+        
+        info!("Successfully opened connection");
+        Ok(Self::connected(Box::new(VisaTransport::new(device, rm)), timeouts))
+    }
+
+    /// Set up a freshly opened connection.
+    fn connected(transport: Box<dyn Transport>, timeouts: Timeouts) -> Self {
+        let mut scope = Self::with_transport(transport);
+        scope.set_timeouts(timeouts);
+        if let Err(e) = scope.detect_byte_order() {
+            warn!("Could not detect the byte order, assuming little-endian: {}", e);
+        }
+        scope
+    }
+
+    /// Talk to an instrument through `transport` instead of VISA, e.g. a
+    /// [`SimulatedScope`](crate::simulator::SimulatedScope). The transport's
+    /// own I/O timeout is kept until `set_timeouts` is called.
+    ///
+    /// The traffic is traced as described in [`trace`](crate::trace).
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self {
+        let scpi_log = TraceFile::default();
+        Self {
+            device: Box::new(TracingTransport::new(transport, Arc::clone(&scpi_log))),
+            error_check: ErrorCheck::default(),
+            wait_timeout: DEFAULT_WAIT_TIMEOUT,
+            timeouts: Timeouts::default(),
+            progress_callback: None,
+            channel_scaling: std::array::from_fn(|_| Cell::new(ChannelScaling::default())),
+            scpi_log,
+            byte_order: ByteOrderMode::default(),
+            time_reference: TimeReference::default(),
+            run_on_close: true,
+            closed: false,
+        }
+    }
+
+    /// Change the I/O timeouts and apply the command timeout to the
+    /// session. If the instrument rejects it, the session keeps its
+    /// current timeout.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+        if let Err(e) = self.set_io_timeout(timeouts.command) {
+            warn!("Could not set the command timeout to {:?}, keeping the session default: {}", timeouts.command, e);
+        }
+    }
+
+    /// The I/O timeouts in use.
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Whether closing the connection restarts acquisition with `RUN`, on
+    /// by default. Turn it off to keep the last acquisition on the screen.
+    pub fn set_run_on_close(&mut self, run: bool) {
+        self.run_on_close = run;
+    }
+
+    /// Hand the instrument back to its front panel and close the
+    /// connection.
+    ///
+    /// Pending operations are aborted with a device clear, acquisition is
+    /// restarted unless turned off with
+    /// [`set_run_on_close`](Self::set_run_on_close), and the instrument is
+    /// returned to local control. Dropping the connection does the same but
+    /// can only log failures.
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.release()
+    }
+
+    /// The steps of [`close`](Self::close), each with a short timeout. They
+    /// stop at the first failure, as the connection is most likely gone
+    /// then.
+    fn release(&self) -> Result<()> {
+        if let Err(e) = self.set_io_timeout(CLOSE_TIMEOUT) {
+            warn!("Could not shorten the timeout for closing: {}", e);
+        }
+        self.device.clear()?;
+        if self.run_on_close {
+            self.send_command("RUN")?;
+        }
+        self.device.go_to_local()?;
+        info!("Returned the instrument to local control");
+        Ok(())
+    }
+
+    /// Report the progress of waveform and other block transfers.
+    ///
+    /// The callback runs every 64 KiB and once when the block is complete.
+    pub fn set_progress_callback(&mut self, callback: Box<dyn Fn(TransferProgress) + Send>) {
+        self.progress_callback = Some(callback);
+    }
+
+    /// The connected instrument's `*IDN?` reply.
+    pub fn idn(&self) -> Result<String> {
+        self.query("*IDN?")
+    }
+
+    /// Query the identity of the connected instrument.
+    pub fn identity(&self) -> Result<Identity> {
+        Ok(Identity::parse(&self.idn()?))
+    }
+
+    /// Send a single SCPI command, appending the line terminator.
+    pub(crate) fn send_command(&self, cmd: &str) -> Result<()> {
+        self.device.send(format!("{}\n", cmd).as_bytes())?;
+        Ok(())
+    }
+
+    /// Send raw bytes, retrying timeouts and transient VISA I/O errors.
+    ///
+    /// `cmd` is sent as is, so it has to include the line terminator. Up to
+    /// `max_attempts` attempts are made, waiting `backoff` before the first
+    /// retry and twice as long before each further one. Any other error,
+    /// e.g. a lost connection, is returned at once.
+    pub fn send_with_retry(&self, cmd: &[u8], max_attempts: u32, backoff: Duration) -> Result<()> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match self.device.send(cmd).map_err(ScopeError::from) {
+                Err(error) if error.is_transient() && attempt < max_attempts => {
+                    warn!("Send failed on attempt {} of {}: {}, retrying in {:?}", attempt, max_attempts, error, delay);
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a SCPI query and return the trimmed response line.
+    pub(crate) fn query(&self, cmd: &str) -> Result<String> {
+        self.send_command(cmd)?;
+        self.read_line()
+    }
+
+    /// Read a text response, e.g. the second of two queries sent at once.
+    pub(crate) fn read_line(&self) -> Result<String> {
+        let mut buf_reader = BufReader::new(&*self.device);
+        let mut response = String::new();
+        buf_reader.read_line(&mut response)?;
+        Ok(response.trim().to_string())
+    }
+
+    /// Read an IEEE-488.2 block that follows a query, including what
+    /// terminates it. Both definite-length (`#<n><length><data>`) and
+    /// indefinite-length (`#0<data>`) blocks are accepted.
+    pub(crate) fn read_binary_block(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_binary_block_into(&mut data)?;
+        Ok(data)
+    }
+
+    /// Read a block into `data`, reusing its allocation.
+    /// Returns the time spent on the data after the header arrived.
+    pub(crate) fn read_binary_block_into(&self, data: &mut Vec<u8>) -> Result<Duration> {
+        self.read_binary_block_with(data, &no_progress)
+    }
+
+    /// Read a block into `data` and call `progress` with the bytes read
+    /// so far and the block size after every chunk, in addition to the
+    /// callback set with `set_progress_callback`. The size is 0 for an
+    /// indefinite-length block.
+    pub(crate) fn read_binary_block_with(&self, data: &mut Vec<u8>, progress: &dyn Fn(usize, usize))
+        -> Result<Duration> {
+        let transfer = self.with_transfer_timeout(|| {
+            read_ieee_block(&*self.device, data, |bytes_received, bytes_total, elapsed| {
+                progress(bytes_received, bytes_total);
+                if let Some(callback) = &self.progress_callback {
+                    callback(TransferProgress { bytes_received, bytes_total, elapsed });
+                }
+            })
+        })?;
+        if transfer.terminator_pending {
+            self.skip_block_terminator()?;
+        }
+        Ok(transfer.elapsed)
+    }
+
+    /// Discard the terminator after a definite-length block, with a short
+    /// I/O timeout as there may be none. More than a terminator left over
+    /// means the stream is out of step, so it is resynchronized.
+    fn skip_block_terminator(&self) -> Result<()> {
+        let previous = self.io_timeout()?;
+        let shortened = self.set_io_timeout(TERMINATOR_TIMEOUT).is_ok();
+        let skipped = skip_terminator(&*self.device);
+        if shortened {
+            self.set_io_timeout(previous)?;
+        }
+        if skipped?.is_none() {
+            warn!("No newline within {} bytes after the block, resynchronizing", MAX_TERMINATOR_BYTES);
+            self.resynchronize()?;
+        }
+        Ok(())
+    }
+
+    /// Bring the connection back in step with the instrument, e.g. after a
+    /// response did not parse because bytes of an earlier one were left
+    /// unread.
+    ///
+    /// Pending responses are discarded with a device clear. Then `*OPC?`
+    /// has to return `1`, showing that queries get their own responses
+    /// again.
+    pub fn resynchronize(&self) -> Result<()> {
+        self.device.clear()?;
+        let response = self.query("*OPC?")?;
+        if response != "1" {
+            return Err(ScopeError::UnexpectedResponse { command: "*OPC?".to_string(), response });
+        }
+        info!("Connection resynchronized");
+        Ok(())
+    }
+
+    /// Run `transfer` with the I/O timeout set to the transfer timeout, see
+    /// [`with_io_timeout`](Self::with_io_timeout).
+    fn with_transfer_timeout<T>(&self, transfer: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.with_io_timeout(self.timeouts.transfer, transfer)
+    }
+
+    /// Run `operation` with the I/O timeout set to `timeout` and restore the
+    /// previous one afterwards. If the timeout can't be changed,
+    /// `operation` runs with the session's current one.
+    pub(crate) fn with_io_timeout<T>(&self, timeout: Duration, operation: impl FnOnce() -> Result<T>) -> Result<T> {
+        let previous = match self.io_timeout() {
+            Ok(previous) => previous,
+            Err(e) => {
+                warn!("Could not read the I/O timeout, keeping it: {}", e);
+                return operation();
+            }
+        };
+        if previous == timeout {
+            return operation();
+        }
+        if let Err(e) = self.set_io_timeout(timeout) {
+            warn!("Could not set the I/O timeout to {:?}, keeping {:?}: {}", timeout, previous, e);
+            return operation();
+        }
+        let result = operation();
+        if let Err(e) = self.set_io_timeout(previous) {
+            warn!("Could not restore the I/O timeout of {:?}: {}", previous, e);
+        }
+        result
+    }
+
+    /// Send `cmd` with `data` as a definite-length block argument.
+    pub(crate) fn write_binary_block(&self, cmd: &str, data: &[u8]) -> Result<()> {
+        let length = data.len().to_string();
+        if length.len() > 9 {
+            return Err(ScopeError::InvalidBlockLength(length));
+        }
+        let mut message = format!("{} #{}{}", cmd, length.len(), length).into_bytes();
+        message.reserve(data.len() + 1);
+        message.extend_from_slice(data);
+        message.push(b'\n');
+        self.device.send(&message)?;
+        Ok(())
+    }
+
+    /// Current VISA I/O timeout of the session.
+    pub(crate) fn io_timeout(&self) -> Result<Duration> {
+        self.device.timeout()
+    }
+
+    /// Change the VISA I/O timeout of the session.
+    pub(crate) fn set_io_timeout(&self, timeout: Duration) -> Result<()> {
+        self.device.set_timeout(timeout)
+    }
+
+    /// Send a SCPI query and parse the response as a number.
+    pub(crate) fn query_f64(&self, cmd: &str) -> Result<f64> {
+        let response = self.query(cmd)?;
+        response.parse::<f64>()
+            .map_err(|_| ScopeError::UnexpectedResponse { command: cmd.to_string(), response })
+    }
+}
+
+impl Drop for OscilloscopeWaveform {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(e) = self.release() {
+            warn!("Could not return the instrument to local control: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use visa_rs::enums::status::ErrorCode;
+    use crate::VisaError;
+
+    /// Reader handing out at most `chunk` bytes per read, like a slow link.
+    struct Chunked<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let count = buf.len().min(self.chunk).min(self.data.len());
+            buf[..count].copy_from_slice(&self.data[..count]);
+            self.data = &self.data[count..];
+            Ok(count)
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        read_ieee_block(Cursor::new(bytes), &mut data, |_, _, _| ())?;
+        Ok(data)
+    }
+
+    #[test]
+    fn reads_definite_blocks() {
+        assert_eq!(read(b"#15hello\n").unwrap(), b"hello");
+        assert_eq!(read(b"#10\n").unwrap(), b"");
+
+        // The terminator is left to skip_terminator, the next response stays
+        // in the reader
+        let block: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut bytes = format!("#6{}", block.len()).into_bytes();
+        bytes.extend_from_slice(&block);
+        bytes.extend_from_slice(b"\nnext");
+        let mut reader = Chunked { data: &bytes, chunk: 1000 };
+        let mut data = Vec::new();
+        let last_report = Cell::new((0, 0));
+        let transfer = read_ieee_block(&mut reader, &mut data, |received, total, _| {
+            last_report.set((received, total))
+        }).unwrap();
+        assert!(transfer.terminator_pending);
+        assert_eq!(data, block);
+        assert_eq!(last_report.get(), (block.len(), block.len()));
+        assert_eq!(skip_terminator(&mut reader).unwrap(), Some(1));
+        assert_eq!(reader.data, b"next");
+    }
+
+    #[test]
+    fn skips_any_block_terminator() {
+        let mut reader = Cursor::new(&b"\r\nnext"[..]);
+        assert_eq!(skip_terminator(&mut reader).unwrap(), Some(2));
+        assert_eq!(reader.position(), 2);
+        assert_eq!(skip_terminator(Cursor::new(&b""[..])).unwrap(), Some(0));
+        assert_eq!(skip_terminator(Cursor::new(&[b'x'; MAX_TERMINATOR_BYTES + 1][..])).unwrap(), None);
+
+        // A missing terminator times out, the data is still complete
+        struct Silent;
+        impl Read for Silent {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::TimedOut.into())
+            }
+        }
+        assert_eq!(skip_terminator(Silent).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn reads_indefinite_blocks() {
+        assert_eq!(read(b"#0hello\n").unwrap(), b"hello");
+        assert_eq!(read(b"#0\n").unwrap(), b"");
+        assert_eq!(read(b"#0no newline").unwrap(), b"no newline");
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        assert!(matches!(read(b"#x12\n"), Err(ScopeError::InvalidHeader { got: b'x' })));
+        assert!(matches!(read(b"#3a1\n"), Err(ScopeError::InvalidBlockLength(length)) if length == "a1\n"));
+        assert!(matches!(read(b"#15hel"), Err(ScopeError::Io(_))));
+        assert!(matches!(read(b""), Err(ScopeError::Io(_))));
+
+        // Text instead of a block is reported up to its newline only
+        let mut reader = Cursor::new(&b"-113,\"Undefined header\"\n#15hello\n"[..]);
+        let error = read_ieee_block(&mut reader, &mut Vec::new(), |_, _, _| ()).unwrap_err();
+        assert!(matches!(&error, ScopeError::NotABlock(text) if text == "-113,\"Undefined header\""), "{}", error);
+        assert_eq!(reader.position(), 24);
+    }
+
+    /// Transport answering every read with one block, recording the
+    /// timeout of each read. Clones share their state.
+    #[derive(Clone)]
+    struct TimedBlock {
+        block: Arc<Mutex<Cursor<Vec<u8>>>>,
+        timeout: Arc<Mutex<Duration>>,
+        read_timeouts: Arc<Mutex<Vec<Duration>>>,
+        rejects_timeouts: bool,
+    }
+
+    impl TimedBlock {
+        fn new(rejects_timeouts: bool) -> Self {
+            Self {
+                block: Arc::new(Mutex::new(Cursor::new(b"#15hello\n".to_vec()))),
+                timeout: Arc::new(Mutex::new(Duration::from_secs(2))),
+                read_timeouts: Arc::new(Mutex::new(Vec::new())),
+                rejects_timeouts,
+            }
+        }
+    }
+
+    impl Transport for TimedBlock {
+        fn send(&self, _data: &[u8]) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn receive(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.read_timeouts.lock().unwrap().push(*self.timeout.lock().unwrap());
+            self.block.lock().unwrap().read(buf)
+        }
+
+        fn clear(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn timeout(&self) -> Result<Duration> {
+            Ok(*self.timeout.lock().unwrap())
+        }
+
+        fn set_timeout(&self, timeout: Duration) -> Result<()> {
+            if self.rejects_timeouts {
+                return Err(ScopeError::InvalidArgument("read-only timeout".to_string()));
+            }
+            *self.timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn raises_the_timeout_for_block_transfers() {
+        let timeouts = Timeouts { transfer: Duration::from_secs(120), ..Timeouts::default() };
+        let transport = TimedBlock::new(false);
+        let mut scope = OscilloscopeWaveform::with_transport(Box::new(transport.clone()));
+        scope.set_timeouts(timeouts);
+        assert_eq!(*transport.timeout.lock().unwrap(), timeouts.command);
+
+        assert_eq!(scope.read_binary_block().unwrap(), b"hello");
+        // The terminator gets a short timeout of its own
+        let read_timeouts = transport.read_timeouts.lock().unwrap();
+        let (terminator, block) = read_timeouts.split_last().unwrap();
+        assert!(!block.is_empty());
+        assert!(block.iter().all(|&timeout| timeout == timeouts.transfer), "{:?}", read_timeouts);
+        assert_eq!(*terminator, TERMINATOR_TIMEOUT);
+        assert_eq!(*transport.timeout.lock().unwrap(), timeouts.command);
+    }
+
+    /// Transport failing every send with the queued errors first.
+    struct Flaky {
+        errors: Mutex<Vec<ErrorCode>>,
+        sends: Arc<Mutex<u32>>,
+    }
+
+    impl Transport for Flaky {
+        fn send(&self, _data: &[u8]) -> std::io::Result<()> {
+            *self.sends.lock().unwrap() += 1;
+            match self.errors.lock().unwrap().pop() {
+                Some(code) => Err(std::io::Error::other(visa_rs::Error(code))),
+                None => Ok(()),
+            }
+        }
+
+        fn receive(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+
+        fn clear(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn timeout(&self) -> Result<Duration> {
+            Ok(Duration::from_secs(2))
+        }
+
+        fn set_timeout(&self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn flaky(errors: &[ErrorCode]) -> (OscilloscopeWaveform, Arc<Mutex<u32>>) {
+        let sends = Arc::new(Mutex::new(0));
+        let transport = Flaky { errors: Mutex::new(errors.iter().rev().copied().collect()), sends: sends.clone() };
+        (OscilloscopeWaveform::with_transport(Box::new(transport)), sends)
+    }
+
+    #[test]
+    fn retries_transient_send_errors_only() {
+        let backoff = Duration::from_millis(1);
+        let (scope, sends) = flaky(&[ErrorCode::ErrorTmo, ErrorCode::ErrorIo]);
+        scope.send_with_retry(b"*CLS\n", 3, backoff).unwrap();
+        assert_eq!(*sends.lock().unwrap(), 3);
+
+        let (scope, sends) = flaky(&[ErrorCode::ErrorIo, ErrorCode::ErrorIo]);
+        let error = scope.send_with_retry(b"*CLS\n", 2, backoff).unwrap_err();
+        assert!(matches!(error, ScopeError::Visa(VisaError::Io)), "{}", error);
+        assert_eq!(*sends.lock().unwrap(), 2);
+
+        let (scope, sends) = flaky(&[ErrorCode::ErrorConnLost]);
+        let error = scope.send_with_retry(b"*CLS\n", 5, backoff).unwrap_err();
+        assert!(matches!(error, ScopeError::Visa(VisaError::ConnectionLost)), "{}", error);
+        assert!(!error.is_transient());
+        assert_eq!(*sends.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn maps_visa_status_codes() {
+        assert_eq!(VisaError::from(visa_rs::Error(ErrorCode::ErrorRsrcNfound)), VisaError::ResourceNotFound);
+        assert_eq!(VisaError::from(visa_rs::Error(ErrorCode::ErrorInvRsrcName)), VisaError::InvalidResourceName);
+        assert!(VisaError::from(visa_rs::Error(ErrorCode::ErrorTmo)).is_transient());
+        assert!(matches!(VisaError::from(visa_rs::Error(ErrorCode::ErrorNsupOper)), VisaError::Other(_)));
+        assert!(matches!(ScopeError::from(visa_rs::Error(ErrorCode::ErrorTmo)), ScopeError::Timeout));
+    }
+
+    #[test]
+    fn keeps_the_session_timeout_if_it_cannot_be_changed() {
+        let transport = TimedBlock::new(true);
+        let mut scope = OscilloscopeWaveform::with_transport(Box::new(transport.clone()));
+        scope.set_timeouts(Timeouts::default());
+        assert_eq!(scope.read_binary_block().unwrap(), b"hello");
+        assert!(transport.read_timeouts.lock().unwrap().iter().all(|&timeout| timeout == Duration::from_secs(2)));
+    }
+
+    /// Transport listing the commands, clears and go-to-locals it sees, or
+    /// failing everything once the connection is dead. Clones share their
+    /// state.
+    #[derive(Clone, Default)]
+    struct Session {
+        events: Arc<Mutex<Vec<String>>>,
+        dead: bool,
+    }
+
+    impl Session {
+        fn record(&self, event: &str) -> std::io::Result<()> {
+            if self.dead {
+                return Err(std::io::Error::other(visa_rs::Error(ErrorCode::ErrorConnLost)));
+            }
+            self.events.lock().unwrap().push(event.to_string());
+            Ok(())
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for Session {
+        fn send(&self, data: &[u8]) -> std::io::Result<()> {
+            self.record(String::from_utf8_lossy(data).trim())
+        }
+
+        fn receive(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::TimedOut.into())
+        }
+
+        fn clear(&self) -> Result<()> {
+            Ok(self.record("clear")?)
+        }
+
+        fn timeout(&self) -> Result<Duration> {
+            Ok(Duration::from_secs(2))
+        }
+
+        fn set_timeout(&self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        fn go_to_local(&self) -> Result<()> {
+            Ok(self.record("go to local")?)
+        }
+    }
+
+    #[test]
+    fn returns_to_local_control_when_dropped_or_closed() {
+        let session = Session::default();
+        drop(OscilloscopeWaveform::with_transport(Box::new(session.clone())));
+        assert_eq!(session.events(), ["clear", "RUN", "go to local"]);
+
+        let session = Session::default();
+        let mut scope = OscilloscopeWaveform::with_transport(Box::new(session.clone()));
+        scope.set_run_on_close(false);
+        scope.close().unwrap();
+        // Closing does not repeat the steps on drop
+        assert_eq!(session.events(), ["clear", "go to local"]);
+    }
+
+    #[test]
+    fn closing_a_dead_connection_fails_without_panicking() {
+        let session = Session { dead: true, ..Session::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(session.clone()));
+        let error = scope.close().unwrap_err();
+        assert!(matches!(error, ScopeError::Visa(VisaError::ConnectionLost)), "{}", error);
+        // Dropping only logs the failure
+        drop(OscilloscopeWaveform::with_transport(Box::new(session)));
+    }
+
+    #[test]
+    fn parses_connection_targets() {
+        let parse = |value: &str| value.parse::<ConnectionTarget>();
+        assert_eq!(parse("auto"), Ok(ConnectionTarget::Auto));
+        assert_eq!(parse("usb::A1B2C3"), Ok(ConnectionTarget::Usb { serial: "A1B2C3".to_string() }));
+        assert_eq!(parse("tcpip::192.168.1.50"),
+            Ok(ConnectionTarget::Tcp { host: "192.168.1.50".to_string(), port: None }));
+        assert_eq!(parse("TCPIP::scope.local::5025"),
+            Ok(ConnectionTarget::Tcp { host: "scope.local".to_string(), port: Some(5025) }));
+        assert_eq!(parse("visa::USB0::0x1234::0x5678::SN::INSTR"),
+            Ok(ConnectionTarget::VisaResource("USB0::0x1234::0x5678::SN::INSTR".to_string())));
+        assert_eq!(parse("serial::/dev/ttyACM0"),
+            Ok(ConnectionTarget::Serial { port: "/dev/ttyACM0".to_string(), baud: DEFAULT_BAUD_RATE }));
+        assert_eq!(parse("serial::COM3::9600"), Ok(ConnectionTarget::Serial { port: "COM3".to_string(), baud: 9600 }));
+        for invalid in ["", "192.168.1.50", "usb::", "gpib::7", "tcpip::host::http", "serial::COM3::fast"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        for target in ["auto", "usb::A1B2C3", "tcpip::scope.local::5025", "visa::ASRL1::INSTR", "serial::COM3::9600"] {
+            assert_eq!(parse(target).unwrap().to_string(), target);
+        }
+    }
+
+    #[cfg(not(feature = "serial"))]
+    #[test]
+    fn rejects_serial_targets_without_the_feature() {
+        let target = ConnectionTarget::Serial { port: "COM3".to_string(), baud: DEFAULT_BAUD_RATE };
+        assert!(matches!(OscilloscopeWaveform::new(&target, Timeouts::default()),
+            Err(ScopeError::TransportUnavailable { .. })));
+    }
+}
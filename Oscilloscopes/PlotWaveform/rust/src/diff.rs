@@ -0,0 +1,307 @@
+//! Comparison of captures with a golden reference for production tests.
+//!
+//! A reference is captured once from a known good unit. Every unit under
+//! test is then compared with it sample by sample, and a [`DiffAlarm`]
+//! decides what a failure does:
+//!
+//! ```no_run
+//! use oscilloscope_waveform::diff::{waveform_diff, AlarmAction, DiffAlarm, WaveformDiffThreshold};
+//! use oscilloscope_waveform::{DataRange, DeviceSelector, OscilloscopeWaveform, TransferFormat};
+//!
+//! let scope = OscilloscopeWaveform::open(&DeviceSelector::Auto)?;
+//! let (_, golden) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, None)?;
+//! let alarm = DiffAlarm { threshold: WaveformDiffThreshold::default(), action: AlarmAction::Log };
+//! loop {
+//!     let (_, measured) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, None)?;
+//!     alarm.check(&waveform_diff(&golden, &measured, 0.05));
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::ops::Range;
+
+use anyhow::anyhow;
+use log::{error, info, warn};
+use plotters::coord::Shift;
+use plotters::prelude::*;
+
+use crate::plot::{decimate_waveform, padded_voltage_range};
+use crate::{PlotFormat, PlotOptions};
+
+/// Violation regions beyond this many are counted but not shaded in plots.
+const MAX_HIGHLIGHTED_REGIONS: usize = 1_000;
+
+/// Deviation of a measured waveform from its reference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaveformDiffResult {
+    /// Largest absolute difference of two samples.
+    pub max_deviation: f32,
+    /// RMS value of the differences.
+    pub rms_deviation: f32,
+    /// Indices of the samples outside the tolerance, in order.
+    pub violation_indices: Vec<usize>,
+    /// True if every sample is within the tolerance.
+    pub pass: bool,
+}
+
+/// Compare `measured` with `reference` sample by sample.
+///
+/// Samples differing by more than `tolerance_abs`, or not a number, are
+/// violations. If the lengths differ, the samples only one waveform has
+/// count as violations too, while the deviations cover the common part.
+pub fn waveform_diff(reference: &[f32], measured: &[f32], tolerance_abs: f32) -> WaveformDiffResult {
+    let common = reference.len().min(measured.len());
+    if reference.len() != measured.len() {
+        warn!("Comparing {} measured samples with {} reference samples", measured.len(), reference.len());
+    }
+
+    let mut max_deviation = 0.0f32;
+    let mut sum_squares = 0.0f64;
+    let mut violation_indices = Vec::new();
+    for (index, (&expected, &actual)) in reference.iter().zip(measured).enumerate() {
+        let deviation = (actual - expected).abs();
+        if deviation.is_nan() || deviation > tolerance_abs {
+            violation_indices.push(index);
+        }
+        max_deviation = max_deviation.max(deviation);
+        sum_squares += deviation as f64 * deviation as f64;
+    }
+    violation_indices.extend(common..reference.len().max(measured.len()));
+
+    let result = WaveformDiffResult {
+        max_deviation,
+        rms_deviation: (sum_squares / common.max(1) as f64).sqrt() as f32,
+        pass: violation_indices.is_empty(),
+        violation_indices,
+    };
+    info!("Waveform diff: max {} V, RMS {} V, {} violations",
+        result.max_deviation, result.rms_deviation, result.violation_indices.len());
+    result
+}
+
+/// Limits a [`WaveformDiffResult`] is checked against by a [`DiffAlarm`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WaveformDiffThreshold {
+    /// Largest allowed deviation of a single sample, `None` for no limit.
+    pub max_deviation: Option<f32>,
+    /// Largest allowed RMS deviation, `None` for no limit.
+    pub rms_deviation: Option<f32>,
+    /// Number of samples allowed outside the tolerance. The default of 0
+    /// fails every result that did not pass.
+    pub max_violations: usize,
+}
+
+impl WaveformDiffThreshold {
+    /// Whether `result` exceeds any of the limits.
+    pub fn is_exceeded_by(&self, result: &WaveformDiffResult) -> bool {
+        let exceeds = |value: f32, limit: Option<f32>| limit.is_some_and(|limit| value.is_nan() || value > limit);
+        result.violation_indices.len() > self.max_violations
+            || exceeds(result.max_deviation, self.max_deviation)
+            || exceeds(result.rms_deviation, self.rms_deviation)
+    }
+}
+
+/// What a [`DiffAlarm`] does when its threshold is exceeded.
+pub enum AlarmAction {
+    /// Log the deviations as an error.
+    Log,
+    /// Panic, e.g. to stop a test run at the first bad unit.
+    PanicOnFail,
+    /// Hand the result to a callback, e.g. to drive a reject gate.
+    Callback(Box<dyn Fn(&WaveformDiffResult) + Send>),
+}
+
+impl std::fmt::Debug for AlarmAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlarmAction::Log => f.write_str("Log"),
+            AlarmAction::PanicOnFail => f.write_str("PanicOnFail"),
+            AlarmAction::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// An action taken for every diff result exceeding a threshold.
+#[derive(Debug)]
+pub struct DiffAlarm {
+    pub threshold: WaveformDiffThreshold,
+    pub action: AlarmAction,
+}
+
+impl DiffAlarm {
+    /// Check `result` against the threshold and take the action if it is
+    /// exceeded. Returns whether the alarm went off.
+    pub fn check(&self, result: &WaveformDiffResult) -> bool {
+        if !self.threshold.is_exceeded_by(result) {
+            return false;
+        }
+        match &self.action {
+            AlarmAction::Log => error!("Waveform out of tolerance: max {} V, RMS {} V, {} violations",
+                result.max_deviation, result.rms_deviation, result.violation_indices.len()),
+            AlarmAction::PanicOnFail => panic!("Waveform out of tolerance: max {} V, RMS {} V, {} violations",
+                result.max_deviation, result.rms_deviation, result.violation_indices.len()),
+            AlarmAction::Callback(callback) => callback(result),
+        }
+        true
+    }
+}
+
+/// Runs of consecutive indices, as index ranges.
+pub(crate) fn violation_regions(indices: &[usize]) -> Vec<Range<usize>> {
+    let mut regions: Vec<Range<usize>> = Vec::new();
+    for &index in indices {
+        match regions.last_mut() {
+            Some(region) if region.end == index => region.end = index + 1,
+            _ => regions.push(index..index + 1),
+        }
+    }
+    regions
+}
+
+fn draw_diff_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str, time: &[f32],
+    reference: &[f32], measured: &[f32], result: &WaveformDiffResult) -> anyhow::Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let voltages: Vec<f32> = reference.iter().chain(measured).copied().collect();
+    let (min_voltage, max_voltage) = padded_voltage_range(&voltages);
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(time[0]..time[time.len() - 1], min_voltage..max_voltage)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Time (s)")
+        .y_desc("Voltage (V)")
+        .draw()?;
+
+    // Shade every run of violations, widened by half a sample interval on
+    // either side so single samples show
+    let half_step = (time[time.len() - 1] - time[0]) / (time.len() - 1).max(1) as f32 / 2.0;
+    let regions = violation_regions(&result.violation_indices);
+    chart.draw_series(regions.iter()
+        .filter(|region| region.start < time.len())
+        .take(MAX_HIGHLIGHTED_REGIONS)
+        .map(|region| {
+            let (start, end) = (time[region.start], time[(region.end - 1).min(time.len() - 1)]);
+            Rectangle::new([(start - half_step, min_voltage), (end + half_step, max_voltage)], RED.mix(0.25).filled())
+        }))?;
+
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    for (waveform, color, label) in [(reference, BLACK.mix(0.5), "Reference"), (measured, BLUE.mix(1.0), "Measured")] {
+        let (times, values) = decimate_waveform(time, waveform, 2 * columns);
+        chart.draw_series(LineSeries::new(times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)), color))?
+            .label(label)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+    }
+    chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw()?;
+
+    root.present()?;
+    Ok(())
+}
+
+/// Plot the measured waveform over its reference with the regions outside
+/// the tolerance shaded in red. Only PNG and SVG output are supported.
+pub fn plot_diff(time: &[f32], reference: &[f32], measured: &[f32], result: &WaveformDiffResult,
+    options: &PlotOptions) -> anyhow::Result<()> {
+    if time.is_empty() || reference.len() != time.len() || measured.len() != time.len() {
+        return Err(anyhow!("Time, reference and measured waveform must be non-empty and of equal length"));
+    }
+
+    info!("Creating diff plot");
+    let size = (options.width, options.height);
+    match options.format {
+        PlotFormat::Png => {
+            let root = BitMapBackend::new(&options.path, size).into_drawing_area();
+            draw_diff_chart(root, &options.title, time, reference, measured, result)?;
+        }
+        PlotFormat::Svg => {
+            let root = SVGBackend::new(&options.path, size).into_drawing_area();
+            draw_diff_chart(root, &options.title, time, reference, measured, result)?;
+        }
+        PlotFormat::Html { .. } => return Err(anyhow!("Diff plots support PNG and SVG only")),
+    }
+    info!("Plot saved as {}", options.path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn measures_deviations_and_violations() {
+        let reference = [0.0, 1.0, 2.0, 3.0, 4.0];
+        let measured = [0.0, 1.5, 2.05, 1.0, f32::NAN];
+        let result = waveform_diff(&reference, &measured[..4], 0.1);
+        assert!(!result.pass);
+        assert_eq!(result.max_deviation, 2.0);
+        assert!((result.rms_deviation - (4.25f32 + 0.0025).sqrt() / 2.0).abs() < 1e-6);
+        // The reference's last sample has no measured counterpart
+        assert_eq!(result.violation_indices, [1, 3, 4]);
+
+        assert_eq!(waveform_diff(&reference, &measured, 0.1).violation_indices, [1, 3, 4]);
+        let identical = waveform_diff(&reference, &reference, 0.0);
+        assert!(identical.pass);
+        assert_eq!((identical.max_deviation, identical.rms_deviation), (0.0, 0.0));
+        assert!(waveform_diff(&[], &[], 0.1).pass);
+    }
+
+    #[test]
+    fn raises_alarms_beyond_the_threshold() {
+        let result = waveform_diff(&[0.0, 0.0, 0.0, 0.0], &[0.0, 0.2, 0.0, 0.0], 0.1);
+        let lenient = WaveformDiffThreshold { max_deviation: Some(0.5), rms_deviation: None, max_violations: 1 };
+        assert!(!lenient.is_exceeded_by(&result));
+        assert!(WaveformDiffThreshold { rms_deviation: Some(0.05), ..lenient }.is_exceeded_by(&result));
+        assert!(WaveformDiffThreshold::default().is_exceeded_by(&result));
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&calls);
+        let alarm = DiffAlarm {
+            threshold: WaveformDiffThreshold::default(),
+            action: AlarmAction::Callback(Box::new(move |result| {
+                recorded.lock().unwrap().push(result.violation_indices.clone())
+            })),
+        };
+        assert!(alarm.check(&result));
+        assert!(!alarm.check(&waveform_diff(&[1.0], &[1.0], 0.1)));
+        assert_eq!(*calls.lock().unwrap(), [vec![1]]);
+        assert!(DiffAlarm { threshold: lenient, action: AlarmAction::Log }.check(&waveform_diff(&[0.0], &[1.0], 0.1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "out of tolerance")]
+    fn panics_on_failure_if_asked_to() {
+        let alarm = DiffAlarm { threshold: WaveformDiffThreshold::default(), action: AlarmAction::PanicOnFail };
+        alarm.check(&waveform_diff(&[0.0], &[1.0], 0.1));
+    }
+
+    #[test]
+    fn shades_violation_regions() {
+        assert_eq!(violation_regions(&[2, 3, 4, 8, 10, 11]), [2..5, 8..9, 10..12]);
+
+        let time: Vec<f32> = (0..100).map(|n| n as f32 * 1e-6).collect();
+        let reference: Vec<f32> = time.iter().map(|&t| (t * 1e5).sin()).collect();
+        let mut measured = reference.clone();
+        measured[40..45].iter_mut().for_each(|v| *v += 0.5);
+        let result = waveform_diff(&reference, &measured, 0.1);
+        let path = std::env::temp_dir().join(format!("waveform-diff-{}.svg", std::process::id()));
+        let options = PlotOptions {
+            path: path.display().to_string(),
+            format: PlotFormat::Svg,
+            ..PlotOptions::default()
+        };
+        plot_diff(&time, &reference, &measured, &result, &options).unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(svg.contains("#FF0000"), "no red shading");
+        assert!(plot_diff(&time, &reference, &measured[..50], &result, &options).is_err());
+    }
+}
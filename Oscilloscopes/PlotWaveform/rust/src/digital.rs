@@ -0,0 +1,214 @@
+//! Capturing the digital inputs.
+//!
+//! The 16 digital lines D0 to D15 are grouped into two pods of eight. A pod
+//! transfers its samples bit-packed: the block starts with the same header
+//! as a `V` type analog block, followed by `POD_WIDTH` bits per sample in
+//! one continuous stream. Bits are filled least significant first, so bit 0
+//! of the first byte is the lowest line of the pod in the first sample.
+
+use std::ops::Range;
+
+use log::info;
+
+use crate::{
+    parse_metadata, ByteOrderMode, Float32Decoder, OscilloscopeWaveform, Result, SampleDecoder, ScopeError,
+    TransferFormat,
+};
+
+/// Number of digital lines per pod.
+pub const POD_WIDTH: u8 = 8;
+
+/// Number of digital pods.
+const POD_COUNT: u8 = 2;
+
+fn check_pod(pod: u8) -> Result<()> {
+    if !(1..=POD_COUNT).contains(&pod) {
+        return Err(ScopeError::InvalidArgument(format!("Invalid digital pod {}", pod)));
+    }
+    Ok(())
+}
+
+/// Digital lines of a pod, e.g. D8 to D15 for pod 2.
+pub fn pod_lines(pod: u8) -> Range<u8> {
+    let first = pod.saturating_sub(1) * POD_WIDTH;
+    first..first + POD_WIDTH
+}
+
+/// A selection of channels of one pod, channel n being the n-th line of
+/// the pod.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DigitalBus {
+    pub pod: u8,
+    pub channels: [bool; POD_WIDTH as usize],
+}
+
+impl DigitalBus {
+    /// All channels of `pod`.
+    pub fn all(pod: u8) -> Self {
+        Self { pod, channels: [true; POD_WIDTH as usize] }
+    }
+
+    /// Move the selected channels of every pod sample, as returned by
+    /// [`get_digital_bus_data`](OscilloscopeWaveform::get_digital_bus_data),
+    /// into consecutive bits starting at bit 0, lowest channel first.
+    pub fn pack(&self, samples: &[u8]) -> Vec<u8> {
+        let selected: Vec<usize> = (0..POD_WIDTH as usize).filter(|&channel| self.channels[channel]).collect();
+        samples.iter()
+            .map(|&bits| {
+                selected.iter().enumerate()
+                    .fold(0u8, |packed, (bit, &channel)| packed | ((bits >> channel) & 1) << bit)
+            })
+            .collect()
+    }
+}
+
+/// Split a bit stream into `count` samples of `width` bits.
+///
+/// Bits are taken least significant first from each byte, and the first
+/// bit of a sample ends up in bit 0 of the result.
+pub fn unpack_samples(packed: &[u8], width: u8, count: usize) -> Result<Vec<u16>> {
+    if !(1..=16).contains(&width) {
+        return Err(ScopeError::InvalidArgument(format!("Invalid sample width {} bits", width)));
+    }
+    let width = width as usize;
+    let needed = (count * width).div_ceil(8);
+    if packed.len() < needed {
+        return Err(ScopeError::InvalidBlockLength(format!(
+            "{} bytes for {} samples of {} bits, {} needed", packed.len(), count, width, needed
+        )));
+    }
+
+    let samples = (0..count)
+        .map(|sample| {
+            (0..width).fold(0u16, |bits, bit| {
+                let position = sample * width + bit;
+                let value = (packed[position / 8] >> (position % 8)) & 1;
+                bits | (value as u16) << bit
+            })
+        })
+        .collect();
+    Ok(samples)
+}
+
+/// Decode a digital data block into time values and per-sample bitfields.
+///
+/// Bit n of every sample is line Dn, so the samples of pod 2 occupy bits 8
+/// to 15. `order` is the byte order of the metadata.
+pub fn extract_digital(data: &[u8], pod: u8, order: ByteOrderMode) -> Result<(Vec<f32>, Vec<u16>)> {
+    check_pod(pod)?;
+    let metadata = parse_metadata(data, TransferFormat::Volts, order)?;
+    let packed = &data[Float32Decoder::new(order).metadata_len()..];
+    let shift = pod_lines(pod).start;
+    let samples: Vec<u16> = unpack_samples(packed, POD_WIDTH, metadata.sample_count as usize)?
+        .into_iter()
+        .map(|bits| bits << shift)
+        .collect();
+
+    let time_values = (0..samples.len())
+        .map(|i| metadata.start_time + (i as f32) * metadata.time_delta)
+        .collect();
+    Ok((time_values, samples))
+}
+
+impl OscilloscopeWaveform {
+    /// Capture a digital pod and return its time values and samples.
+    ///
+    /// See [`extract_digital`] for the bit layout of the samples.
+    pub fn get_digital_data(&self, pod: u8) -> Result<(Vec<f32>, Vec<u16>)> {
+        check_pod(pod)?;
+        self.require_capability("digital pods", |capabilities| capabilities.has_mso)?;
+        info!("Enabling digital pod {}", pod);
+        self.send_command(&format!("DIGital:POD{}:STATe 1", pod))?;
+        self.send_command("RUN")?;
+        self.verify_no_errors("digital pod setup")?;
+        self.wait_for_sequence(1)?;
+
+        info!("Capturing digital data");
+        self.send_command(&format!("DIGital:POD{}:DATa:PACK? ALL", pod))?;
+        let data = self.read_binary_block()?;
+        extract_digital(&data, pod, self.byte_order)
+    }
+
+    /// Capture a digital pod and return its time values and one byte per
+    /// sample, bit n being channel n of the pod.
+    pub fn get_digital_bus_data(&self, pod: u8) -> Result<(Vec<f32>, Vec<u8>)> {
+        let (time_values, samples) = self.get_digital_data(pod)?;
+        let shift = pod_lines(pod).start;
+        Ok((time_values, samples.into_iter().map(|bits| (bits >> shift) as u8).collect()))
+    }
+
+    /// Capture a single channel of a digital pod, e.g. channel 3 of pod 2
+    /// for D11, as one state per time value.
+    pub fn get_digital_channel_data(&self, pod: u8, channel: u8) -> Result<(Vec<f32>, Vec<bool>)> {
+        if channel >= POD_WIDTH {
+            return Err(ScopeError::InvalidArgument(format!("Invalid channel {} of digital pod {}", channel, pod)));
+        }
+        let (time_values, samples) = self.get_digital_bus_data(pod)?;
+        Ok((time_values, samples.into_iter().map(|bits| (bits >> channel) & 1 != 0).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digital_block(sample_count: u32, packed: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [1e-6f32, 0.0, 1e-6 * sample_count as f32] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&sample_count.to_le_bytes());
+        data.extend_from_slice(packed);
+        data
+    }
+
+    #[test]
+    fn unpacks_least_significant_bit_first() {
+        // One byte per sample, bit 0 is the lowest line
+        assert_eq!(unpack_samples(&[0x01, 0x80, 0xA5], 8, 3).unwrap(), [0x01, 0x80, 0xA5]);
+        // Single lines: samples 0, 3 and 7 of the first byte are high
+        assert_eq!(unpack_samples(&[0b1000_1001], 1, 8).unwrap(), [1, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn unpacks_samples_across_byte_boundaries() {
+        // Three 3-bit samples 0b101, 0b011, 0b110 fill bits 0 to 8
+        let packed = [0b10_011_101, 0b0000_0001];
+        assert_eq!(unpack_samples(&packed, 3, 3).unwrap(), [0b101, 0b011, 0b110]);
+        // Two 12-bit samples in three bytes
+        assert_eq!(unpack_samples(&[0x21, 0x43, 0x65], 12, 2).unwrap(), [0x321, 0x654]);
+    }
+
+    #[test]
+    fn rejects_short_or_invalid_input() {
+        assert!(unpack_samples(&[0xFF], 3, 3).is_err());
+        assert!(unpack_samples(&[0xFF], 0, 1).is_err());
+        assert!(unpack_samples(&[0xFF; 4], 17, 1).is_err());
+    }
+
+    #[test]
+    fn maps_pods_to_line_numbers() {
+        let data = digital_block(2, &[0x81, 0x02]);
+        let (time, samples) = extract_digital(&data, 1, ByteOrderMode::default()).unwrap();
+        assert_eq!(time, [0.0, 1e-6]);
+        assert_eq!(samples, [0x0081, 0x0002]);
+
+        let (_, samples) = extract_digital(&data, 2, ByteOrderMode::default()).unwrap();
+        assert_eq!(samples, [0x8100, 0x0200]);
+        assert_eq!(pod_lines(2), 8..16);
+        assert!(extract_digital(&data, 3, ByteOrderMode::default()).is_err());
+    }
+
+    #[test]
+    fn packs_selected_bus_channels() {
+        let samples = [0b1010_0101, 0b0101_1010, 0xFF];
+        assert_eq!(DigitalBus::all(1).pack(&samples), samples);
+
+        // Channels 0, 2 and 7 become bits 0 to 2
+        let mut bus = DigitalBus { pod: 1, channels: [false; 8] };
+        for channel in [0, 2, 7] {
+            bus.channels[channel] = true;
+        }
+        assert_eq!(bus.pack(&samples), [0b111, 0b000, 0b111]);
+    }
+}
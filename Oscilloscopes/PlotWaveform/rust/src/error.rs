@@ -0,0 +1,176 @@
+//! Error type of the library.
+
+use std::io;
+
+use thiserror::Error;
+use visa_rs::enums::status::ErrorCode;
+
+use crate::acquisition::CaptureError;
+use crate::waveform::MetadataValidationError;
+
+/// VISA status codes a caller may want to tell apart, e.g. to decide
+/// whether retrying a call can help.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum VisaError {
+    /// `VI_ERROR_TMO`
+    #[error("Timeout expired before the operation completed")]
+    Timeout,
+    /// `VI_ERROR_RSRC_NFOUND`
+    #[error("Resource not present in the system")]
+    ResourceNotFound,
+    /// `VI_ERROR_INV_RSRC_NAME`
+    #[error("Invalid resource name")]
+    InvalidResourceName,
+    /// `VI_ERROR_IO`
+    #[error("Unknown I/O error during transfer")]
+    Io,
+    /// `VI_ERROR_CONN_LOST`
+    #[error("Connection to the instrument lost")]
+    ConnectionLost,
+    /// `VI_ERROR_RSRC_LOCKED`
+    #[error("Resource locked by another session")]
+    ResourceLocked,
+    /// `VI_ERROR_RSRC_BUSY`
+    #[error("Resource busy")]
+    ResourceBusy,
+    /// Any other VISA status code.
+    #[error("{0}")]
+    Other(visa_rs::Error),
+}
+
+impl VisaError {
+    /// True for errors that may not recur when the call is repeated.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, VisaError::Timeout | VisaError::Io)
+    }
+}
+
+impl From<visa_rs::Error> for VisaError {
+    fn from(error: visa_rs::Error) -> Self {
+        match error.0 {
+            ErrorCode::ErrorTmo => VisaError::Timeout,
+            ErrorCode::ErrorRsrcNfound => VisaError::ResourceNotFound,
+            ErrorCode::ErrorInvRsrcName => VisaError::InvalidResourceName,
+            ErrorCode::ErrorIo => VisaError::Io,
+            ErrorCode::ErrorConnLost => VisaError::ConnectionLost,
+            ErrorCode::ErrorRsrcLocked => VisaError::ResourceLocked,
+            ErrorCode::ErrorRsrcBusy => VisaError::ResourceBusy,
+            _ => VisaError::Other(error),
+        }
+    }
+}
+
+/// Errors returned by `OscilloscopeWaveform`.
+#[derive(Debug, Error)]
+pub enum ScopeError {
+    #[error("No Batronix device found")]
+    NoDeviceFound,
+    /// A VISA call or instrument read did not complete within the I/O
+    /// timeout.
+    #[error("Instrument did not respond in time")]
+    Timeout,
+    /// A VISA call failed with a status other than a timeout.
+    #[error("VISA error: {0}")]
+    Visa(VisaError),
+    /// A binary block had no digit after the `#`.
+    #[error("Invalid data block header byte 0x{got:02X}")]
+    InvalidHeader { got: u8 },
+    #[error("Invalid data block length: {0}")]
+    InvalidBlockLength(String),
+    /// A text line arrived where a binary block was expected, usually an
+    /// error message of the instrument.
+    #[error("Expected a data block, instrument sent: {0}")]
+    NotABlock(String),
+    #[error("Data too short for metadata: {len} bytes, {needed} needed")]
+    MetadataTooShort { len: usize, needed: usize },
+    /// The metadata of a block contradicts itself, see
+    /// [`WaveformMetadata::validate`](crate::WaveformMetadata::validate).
+    #[error("Invalid waveform metadata: {0}")]
+    InvalidMetadata(#[from] MetadataValidationError),
+    /// An entry of the instrument's error queue.
+    #[error("Instrument error {code}: {message}")]
+    ScpiError { code: i32, message: String },
+    #[error("Unexpected response to {command}: {response}")]
+    UnexpectedResponse { command: String, response: String },
+    /// The instrument applied a different value than requested, e.g.
+    /// because the model does not support it.
+    #[error("{setting}: requested {requested}, instrument applied {applied}")]
+    SettingRejected { setting: String, requested: String, applied: String },
+    #[error("Invalid channel {0}")]
+    InvalidChannel(u8),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    /// `*CAL?` reported a failure, with its raw response.
+    #[error("Self-calibration failed: {0}")]
+    CalibrationFailed(String),
+    #[error("The {model} does not support {feature}")]
+    Unsupported { model: String, feature: String },
+    /// The connection needs a transport the library was built without.
+    #[error("{transport} connections need the `{feature}` feature, rebuild with --features {feature}")]
+    TransportUnavailable { transport: String, feature: String },
+    /// A setup file is truncated, corrupt or not a setup file at all.
+    #[error("Invalid setup file: {0}")]
+    InvalidSetupFile(String),
+    /// A measurement preset file is not valid TOML or lacks a setting.
+    #[error("Invalid preset: {0}")]
+    InvalidPreset(String),
+    #[error(transparent)]
+    Capture(#[from] CaptureError),
+    #[error("Plot error: {0}")]
+    Plot(String),
+    /// Captured data was unsuitable for an analysis, e.g. a flat signal
+    /// for a phase measurement.
+    #[error("Analysis error: {0}")]
+    Analysis(String),
+    /// Decoding or encoding a display image failed.
+    #[error("Image error: {0}")]
+    Image(String),
+    #[error("I/O error: {0}")]
+    Io(io::Error),
+}
+
+impl ScopeError {
+    /// True for responses that did not parse as expected, which is what
+    /// happens when the connection is out of step with the instrument.
+    /// [`OscilloscopeWaveform::resynchronize`](crate::OscilloscopeWaveform::resynchronize)
+    /// recovers from that.
+    pub fn is_out_of_step(&self) -> bool {
+        matches!(self, ScopeError::InvalidHeader { .. } | ScopeError::InvalidBlockLength(_) | ScopeError::NotABlock(_))
+    }
+
+    /// True for timeouts and transient VISA errors, see
+    /// [`VisaError::is_transient`].
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ScopeError::Timeout => true,
+            ScopeError::Visa(error) => error.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+impl From<visa_rs::Error> for ScopeError {
+    fn from(error: visa_rs::Error) -> Self {
+        match VisaError::from(error) {
+            VisaError::Timeout => ScopeError::Timeout,
+            error => ScopeError::Visa(error),
+        }
+    }
+}
+
+impl From<io::Error> for ScopeError {
+    /// Instrument reads and writes report VISA errors as `io::Error`, so
+    /// unwrap those again.
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::TimedOut {
+            return ScopeError::Timeout;
+        }
+        match visa_rs::Error::try_from(error) {
+            Ok(visa) => visa.into(),
+            Err(error) => ScopeError::Io(error),
+        }
+    }
+}
+
+/// Result type of the library.
+pub type Result<T> = std::result::Result<T, ScopeError>;
@@ -0,0 +1,616 @@
+//! Exporting captures to other file formats.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use byteorder::{LittleEndian, WriteBytesExt};
+use log::info;
+#[cfg(feature = "audio")]
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::WaveformMetadata;
+
+/// Magic string and version 1.0 that start every `.npy` file.
+const NPY_MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+
+/// numpy pads `.npy` headers to this size. The format only requires 16.
+const NPY_HEADER_ALIGNMENT: usize = 64;
+
+/// MS-DOS date of 1980-01-01, the earliest a ZIP entry can carry.
+const ZIP_EPOCH_DATE: u16 = 0x21;
+
+/// Fraction of full scale left free when normalizing WAV output.
+#[cfg(feature = "audio")]
+const WAV_HEADROOM: f32 = 0.05;
+
+/// Highest sample rate common audio software is expected to play.
+#[cfg(feature = "audio")]
+const MAX_AUDIO_SAMPLE_RATE: f64 = 192_000.0;
+
+/// Rates a non-integer sample rate is rounded to for PCM export.
+#[cfg(feature = "audio")]
+const STANDARD_AUDIO_RATES: [u32; 4] = [8_000, 16_000, 44_100, 48_000];
+
+/// Relative deviation from a whole number up to which a sample rate
+/// derived from `f32` time values still counts as an integer.
+#[cfg(feature = "audio")]
+const SAMPLE_RATE_TOLERANCE: f64 = 1e-5;
+
+/// How a capture is converted to audio.
+#[cfg(feature = "audio")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavOptions {
+    /// Scale the peak to just below full scale. Without it, samples are
+    /// written in volts, so absolute levels are kept but may clip.
+    pub normalize: bool,
+    /// Average every `decimation` samples into one to lower the sample
+    /// rate. 1 keeps every sample.
+    pub decimation: usize,
+}
+
+#[cfg(feature = "audio")]
+impl Default for WavOptions {
+    fn default() -> Self {
+        Self { normalize: true, decimation: 1 }
+    }
+}
+
+/// Write a capture as a mono 32-bit float WAV file.
+///
+/// The sample rate is `1 / time_delta` after decimation, rounded to whole
+/// hertz.
+#[cfg(feature = "audio")]
+pub fn export_wav_float(path: &str, time_delta: f32, samples: &[f32], options: &WavOptions) -> Result<()> {
+    if !time_delta.is_finite() || time_delta <= 0.0 {
+        return Err(anyhow!("Invalid sample interval {}", time_delta));
+    }
+    if options.decimation == 0 {
+        return Err(anyhow!("Decimation factor must be at least 1"));
+    }
+
+    let sample_rate = (1.0 / (time_delta as f64 * options.decimation as f64)).round();
+    if !(1.0..=u32::MAX as f64).contains(&sample_rate) {
+        return Err(anyhow!(
+            "Sample rate {} Hz does not fit a WAV header, adjust the decimation factor", sample_rate
+        ));
+    }
+    if sample_rate > MAX_AUDIO_SAMPLE_RATE {
+        warn!("Sample rate {} Hz is above what most audio software plays, consider decimating", sample_rate);
+    }
+
+    let mut audio: Vec<f32> = samples.chunks(options.decimation)
+        .map(|chunk| chunk.iter().sum::<f32>() / chunk.len() as f32)
+        .collect();
+    if options.normalize {
+        let peak = audio.iter().fold(0f32, |peak, &v| peak.max(v.abs()));
+        if peak > 0.0 {
+            let gain = (1.0 - WAV_HEADROOM) / peak;
+            audio.iter_mut().for_each(|v| *v *= gain);
+        }
+    }
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for &sample in &audio {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+
+    info!("Wrote {} samples at {} Hz to {}", audio.len(), spec.sample_rate, path);
+    Ok(())
+}
+
+/// Sample rate of a capture from its mean sample interval.
+///
+/// A rate that is not a whole number is replaced by the nearest standard
+/// audio rate with a warning, so the file plays at a common rate.
+#[cfg(feature = "audio")]
+fn wav_sample_rate(time_values: &[f32]) -> Result<u32> {
+    let (Some(&first), Some(&last)) = (time_values.first(), time_values.last()) else {
+        return Err(anyhow!("No samples to export"));
+    };
+    if time_values.len() < 2 || last <= first {
+        return Err(anyhow!("At least two samples in increasing time order are needed for a sample rate"));
+    }
+    let rate = (time_values.len() - 1) as f64 / (last as f64 - first as f64);
+    let rounded = rate.round();
+    if (rate - rounded).abs() <= rate * SAMPLE_RATE_TOLERANCE {
+        if !(1.0..=u32::MAX as f64).contains(&rounded) {
+            return Err(anyhow!("Sample rate {} Hz does not fit a WAV header", rounded));
+        }
+        if rounded > MAX_AUDIO_SAMPLE_RATE {
+            warn!("Sample rate {} Hz is above what most audio software plays, consider decimating", rounded);
+        }
+        return Ok(rounded as u32);
+    }
+    let standard = STANDARD_AUDIO_RATES.into_iter()
+        .min_by(|&a, &b| (a as f64 - rate).abs().total_cmp(&(b as f64 - rate).abs()))
+        .expect("standard rates are not empty");
+    warn!("Sample rate {:.3} Hz is not a whole number, writing at {} Hz", rate, standard);
+    Ok(standard)
+}
+
+/// Write a capture as a mono 16-bit PCM WAV file.
+///
+/// The sample rate follows from the mean interval of `time_values`, see
+/// [`export_wav_multichannel`]. The peak is scaled to just below full
+/// scale.
+#[cfg(feature = "audio")]
+pub fn export_wav(time_values: &[f32], waveform: &[f32], path: &str) -> Result<()> {
+    export_wav_multichannel(&[(time_values.to_vec(), waveform.to_vec())], path)
+}
+
+/// Write several captures as the channels of one 16-bit PCM WAV file,
+/// e.g. two for stereo. Every entry holds time and sample values.
+///
+/// All channels need the same number of samples and sample rate, which
+/// is taken from the mean sample interval. A rate that is not a whole
+/// number is replaced by the nearest of 8, 16, 44.1 and 48 kHz with a
+/// warning. All channels are scaled by the same factor, so their
+/// relative levels are kept, with the overall peak just below full scale.
+#[cfg(feature = "audio")]
+pub fn export_wav_multichannel(channels: &[(Vec<f32>, Vec<f32>)], path: &str) -> Result<()> {
+    let Some((first_time, first_waveform)) = channels.first() else {
+        return Err(anyhow!("No channels to export"));
+    };
+    if channels.len() > u16::MAX as usize {
+        return Err(anyhow!("{} channels do not fit a WAV header", channels.len()));
+    }
+    let len = first_waveform.len();
+    for (i, (time, waveform)) in channels.iter().enumerate() {
+        if time.len() != waveform.len() || waveform.len() != len {
+            return Err(anyhow!("Channel {} has {} time values and {} samples, expected {} of both",
+                i + 1, time.len(), waveform.len(), len));
+        }
+    }
+    let sample_rate = wav_sample_rate(first_time)?;
+    for (i, (time, _)) in channels.iter().enumerate().skip(1) {
+        if wav_sample_rate(time)? != sample_rate {
+            return Err(anyhow!("Channel {} has a different sample rate than channel 1", i + 1));
+        }
+    }
+
+    let peak = channels.iter()
+        .flat_map(|(_, waveform)| waveform)
+        .fold(0f32, |peak, &v| peak.max(v.abs()));
+    let gain = if peak > 0.0 { (1.0 - WAV_HEADROOM) * i16::MAX as f32 / peak } else { 0.0 };
+
+    let spec = hound::WavSpec {
+        channels: channels.len() as u16,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for i in 0..len {
+        for (_, waveform) in channels {
+            writer.write_sample((waveform[i] * gain).round() as i16)?;
+        }
+    }
+    writer.finalize()?;
+
+    info!("Wrote {} samples of {} channels at {} Hz to {}", len, channels.len(), sample_rate, path);
+    Ok(())
+}
+
+/// Write a capture as CSV with a `time_s,voltage_v` header.
+pub fn export_csv(path: &str, time: &[f32], voltage: &[f32]) -> Result<()> {
+    if time.len() != voltage.len() {
+        return Err(anyhow!("Time and voltage lengths differ ({} vs {})", time.len(), voltage.len()));
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "time_s,voltage_v")?;
+    for (t, v) in time.iter().zip(voltage) {
+        writeln!(writer, "{:e},{}", t, v)?;
+    }
+    writer.flush()?;
+    info!("Wrote {} samples to {}", voltage.len(), path);
+    Ok(())
+}
+
+/// A version 1.0 `.npy` file of a one-dimensional little-endian f32 array.
+fn npy_bytes(samples: &[f32]) -> Vec<u8> {
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}", samples.len());
+    // Spaces up to the alignment, then a newline ends the header
+    let unpadded = NPY_MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(NPY_HEADER_ALIGNMENT - unpadded % NPY_HEADER_ALIGNMENT));
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(NPY_MAGIC.len() + 2 + header.len() + 4 * samples.len());
+    bytes.extend_from_slice(NPY_MAGIC);
+    // The dictionary stays far below the 65535 bytes of a version 1.0 header
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
+
+/// Write samples as a NumPy `.npy` file, loadable with `numpy.load`.
+pub fn export_npy(path: &str, samples: &[f32]) -> Result<()> {
+    std::fs::write(path, npy_bytes(samples))?;
+    info!("Wrote {} samples to {}", samples.len(), path);
+    Ok(())
+}
+
+/// Write a capture as a NumPy `.npz` archive with the arrays `time` and
+/// `voltage`, e.g. `numpy.load(path)["voltage"]`.
+///
+/// The arrays are stored uncompressed. Archives of 4 GB and more would
+/// need ZIP64 and are rejected.
+pub fn export_npz(path: &str, time: &[f32], voltage: &[f32]) -> Result<()> {
+    if time.len() != voltage.len() {
+        return Err(anyhow!("Time and voltage lengths differ ({} vs {})", time.len(), voltage.len()));
+    }
+    let entries = [("time.npy", npy_bytes(time)), ("voltage.npy", npy_bytes(voltage))];
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut central_directory = Vec::new();
+    let mut offset = 0u64;
+    let too_large = || anyhow!("{} is too large for an archive without ZIP64", path);
+    for (name, data) in &entries {
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let local_offset = u32::try_from(offset).map_err(|_| too_large())?;
+        let crc = crc32fast::hash(data);
+
+        // Local file header: version 2.0, no flags, stored, 1980-01-01
+        writer.write_u32::<LittleEndian>(0x0403_4b50)?;
+        for field in [20, 0, 0, 0, ZIP_EPOCH_DATE] {
+            writer.write_u16::<LittleEndian>(field)?;
+        }
+        for field in [crc, size, size] {
+            writer.write_u32::<LittleEndian>(field)?;
+        }
+        writer.write_u16::<LittleEndian>(name.len() as u16)?;
+        writer.write_u16::<LittleEndian>(0)?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(data)?;
+        offset += 30 + name.len() as u64 + data.len() as u64;
+
+        central_directory.write_u32::<LittleEndian>(0x0201_4b50)?;
+        for field in [20, 20, 0, 0, 0, ZIP_EPOCH_DATE] {
+            central_directory.write_u16::<LittleEndian>(field)?;
+        }
+        for field in [crc, size, size] {
+            central_directory.write_u32::<LittleEndian>(field)?;
+        }
+        // Name length, no extra field, comment, disk number or attributes
+        for field in [name.len() as u16, 0, 0, 0, 0] {
+            central_directory.write_u16::<LittleEndian>(field)?;
+        }
+        central_directory.write_u32::<LittleEndian>(0)?;
+        central_directory.write_u32::<LittleEndian>(local_offset)?;
+        central_directory.write_all(name.as_bytes())?;
+    }
+    let directory_offset = u32::try_from(offset).map_err(|_| too_large())?;
+    writer.write_all(&central_directory)?;
+
+    // End of central directory record
+    writer.write_u32::<LittleEndian>(0x0605_4b50)?;
+    for field in [0, 0, entries.len() as u16, entries.len() as u16] {
+        writer.write_u16::<LittleEndian>(field)?;
+    }
+    writer.write_u32::<LittleEndian>(central_directory.len() as u32)?;
+    writer.write_u32::<LittleEndian>(directory_offset)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.flush()?;
+    info!("Wrote {} samples to {}", voltage.len(), path);
+    Ok(())
+}
+
+/// How samples are stored in JSON files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleEncoding {
+    /// Plain JSON number arrays.
+    #[default]
+    Array,
+    /// Base64 of the samples packed as little-endian f32, about a third of
+    /// the size of number arrays.
+    Base64F32Le,
+}
+
+/// A capture with everything needed to interpret it later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaveformCapture {
+    /// Capture time in seconds since the Unix epoch.
+    pub captured_at_unix_s: f64,
+    pub channel: u8,
+    /// The instrument's `*IDN?` reply.
+    pub idn: String,
+    pub metadata: WaveformMetadata,
+    pub time: Vec<f32>,
+    pub voltage: Vec<f32>,
+}
+
+impl WaveformCapture {
+    /// Bundle a capture taken just now.
+    pub fn new(channel: u8, idn: &str, metadata: WaveformMetadata, time: Vec<f32>, voltage: Vec<f32>) -> Self {
+        let captured_at_unix_s = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or(0.0);
+        Self { captured_at_unix_s, channel, idn: idn.to_string(), metadata, time, voltage }
+    }
+}
+
+/// Sample array in a JSON file, in either encoding.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonSamples {
+    Array(Vec<f32>),
+    Packed(String),
+}
+
+impl JsonSamples {
+    fn encode(samples: &[f32], encoding: SampleEncoding) -> Self {
+        match encoding {
+            SampleEncoding::Array => JsonSamples::Array(samples.to_vec()),
+            SampleEncoding::Base64F32Le => {
+                let bytes: Vec<u8> = samples.iter().flat_map(|v| v.to_le_bytes()).collect();
+                JsonSamples::Packed(BASE64_STANDARD.encode(bytes))
+            }
+        }
+    }
+
+    fn decode(self) -> Result<Vec<f32>> {
+        match self {
+            JsonSamples::Array(samples) => Ok(samples),
+            JsonSamples::Packed(packed) => {
+                let bytes = BASE64_STANDARD.decode(packed)?;
+                if bytes.len() % 4 != 0 {
+                    return Err(anyhow!("Packed samples are not a multiple of 4 bytes"));
+                }
+                Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+            }
+        }
+    }
+}
+
+/// Layout of the JSON file.
+#[derive(Serialize, Deserialize)]
+struct JsonCapture {
+    captured_at_unix_s: f64,
+    channel: u8,
+    idn: String,
+    encoding: SampleEncoding,
+    metadata: WaveformMetadata,
+    time: JsonSamples,
+    voltage: JsonSamples,
+}
+
+/// Write a capture as JSON.
+///
+/// The file holds `captured_at_unix_s`, `channel`, `idn`, `encoding`,
+/// `metadata`, `time` and `voltage` at the top level. With
+/// `SampleEncoding::Base64F32Le`, `time` and `voltage` are strings.
+pub fn export_json(path: &str, capture: &WaveformCapture, encoding: SampleEncoding) -> Result<()> {
+    if capture.time.len() != capture.voltage.len() {
+        return Err(anyhow!(
+            "Time and voltage lengths differ ({} vs {})", capture.time.len(), capture.voltage.len()
+        ));
+    }
+
+    let json = JsonCapture {
+        captured_at_unix_s: capture.captured_at_unix_s,
+        channel: capture.channel,
+        idn: capture.idn.clone(),
+        encoding,
+        metadata: capture.metadata,
+        time: JsonSamples::encode(&capture.time, encoding),
+        voltage: JsonSamples::encode(&capture.voltage, encoding),
+    };
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &json)?;
+    info!("Wrote {} samples to {}", capture.voltage.len(), path);
+    Ok(())
+}
+
+/// Read a capture written by [`export_json`].
+pub fn import_json(path: &str) -> Result<WaveformCapture> {
+    let json: JsonCapture = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    Ok(WaveformCapture {
+        captured_at_unix_s: json.captured_at_unix_s,
+        channel: json.channel,
+        idn: json.idn,
+        metadata: json.metadata,
+        time: json.time.decode()?,
+        voltage: json.voltage.decode()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "audio")]
+    fn read_back(name: &str, time_delta: f32, samples: &[f32], options: &WavOptions) -> (u32, Vec<f32>) {
+        let path = std::env::temp_dir().join(format!("{}-{}.wav", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        export_wav_float(path, time_delta, samples, options).unwrap();
+
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let spec = reader.spec();
+        assert_eq!((spec.channels, spec.bits_per_sample), (1, 32));
+        let audio = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+        std::fs::remove_file(path).unwrap();
+        (spec.sample_rate, audio)
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn round_trips_absolute_levels() {
+        let samples = [0.0, 2.5, -1.25, 0.125];
+        let options = WavOptions { normalize: false, decimation: 1 };
+        let (sample_rate, audio) = read_back("absolute", 1.0 / 48_000.0, &samples, &options);
+        assert_eq!(sample_rate, 48_000);
+        assert_eq!(audio, samples);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn normalizes_to_peak_with_headroom() {
+        let samples = [0.0, 2.0, -4.0, 1.0];
+        let (_, audio) = read_back("normalized", 1e-5, &samples, &WavOptions::default());
+        let expected: Vec<f32> = samples.iter().map(|v| v * 0.95 / 4.0).collect();
+        assert_eq!(audio, expected);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn decimates_by_averaging() {
+        let samples = [1.0, 3.0, 5.0, 7.0, 9.0];
+        let options = WavOptions { normalize: false, decimation: 2 };
+        let (sample_rate, audio) = read_back("decimated", 1e-6, &samples, &options);
+        assert_eq!(sample_rate, 500_000);
+        assert_eq!(audio, [2.0, 6.0, 9.0]);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert!(export_wav_float("unused.wav", 0.0, &[0.0], &WavOptions::default()).is_err());
+        let options = WavOptions { normalize: true, decimation: 0 };
+        assert!(export_wav_float("unused.wav", 1e-3, &[0.0], &options).is_err());
+        // 10 GHz does not fit into the header
+        assert!(export_wav_float("unused.wav", 1e-10, &[0.0], &WavOptions::default()).is_err());
+
+        assert!(export_wav(&[0.0], &[0.0], "unused.wav").is_err());
+        assert!(export_wav(&[0.0, 1e-3], &[0.0], "unused.wav").is_err());
+        let channels = [(vec![0.0, 1e-3], vec![0.0, 1.0]), (vec![0.0, 2e-3], vec![0.0, 1.0])];
+        assert!(export_wav_multichannel(&channels, "unused.wav").is_err());
+        assert!(export_wav_multichannel(&[], "unused.wav").is_err());
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn writes_pcm_at_derived_or_standard_rates() {
+        let path = std::env::temp_dir().join(format!("pcm-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // 8 kHz, peak scaled to full scale less the headroom
+        let time: Vec<f32> = (0..4).map(|n| n as f32 / 8_000.0).collect();
+        export_wav(&time, &[0.0, 0.5, -1.0, 0.25], path).unwrap();
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let spec = reader.spec();
+        assert_eq!((spec.channels, spec.bits_per_sample, spec.sample_rate), (1, 16, 8_000));
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, [0, 15_564, -31_129, 7_782]);
+
+        // 44.1005 kHz is rounded to 44.1 kHz, channels are interleaved
+        let time: Vec<f32> = (0..3).map(|n| n as f32 / 44_100.5).collect();
+        let channels = [(time.clone(), vec![1.0, 0.0, -1.0]), (time, vec![0.5, 0.5, 0.5])];
+        export_wav_multichannel(&channels, path).unwrap();
+        let mut reader = hound::WavReader::open(path).unwrap();
+        assert_eq!((reader.spec().channels, reader.spec().sample_rate), (2, 44_100));
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, [31_129, 15_564, 0, 15_564, -31_129, 15_564]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn sample_capture() -> WaveformCapture {
+        let metadata = WaveformMetadata {
+            time_delta: 1e-6,
+            start_time: -2e-6,
+            end_time: 1e-6,
+            sample_start: 0,
+            sample_length: 4,
+            vertical_start: -1.0,
+            vertical_step: 2.0,
+            sample_count: 4,
+        };
+        let time = vec![-2e-6, -1e-6, 0.0, 1e-6];
+        let voltage = vec![0.1, -0.25, 1.0e-3, 3.75];
+        WaveformCapture::new(2, "Batronix,Magnova,12345,1.0", metadata, time, voltage)
+    }
+
+    fn json_round_trip(name: &str, original: &WaveformCapture, encoding: SampleEncoding) -> (serde_json::Value, WaveformCapture) {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        export_json(path, original, encoding).unwrap();
+        let value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let capture = import_json(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        (value, capture)
+    }
+
+    #[test]
+    fn writes_npy_headers_like_numpy() {
+        // numpy.save of np.array([1, 2, 3], dtype='<f4')
+        let mut expected = b"\x93NUMPY\x01\x00\x76\x00".to_vec();
+        expected.extend(b"{'descr': '<f4', 'fortran_order': False, 'shape': (3,), }");
+        expected.resize(127, b' ');
+        expected.push(b'\n');
+        expected.extend([0, 0, 0x80, 0x3f, 0, 0, 0, 0x40, 0, 0, 0x40, 0x40]);
+        assert_eq!(npy_bytes(&[1.0, 2.0, 3.0]), expected);
+
+        // The data starts aligned whatever the length of the shape
+        for len in [0, 7, 1_000_000] {
+            let bytes = npy_bytes(&vec![0.0; len]);
+            let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+            assert_eq!((10 + header_len) % 64, 0);
+            assert_eq!(bytes[9 + header_len], b'\n');
+            assert_eq!(bytes.len(), 10 + header_len + 4 * len);
+        }
+    }
+
+    #[test]
+    fn writes_npz_archives() {
+        let path = std::env::temp_dir().join(format!("capture-{}.npz", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (time, voltage) = ([0.0, 1e-6], [0.5, -0.5]);
+        export_npz(path, &time, &voltage).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        // Walk the entries from the central directory
+        let end = bytes.len() - 22;
+        assert_eq!(u32_at(end), 0x0605_4b50);
+        assert_eq!(u16_at(end + 10), 2);
+        let mut entry = u32_at(end + 16);
+        for (name, samples) in [("time.npy", &time), ("voltage.npy", &voltage)] {
+            assert_eq!(u32_at(entry), 0x0201_4b50);
+            assert_eq!(&bytes[entry + 46..entry + 46 + u16_at(entry + 28)], name.as_bytes());
+            let local = u32_at(entry + 42);
+            let data_start = local + 30 + u16_at(local + 26);
+            let data = &bytes[data_start..data_start + u32_at(entry + 20)];
+            assert_eq!(data, npy_bytes(samples));
+            assert_eq!(u32_at(entry + 16), crc32fast::hash(data) as usize);
+            entry += 46 + name.len();
+        }
+        assert_eq!(entry, end);
+
+        assert!(export_npz(path, &time, &voltage[..1]).is_err());
+    }
+
+    #[test]
+    fn round_trips_json_arrays() {
+        let original = sample_capture();
+        let (value, capture) = json_round_trip("array", &original, SampleEncoding::Array);
+        assert_eq!(value["channel"], 2);
+        assert_eq!(value["idn"], "Batronix,Magnova,12345,1.0");
+        assert_eq!(value["metadata"]["sample_count"], 4);
+        assert!(value["voltage"].is_array());
+        assert_eq!(capture, original);
+    }
+
+    #[test]
+    fn round_trips_packed_samples() {
+        let original = sample_capture();
+        let (value, capture) = json_round_trip("packed", &original, SampleEncoding::Base64F32Le);
+        assert_eq!(value["encoding"], "base64_f32_le");
+        assert!(value["voltage"].is_string());
+        assert_eq!(capture.voltage, original.voltage);
+        assert_eq!(capture.time, original.time);
+    }
+}
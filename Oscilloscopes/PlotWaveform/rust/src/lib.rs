@@ -0,0 +1,59 @@
+//! Waveform capture and analysis for Batronix oscilloscopes.
+//!
+//! [`OscilloscopeWaveform`] wraps the VISA connection to an instrument.
+//! Its methods are spread over the feature modules below, while the block
+//! decoding in [`waveform`] and the offline analysis work without one.
+
+pub mod acquisition;
+pub mod analysis;
+#[cfg(feature = "async")]
+pub mod async_scope;
+pub mod awg;
+pub mod benchmark;
+pub mod capture_sink;
+pub mod cursor;
+pub mod decoders;
+pub mod device;
+pub mod diff;
+pub mod digital;
+pub mod error;
+pub mod export;
+pub mod mask;
+pub mod math;
+pub mod measurement;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod model;
+pub mod multi_scope;
+pub mod persistence;
+pub mod plot;
+pub mod preset;
+pub mod recorder;
+#[cfg(feature = "pdf")]
+pub mod report;
+pub mod sample_format;
+pub mod scpi;
+pub mod screenshot;
+pub mod segments;
+pub mod selftest;
+pub mod settings;
+pub mod setup;
+pub mod simulator;
+pub mod trace;
+pub mod transport;
+pub mod units;
+pub mod waveform;
+
+pub use device::{
+    discover_devices, ConnectionTarget, DeviceSelector, DiscoveredDevice, OscilloscopeWaveform, Timeouts,
+    TransferProgress,
+};
+pub use error::{Result, ScopeError, VisaError};
+pub use plot::{PlotAnnotation, PlotBackend, PlotConfig, PlotFormat, PlotOptions, SegmentPlotMode};
+pub use sample_format::{Float32Decoder, RawU16Decoder, SampleDecoder, TransferFormat};
+pub use waveform::{
+    code_to_voltage, extract_waveform, parse_metadata, ByteOrderMode, DataRange, Decimation,
+    MetadataValidationError, TimeReference, WaveformMetadata, WaveformRecord,
+};
+
+pub(crate) use device::{check_channel, CHANNEL_COUNT};
@@ -1,301 +1,740 @@
-use std::ffi::CString;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::time::{Duration, Instant};
-use anyhow::{Result, anyhow};
-use byteorder::{ByteOrder, LittleEndian};
-use log::{info, error};
-use plotters::prelude::*;
-use visa_rs::prelude::*;
-
-#[derive(Debug)]
-struct WaveformMetadata {
-    time_delta: f32,
-    start_time: f32,
-    end_time: f32,
-    sample_start: u32,
-    sample_length: u32,
-    vertical_start: f32,
-    vertical_step: f32,
-    sample_count: u32,
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+
+use oscilloscope_waveform::analysis::histogram::plot_histogram;
+use oscilloscope_waveform::analysis::{
+    compare_traces, find_crossings_with_hysteresis, histogram, measure_pulses, CompareReport, CompareTolerance,
+    Crossing, Edge, Histogram, PulseMeasurements,
+};
+use oscilloscope_waveform::benchmark::{write_benchmark_csv, BenchmarkResult};
+use oscilloscope_waveform::capture_sink::{CaptureSink, Retention};
+use oscilloscope_waveform::export::{export_csv, export_npy, export_npz, import_json};
+use oscilloscope_waveform::mask::{plot_mask_test, Mask, MaskResult};
+use oscilloscope_waveform::math::{Filter, MathExpression};
+use oscilloscope_waveform::measurement::MeasKind;
+use oscilloscope_waveform::multi_scope::MultiScope;
+use oscilloscope_waveform::preset::MeasurementPreset;
+use oscilloscope_waveform::settings::TriggerPosition;
+use oscilloscope_waveform::simulator::{SimulatedScope, SimulationConfig};
+use oscilloscope_waveform::units::format_si;
+use oscilloscope_waveform::{
+    discover_devices, ConnectionTarget, DataRange, Decimation, DeviceSelector, DiscoveredDevice, OscilloscopeWaveform,
+    PlotOptions, TimeReference, Timeouts, TransferFormat, TransferProgress,
+};
+use visa_rs::DefaultRM;
+
+/// Capture a waveform from a Batronix oscilloscope and plot it.
+#[derive(Parser, Debug)]
+struct Args {
+    /// List all connected instruments and exit
+    #[arg(long)]
+    list: bool,
+
+    /// With --list, also browse the network for LXI instruments with mDNS
+    /// for this long, e.g. 2s
+    #[cfg(feature = "mdns")]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, requires = "list")]
+    browse: Option<Duration>,
+
+    /// Connect to the Batronix instrument with this serial number. Repeat
+    /// to capture from several instruments at once
+    #[arg(long, value_name = "SN")]
+    serial: Vec<String>,
+
+    /// Connect to this VISA resource, e.g. TCPIP::192.168.1.10::INSTR.
+    /// Repeat to capture from several instruments at once
+    #[arg(long, value_name = "VISA_STRING")]
+    resource: Vec<String>,
+
+    /// Connect to one instrument directly: usb::SERIALNUMBER,
+    /// tcpip::HOST[::PORT], visa::RESOURCE or serial::PORT[::BAUD], e.g.
+    /// tcpip::192.168.1.50 or serial::COM3. Serial needs the serial feature
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["list", "serial", "resource"])]
+    connect: Option<ConnectionTarget>,
+
+    /// Capture from a simulated instrument with a 1 kHz sine on every
+    /// channel, to try the tool without hardware
+    #[arg(long, conflicts_with_all = ["list", "serial", "resource", "connect"])]
+    simulate: bool,
+
+    /// Keep the instrument stopped on exit instead of restarting
+    /// acquisition, to preserve the last acquisition on its screen
+    #[arg(long, conflicts_with = "list")]
+    leave_stopped: bool,
+
+    /// Write every SCPI command and response to this file with sequence
+    /// numbers and timestamps, whatever the log level
+    #[arg(long, value_name = "FILE")]
+    scpi_log: Option<PathBuf>,
+
+    /// How long every instrument gets to answer during discovery, e.g. 500ms
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout_discovery: Option<Duration>,
+
+    /// How long to wait for the instrument to answer a command, e.g. 10s
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout_command: Option<Duration>,
+
+    /// How long a waveform transfer may take, e.g. 120s for deep memory
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout_transfer: Option<Duration>,
+
+    /// Apply an instrument setup saved with --save-setup before capturing
+    #[arg(long, value_name = "FILE")]
+    setup: Option<PathBuf>,
+
+    /// Save the current instrument setup to this file
+    #[arg(long, value_name = "FILE")]
+    save_setup: Option<PathBuf>,
+
+    /// Apply a measurement preset TOML file (trigger, vertical, timebase
+    /// and memory depth) before capturing, after --setup
+    #[arg(long, value_name = "FILE")]
+    preset: Option<PathBuf>,
+
+    /// Place the trigger in the record: a share of the record before the
+    /// trigger such as 20%, or the pre-trigger time in seconds such as 2e-4
+    #[arg(long, value_name = "POSITION", value_parser = parse_trigger_position, allow_hyphen_values = true)]
+    trigger_position: Option<TriggerPosition>,
+
+    /// Start the time axis at the first sample of the record instead of
+    /// the trigger point
+    #[arg(long, conflicts_with = "window")]
+    absolute_time: bool,
+
+    /// Also save the instrument's display contents to this file
+    #[arg(long, value_name = "PATH")]
+    screenshot: Option<PathBuf>,
+
+    /// Skip the waveform capture, e.g. to only take a screenshot
+    #[arg(long)]
+    no_waveform: bool,
+
+    /// Plot one channel against another instead of channel 1 against time
+    #[arg(long, value_name = "CH1,CH2", value_parser = parse_channel_pair, conflicts_with = "no_waveform")]
+    xy: Option<(u8, u8)>,
+
+    /// Print the time skew of the second channel relative to the first
+    /// instead of plotting
+    #[arg(long, value_name = "CH1,CH2", value_parser = parse_channel_pair, conflicts_with_all = ["no_waveform", "xy"])]
+    skew: Option<(u8, u8)>,
+
+    /// Print the times channel 1 crosses this level, e.g. 3.0,rising
+    /// (rising, falling or both)
+    #[arg(long, value_name = "LEVEL,EDGE", value_parser = parse_crossing, conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    crossing: Option<(f32, Edge)>,
+
+    /// Print measurements of channel 1: pulse for duty cycle, pulse widths,
+    /// overshoot and preshoot of a square wave, onboard for the instrument's
+    /// measurements next to the same ones computed from the capture
+    #[arg(long, value_name = "KIND", value_parser = parse_measurement,
+        conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    measure: Option<Measurement>,
+
+    /// Dead band around the crossing level in volts, so noise on an edge
+    /// counts as one crossing
+    #[arg(long, value_name = "VOLTS", default_value_t = 0.0, requires = "crossing")]
+    hysteresis: f32,
+
+    /// Test every capture against the envelope in this CSV or JSON file,
+    /// exiting with code 2 if any capture fails
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    mask: Option<PathBuf>,
+
+    /// Compare every capture with the golden reference capture in this
+    /// JSON file, exiting with code 2 if any capture deviates
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["no_waveform", "xy", "skew", "mask"])]
+    compare: Option<PathBuf>,
+
+    /// Largest deviation from the --compare reference in volts
+    #[arg(long, value_name = "VOLTS", default_value_t = 0.05, requires = "compare")]
+    compare_tolerance: f32,
+
+    /// Search up to this many seconds either way for a trigger offset to
+    /// the --compare reference and remove it before comparing, e.g. 1e-6
+    #[arg(long, value_name = "SECONDS", default_value_t = 0.0, requires = "compare")]
+    compare_shift: f32,
+
+    /// Keep only the samples from START to END seconds relative to the
+    /// trigger for plotting and export, e.g. -1e-6,5e-6
+    #[arg(long, value_name = "START,END", value_parser = parse_window, allow_hyphen_values = true,
+        conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    window: Option<(f32, f32)>,
+
+    /// Reduce the sample rate by this factor before plotting and export
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    decimate: Option<u32>,
+
+    /// How --decimate reduces the samples: nth, mean or minmax (two
+    /// samples per block, keeping spikes)
+    #[arg(long, value_name = "STRATEGY", default_value = "minmax", requires = "decimate")]
+    decimation: Decimation,
+
+    /// Part of the record to transfer: all, visible or START:COUNT in
+    /// samples, e.g. 0:100000 to cut the transfer time at large memory
+    /// depths
+    #[arg(long, value_name = "RANGE", default_value = "all", conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    range: DataRange,
+
+    /// Plot a math trace computed from channels captured on the same
+    /// trigger instead of channel 1, e.g. CH1-CH2 for a differential signal
+    #[arg(long, value_name = "EXPRESSION",
+        conflicts_with_all = ["no_waveform", "xy", "skew", "window", "decimate", "range"])]
+    math: Option<MathExpression>,
+
+    /// Print amplitude statistics and plot the amplitude histogram to
+    /// histogram.png, with this many bins or a count chosen from the spread
+    /// of the samples
+    #[arg(long, value_name = "BINS", num_args = 0..=1, default_missing_value = "0",
+        conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    histogram: Option<usize>,
+
+    /// Filter the plotted trace: lowpass:<cutoff Hz> or average:<samples>
+    #[arg(long, value_name = "FILTER", conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    filter: Option<Filter>,
+
+    /// Capture this many times, 0 to capture until interrupted
+    #[arg(long, value_name = "N", default_value_t = 1, conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    count: u64,
+
+    /// Seconds to wait between captures
+    #[arg(long, value_name = "SECONDS", default_value_t = 0.0)]
+    interval: f64,
+
+    /// Save every capture as timestamped PNG and CSV files in this directory
+    /// instead of overwriting waveform.png
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    output_dir: Option<PathBuf>,
+
+    /// Format of the samples saved in the output directory: csv, npy for
+    /// NumPy time and voltage arrays or npz for both in one archive
+    #[arg(long, value_name = "FORMAT", value_parser = parse_export_format, default_value = "csv",
+        requires = "output_dir")]
+    format: ExportFormat,
+
+    /// Keep only the newest N captures in the output directory
+    #[arg(long, value_name = "N", requires = "output_dir", conflicts_with = "max_mb")]
+    keep_last: Option<usize>,
+
+    /// Keep only the newest captures that fit into this many megabytes
+    #[arg(long, value_name = "MB", requires = "output_dir")]
+    max_mb: Option<f64>,
+
+    /// Adjust the vertical scale and offset of channel 1 to the signal
+    /// before capturing
+    #[arg(long, conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    autoscale: bool,
+
+    /// Time RAW and float transfers of channel 1 instead of plotting
+    #[arg(long, conflicts_with_all = ["no_waveform", "xy", "skew"])]
+    benchmark: bool,
+
+    /// Number of reads per transfer type and memory depth
+    #[arg(long, value_name = "N", default_value_t = 5, requires = "benchmark")]
+    benchmark_runs: usize,
+
+    /// Memory depths to benchmark
+    #[arg(long, value_name = "DEPTHS", value_delimiter = ',', default_value = "10000,100000,1000000",
+        requires = "benchmark")]
+    benchmark_depths: Vec<u32>,
+
+    /// Also save every benchmark run to this CSV file
+    #[arg(long, value_name = "PATH", requires = "benchmark")]
+    benchmark_csv: Option<String>,
 }
 
-struct OscilloscopeWaveform {
-    device: Instrument,
-    #[allow(dead_code)]
-    rm: DefaultRM,  // Keep the resource manager alive
+/// Parse an XY channel pair such as `1,2` or `CH1,CH2`.
+fn parse_channel_pair(value: &str) -> Result<(u8, u8), String> {
+    let channel = |part: &str| {
+        let part = part.trim();
+        let number = part.strip_prefix("CH").or_else(|| part.strip_prefix("ch")).unwrap_or(part);
+        number.parse::<u8>().map_err(|_| format!("Invalid channel '{}'", part))
+    };
+    match value.split_once(',') {
+        Some((x, y)) => Ok((channel(x)?, channel(y)?)),
+        None => Err("Expected two channels separated by a comma".to_string()),
+    }
 }
 
-impl OscilloscopeWaveform {
-    fn find_batronix_device(rm: &DefaultRM) -> Result<Instrument> {
-        info!("Searching for VISA devices");
-        
-        // Try different resource patterns
-        let patterns = ["?*::INSTR", "USB?*INSTR", "TCPIP?*INSTR"];
-        
-        for pattern in patterns {
-            info!("Trying pattern: {}", pattern);
-            let expr = CString::new(pattern)?.into();
-            
-            match rm.find_res_list(&expr) {
-                Ok(resources) => {
-                    for res in resources {
-                        if let Ok(resource) = res {
-                            info!("Found resource: {:?}", resource);
-                            // Try to open this device
-                            if let Ok(device) = rm.open(&resource, AccessMode::NO_LOCK, Duration::from_secs(1)) {
-                                // Query device identification
-                                if let Ok(_) = (&device).write_all(b"*IDN?\n") {
-                                    let mut buf_reader = BufReader::new(&device);
-                                    let mut idn = String::new();
-                                    if buf_reader.read_line(&mut idn).is_ok() {
-                                        info!("Device responded: {}", idn.trim());
-                                        if idn.contains("Batronix") {
-                                            info!("Found Batronix device!");
-                                            // Reopen with longer timeout
-                                            if let Ok(device) = rm.open(&resource, AccessMode::NO_LOCK, Duration::from_secs(10)) {
-                                                return Ok(device);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => error!("Error listing resources for pattern {}: {}", pattern, e),
-            }
+/// Parse a time window such as `-1e-6,5e-6`.
+fn parse_window(value: &str) -> Result<(f32, f32), String> {
+    let time = |part: &str| part.trim().parse::<f32>().map_err(|_| format!("Invalid time '{}'", part));
+    match value.split_once(',') {
+        Some((start, end)) => Ok((time(start)?, time(end)?)),
+        None => Err("Expected a start and end time separated by a comma".to_string()),
+    }
+}
+
+/// Parse a crossing level and edge such as `3.0,rising`.
+fn parse_crossing(value: &str) -> Result<(f32, Edge), String> {
+    let (level, edge) = value.split_once(',').unwrap_or((value, "both"));
+    let level = level.trim().parse::<f32>().map_err(|_| format!("Invalid level '{}'", level))?;
+    Ok((level, edge.parse()?))
+}
+
+/// Parse a trigger position such as `20%` or `2e-4` seconds.
+fn parse_trigger_position(value: &str) -> Result<TriggerPosition, String> {
+    let value = value.trim();
+    match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse().map(TriggerPosition::Percent),
+        None => value.parse().map(TriggerPosition::PreTrigger),
+    }
+    .map_err(|_| format!("Invalid trigger position '{}', expected a percentage or seconds", value))
+}
+
+/// Measurement set printed with --measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Measurement {
+    Pulse,
+    Onboard,
+}
+
+fn parse_measurement(value: &str) -> Result<Measurement, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "pulse" => Ok(Measurement::Pulse),
+        "onboard" => Ok(Measurement::Onboard),
+        _ => Err(format!("Unknown measurement '{}', expected pulse or onboard", value)),
+    }
+}
+
+/// Sample file format of --output-dir.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    Csv,
+    Npy,
+    Npz,
+}
+
+fn parse_export_format(value: &str) -> Result<ExportFormat, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "csv" => Ok(ExportFormat::Csv),
+        "npy" => Ok(ExportFormat::Npy),
+        "npz" => Ok(ExportFormat::Npz),
+        _ => Err(format!("Unknown format '{}', expected csv, npy or npz", value)),
+    }
+}
+
+impl Args {
+    fn retention(&self) -> Retention {
+        match (self.keep_last, self.max_mb) {
+            (Some(count), _) => Retention::KeepLast(count),
+            (None, Some(mb)) => Retention::MaxBytes((mb * 1e6) as u64),
+            (None, None) => Retention::KeepAll,
         }
-        
-        Err(anyhow!("No Batronix device found"))
     }
 
-    fn new(url: Option<&str>, _protocol: &str) -> Result<Self> {
-        info!("Initializing VISA");
-        let rm = DefaultRM::new()?;
-        
-        let device = if let Some(url) = url {
-            // Use specified network connection
-            info!("Trying network connection to {}", url);
-            let resource_str = CString::new(format!("TCPIP::{}::INSTR", url))?.into();
-            rm.open(&resource_str, AccessMode::NO_LOCK, Duration::from_secs(10))?
-        } else {
-            // Search for Batronix device
-            Self::find_batronix_device(&rm)?
-        };
-        
-        info!("Successfully opened connection");
-        Ok(Self { device, rm })
+    /// Timeouts from the --timeout-* options, the defaults for the others.
+    fn timeouts(&self) -> Timeouts {
+        let defaults = Timeouts::default();
+        Timeouts {
+            discovery: self.timeout_discovery.unwrap_or(defaults.discovery),
+            command: self.timeout_command.unwrap_or(defaults.command),
+            transfer: self.timeout_transfer.unwrap_or(defaults.transfer),
+        }
     }
-    
-    fn get_waveform_data(&self, channel: u8, data_length: &str, data_transfer_type: &str) 
-        -> Result<(Vec<f32>, Vec<f32>)> {
-        // Enable only selected channel
-        info!("Configuring channels");
-        (&self.device).write_all(format!("CHAN{}:STATe 1\n", channel).as_bytes())?;
-        for i in 1..=4 {
-            if i != channel {
-                (&self.device).write_all(format!("CHAN{}:STATe 0\n", i).as_bytes())?;
-            }
+
+    /// Instruments picked with --serial and --resource, or the first one
+    /// found if none is given.
+    fn selectors(&self) -> Vec<DeviceSelector> {
+        let mut selectors: Vec<DeviceSelector> = self.serial.iter().cloned().map(DeviceSelector::Serial)
+            .chain(self.resource.iter().cloned().map(DeviceSelector::Resource))
+            .collect();
+        if selectors.is_empty() {
+            selectors.push(DeviceSelector::Auto);
         }
-        
-        // Run acquisition with 1M memory depth
-        info!("Starting acquisition");
-        (&self.device).write_all(b"RUN\n")?;
-        (&self.device).write_all(b"ACQUire:MDEPth 1000000\n")?;
-        
-        // Query memory depth
-        (&self.device).write_all(b"ACQuire:MDEPth?\n")?;
-        let mut buf_reader = BufReader::new(&self.device);
-        let mut memory_depth = String::new();
-        buf_reader.read_line(&mut memory_depth)?;
-        info!("Memory Depth: {}", memory_depth.trim());
-        
-        // Configure channel settings
-        (&self.device).write_all(
-            format!("CHAN{}:DATa:TYPE {}\n", channel, data_transfer_type).as_bytes()
-        )?;
-        
-        // Wait for acquisition
-        (&self.device).write_all(b"SEQuence:WAIT? 1\n")?;
-        let mut wait_response = String::new();
-        buf_reader.read_line(&mut wait_response)?;
-        
-        // Capture waveform data
-        info!("Capturing waveform data");
-        let start_time = Instant::now();
-        
-        // First query the data size
-        let data_cmd = format!("CHAN{}:DATa:PACK? {}, {}\n", channel, data_length, data_transfer_type);
-        (&self.device).write_all(data_cmd.as_bytes())?;
-        
-        // Read the header first
-        let mut header = [0u8; 2];
-        (&self.device).read_exact(&mut header)?;
-        if header[0] != b'#' {
-            return Err(anyhow!("Invalid header start"));
+        selectors
+    }
+
+    /// Whether any option beyond a plain capture of channel 1 is set.
+    fn needs_single_instrument(&self) -> bool {
+        self.setup.is_some() || self.save_setup.is_some() || self.preset.is_some() || self.screenshot.is_some()
+            || self.trigger_position.is_some() || self.absolute_time || self.no_waveform
+            || self.xy.is_some() || self.skew.is_some() || self.crossing.is_some() || self.mask.is_some()
+            || self.compare.is_some()
+            || self.count != 1 || self.output_dir.is_some() || self.benchmark || self.window.is_some()
+            || self.decimate.is_some() || self.math.is_some() || self.filter.is_some() || self.range != DataRange::All
+            || self.histogram.is_some() || self.measure.is_some() || self.scpi_log.is_some() || self.autoscale
+    }
+
+    /// Legend entry for the plotted trace if it is computed rather than
+    /// channel 1 as captured.
+    fn trace_label(&self) -> Option<String> {
+        let source = match self.math {
+            Some(expression) => format!("MATH {}", expression),
+            None => "CH1".to_string(),
+        };
+        match (&self.filter, self.math) {
+            (Some(filter), _) => Some(format!("{}, {}", source, filter)),
+            (None, Some(_)) => Some(source),
+            (None, None) => None,
         }
-        
-        let size_len = (header[1] - b'0') as usize;
-        let mut size_str = vec![0u8; size_len];
-        (&self.device).read_exact(&mut size_str)?;
-        let data_size = std::str::from_utf8(&size_str)?.parse::<usize>()?;
-        
-        // Now read the actual data
-        let mut data = vec![0u8; data_size];
-        (&self.device).read_exact(&mut data)?;
-        
-        // Read the trailing newline
-        let mut newline = [0u8; 1];
-        (&self.device).read_exact(&mut newline)?;
-        
-        info!("Data capture time: {:.3} seconds", start_time.elapsed().as_secs_f32());
-        
-        if data.is_empty() {
-            error!("No data received");
-            return Ok((vec![], vec![]));
+    }
+}
+
+fn print_devices(devices: &[DiscoveredDevice]) {
+    if devices.is_empty() {
+        println!("No instruments found");
+        return;
+    }
+    println!("{:<40} {:<16} {:<16} {:<16} FIRMWARE", "RESOURCE", "MANUFACTURER", "MODEL", "SERIAL");
+    for device in devices {
+        match &device.identity {
+            Some(id) => println!("{:<40} {:<16} {:<16} {:<16} {}",
+                device.resource, id.manufacturer, id.model, id.serial, id.firmware),
+            None => println!("{:<40} (unresponsive)", device.resource),
         }
-        
-        // Parse metadata
-        let metadata = self.parse_metadata(&data, data_transfer_type)?;
-        let waveform = self.extract_waveform(&data, &metadata, data_transfer_type)?;
-        
-        // Create time base
-        let time_values: Vec<f32> = (0..waveform.len())
-            .map(|i| metadata.start_time + (i as f32) * metadata.time_delta)
+    }
+}
+
+fn print_benchmark(results: &[BenchmarkResult]) {
+    println!("{:<5} {:>10} {:>10} {:>14} {:>13} {:>11} {:>8}",
+        "TYPE", "DEPTH", "BYTES", "ROUNDTRIP ms", "TRANSFER ms", "DECODE ms", "MB/s");
+    for result in results {
+        let mean = result.mean();
+        println!("{:<5} {:>10} {:>10} {:>14.2} {:>13.2} {:>11.2} {:>8.1}",
+            result.data_transfer_type, result.memory_depth, mean.bytes,
+            mean.round_trip.as_secs_f64() * 1e3, mean.transfer.as_secs_f64() * 1e3,
+            mean.decode.as_secs_f64() * 1e3, result.throughput_mb_s());
+    }
+}
+
+/// Number of crossing times printed.
+const CROSSINGS_SHOWN: usize = 10;
+
+fn print_crossings(crossings: &[Crossing]) {
+    println!("{} crossings", crossings.len());
+    for crossing in crossings.iter().take(CROSSINGS_SHOWN) {
+        println!("{:>12.6e} s  {:?}", crossing.time_s, crossing.edge);
+    }
+    if crossings.len() > CROSSINGS_SHOWN {
+        println!("...");
+    }
+}
+
+/// Exit code when a capture fails the mask test or the comparison with a
+/// reference, apart from 1 for errors.
+const TEST_FAILED_EXIT_CODE: u8 = 2;
+
+fn print_mask_result(result: &MaskResult) {
+    match result.violations.first() {
+        None => println!("Mask test passed, {} samples", result.samples_tested),
+        Some(first) => println!("Mask test FAILED, {} of {} samples outside the mask, first at {:e} s: {} V beyond the {:?} limit of {} V",
+            result.violations.len(), result.samples_tested, first.time_s, first.voltage, first.bound, first.limit),
+    }
+}
+
+/// Deviation regions listed by --compare.
+const REGIONS_SHOWN: usize = 10;
+
+fn print_compare_report(report: &CompareReport) {
+    let verdict = if report.pass() { "passed" } else { "FAILED" };
+    println!("Comparison {}, {} points, max deviation {:.6} V, RMS {:.6} V, time shift {:e} s",
+        verdict, report.time.len(), report.diff.max_deviation, report.diff.rms_deviation, report.time_shift_s);
+    for region in report.regions.iter().take(REGIONS_SHOWN) {
+        println!("  {:e} s to {:e} s: up to {:.6} V", region.start_s, region.end_s, region.max_deviation);
+    }
+    if report.regions.len() > REGIONS_SHOWN {
+        println!("  ... {} regions in total", report.regions.len());
+    }
+}
+
+fn print_histogram(hist: &Histogram) {
+    println!("Amplitude: mean {:.6} V, standard deviation {:.6} V, skewness {:.3} over {} bins",
+        hist.mean(), hist.std_dev(), hist.skewness(), hist.bins.len());
+    if hist.non_finite > 0 {
+        println!("{} samples were NaN or infinite and are not in the histogram", hist.non_finite);
+    }
+}
+
+fn format_measurement(kind: MeasKind, value: Option<f64>) -> String {
+    match value {
+        None => "n/a".to_string(),
+        Some(value) if kind.unit() == "%" => format!("{:.2} %", value),
+        Some(value) => format_si(value, kind.unit()),
+    }
+}
+
+fn print_onboard_measurements(onboard: &[(MeasKind, Option<f64>)], time: &[f32], waveform: &[f32]) {
+    println!("{:<15} {:>12} {:>12}", "Measurement", "Instrument", "Capture");
+    for &(kind, value) in onboard {
+        println!("{:<15} {:>12} {:>12}", kind, format_measurement(kind, value),
+            format_measurement(kind, kind.compute(time, waveform)));
+    }
+}
+
+fn print_pulses(pulses: Option<PulseMeasurements>) {
+    let Some(pulses) = pulses else {
+        println!("Pulse measurements need two distinct levels and at least two full cycles");
+        return;
+    };
+    println!("Base {:.4} V, top {:.4} V, amplitude {:.4} V over {} cycles",
+        pulses.base, pulses.top, pulses.amplitude(), pulses.cycles);
+    for (name, spread, unit, factor) in [
+        ("Duty cycle", pulses.duty_cycle_percent, "%", 1.0),
+        ("Positive width", pulses.positive_width_s, "us", 1e6),
+        ("Negative width", pulses.negative_width_s, "us", 1e6),
+    ] {
+        println!("{:<15} mean {:>10.4} {unit}, min {:>10.4} {unit}, max {:>10.4} {unit}",
+            name, spread.mean * factor, spread.min * factor, spread.max * factor);
+    }
+    println!("Overshoot {:.2} %, preshoot {:.2} %", pulses.overshoot_percent, pulses.preshoot_percent);
+}
+
+/// Width of the transfer progress bar in characters.
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+/// Redraw the transfer progress bar on stderr.
+fn print_progress(progress: TransferProgress) {
+    let mut stderr = std::io::stderr().lock();
+    if progress.bytes_total == 0 {
+        // Indeterminate, the block size is only known at its end
+        let _ = write!(stderr, "\r{:.1} MB received, {:.1} MB/s",
+            progress.bytes_received as f64 / 1e6, progress.throughput_mb_s());
+        let _ = stderr.flush();
+        return;
+    }
+    let fraction = progress.bytes_received as f64 / progress.bytes_total.max(1) as f64;
+    let filled = (fraction * PROGRESS_BAR_WIDTH as f64) as usize;
+    let _ = write!(stderr, "\r[{}{}] {:5.1}% {:.1} MB/s",
+        "#".repeat(filled), " ".repeat(PROGRESS_BAR_WIDTH - filled), fraction * 100.0, progress.throughput_mb_s());
+    if progress.bytes_received >= progress.bytes_total {
+        let _ = writeln!(stderr);
+    }
+    let _ = stderr.flush();
+}
+
+/// Capture channel 1 of several instruments from one arming, plotted into
+/// `waveform_<serial>.png` each. An instrument failing does not stop the
+/// others, but makes the exit code 1.
+fn capture_several(selectors: &[DeviceSelector], timeouts: Timeouts, leave_stopped: bool) -> Result<ExitCode> {
+    let (mut multi, mut errors) = MultiScope::open_with_timeouts(selectors, timeouts);
+    for (_, scope) in multi.scopes_mut() {
+        scope.set_run_on_close(!leave_stopped);
+    }
+    let readout = multi.capture(1, TransferFormat::Raw, Some(1_000_000));
+    errors.extend(readout.errors);
+    let mut failed = !errors.is_empty();
+    for error in &errors {
+        eprintln!("{}: {}", error.device, error.error);
+    }
+    for (label, (time_values, waveform)) in &readout.results {
+        // Resource strings contain colons, keep file names portable
+        let file_label: String = label.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
             .collect();
-            
-        Ok((time_values, waveform))
+        let options = PlotOptions {
+            path: format!("waveform_{}.png", file_label),
+            title: format!("Oscilloscope Waveform {}", label),
+            ..PlotOptions::default()
+        };
+        let scope = multi.scope(label).expect("captured instruments are open");
+        if let Err(error) = scope.plot_waveform(time_values, waveform, &options) {
+            eprintln!("{}: {}", label, error);
+            failed = true;
+        }
     }
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+fn main() -> Result<ExitCode> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .init();
+    let args = Args::parse();
     
-    fn parse_metadata(&self, data: &[u8], data_transfer_type: &str) -> Result<WaveformMetadata> {
-        let metadata_size = if data_transfer_type == "RAW" { 32 } else { 16 };
-        if data.len() < metadata_size {
-            return Err(anyhow!("Data too short for metadata"));
-        }
-        
-        let metadata = WaveformMetadata {
-            time_delta: LittleEndian::read_f32(&data[0..4]),
-            start_time: LittleEndian::read_f32(&data[4..8]),
-            end_time: LittleEndian::read_f32(&data[8..12]),
-            sample_start: if data_transfer_type == "RAW" { 
-                LittleEndian::read_u32(&data[12..16]) 
-            } else { 0 },
-            sample_length: if data_transfer_type == "RAW" { 
-                LittleEndian::read_u32(&data[16..20]) 
-            } else { 0 },
-            vertical_start: if data_transfer_type == "RAW" { 
-                LittleEndian::read_f32(&data[20..24]) 
-            } else { 0.0 },
-            vertical_step: if data_transfer_type == "RAW" { 
-                LittleEndian::read_f32(&data[24..28]) 
-            } else { 0.0 },
-            sample_count: if data_transfer_type == "RAW" { 
-                LittleEndian::read_u32(&data[28..32]) 
-            } else { 
-                LittleEndian::read_u32(&data[12..16]) 
-            },
-        };
-        
-        info!("Metadata:");
-        info!("  TimeDelta = {}", metadata.time_delta);
-        info!("  StartTime = {}", metadata.start_time);
-        info!("  EndTime = {}", metadata.end_time);
-        if data_transfer_type == "RAW" {
-            info!("  SampleStart = {}", metadata.sample_start);
-            info!("  SampleLength = {}", metadata.sample_length);
-            info!("  VerticalStart = {}", metadata.vertical_start);
-            info!("  VerticalStep = {}", metadata.vertical_step);
+    if args.list {
+        let rm = DefaultRM::new()?;
+        #[cfg(feature = "mdns")]
+        if let Some(browse) = args.browse {
+            let devices = oscilloscope_waveform::mdns::discover_all_devices(&rm, args.timeouts().discovery, browse)?;
+            print_devices(&devices);
+            return Ok(ExitCode::SUCCESS);
         }
-        info!("  SampleCount = {}", metadata.sample_count);
-        
-        Ok(metadata)
+        print_devices(&discover_devices(&rm, args.timeouts().discovery)?);
+        return Ok(ExitCode::SUCCESS);
     }
     
-    fn extract_waveform(&self, data: &[u8], metadata: &WaveformMetadata, data_transfer_type: &str) 
-        -> Result<Vec<f32>> {
-        let metadata_size = if data_transfer_type == "RAW" {
-            std::mem::size_of::<f32>() * 3 + std::mem::size_of::<u32>() * 5
-        } else {
-            std::mem::size_of::<f32>() * 3 + std::mem::size_of::<u32>()
+    let selectors = args.selectors();
+    if selectors.len() > 1 {
+        if args.needs_single_instrument() {
+            bail!("With several instruments only plain captures of channel 1 are supported");
+        }
+        return capture_several(&selectors, args.timeouts(), args.leave_stopped);
+    }
+    let mut scope = if args.simulate {
+        OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())))
+    } else if let Some(target) = &args.connect {
+        OscilloscopeWaveform::new(target, args.timeouts())?
+    } else {
+        OscilloscopeWaveform::open_with_timeouts(&selectors[0], args.timeouts())?
+    };
+    // Dropping the scope, also on errors, restarts acquisition and returns
+    // it to local control
+    scope.set_run_on_close(!args.leave_stopped);
+    if let Some(path) = &args.scpi_log {
+        scope.set_scpi_log(Some(&path.display().to_string()))?;
+    }
+    if let Some(path) = &args.setup {
+        scope.load_setup(path)?;
+    }
+    if let Some(path) = &args.preset {
+        scope.apply_preset(&MeasurementPreset::from_toml(path)?)?;
+    }
+    if let Some(position) = args.trigger_position {
+        scope.set_trigger_position(position)?;
+    }
+    if args.absolute_time {
+        scope.set_time_reference(TimeReference::Acquisition);
+    }
+    if let Some(path) = &args.save_setup {
+        scope.save_setup(path)?;
+    }
+    if args.benchmark {
+        // Run without the progress bar, it would add to the transfer times
+        let mut results = Vec::new();
+        for &depth in &args.benchmark_depths {
+            for dtype in TransferFormat::ALL {
+                results.push(scope.benchmark_transfer(1, dtype, depth, args.benchmark_runs)?);
+            }
+        }
+        print_benchmark(&results);
+        if let Some(path) = &args.benchmark_csv {
+            write_benchmark_csv(path, &results)?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.autoscale {
+        println!("Channel 1 autoscaled to {}/div", format_si(scope.autoscale_vertical(1)?, "V"));
+    }
+    scope.set_progress_callback(Box::new(print_progress));
+    if let Some(path) = &args.screenshot {
+        scope.capture_screenshot(path)?;
+    }
+    if let Some((x_channel, y_channel)) = args.xy {
+        let (x, y) = scope.get_xy_data(x_channel, y_channel, TransferFormat::Raw)?;
+        let options = PlotOptions {
+            path: "xy.png".to_string(),
+            title: format!("CH{} vs CH{}", y_channel, x_channel),
+            ..PlotOptions::default()
         };
-        
-        if data.len() < metadata_size {
-            error!("Data too short for metadata");
-            return Ok(vec![]);
+        scope.plot_xy(&x, &y, &options)?;
+    } else if let Some((a, b)) = args.skew {
+        let skew = scope.measure_channel_skew(a, b, TransferFormat::Raw)?;
+        match skew.delay_s {
+            Some(delay) => println!("CH{} lags CH{} by {:.3} ns (correlation {:.3})",
+                b, a, delay * 1e9, skew.correlation),
+            None => println!("No reliable skew between CH{} and CH{} (correlation {:.3})",
+                a, b, skew.correlation),
         }
-        
-        let waveform_data = &data[metadata_size..];
-        
-        if data_transfer_type == "RAW" {
-            // Convert bytes to u16 values and scale them to voltage
-            let mut values = Vec::with_capacity(waveform_data.len() / 2);
-            for chunk in waveform_data.chunks_exact(2) {
-                let raw_value = LittleEndian::read_u16(chunk);
-                // The vertical step is already scaled for 16-bit range
-                let voltage = metadata.vertical_start + (raw_value as f32) * metadata.vertical_step / 65536.0;
-                values.push(voltage);
+    } else if !args.no_waveform {
+        let mut sink = match &args.output_dir {
+            Some(dir) => Some(CaptureSink::new(dir, args.retention())?),
+            None => None,
+        };
+        let mask = args.mask.as_ref().map(Mask::load).transpose()?;
+        let golden = args.compare.as_ref().map(|path| import_json(&path.display().to_string())).transpose()?;
+        let tolerance = CompareTolerance { voltage: args.compare_tolerance, max_time_shift_s: args.compare_shift };
+        let mut failed = false;
+        let mut captured = 0;
+        while args.count == 0 || captured < args.count {
+            if captured > 0 && args.interval > 0.0 {
+                std::thread::sleep(Duration::from_secs_f64(args.interval));
+            }
+            let mut subtitle = None;
+            let mut warning = None;
+            let mut trace = match args.math {
+                Some(expression) => scope.compute_math(expression, TransferFormat::Raw)?,
+                None => {
+                    let mut record = scope.get_waveform_record(1, args.range, Some(1_000_000))?;
+                    if let Some((start, end)) = args.window {
+                        record = record.slice_time(start, end);
+                    }
+                    if let Some(factor) = args.decimate {
+                        record = record.decimate(factor as usize, args.decimation);
+                    }
+                    subtitle = Some(scope.plot_annotation(1, &record.metadata)?.caption());
+                    let clipped = record.clipped_samples();
+                    if clipped > 0 {
+                        eprintln!("Warning: {} samples are clipped at the ADC range", clipped);
+                        warning = Some(format!("Clipped: {} samples at the limits of the vertical range", clipped));
+                    }
+                    (record.time_values().collect(), record.voltages().collect())
+                }
+            };
+            if let Some(filter) = &args.filter {
+                trace = filter.apply(&trace)?;
             }
-            Ok(values)
-        } else {
-            // For non-RAW data, just interpret as f32
-            let mut values = Vec::with_capacity(waveform_data.len() / 4);
-            for chunk in waveform_data.chunks_exact(4) {
-                let value = LittleEndian::read_f32(chunk);
-                values.push(value);
+            let (time_values, waveform) = trace;
+            if let Some((level, edge)) = args.crossing {
+                print_crossings(&find_crossings_with_hysteresis(&time_values, &waveform, level, args.hysteresis, edge));
             }
-            Ok(values)
+            match args.measure {
+                Some(Measurement::Pulse) => print_pulses(measure_pulses(&time_values, &waveform)),
+                Some(Measurement::Onboard) => {
+                    print_onboard_measurements(&scope.query_all_measurements(1)?, &time_values, &waveform)
+                }
+                None => {}
+            }
+            let name = sink.as_mut().map(|sink| sink.next_capture(1));
+            let options = PlotOptions {
+                path: match &name {
+                    Some(name) => name.path("png").display().to_string(),
+                    None => PlotOptions::default().path,
+                },
+                subtitle,
+                warning,
+                trace_label: args.trace_label(),
+                ..PlotOptions::default()
+            };
+            if let Some(mask) = &mask {
+                let result = mask.test(&time_values, &waveform);
+                print_mask_result(&result);
+                failed |= !result.passed();
+                plot_mask_test(&time_values, &waveform, mask, &result, &options)?;
+            } else if let Some(golden) = &golden {
+                let report = compare_traces((&golden.time, &golden.voltage), (&time_values, &waveform), tolerance);
+                print_compare_report(&report);
+                failed |= !report.pass();
+                report.plot(&PlotOptions { title: "Comparison with Reference".to_string(), ..options })?;
+            } else {
+                scope.plot_waveform(&time_values, &waveform, &options)?;
+            }
+            if let Some(bins) = args.histogram {
+                let hist = histogram(&waveform, bins);
+                print_histogram(&hist);
+                let options = PlotOptions {
+                    path: match &name {
+                        Some(name) => name.path("histogram.png").display().to_string(),
+                        None => "histogram.png".to_string(),
+                    },
+                    title: "Amplitude Histogram".to_string(),
+                    ..PlotOptions::default()
+                };
+                plot_histogram(&hist, &options)?;
+            }
+            if let (Some(sink), Some(name)) = (&sink, &name) {
+                let path = |extension: &str| name.path(extension).display().to_string();
+                match args.format {
+                    ExportFormat::Csv => export_csv(&path("csv"), &time_values, &waveform)?,
+                    ExportFormat::Npy => {
+                        export_npy(&path("time.npy"), &time_values)?;
+                        export_npy(&path("voltage.npy"), &waveform)?;
+                    }
+                    ExportFormat::Npz => export_npz(&path("npz"), &time_values, &waveform)?,
+                }
+                sink.prune()?;
+            }
+            captured += 1;
+        }
+        if failed {
+            return Ok(ExitCode::from(TEST_FAILED_EXIT_CODE));
         }
     }
     
-    fn plot_waveform(&self, time_values: &[f32], waveform: &[f32]) -> Result<()> {
-        info!("Creating plot");
-        let root = BitMapBackend::new("waveform.png", (1200, 600))
-            .into_drawing_area();
-        root.fill(&WHITE)?;
-
-        let min_time = time_values.first().unwrap_or(&0.0);
-        let max_time = time_values.last().unwrap_or(&1.0);
-        let min_voltage = waveform.iter().fold(f32::INFINITY, |a, &b| a.min(b));
-        let max_voltage = waveform.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
-        
-        // Add some padding to the voltage range
-        let voltage_padding = (max_voltage - min_voltage) * 0.1;
-        let min_voltage = min_voltage - voltage_padding;
-        let max_voltage = max_voltage + voltage_padding;
-
-        let mut chart = ChartBuilder::on(&root)
-            .caption("Oscilloscope Waveform", ("sans-serif", 40))
-            .margin(10)
-            .x_label_area_size(40)
-            .y_label_area_size(60)
-            .build_cartesian_2d(
-                *min_time..*max_time,
-                min_voltage..max_voltage,
-            )?;
-
-        chart
-            .configure_mesh()
-            .x_desc("Time (s)")
-            .y_desc("Voltage (V)")
-            .draw()?;
-
-        chart.draw_series(LineSeries::new(
-            time_values.iter().zip(waveform.iter()).map(|(&x, &y)| (x, y)),
-            &BLUE,
-        ))?;
-
-        info!("Plot saved as waveform.png");
-        Ok(())
-    }
-}
-
-fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .init();
-    
-    let scope = OscilloscopeWaveform::new(None, "raw")?;
-    let (time_values, waveform) = scope.get_waveform_data(1, "ALL", "RAW")?;
-    scope.plot_waveform(&time_values, &waveform)?;
-    
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,410 @@
+//! Mask (pass/fail) testing on the instrument and offline.
+//!
+//! Instrument masks are made of rectangular [`MaskRegion`]s. For offline
+//! tests, a [`Mask`] describes an upper and lower envelope instead, which
+//! is interpolated between its points.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use log::info;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::plot::{decimate_waveform, padded_voltage_range};
+use crate::{OscilloscopeWaveform, PlotFormat, PlotOptions, Result, ScopeError};
+
+/// Violations beyond this many are counted but not marked in plots.
+const MAX_VIOLATION_MARKERS: usize = 10_000;
+
+/// A rectangular tolerance region: while the time is between `time_start`
+/// and `time_end`, the waveform must stay between `voltage_min` and
+/// `voltage_max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaskRegion {
+    pub time_start: f32,
+    pub time_end: f32,
+    pub voltage_min: f32,
+    pub voltage_max: f32,
+}
+
+impl MaskRegion {
+    fn contains_time(&self, time: f32) -> bool {
+        time >= self.time_start && time <= self.time_end
+    }
+
+    fn is_violated_by(&self, voltage: f32) -> bool {
+        voltage < self.voltage_min || voltage > self.voltage_max
+    }
+}
+
+/// Pass/fail counters of a mask test run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaskTestResult {
+    pub total: u64,
+    pub passed: u64,
+    pub failed: u64,
+}
+
+impl OscilloscopeWaveform {
+    /// Replace the mask on the instrument with the given regions.
+    pub fn load_mask(&self, regions: &[MaskRegion]) -> Result<()> {
+        if regions.is_empty() {
+            return Err(ScopeError::InvalidArgument("Mask must contain at least one region".to_string()));
+        }
+        for (i, region) in regions.iter().enumerate() {
+            let bounds = [region.time_start, region.time_end, region.voltage_min, region.voltage_max];
+            if !bounds.iter().all(|bound| bound.is_finite()) {
+                return Err(ScopeError::InvalidArgument(format!("Mask region {} has a non-finite bound", i + 1)));
+            }
+            if region.time_start >= region.time_end {
+                return Err(ScopeError::InvalidArgument(format!("Mask region {} has an empty time span", i + 1)));
+            }
+            if region.voltage_min >= region.voltage_max {
+                return Err(ScopeError::InvalidArgument(format!("Mask region {} has an empty voltage span", i + 1)));
+            }
+        }
+
+        info!("Loading mask with {} regions", regions.len());
+        self.send_command("MASK:REGion:CLEar")?;
+        for region in regions {
+            self.send_command(&format!(
+                "MASK:REGion:ADD {},{},{},{}",
+                region.time_start, region.time_end, region.voltage_min, region.voltage_max
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Start or stop the mask test. Enabling also resets the counters.
+    pub fn enable_mask_test(&self, enable: bool) -> Result<()> {
+        if enable {
+            self.send_command("MASK:RESet")?;
+        }
+        self.send_command(&format!("MASK:ENABle {}", if enable { 1 } else { 0 }))
+    }
+
+    fn query_u64(&self, command: &str) -> Result<u64> {
+        let response = self.query(command)?;
+        response.parse()
+            .map_err(|_| ScopeError::UnexpectedResponse { command: command.to_string(), response })
+    }
+
+    /// Read the pass/fail counters of the running mask test.
+    pub fn get_mask_test_result(&self) -> Result<MaskTestResult> {
+        let result = MaskTestResult {
+            total: self.query_u64("MASK:COUNt:TOTal?")?,
+            passed: self.query_u64("MASK:COUNt:PASS?")?,
+            failed: self.query_u64("MASK:COUNt:FAIL?")?,
+        };
+        info!("Mask test: {} tested, {} passed, {} failed", result.total, result.passed, result.failed);
+        Ok(result)
+    }
+}
+
+/// Check a captured waveform against the mask without an instrument.
+///
+/// The capture counts as one tested waveform, which fails if any sample
+/// inside a region's time span leaves the region's voltage band.
+pub fn software_mask_test(time: &[f32], waveform: &[f32], regions: &[MaskRegion]) -> MaskTestResult {
+    let failed = time.iter().zip(waveform.iter()).any(|(&t, &v)| {
+        regions.iter().any(|region| region.contains_time(t) && region.is_violated_by(v))
+    });
+
+    MaskTestResult {
+        total: 1,
+        passed: if failed { 0 } else { 1 },
+        failed: if failed { 1 } else { 0 },
+    }
+}
+
+/// Upper and lower limit lines for offline mask tests.
+///
+/// Both bounds are lists of `(time, voltage)` points in increasing time
+/// order. Between points the limit is interpolated linearly, and outside
+/// the time span of a bound it does not apply. Either bound may be empty.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Mask {
+    #[serde(default)]
+    pub upper: Vec<(f32, f32)>,
+    #[serde(default)]
+    pub lower: Vec<(f32, f32)>,
+}
+
+/// The limit a sample violated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskBound {
+    Upper,
+    Lower,
+}
+
+/// A sample outside the mask envelope.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaskViolation {
+    pub index: usize,
+    pub time_s: f32,
+    pub voltage: f32,
+    pub bound: MaskBound,
+    /// Interpolated limit at the sample time.
+    pub limit: f32,
+}
+
+/// Outcome of [`Mask::test`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaskResult {
+    pub samples_tested: usize,
+    pub violations: Vec<MaskViolation>,
+}
+
+impl MaskResult {
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Limit of `points` at `time`, or `None` outside their time span.
+fn bound_at(points: &[(f32, f32)], time: f32) -> Option<f32> {
+    let (first, last) = (points.first()?, points.last()?);
+    if time < first.0 || time > last.0 {
+        return None;
+    }
+    let next = points.partition_point(|&(t, _)| t < time);
+    if next == 0 {
+        return Some(first.1);
+    }
+    let ((t0, v0), (t1, v1)) = (points[next - 1], points[next]);
+    Some(v0 + (v1 - v0) * (time - t0) / (t1 - t0))
+}
+
+impl Mask {
+    /// Check that there is at least one point and every bound is in
+    /// strictly increasing time order.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.upper.is_empty() && self.lower.is_empty() {
+            return Err(anyhow!("Mask has no points"));
+        }
+        for (name, points) in [("upper", &self.upper), ("lower", &self.lower)] {
+            if let Some(&(t, v)) = points.iter().find(|(t, v)| !t.is_finite() || !v.is_finite()) {
+                return Err(anyhow!("Mask {} bound has an invalid point ({}, {})", name, t, v));
+            }
+            if let Some(pair) = points.windows(2).find(|pair| pair[1].0 <= pair[0].0) {
+                return Err(anyhow!("Mask {} bound is not in time order at {} s", name, pair[1].0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse CSV rows of `time,lower,upper`. Either voltage may be left
+    /// empty to add a point to one bound only. A header line and lines
+    /// starting with `#` are skipped.
+    pub fn from_csv(text: &str) -> anyhow::Result<Self> {
+        let mut mask = Mask::default();
+        let lines = text.lines().enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+        for (row, (number, line)) in lines.enumerate() {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if row == 0 && fields[0].parse::<f32>().is_err() {
+                continue;
+            }
+            if fields.len() != 3 {
+                return Err(anyhow!("Line {}: expected time,lower,upper", number + 1));
+            }
+            let parse = |field: &str| field.parse::<f32>().with_context(|| format!("Line {}: invalid number '{}'", number + 1, field));
+            let time = parse(fields[0])?;
+            if !fields[1].is_empty() {
+                mask.lower.push((time, parse(fields[1])?));
+            }
+            if !fields[2].is_empty() {
+                mask.upper.push((time, parse(fields[2])?));
+            }
+        }
+        mask.validate()?;
+        Ok(mask)
+    }
+
+    /// Load a mask from a `.json` file holding `upper` and `lower` point
+    /// arrays, or from CSV as described in [`from_csv`](Self::from_csv).
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).with_context(|| format!("Reading mask {}", path.display()))?;
+        let is_json = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let mask = if is_json {
+            let mask: Mask = serde_json::from_str(&text)?;
+            mask.validate()?;
+            mask
+        } else {
+            Self::from_csv(&text)?
+        };
+        info!("Loaded mask with {} upper and {} lower points", mask.upper.len(), mask.lower.len());
+        Ok(mask)
+    }
+
+    /// Compare every sample with the limits at its time.
+    pub fn test(&self, time: &[f32], samples: &[f32]) -> MaskResult {
+        let mut result = MaskResult { samples_tested: time.len().min(samples.len()), violations: Vec::new() };
+        for (index, (&t, &v)) in time.iter().zip(samples).enumerate() {
+            if let Some(limit) = bound_at(&self.upper, t).filter(|&limit| v > limit) {
+                result.violations.push(MaskViolation { index, time_s: t, voltage: v, bound: MaskBound::Upper, limit });
+            } else if let Some(limit) = bound_at(&self.lower, t).filter(|&limit| v < limit) {
+                result.violations.push(MaskViolation { index, time_s: t, voltage: v, bound: MaskBound::Lower, limit });
+            }
+        }
+        info!("Mask test of {} samples: {} violations", result.samples_tested, result.violations.len());
+        result
+    }
+}
+
+fn draw_mask_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str, time: &[f32], waveform: &[f32],
+    mask: &Mask, result: &MaskResult) -> anyhow::Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let voltages: Vec<f32> = waveform.iter().copied()
+        .chain(mask.upper.iter().chain(&mask.lower).map(|&(_, v)| v))
+        .collect();
+    let (min_voltage, max_voltage) = padded_voltage_range(&voltages);
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(time[0]..time[time.len() - 1], min_voltage..max_voltage)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Time (s)")
+        .y_desc("Voltage (V)")
+        .draw()?;
+
+    // Shade the forbidden area beyond each bound
+    let shade = RED.mix(0.15).filled();
+    for (points, edge) in [(&mask.upper, max_voltage), (&mask.lower, min_voltage)] {
+        if let (Some(first), Some(last)) = (points.first(), points.last()) {
+            let outline: Vec<(f32, f32)> = points.iter().copied()
+                .chain([(last.0, edge), (first.0, edge)])
+                .collect();
+            chart.draw_series(std::iter::once(Polygon::new(outline, shade)))?;
+            chart.draw_series(LineSeries::new(points.iter().copied(), &RED))?;
+        }
+    }
+
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    let (times, values) = decimate_waveform(time, waveform, 2 * columns);
+    chart.draw_series(LineSeries::new(
+        times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+        &BLUE,
+    ))?;
+
+    chart.draw_series(result.violations.iter().take(MAX_VIOLATION_MARKERS).map(|violation| {
+        Circle::new((violation.time_s, violation.voltage), 3, RED.filled())
+    }))?;
+
+    root.present()?;
+    Ok(())
+}
+
+/// Plot the waveform over the shaded mask with every violation marked in
+/// red. Only PNG and SVG output are supported.
+pub fn plot_mask_test(time: &[f32], waveform: &[f32], mask: &Mask, result: &MaskResult, options: &PlotOptions)
+    -> anyhow::Result<()> {
+    if waveform.is_empty() || time.len() != waveform.len() {
+        return Err(anyhow!("Time and waveform must be non-empty and of equal length"));
+    }
+
+    info!("Creating mask plot");
+    let size = (options.width, options.height);
+    match options.format {
+        PlotFormat::Png => {
+            let root = BitMapBackend::new(&options.path, size).into_drawing_area();
+            draw_mask_chart(root, &options.title, time, waveform, mask, result)?;
+        }
+        PlotFormat::Svg => {
+            let root = SVGBackend::new(&options.path, size).into_drawing_area();
+            draw_mask_chart(root, &options.title, time, waveform, mask, result)?;
+        }
+        PlotFormat::Html { .. } => return Err(anyhow!("Mask plots support PNG and SVG only")),
+    }
+    info!("Plot saved as {}", options.path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_envelope_and_reports_violations() {
+        let mask = Mask { upper: vec![(0.0, 1.0), (10.0, 3.0)], lower: vec![(2.0, 0.0), (4.0, 0.0)] };
+        let time = [0.0, 2.0, 3.0, 5.0, 10.0, 11.0];
+        let samples = [0.5, 1.5, -0.5, 2.0, 3.5, 9.0];
+        let result = mask.test(&time, &samples);
+        assert_eq!(result.samples_tested, 6);
+        assert!(!result.passed());
+
+        let found: Vec<(usize, MaskBound)> = result.violations.iter().map(|v| (v.index, v.bound)).collect();
+        // 1.5 V is above the limit of 1.4 V at 2 s, the sample at 11 s is
+        // outside both bounds' time span
+        assert_eq!(found, [(1, MaskBound::Upper), (2, MaskBound::Lower), (4, MaskBound::Upper)]);
+        assert!((result.violations[0].limit - 1.4).abs() < 1e-6);
+        assert!(mask.test(&time[..1], &samples[..1]).passed());
+    }
+
+    #[test]
+    fn parses_csv_and_json_masks() {
+        let csv = "time_s,lower_v,upper_v\n# settling\n0,,2.0\n1e-6,-0.5,1.5\n2e-6,-0.5,\n";
+        let mask = Mask::from_csv(csv).unwrap();
+        assert_eq!(mask.upper, [(0.0, 2.0), (1e-6, 1.5)]);
+        assert_eq!(mask.lower, [(1e-6, -0.5), (2e-6, -0.5)]);
+
+        let json: Mask = serde_json::from_str(r#"{"upper": [[0, 2.0], [1e-6, 1.5]]}"#).unwrap();
+        assert_eq!(json.upper, mask.upper);
+        assert!(json.lower.is_empty());
+
+        assert!(Mask::from_csv("0,0,1\n0,0,1\n").is_err(), "times must increase");
+        assert!(Mask::from_csv("0,0\n").is_err());
+        assert!(Mask::from_csv("").is_err());
+    }
+
+    #[test]
+    fn software_mask_test_checks_every_region() {
+        let time: Vec<f32> = (0..10).map(|i| i as f32).collect();
+        let waveform = [0.0, 0.1, 0.2, 1.0, 1.1, 0.9, 1.0, 0.2, 0.1, 0.0];
+        let low = MaskRegion { time_start: 0.0, time_end: 2.0, voltage_min: -0.5, voltage_max: 0.5 };
+        let high = MaskRegion { time_start: 3.0, time_end: 6.0, voltage_min: 0.8, voltage_max: 1.2 };
+        let pass = MaskTestResult { total: 1, passed: 1, failed: 0 };
+        let fail = MaskTestResult { total: 1, passed: 0, failed: 1 };
+        assert_eq!(software_mask_test(&time, &waveform, &[low, high]), pass);
+
+        // Only the sample at 4 s leaves the narrower band
+        let narrow = MaskRegion { voltage_max: 1.05, ..high };
+        assert_eq!(software_mask_test(&time, &waveform, &[low, narrow]), fail);
+
+        // A region between two samples tests nothing, however tight it is
+        let empty = MaskRegion { time_start: 6.2, time_end: 6.8, voltage_min: 5.0, voltage_max: 6.0 };
+        assert_eq!(software_mask_test(&time, &waveform, &[low, high, empty]), pass);
+        // The same region over a sample fails
+        assert_eq!(software_mask_test(&time, &waveform, &[MaskRegion { time_end: 7.0, ..empty }]), fail);
+    }
+
+    #[test]
+    fn rejects_non_finite_mask_bounds() {
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        let region = MaskRegion { time_start: 0.0, time_end: 1e-3, voltage_min: -0.5, voltage_max: 0.5 };
+        for broken in [
+            MaskRegion { voltage_max: f32::NAN, ..region },
+            MaskRegion { voltage_min: f32::NAN, ..region },
+            MaskRegion { time_end: f32::INFINITY, ..region },
+            MaskRegion { time_start: f32::NEG_INFINITY, ..region },
+        ] {
+            assert!(matches!(scope.load_mask(&[region, broken]), Err(ScopeError::InvalidArgument(_))), "{:?}", broken);
+        }
+        // Nothing was sent, the simulator would report the mask commands
+        // as undefined
+        assert!(scope.check_errors().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+//! The instrument's math channel, and the same operations plus filters
+//! computed on captured traces.
+//!
+//! The client-side functions work on `(time, samples)` pairs as returned by
+//! `get_waveform_data` and return new ones, so they can be chained:
+//!
+//! ```no_run
+//! # use oscilloscope_waveform::math::{subtract, Filter};
+//! # let (ch1, ch2) = ((vec![0.0f32], vec![0.0f32]), (vec![0.0f32], vec![0.0f32]));
+//! let differential = Filter::LowPass { cutoff_hz: 1e6 }.apply(&subtract(&ch1, &ch2)?)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use log::info;
+
+use crate::device::no_progress;
+use crate::settings::ChannelScaling;
+use crate::{check_channel, OscilloscopeWaveform, Result, ScopeError, TransferFormat};
+
+/// Time values and samples of a capture.
+pub type Trace = (Vec<f32>, Vec<f32>);
+
+/// Fraction of a sample interval up to which the time bases of two traces
+/// may differ and still count as equal, which absorbs `f32` rounding.
+const TIME_BASE_TOLERANCE: f64 = 0.01;
+
+/// Operation computed by the instrument's math channel.
+///
+/// Each variant takes source channel numbers (1-4). `Fft` always works on a
+/// single channel and cannot be nested in another operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathExpression {
+    Add(u8, u8),
+    Subtract(u8, u8),
+    Multiply(u8, u8),
+    Divide(u8, u8),
+    Fft(u8),
+}
+
+impl MathExpression {
+    /// Source channels, each once.
+    fn channels(self) -> Vec<u8> {
+        match self {
+            MathExpression::Add(a, b) | MathExpression::Subtract(a, b)
+            | MathExpression::Multiply(a, b) | MathExpression::Divide(a, b) if a != b => vec![a, b],
+            MathExpression::Add(a, _) | MathExpression::Subtract(a, _)
+            | MathExpression::Multiply(a, _) | MathExpression::Divide(a, _) | MathExpression::Fft(a) => vec![a],
+        }
+    }
+
+    /// Check the source channels and format the `MATH:EXPRession` argument,
+    /// e.g. `"CH1+CH2"` or `"FFT(CH3)"`.
+    fn to_scpi(self) -> Result<String> {
+        for channel in self.channels() {
+            check_channel(channel)?;
+        }
+        Ok(self.to_string())
+    }
+}
+
+impl fmt::Display for MathExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (a, operator, b) = match *self {
+            MathExpression::Add(a, b) => (a, '+', b),
+            MathExpression::Subtract(a, b) => (a, '-', b),
+            MathExpression::Multiply(a, b) => (a, '*', b),
+            MathExpression::Divide(a, b) => (a, '/', b),
+            MathExpression::Fft(channel) => return write!(f, "FFT(CH{})", channel),
+        };
+        write!(f, "CH{}{}CH{}", a, operator, b)
+    }
+}
+
+impl FromStr for MathExpression {
+    type Err = String;
+
+    /// Parse an expression like `CH1-CH2`, `1*2` or `FFT(CH3)`.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let expression: String = value.split_whitespace().collect::<String>().to_ascii_uppercase();
+        let channel = |part: &str| {
+            let number = part.strip_prefix("CH").unwrap_or(part);
+            number.parse::<u8>().ok()
+                .filter(|channel| check_channel(*channel).is_ok())
+                .ok_or_else(|| format!("Invalid channel '{}' in '{}'", part, value))
+        };
+        if let Some(source) = expression.strip_prefix("FFT(").and_then(|rest| rest.strip_suffix(')')) {
+            return Ok(MathExpression::Fft(channel(source)?));
+        }
+        let Some(position) = expression.find(['+', '-', '*', '/']) else {
+            return Err(format!("Expected CHa+CHb, CHa-CHb, CHa*CHb, CHa/CHb or FFT(CHa), got '{}'", value));
+        };
+        let (a, b) = (channel(&expression[..position])?, channel(&expression[position + 1..])?);
+        Ok(match &expression[position..position + 1] {
+            "+" => MathExpression::Add(a, b),
+            "-" => MathExpression::Subtract(a, b),
+            "*" => MathExpression::Multiply(a, b),
+            _ => MathExpression::Divide(a, b),
+        })
+    }
+}
+
+/// Reject traces whose time and sample counts differ.
+fn check_trace((time, samples): &Trace) -> anyhow::Result<()> {
+    if time.len() != samples.len() {
+        return Err(anyhow!("Trace has {} time values for {} samples", time.len(), samples.len()));
+    }
+    if time.is_empty() {
+        return Err(anyhow!("Trace has no samples"));
+    }
+    Ok(())
+}
+
+/// Mean sample interval of a checked trace, 0 for a single sample.
+fn time_delta(time: &[f32]) -> f64 {
+    if time.len() < 2 {
+        return 0.0;
+    }
+    (time[time.len() - 1] as f64 - time[0] as f64) / (time.len() - 1) as f64
+}
+
+/// Combine two traces on the same time base sample by sample.
+fn combine(a: &Trace, b: &Trace, operation: impl Fn(f32, f32) -> f32) -> anyhow::Result<Trace> {
+    check_trace(a)?;
+    check_trace(b)?;
+    if a.0.len() != b.0.len() {
+        return Err(anyhow!("Traces have {} and {} samples", a.0.len(), b.0.len()));
+    }
+    let (delta_a, delta_b) = (time_delta(&a.0), time_delta(&b.0));
+    let tolerance = TIME_BASE_TOLERANCE * delta_a.max(delta_b);
+    if (delta_a - delta_b).abs() > tolerance || (a.0[0] as f64 - b.0[0] as f64).abs() > tolerance {
+        return Err(anyhow!("Time bases differ: {:e} s per sample from {:e} s, and {:e} s per sample from {:e} s",
+            delta_a, a.0[0], delta_b, b.0[0]));
+    }
+    let samples = a.1.iter().zip(&b.1).map(|(&x, &y)| operation(x, y)).collect();
+    Ok((a.0.clone(), samples))
+}
+
+/// Sum of two traces on the same time base.
+pub fn add(a: &Trace, b: &Trace) -> anyhow::Result<Trace> {
+    combine(a, b, |x, y| x + y)
+}
+
+/// Difference `a - b` of two traces on the same time base, e.g. a
+/// differential signal measured with two single-ended probes.
+pub fn subtract(a: &Trace, b: &Trace) -> anyhow::Result<Trace> {
+    combine(a, b, |x, y| x - y)
+}
+
+/// Product of two traces on the same time base, e.g. voltage and current
+/// to power.
+pub fn multiply(a: &Trace, b: &Trace) -> anyhow::Result<Trace> {
+    combine(a, b, |x, y| x * y)
+}
+
+/// Quotient `a / b` of two traces on the same time base. Samples where `b`
+/// is 0 become infinite or NaN.
+pub fn divide(a: &Trace, b: &Trace) -> anyhow::Result<Trace> {
+    combine(a, b, |x, y| x / y)
+}
+
+/// Every sample multiplied by `scale`, then `offset` added.
+pub fn scale_offset(trace: &Trace, scale: f32, offset: f32) -> anyhow::Result<Trace> {
+    check_trace(trace)?;
+    Ok((trace.0.clone(), trace.1.iter().map(|&v| v * scale + offset).collect()))
+}
+
+/// Filter applied to a captured trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// Single-pole low-pass with its -3 dB point at `cutoff_hz`.
+    LowPass { cutoff_hz: f64 },
+    /// Moving average over `samples` samples centred on each one.
+    MovingAverage { samples: usize },
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Parse `lowpass:<cutoff Hz>` or `average:<samples>`.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (kind, parameter) = value.split_once(':')
+            .ok_or_else(|| format!("Expected lowpass:<Hz> or average:<samples>, got '{}'", value))?;
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "lowpass" | "lp" => parameter.trim().parse::<f64>().ok()
+                .filter(|cutoff| cutoff.is_finite() && *cutoff > 0.0)
+                .map(|cutoff_hz| Filter::LowPass { cutoff_hz })
+                .ok_or_else(|| format!("Invalid cutoff frequency '{}'", parameter)),
+            "average" | "avg" => parameter.trim().parse::<usize>().ok()
+                .filter(|&samples| samples > 0)
+                .map(|samples| Filter::MovingAverage { samples })
+                .ok_or_else(|| format!("Invalid number of samples '{}'", parameter)),
+            _ => Err(format!("Unknown filter '{}', expected lowpass or average", kind)),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::LowPass { cutoff_hz } => write!(f, "low-pass {:e} Hz", cutoff_hz),
+            Filter::MovingAverage { samples } => write!(f, "average of {}", samples),
+        }
+    }
+}
+
+impl Filter {
+    /// Filter `trace`. The low-pass cutoff is converted to a coefficient
+    /// with the trace's mean sample interval and must lie below its
+    /// Nyquist frequency.
+    pub fn apply(&self, trace: &Trace) -> anyhow::Result<Trace> {
+        check_trace(trace)?;
+        let samples = match *self {
+            Filter::LowPass { cutoff_hz } => low_pass(&trace.1, cutoff_hz, time_delta(&trace.0))?,
+            Filter::MovingAverage { samples } => moving_average(&trace.1, samples)?,
+        };
+        Ok((trace.0.clone(), samples))
+    }
+}
+
+/// Single-pole IIR low-pass `y[n] = y[n-1] + alpha * (x[n] - y[n-1])`.
+///
+/// `alpha` is chosen so the gain is exactly -3 dB at `cutoff_hz`, which
+/// for cutoffs well below the sample rate is the familiar RC response.
+/// The filter starts settled at the first sample.
+fn low_pass(samples: &[f32], cutoff_hz: f64, time_delta: f64) -> anyhow::Result<Vec<f32>> {
+    if !cutoff_hz.is_finite() || cutoff_hz <= 0.0 {
+        return Err(anyhow!("Invalid cutoff frequency {} Hz", cutoff_hz));
+    }
+    if !time_delta.is_finite() || time_delta <= 0.0 {
+        return Err(anyhow!("A low-pass needs at least two samples in increasing time order"));
+    }
+    let nyquist_hz = 0.5 / time_delta;
+    if cutoff_hz >= nyquist_hz {
+        return Err(anyhow!("Cutoff {} Hz is not below the Nyquist frequency of {} Hz", cutoff_hz, nyquist_hz));
+    }
+    // |H|^2 = alpha^2 / (1 - 2 (1 - alpha) cos w + (1 - alpha)^2) = 1/2 at the cutoff
+    let cos_w = (2.0 * PI * cutoff_hz * time_delta).cos();
+    let pole = (2.0 - cos_w) - ((2.0 - cos_w).powi(2) - 1.0).sqrt();
+    let alpha = 1.0 - pole;
+
+    let mut state = samples[0] as f64;
+    Ok(samples.iter()
+        .map(|&x| {
+            state += alpha * (x as f64 - state);
+            state as f32
+        })
+        .collect())
+}
+
+/// Mean of the `window` samples around each one, fewer at both ends.
+fn moving_average(samples: &[f32], window: usize) -> anyhow::Result<Vec<f32>> {
+    if window == 0 {
+        return Err(anyhow!("Moving average needs at least one sample"));
+    }
+    let mut prefix = Vec::with_capacity(samples.len() + 1);
+    prefix.push(0.0f64);
+    for &v in samples {
+        prefix.push(prefix[prefix.len() - 1] + v as f64);
+    }
+    Ok((0..samples.len())
+        .map(|i| {
+            let first = i.saturating_sub((window - 1) / 2);
+            let last = (i + window / 2).min(samples.len() - 1);
+            ((prefix[last + 1] - prefix[first]) / (last + 1 - first) as f64) as f32
+        })
+        .collect())
+}
+
+impl OscilloscopeWaveform {
+    /// Set up and enable the math channel.
+    pub fn configure_math_channel(&self, expr: MathExpression) -> Result<()> {
+        let expression = expr.to_scpi()?;
+        self.send_command(&format!("MATH:EXPRession \"{}\"", expression))?;
+        self.send_command("MATH:STATe 1")?;
+        self.verify_no_errors("math channel setup")?;
+        info!("Math channel: {}", expression);
+        Ok(())
+    }
+
+    /// Capture the source channels of `expr` from the same trigger with data
+    /// type `dtype` and compute it on the client, leaving the instrument's
+    /// math channel alone. `Fft` is only available on the instrument.
+    pub fn compute_math(&self, expr: MathExpression, dtype: TransferFormat) -> Result<Trace> {
+        if let MathExpression::Fft(_) = expr {
+            return Err(ScopeError::InvalidArgument("FFT is only computed by the instrument".to_string()));
+        }
+        let mut captures: Vec<Trace> = self.capture_channels(&expr.channels(), dtype, None)?.into_iter()
+            .map(|(metadata, samples)| {
+                let time = (0..samples.len()).map(|i| metadata.start_time + (i as f32) * metadata.time_delta);
+                (time.collect(), samples)
+            })
+            .collect();
+        let b = captures.pop().expect("one capture per channel");
+        let a = captures.pop().unwrap_or_else(|| b.clone());
+        let result = match expr {
+            MathExpression::Add(..) => add(&a, &b),
+            MathExpression::Subtract(..) => subtract(&a, &b),
+            MathExpression::Multiply(..) => multiply(&a, &b),
+            MathExpression::Divide(..) => divide(&a, &b),
+            MathExpression::Fft(_) => unreachable!("rejected above"),
+        };
+        info!("Computed {} on the client", expr);
+        result.map_err(|e| ScopeError::Analysis(e.to_string()))
+    }
+
+    /// Read the computed math channel.
+    ///
+    /// Uses the same block format as `get_waveform_data`. For an FFT the
+    /// first vector holds the frequency axis instead of the time axis.
+    pub fn get_math_channel_data(&self, dtype: TransferFormat) -> Result<(Vec<f32>, Vec<f32>)> {
+        self.send_command(&format!("MATH:DATa:TYPE {}", dtype))?;
+        self.verify_no_errors("math data type configuration")?;
+        self.read_waveform(&format!("MATH:DATa:PACK? ALL, {}", dtype), dtype, None, ChannelScaling::default(),
+            &no_progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+
+    #[test]
+    fn formats_expressions() {
+        assert_eq!(MathExpression::Add(1, 2).to_scpi().unwrap(), "CH1+CH2");
+        assert_eq!(MathExpression::Subtract(4, 3).to_scpi().unwrap(), "CH4-CH3");
+        assert_eq!(MathExpression::Multiply(2, 2).to_scpi().unwrap(), "CH2*CH2");
+        assert_eq!(MathExpression::Divide(3, 1).to_scpi().unwrap(), "CH3/CH1");
+        assert_eq!(MathExpression::Fft(2).to_scpi().unwrap(), "FFT(CH2)");
+    }
+
+    #[test]
+    fn rejects_invalid_channels() {
+        assert!(MathExpression::Add(0, 1).to_scpi().is_err());
+        assert!(MathExpression::Divide(1, 5).to_scpi().is_err());
+        assert!(MathExpression::Fft(0).to_scpi().is_err());
+    }
+
+    #[test]
+    fn parses_expressions_and_filters() {
+        assert_eq!("CH1-CH2".parse::<MathExpression>().unwrap(), MathExpression::Subtract(1, 2));
+        assert_eq!(" ch3 * 4 ".parse::<MathExpression>().unwrap(), MathExpression::Multiply(3, 4));
+        assert_eq!("fft(ch2)".parse::<MathExpression>().unwrap(), MathExpression::Fft(2));
+        assert!("CH1".parse::<MathExpression>().is_err());
+        assert!("CH1+CH5".parse::<MathExpression>().is_err());
+
+        assert_eq!("lowpass:1e6".parse::<Filter>().unwrap(), Filter::LowPass { cutoff_hz: 1e6 });
+        assert_eq!("average:16".parse::<Filter>().unwrap(), Filter::MovingAverage { samples: 16 });
+        assert!("lowpass:0".parse::<Filter>().is_err());
+        assert!("highpass:1e3".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn combines_traces_on_the_same_time_base() {
+        let time: Vec<f32> = (0..4).map(|n| n as f32 * 1e-6).collect();
+        let a = (time.clone(), vec![1.0, 2.0, 3.0, 4.0]);
+        let b = (time.clone(), vec![0.5, 0.5, -1.0, 2.0]);
+        assert_eq!(subtract(&a, &b).unwrap().1, [0.5, 1.5, 4.0, 2.0]);
+        assert_eq!(add(&a, &b).unwrap().1, [1.5, 2.5, 2.0, 6.0]);
+        assert_eq!(multiply(&a, &b).unwrap().1, [0.5, 1.0, -3.0, 8.0]);
+        assert_eq!(scale_offset(&a, 2.0, -1.0).unwrap().1, [1.0, 3.0, 5.0, 7.0]);
+        assert_eq!(Filter::MovingAverage { samples: 3 }.apply(&a).unwrap().1, [1.5, 2.0, 3.0, 3.5]);
+
+        // Shorter, shifted by half a sample and slower traces are rejected
+        assert!(subtract(&a, &(time[..3].to_vec(), vec![0.0; 3])).is_err());
+        let shifted = (time.iter().map(|t| t + 0.5e-6).collect(), vec![0.0; 4]);
+        assert!(subtract(&a, &shifted).is_err());
+        let slower = (time.iter().map(|t| t * 2.0).collect(), vec![0.0; 4]);
+        assert!(subtract(&a, &slower).is_err());
+        assert!(subtract(&a, &(time, vec![0.0; 3])).is_err());
+    }
+
+    #[test]
+    fn low_pass_is_3_db_down_at_the_cutoff() {
+        // Tones from 0.8 to 1.2 times the cutoff at 1 MS/s
+        let time_delta = 1e-6;
+        let cutoff_hz = 10e3;
+        let filter = Filter::LowPass { cutoff_hz };
+        let gain = |frequency: f64| {
+            let time: Vec<f32> = (0..20_000).map(|n| (n as f64 * time_delta) as f32).collect();
+            let tone: Vec<f32> = (0..20_000).map(|n| (2.0 * PI * frequency * n as f64 * time_delta).sin() as f32).collect();
+            let (_, filtered) = filter.apply(&(time, tone)).unwrap();
+            // RMS over whole periods of the settled second half
+            let period = 1.0 / (frequency * time_delta);
+            let len = ((10_000.0 / period).floor() * period).round() as usize;
+            let settled = &filtered[filtered.len() - len..];
+            (2.0 * settled.iter().map(|&v| v as f64 * v as f64).sum::<f64>() / len as f64).sqrt()
+        };
+        let frequencies: Vec<f64> = (0..=40).map(|k| cutoff_hz * (0.8 + 0.01 * k as f64)).collect();
+        let gains: Vec<f64> = frequencies.iter().map(|&f| gain(f)).collect();
+        assert!(gains.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", gains);
+
+        let half_power = std::f64::consts::FRAC_1_SQRT_2;
+        let k = gains.iter().position(|&g| g < half_power).expect("gain falls below -3 dB");
+        let fraction = (gains[k - 1] - half_power) / (gains[k - 1] - gains[k]);
+        let corner = frequencies[k - 1] + fraction * (frequencies[k] - frequencies[k - 1]);
+        assert!((corner / cutoff_hz - 1.0).abs() < 0.01, "-3 dB at {} Hz", corner);
+
+        let time: Vec<f32> = (0..10).map(|n| n as f32 * 1e-6).collect();
+        let trace = (time, vec![0.0; 10]);
+        assert!(Filter::LowPass { cutoff_hz: 500e3 }.apply(&trace).is_err());
+        assert!(Filter::LowPass { cutoff_hz: 1e3 }.apply(&(vec![0.0], vec![1.0])).is_err());
+    }
+
+    #[test]
+    fn computes_math_from_one_trigger() {
+        let config = SimulationConfig { noise_v: 0.0, ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        // Channel 3 lags channel 1 by 180 degrees, so the difference doubles
+        let (time, difference) = scope.compute_math(MathExpression::Subtract(1, 3), TransferFormat::Raw).unwrap();
+        assert_eq!(time.len(), difference.len());
+        for (&t, &v) in time.iter().zip(&difference) {
+            let expected = 2.0 * (2.0 * PI * 1e3 * t as f64).sin() as f32;
+            assert!((v - expected).abs() < 1e-3, "{} V at {} s, expected {} V", v, t, expected);
+        }
+        let (_, squared) = scope.compute_math(MathExpression::Multiply(2, 2), TransferFormat::Raw).unwrap();
+        assert!(squared.iter().all(|&v| v >= 0.0));
+        assert!(scope.compute_math(MathExpression::Fft(1), TransferFormat::Raw).is_err());
+    }
+}
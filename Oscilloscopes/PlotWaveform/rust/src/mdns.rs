@@ -0,0 +1,496 @@
+//! Discovery of network instruments with mDNS, enabled with the `mdns`
+//! feature.
+//!
+//! VISA only lists network instruments its configuration knows about. LXI
+//! instruments announce themselves on the LAN as `_lxi._tcp` and
+//! `_scpi-raw._tcp` services, so browsing for those finds them without any
+//! setup. Queries are sent from an ephemeral port, which makes responders
+//! answer by unicast and leaves port 5353 to a system mDNS daemon.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use visa_rs::DefaultRM;
+
+use crate::device::Identity;
+use crate::{discover_devices, DiscoveredDevice, Result, ScopeError};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Service types browsed for.
+pub const SERVICE_TYPES: [&str; 2] = ["_lxi._tcp.local", "_scpi-raw._tcp.local"];
+
+/// SCPI socket port of LXI instruments, for `*IDN?` when an instrument
+/// announces no `_scpi-raw._tcp` service.
+const SCPI_RAW_PORT: u16 = 5025;
+
+/// How often queries are repeated during a browse, so a lost packet does
+/// not hide an instrument.
+const QUERY_INTERVAL: Duration = Duration::from_millis(500);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// Most compression pointers followed in one name, against loops.
+const MAX_NAME_JUMPS: usize = 32;
+
+/// A service instance found by [`browse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkService {
+    /// Instance name, e.g. `Magnova 1234._lxi._tcp.local`.
+    pub instance: String,
+    /// One of [`SERVICE_TYPES`].
+    pub service_type: String,
+    pub address: Ipv4Addr,
+    pub port: u16,
+    /// Key/value pairs of the TXT record.
+    pub txt: Vec<(String, String)>,
+}
+
+impl NetworkService {
+    /// Value of a TXT key, compared without case as DNS-SD requires.
+    pub fn txt_value(&self, key: &str) -> Option<&str> {
+        self.txt.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+
+    /// Identity from the `Manufacturer`, `Model`, `SerialNumber` and
+    /// `FirmwareVersion` keys LXI defines, if the manufacturer is given.
+    pub fn identity(&self) -> Option<Identity> {
+        let field = |key| self.txt_value(key).unwrap_or_default().to_string();
+        self.txt_value("Manufacturer").map(|manufacturer| Identity {
+            manufacturer: manufacturer.to_string(),
+            model: field("Model"),
+            serial: field("SerialNumber"),
+            firmware: field("FirmwareVersion"),
+        })
+    }
+
+    /// VISA resource string to open the instrument with: a VXI-11
+    /// resource for LXI services, a socket resource for raw SCPI ones.
+    pub fn resource(&self) -> String {
+        if self.service_type.starts_with("_scpi-raw.") {
+            format!("TCPIP::{}::{}::SOCKET", self.address, self.port)
+        } else {
+            format!("TCPIP::{}::INSTR", self.address)
+        }
+    }
+}
+
+/// Append a name as length-prefixed labels.
+fn encode_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+/// A query message asking for every `(name, type)` pair.
+fn encode_query(questions: &[(String, u16)]) -> Vec<u8> {
+    let mut packet = vec![0u8; 12];
+    packet[4..6].copy_from_slice(&(questions.len() as u16).to_be_bytes());
+    for (name, record_type) in questions {
+        encode_name(&mut packet, name);
+        packet.extend_from_slice(&record_type.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    packet
+}
+
+/// Cursor over a DNS message. Reads past the end return `None`.
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.pos..self.pos + count)?;
+        self.pos += count;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Read a name, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        for _ in 0..MAX_NAME_JUMPS {
+            let len = *self.packet.get(pos)? as usize;
+            if len & 0xC0 == 0xC0 {
+                let target = (len & 0x3F) << 8 | *self.packet.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+                continue;
+            }
+            if len == 0 {
+                self.pos = end.unwrap_or(pos + 1);
+                return Some(labels.join("."));
+            }
+            let label = self.packet.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+        None
+    }
+}
+
+/// Records of interest collected from responses.
+#[derive(Debug, Default)]
+struct Records {
+    /// Instances by service type.
+    instances: HashMap<String, Vec<String>>,
+    /// Port and host of every instance.
+    targets: HashMap<String, (u16, String)>,
+    txt: HashMap<String, Vec<(String, String)>>,
+    addresses: HashMap<String, Ipv4Addr>,
+}
+
+/// Names are compared without case, so they are kept in lower case.
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Split TXT strings into key/value pairs. A string without `=` is a
+/// boolean key with an empty value.
+fn parse_txt(mut data: &[u8]) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let entry = String::from_utf8_lossy(&rest[..(len as usize).min(rest.len())]).into_owned();
+        data = &rest[(len as usize).min(rest.len())..];
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.split_once('=') {
+            Some((k, v)) => pairs.push((k.to_string(), v.to_string())),
+            None => pairs.push((entry, String::new())),
+        }
+    }
+    pairs
+}
+
+impl Records {
+    /// Add the records of every section of a response. Malformed packets
+    /// are kept up to where they break.
+    fn add_response(&mut self, packet: &[u8]) {
+        let mut reader = Reader { packet, pos: 4 };
+        let mut counts = [0u16; 4];
+        for count in &mut counts {
+            match reader.u16() {
+                Some(value) => *count = value,
+                None => return,
+            }
+        }
+        // Responses to legacy unicast queries echo the questions
+        for _ in 0..counts[0] {
+            if reader.name().is_none() || reader.bytes(4).is_none() {
+                return;
+            }
+        }
+        let records = counts[1] as usize + counts[2] as usize + counts[3] as usize;
+        for _ in 0..records {
+            if self.add_record(&mut reader).is_none() {
+                return;
+            }
+        }
+    }
+
+    fn add_record(&mut self, reader: &mut Reader) -> Option<()> {
+        let name = key(&reader.name()?);
+        let record_type = reader.u16()?;
+        reader.bytes(6)?; // class and TTL
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        let data = reader.packet.get(reader.pos..end)?;
+        match record_type {
+            TYPE_PTR => {
+                let instance = reader.name()?;
+                let instances = self.instances.entry(name).or_default();
+                if !instances.iter().any(|known| known.eq_ignore_ascii_case(&instance)) {
+                    instances.push(instance);
+                }
+            }
+            TYPE_SRV if len >= 6 => {
+                reader.bytes(4)?; // priority and weight
+                let port = reader.u16()?;
+                let host = reader.name()?;
+                self.targets.insert(name, (port, host));
+            }
+            TYPE_TXT => {
+                self.txt.insert(name, parse_txt(data));
+            }
+            TYPE_A if len == 4 => {
+                self.addresses.insert(name, Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+            }
+            _ => {}
+        }
+        reader.pos = end;
+        Some(())
+    }
+
+    /// Questions for what is still missing: the service types, then SRV
+    /// and TXT records of known instances and addresses of their hosts.
+    fn questions(&self) -> Vec<(String, u16)> {
+        let mut questions: Vec<(String, u16)> = SERVICE_TYPES.iter().map(|t| (t.to_string(), TYPE_PTR)).collect();
+        for instance in self.instances.values().flatten() {
+            match self.targets.get(&key(instance)) {
+                Some((_, host)) if !self.addresses.contains_key(&key(host)) => {
+                    questions.push((host.clone(), TYPE_A));
+                }
+                Some(_) => {}
+                None => questions.push((instance.clone(), TYPE_SRV)),
+            }
+            if !self.txt.contains_key(&key(instance)) {
+                questions.push((instance.clone(), TYPE_TXT));
+            }
+        }
+        questions
+    }
+
+    /// Services whose port and address are known.
+    fn services(&self) -> Vec<NetworkService> {
+        let mut services = Vec::new();
+        for service_type in SERVICE_TYPES {
+            for instance in self.instances.get(&key(service_type)).into_iter().flatten() {
+                let Some((port, host)) = self.targets.get(&key(instance)) else {
+                    continue;
+                };
+                let Some(&address) = self.addresses.get(&key(host)) else {
+                    continue;
+                };
+                services.push(NetworkService {
+                    instance: instance.clone(),
+                    service_type: service_type.to_string(),
+                    address,
+                    port: *port,
+                    txt: self.txt.get(&key(instance)).cloned().unwrap_or_default(),
+                });
+            }
+        }
+        services
+    }
+}
+
+/// Browse the local network for LXI and raw SCPI services for `duration`.
+///
+/// Services are returned once their host address is known, in the order
+/// of [`SERVICE_TYPES`]. An instrument announcing both types is listed
+/// under each.
+pub fn browse(duration: Duration) -> Result<Vec<NetworkService>> {
+    info!("Browsing for network instruments for {:?}", duration);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+    let deadline = Instant::now() + duration;
+    let mut records = Records::default();
+    let mut next_query = Instant::now();
+    let mut buf = [0u8; 9000];
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        if now >= next_query {
+            socket.send_to(&encode_query(&records.questions()), group)?;
+            next_query = now + QUERY_INTERVAL;
+        }
+        socket.set_read_timeout(Some(next_query.min(deadline).saturating_duration_since(now)
+            .max(Duration::from_millis(1))))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, _)) => records.add_response(&buf[..len]),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let services = records.services();
+    info!("Found {} network services", services.len());
+    Ok(services)
+}
+
+/// Ask an instrument for its identity over a raw SCPI socket.
+pub fn query_socket_identity(address: SocketAddr, timeout: Duration) -> Result<Identity> {
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(b"*IDN?\n")?;
+    let mut idn = String::new();
+    BufReader::new(stream).read_line(&mut idn)?;
+    if idn.trim().is_empty() {
+        return Err(ScopeError::UnexpectedResponse { command: "*IDN?".to_string(), response: idn });
+    }
+    Ok(Identity::parse(&idn))
+}
+
+/// List the instruments announcing themselves on the network.
+///
+/// Browses for `browse_duration`, then takes each instrument's identity
+/// from its TXT record, or asks for it with `*IDN?` over the SCPI socket,
+/// waiting up to `timeout`. An instrument announcing several services is
+/// listed once, with its VXI-11 resource where it has one.
+pub fn discover_network_devices(browse_duration: Duration, timeout: Duration) -> Result<Vec<DiscoveredDevice>> {
+    let services = browse(browse_duration)?;
+    let mut devices: Vec<(Ipv4Addr, DiscoveredDevice)> = Vec::new();
+    for service in &services {
+        if devices.iter().any(|(address, _)| *address == service.address) {
+            continue;
+        }
+        let identity = service.identity().or_else(|| {
+            let port = services.iter()
+                .find(|other| other.address == service.address && other.service_type.starts_with("_scpi-raw."))
+                .map_or(SCPI_RAW_PORT, |other| other.port);
+            match query_socket_identity(SocketAddr::from((service.address, port)), timeout) {
+                Ok(identity) => Some(identity),
+                Err(e) => {
+                    warn!("{} did not respond: {}", service.instance, e);
+                    None
+                }
+            }
+        });
+        devices.push((service.address, DiscoveredDevice { resource: service.resource(), identity }));
+    }
+    Ok(devices.into_iter().map(|(_, device)| device).collect())
+}
+
+/// Append the network devices VISA did not list. A device counts as
+/// listed if a VISA device has the same serial number, or the same
+/// resource string.
+pub fn merge_devices(mut visa: Vec<DiscoveredDevice>, network: Vec<DiscoveredDevice>) -> Vec<DiscoveredDevice> {
+    let serial = |device: &DiscoveredDevice| device.identity.as_ref()
+        .map(|identity| identity.serial.clone())
+        .filter(|serial| !serial.is_empty());
+    for device in network {
+        let known = visa.iter().any(|listed| listed.resource.eq_ignore_ascii_case(&device.resource)
+            || serial(listed).is_some_and(|listed| Some(listed) == serial(&device)));
+        if !known {
+            visa.push(device);
+        }
+    }
+    visa
+}
+
+/// [`discover_devices`] followed by an mDNS browse of `browse_duration`,
+/// with every instrument listed once.
+pub fn discover_all_devices(rm: &DefaultRM, timeout: Duration, browse_duration: Duration)
+    -> Result<Vec<DiscoveredDevice>> {
+    let visa = discover_devices(rm, timeout)?;
+    let network = discover_network_devices(browse_duration, timeout)?;
+    Ok(merge_devices(visa, network))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn record(packet: &mut Vec<u8>, name: &[u8], record_type: u16, data: &[u8]) {
+        packet.extend_from_slice(name);
+        packet.extend_from_slice(&record_type.to_be_bytes());
+        packet.extend_from_slice(&[0x80, 0x01, 0, 0, 0x11, 0x94]);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+    }
+
+    /// A response announcing one LXI instrument, with compressed names.
+    fn response() -> Vec<u8> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3];
+        // PTR _lxi._tcp.local -> Magnova._lxi._tcp.local
+        let mut name = Vec::new();
+        encode_name(&mut name, "_lxi._tcp.local");
+        record(&mut packet, &name, TYPE_PTR, &[7, b'M', b'a', b'g', b'n', b'o', b'v', b'a', 0xC0, 12]);
+        let instance = [0xC0, 12 + name.len() as u8 + 10];
+
+        let mut srv = vec![0, 0, 0, 0, 0, 80, 4, b'h', b'o', b's', b't'];
+        srv.extend_from_slice(&[0xC0, 12 + 10]); // .local
+        record(&mut packet, &instance, TYPE_SRV, &srv);
+        let txt = b"\x15Manufacturer=Batronix\x0dModel=Magnova\x0fSerialNumber=42\x06no_key";
+        record(&mut packet, &instance, TYPE_TXT, txt);
+        let mut host = Vec::new();
+        encode_name(&mut host, "HOST.local");
+        record(&mut packet, &host, TYPE_A, &[192, 168, 1, 20]);
+        packet
+    }
+
+    #[test]
+    fn parses_compressed_responses() {
+        let mut records = Records::default();
+        records.add_response(&response());
+        let services = records.services();
+        assert_eq!(services.len(), 1, "{:?}", records);
+        let service = &services[0];
+        assert_eq!(service.instance, "Magnova._lxi._tcp.local");
+        assert_eq!(service.address, Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(service.port, 80);
+        assert_eq!(service.txt_value("manufacturer"), Some("Batronix"));
+        assert_eq!(service.txt_value("no_key"), Some(""));
+        assert_eq!(service.resource(), "TCPIP::192.168.1.20::INSTR");
+        let identity = service.identity().unwrap();
+        assert!(identity.is_batronix());
+        assert_eq!((identity.model.as_str(), identity.serial.as_str()), ("Magnova", "42"));
+        assert_eq!(records.questions().len(), SERVICE_TYPES.len());
+
+        // Truncated packets keep what was complete
+        let mut records = Records::default();
+        records.add_response(&response()[..60]);
+        assert!(records.services().is_empty());
+        assert!(records.questions().iter().any(|question| question.1 == TYPE_SRV));
+    }
+
+    #[test]
+    fn encodes_queries() {
+        let query = encode_query(&[("_lxi._tcp.local".to_string(), TYPE_PTR)]);
+        assert_eq!(&query[..6], &[0, 0, 0, 0, 0, 1]);
+        assert_eq!(&query[12..17], b"\x04_lxi");
+        assert_eq!(&query[query.len() - 4..], &[0, 12, 0, 1]);
+    }
+
+    fn device(resource: &str, serial: Option<&str>) -> DiscoveredDevice {
+        DiscoveredDevice {
+            resource: resource.to_string(),
+            identity: serial.map(|serial| Identity::parse(&format!("Batronix,Magnova,{},1.0", serial))),
+        }
+    }
+
+    #[test]
+    fn merges_devices_by_serial_number() {
+        let visa = vec![device("USB0::0x1::0x2::42::INSTR", Some("42")), device("ASRL1::INSTR", None)];
+        let network = vec![
+            device("TCPIP::192.168.1.20::INSTR", Some("42")),
+            device("TCPIP::192.168.1.21::INSTR", Some("43")),
+            device("TCPIP::192.168.1.22::INSTR", None),
+        ];
+        let merged = merge_devices(visa, network);
+        let resources: Vec<&str> = merged.iter().map(|device| device.resource.as_str()).collect();
+        assert_eq!(resources, [
+            "USB0::0x1::0x2::42::INSTR", "ASRL1::INSTR", "TCPIP::192.168.1.21::INSTR", "TCPIP::192.168.1.22::INSTR",
+        ]);
+
+    }
+
+    #[test]
+    fn asks_sockets_for_their_identity() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            assert_eq!(line, "*IDN?\n");
+            (&stream).write_all(b"Batronix,Magnova,1234,1.2\n").unwrap();
+        });
+        let identity = query_socket_identity(address, Duration::from_secs(2)).unwrap();
+        server.join().unwrap();
+        assert_eq!(identity.serial, "1234");
+    }
+}
@@ -0,0 +1,224 @@
+//! Measurements computed by the instrument.
+//!
+//! The `MEASure` subsystem reports the values shown on the instrument's
+//! screen. [`MeasKind::compute`] derives the same quantities from a capture,
+//! so both can be cross-checked.
+
+use std::fmt;
+use std::str::FromStr;
+
+use log::info;
+
+use crate::analysis::stats::WaveformStats;
+use crate::analysis::{find_crossings, measure_pulses, Edge};
+use crate::{check_channel, OscilloscopeWaveform, Result};
+
+/// Values at or above this are the SCPI marker for an invalid measurement.
+pub(crate) const INVALID_MEASUREMENT: f64 = 9.9e37;
+
+/// A measurement of the instrument's `MEASure` subsystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MeasKind {
+    Frequency,
+    Period,
+    /// Peak-to-peak voltage.
+    Vpp,
+    /// RMS voltage including the DC part.
+    Vrms,
+    Mean,
+    Minimum,
+    Maximum,
+    /// Top minus base level.
+    Amplitude,
+    /// 10 % to 90 % of the amplitude.
+    RiseTime,
+    /// 90 % to 10 % of the amplitude.
+    FallTime,
+    DutyCycle,
+    PositiveWidth,
+    NegativeWidth,
+}
+
+impl MeasKind {
+    pub const ALL: [MeasKind; 13] = [
+        MeasKind::Frequency, MeasKind::Period, MeasKind::Vpp, MeasKind::Vrms, MeasKind::Mean, MeasKind::Minimum,
+        MeasKind::Maximum, MeasKind::Amplitude, MeasKind::RiseTime, MeasKind::FallTime, MeasKind::DutyCycle,
+        MeasKind::PositiveWidth, MeasKind::NegativeWidth,
+    ];
+
+    /// Header of the query `MEASure:<header>? CHAN<n>`.
+    pub fn scpi_header(self) -> &'static str {
+        match self {
+            MeasKind::Frequency => "FREQuency",
+            MeasKind::Period => "PERiod",
+            MeasKind::Vpp => "VPP",
+            MeasKind::Vrms => "RMS",
+            MeasKind::Mean => "MEAN",
+            MeasKind::Minimum => "MINimum",
+            MeasKind::Maximum => "MAXimum",
+            MeasKind::Amplitude => "AMPLitude",
+            MeasKind::RiseTime => "RISetime",
+            MeasKind::FallTime => "FALLtime",
+            MeasKind::DutyCycle => "DUTYcycle",
+            MeasKind::PositiveWidth => "PWIDth",
+            MeasKind::NegativeWidth => "NWIDth",
+        }
+    }
+
+    /// Unit of the measured value.
+    pub fn unit(self) -> &'static str {
+        match self {
+            MeasKind::Frequency => "Hz",
+            MeasKind::Period | MeasKind::RiseTime | MeasKind::FallTime | MeasKind::PositiveWidth
+                | MeasKind::NegativeWidth => "s",
+            MeasKind::DutyCycle => "%",
+            MeasKind::Vpp | MeasKind::Vrms | MeasKind::Mean | MeasKind::Minimum | MeasKind::Maximum
+                | MeasKind::Amplitude => "V",
+        }
+    }
+
+    /// The same measurement computed from a capture, or `None` if the
+    /// waveform does not allow it.
+    ///
+    /// Frequency and period are averaged over the rising crossings of the
+    /// level halfway between minimum and maximum, amplitude, duty cycle and
+    /// widths come from [`measure_pulses`]. Rise and fall time are not
+    /// computed.
+    pub fn compute(self, time: &[f32], waveform: &[f32]) -> Option<f64> {
+        let stats = WaveformStats::compute(waveform);
+        let value = match self {
+            MeasKind::Frequency => 1.0 / mean_period(time, waveform, &stats)?,
+            MeasKind::Period => mean_period(time, waveform, &stats)?,
+            MeasKind::Vpp => stats.peak_to_peak as f64,
+            MeasKind::Vrms => stats.rms as f64,
+            MeasKind::Mean => stats.mean as f64,
+            MeasKind::Minimum => stats.min as f64,
+            MeasKind::Maximum => stats.max as f64,
+            MeasKind::Amplitude => measure_pulses(time, waveform)?.amplitude() as f64,
+            MeasKind::DutyCycle => measure_pulses(time, waveform)?.duty_cycle_percent.mean as f64,
+            MeasKind::PositiveWidth => measure_pulses(time, waveform)?.positive_width_s.mean as f64,
+            MeasKind::NegativeWidth => measure_pulses(time, waveform)?.negative_width_s.mean as f64,
+            MeasKind::RiseTime | MeasKind::FallTime => return None,
+        };
+        value.is_finite().then_some(value)
+    }
+}
+
+impl fmt::Display for MeasKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MeasKind::Frequency => "Frequency",
+            MeasKind::Period => "Period",
+            MeasKind::Vpp => "Vpp",
+            MeasKind::Vrms => "Vrms",
+            MeasKind::Mean => "Mean",
+            MeasKind::Minimum => "Minimum",
+            MeasKind::Maximum => "Maximum",
+            MeasKind::Amplitude => "Amplitude",
+            MeasKind::RiseTime => "Rise time",
+            MeasKind::FallTime => "Fall time",
+            MeasKind::DutyCycle => "Duty cycle",
+            MeasKind::PositiveWidth => "Positive width",
+            MeasKind::NegativeWidth => "Negative width",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for MeasKind {
+    type Err = String;
+
+    /// Parse the name as printed or the SCPI header, in any case and
+    /// without spaces.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let wanted: String = value.chars().filter(|c| !c.is_whitespace() && *c != '_').collect();
+        MeasKind::ALL.into_iter()
+            .find(|kind| {
+                kind.to_string().replace(' ', "").eq_ignore_ascii_case(&wanted)
+                    || kind.scpi_header().eq_ignore_ascii_case(&wanted)
+            })
+            .ok_or_else(|| format!("Unknown measurement '{}'", value))
+    }
+}
+
+/// The value of a measurement response, or `None` if it is the marker for
+/// an invalid measurement or not a finite number.
+pub fn measurement_value(value: f64) -> Option<f64> {
+    (value.is_finite() && value.abs() < INVALID_MEASUREMENT).then_some(value)
+}
+
+/// Mean time between rising crossings of the level halfway between
+/// minimum and maximum.
+fn mean_period(time: &[f32], waveform: &[f32], stats: &WaveformStats) -> Option<f64> {
+    let crossings = find_crossings(time, waveform, (stats.min + stats.max) / 2.0, Edge::Rising);
+    match (crossings.first(), crossings.last()) {
+        (Some(first), Some(last)) if crossings.len() >= 2 =>
+            Some((last.time_s as f64 - first.time_s as f64) / (crossings.len() - 1) as f64),
+        _ => None,
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Query a measurement of a channel from the instrument.
+    ///
+    /// Returns `None` if the instrument reports the measurement as invalid,
+    /// e.g. a frequency without a full period on screen.
+    pub fn query_measurement(&self, channel: u8, kind: MeasKind) -> Result<Option<f64>> {
+        check_channel(channel)?;
+        let value = self.query_f64(&format!("MEASure:{}? CHAN{}", kind.scpi_header(), channel))?;
+        let value = measurement_value(value);
+        if value.is_none() {
+            info!("{} of channel {} is not available", kind, channel);
+        }
+        Ok(value)
+    }
+
+    /// Query all [`MeasKind`]s of a channel in the order of
+    /// [`MeasKind::ALL`].
+    pub fn query_all_measurements(&self, channel: u8) -> Result<Vec<(MeasKind, Option<f64>)>> {
+        let measurements = MeasKind::ALL.into_iter()
+            .map(|kind| Ok((kind, self.query_measurement(channel, kind)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.verify_no_errors("measurement")?;
+        Ok(measurements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+    use crate::{DataRange, TransferFormat};
+
+    #[test]
+    fn filters_invalid_measurements() {
+        assert_eq!(measurement_value(1.5e3), Some(1.5e3));
+        assert_eq!(measurement_value(-2.0), Some(-2.0));
+        for invalid in [9.9e37, -9.9e37, 9.91e37, f64::INFINITY, f64::NAN] {
+            assert_eq!(measurement_value(invalid), None, "{}", invalid);
+        }
+        assert_eq!("rise time".parse::<MeasKind>(), Ok(MeasKind::RiseTime));
+        assert_eq!("PWIDTH".parse::<MeasKind>(), Ok(MeasKind::PositiveWidth));
+        assert!("slew".parse::<MeasKind>().is_err());
+    }
+
+    #[test]
+    fn queries_measurements_from_the_instrument() {
+        let config = SimulationConfig { noise_v: 0.0, ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        assert_eq!(scope.query_measurement(1, MeasKind::Frequency).unwrap(), Some(1e3));
+        assert_eq!(scope.query_measurement(2, MeasKind::Vpp).unwrap(), Some(2.0));
+        // The simulator reports the edge times of its sine as invalid
+        assert_eq!(scope.query_measurement(1, MeasKind::RiseTime).unwrap(), None);
+        assert!(scope.query_measurement(5, MeasKind::Mean).is_err());
+
+        let measurements = scope.query_all_measurements(1).unwrap();
+        assert_eq!(measurements.len(), MeasKind::ALL.len());
+        let (time, waveform) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Volts, None).unwrap();
+        for (kind, value) in measurements {
+            let (Some(onboard), Some(computed)) = (value, kind.compute(&time, &waveform)) else { continue };
+            assert!((onboard - computed).abs() <= 0.01 * onboard.abs().max(1e-3), "{}: {} vs {}", kind, onboard,
+                computed);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Models of the Magnova family and what each of them can do.
+//!
+//! [`OscilloscopeWaveform::identify_model`] tells the models apart by the
+//! model field of `*IDN?`, and [`Capabilities`] come from a registry built
+//! into the library, so settings beyond a model's hardware are rejected
+//! before anything is sent.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::LazyLock;
+
+use log::{info, warn};
+
+use crate::device::Identity;
+use crate::{check_channel, OscilloscopeWaveform, Result, ScopeError};
+
+/// What the hardware of a model offers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    /// Analog channels.
+    pub max_channels: u8,
+    /// Installed acquisition memory in points.
+    pub max_memory_depth: u32,
+    /// Highest real-time sample rate.
+    pub max_sample_rate_hz: f64,
+    /// Whether digital pods can be connected.
+    pub has_mso: bool,
+    /// Whether a waveform generator is built in.
+    pub has_awg: bool,
+}
+
+/// A known instrument model.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceModel {
+    Magnova206,
+    Magnova212,
+    Magnova254,
+    /// A Magnova whose variant is not known, e.g. the simulator. The
+    /// capabilities of the base model are assumed.
+    Magnova,
+    /// Any other instrument, with the model field of its `*IDN?` reply.
+    Other(String),
+}
+
+/// Capabilities of every model but [`DeviceModel::Other`].
+static REGISTRY: LazyLock<HashMap<DeviceModel, Capabilities>> = LazyLock::new(|| {
+    HashMap::from([
+        (DeviceModel::Magnova206, Capabilities {
+            max_channels: 2,
+            max_memory_depth: 25_000_000,
+            max_sample_rate_hz: 1e9,
+            has_mso: false,
+            has_awg: false,
+        }),
+        (DeviceModel::Magnova212, Capabilities {
+            max_channels: 2,
+            max_memory_depth: 50_000_000,
+            max_sample_rate_hz: 1.6e9,
+            has_mso: true,
+            has_awg: false,
+        }),
+        (DeviceModel::Magnova254, Capabilities {
+            max_channels: 4,
+            max_memory_depth: 50_000_000,
+            max_sample_rate_hz: 1.6e9,
+            has_mso: true,
+            has_awg: true,
+        }),
+        (DeviceModel::Magnova, Capabilities {
+            max_channels: 4,
+            max_memory_depth: 50_000_000,
+            max_sample_rate_hz: 1.6e9,
+            has_mso: true,
+            has_awg: false,
+        }),
+    ])
+});
+
+impl DeviceModel {
+    /// The model named in the model field of `*IDN?`, e.g. `Magnova 254`,
+    /// `MAGNOVA-212` or `Magnova`.
+    pub fn from_identity(identity: &Identity) -> Self {
+        let name: String = identity.model.chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        let Some(variant) = name.strip_prefix("magnova") else {
+            return DeviceModel::Other(identity.model.clone());
+        };
+        match variant {
+            "206" => DeviceModel::Magnova206,
+            "212" => DeviceModel::Magnova212,
+            "254" => DeviceModel::Magnova254,
+            _ => {
+                if variant.starts_with(|c: char| c.is_ascii_digit()) {
+                    warn!("Unknown Magnova variant {}, assuming the base model", identity.model);
+                }
+                DeviceModel::Magnova
+            }
+        }
+    }
+
+    /// Capabilities from the built-in registry, `None` for instruments
+    /// outside the Magnova family.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        REGISTRY.get(self).copied()
+    }
+}
+
+impl fmt::Display for DeviceModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceModel::Magnova206 => f.write_str("Magnova 206"),
+            DeviceModel::Magnova212 => f.write_str("Magnova 212"),
+            DeviceModel::Magnova254 => f.write_str("Magnova 254"),
+            DeviceModel::Magnova => f.write_str("Magnova"),
+            DeviceModel::Other(model) => f.write_str(model),
+        }
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Identify the connected model from its `*IDN?` reply.
+    pub fn identify_model(&self) -> Result<DeviceModel> {
+        let model = DeviceModel::from_identity(&self.identity()?);
+        info!("Connected to a {}", model);
+        Ok(model)
+    }
+
+    /// Capabilities of the connected model, `None` if it is not a Magnova.
+    pub fn capabilities(&self) -> Result<Option<Capabilities>> {
+        Ok(DeviceModel::from_identity(&self.identity()?).capabilities())
+    }
+
+    /// Fail with `ScopeError::Unsupported` if the connected model is known
+    /// to lack `feature`, as `has_feature` tells from its capabilities.
+    pub(crate) fn require_capability(&self, feature: &str, has_feature: impl FnOnce(&Capabilities) -> bool)
+        -> Result<()> {
+        let model = DeviceModel::from_identity(&self.identity()?);
+        match model.capabilities() {
+            Some(capabilities) if !has_feature(&capabilities) => {
+                Err(ScopeError::Unsupported { model: model.to_string(), feature: feature.to_string() })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject channel numbers outside of 1..=CHANNEL_COUNT, and with
+    /// `ScopeError::Unsupported` channels the connected model lacks.
+    pub(crate) fn require_channel(&self, channel: u8) -> Result<()> {
+        check_channel(channel)?;
+        self.require_capability(&format!("channel {}", channel), |capabilities| {
+            channel <= capabilities.max_channels
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+
+    fn model(idn: &str) -> DeviceModel {
+        DeviceModel::from_identity(&Identity::parse(idn))
+    }
+
+    #[test]
+    fn identifies_models() {
+        assert_eq!(model("Batronix,Magnova 254,1234,1.0"), DeviceModel::Magnova254);
+        assert_eq!(model("Batronix,MAGNOVA-212,1234,1.0"), DeviceModel::Magnova212);
+        assert_eq!(model("Batronix,Magnova206,1234,1.0"), DeviceModel::Magnova206);
+        assert_eq!(model("Batronix,Magnova 999,1234,1.0"), DeviceModel::Magnova);
+        assert_eq!(model("Rigol,DS1054Z,1234,1.0"), DeviceModel::Other("DS1054Z".to_string()));
+        assert_eq!(model("Batronix,Magnova 212,1,1").to_string(), "Magnova 212");
+
+        assert_eq!(DeviceModel::Magnova206.capabilities().unwrap().max_channels, 2);
+        assert!(DeviceModel::Magnova254.capabilities().unwrap().has_awg);
+        assert_eq!(DeviceModel::Other("DS1054Z".to_string()).capabilities(), None);
+
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        assert_eq!(scope.identify_model().unwrap(), DeviceModel::Magnova);
+        assert!(scope.require_capability("digital pods", |capabilities| capabilities.has_mso).is_ok());
+        assert!(matches!(scope.require_capability("a waveform generator", |capabilities| capabilities.has_awg),
+            Err(ScopeError::Unsupported { .. })));
+    }
+
+    #[test]
+    fn rejects_channels_beyond_the_model() {
+        let config = SimulationConfig { model: "Magnova 206", ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        assert!(scope.require_channel(2).is_ok());
+        assert!(matches!(scope.set_vertical_scale(3, 0.1), Err(ScopeError::Unsupported { .. })));
+        assert!(matches!(scope.set_vertical_offset(4, 0.0), Err(ScopeError::Unsupported { .. })));
+        assert!(matches!(scope.require_channel(5), Err(ScopeError::InvalidChannel(5))));
+        // Nothing was sent for the missing channel
+        assert_eq!(scope.vertical_scale(3).unwrap(), 0.5);
+
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        assert_eq!(scope.set_vertical_scale(4, 0.1).unwrap(), 0.1);
+    }
+}
@@ -0,0 +1,386 @@
+//! Captures from several instruments in one go.
+//!
+//! Every instrument is opened with its own [`OscilloscopeWaveform`], which
+//! owns its own VISA resource manager. VISA reference counts the default
+//! resource manager, so separate handles cost nothing, and sessions stay
+//! independent: no lock is shared between the reading threads, and closing
+//! one instrument cannot affect the others.
+
+use std::sync::Barrier;
+use std::thread;
+
+use log::{info, warn};
+use visa_rs::DefaultRM;
+
+use crate::device::no_progress;
+use crate::waveform::pack_query;
+use crate::{
+    check_channel, discover_devices, DataRange, DeviceSelector, DiscoveredDevice, OscilloscopeWaveform, Result,
+    ScopeError, Timeouts, TransferFormat,
+};
+
+/// An instrument that failed an operation.
+#[derive(Debug)]
+pub struct DeviceError {
+    /// Label of the instrument, or the selector it was opened with.
+    pub device: String,
+    pub error: ScopeError,
+}
+
+/// Per-instrument results of a [`MultiScope`] operation. A failing
+/// instrument does not abort the others, so both lists can be non-empty.
+#[derive(Debug)]
+pub struct MultiReadout<T> {
+    /// Results by instrument label, in the order of the instruments.
+    pub results: Vec<(String, T)>,
+    pub errors: Vec<DeviceError>,
+}
+
+impl<T> Default for MultiReadout<T> {
+    fn default() -> Self {
+        Self { results: Vec::new(), errors: Vec::new() }
+    }
+}
+
+impl<T> MultiReadout<T> {
+    /// True if every instrument succeeded.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Text identifying a selector in labels and error reports.
+fn describe(selector: &DeviceSelector) -> String {
+    match selector {
+        DeviceSelector::Auto => "auto".to_string(),
+        DeviceSelector::Address(value) | DeviceSelector::Serial(value) | DeviceSelector::Resource(value) => {
+            value.clone()
+        }
+    }
+}
+
+/// Several instruments, each tagged with a label for its outputs.
+pub struct MultiScope {
+    scopes: Vec<(String, OscilloscopeWaveform)>,
+}
+
+/// [`MultiScope`] under the name used for synchronised multi-instrument
+/// setups.
+pub type InstrumentManager = MultiScope;
+
+impl MultiScope {
+    /// Open every selected instrument, labelled with its serial number.
+    ///
+    /// Instruments are opened one after the other, since selecting by
+    /// serial number queries every connected instrument. Those that fail
+    /// to open are returned as errors and left out.
+    pub fn open(selectors: &[DeviceSelector]) -> (Self, Vec<DeviceError>) {
+        Self::open_with_timeouts(selectors, Timeouts::default())
+    }
+
+    /// Open every selected instrument with the given timeouts, see
+    /// [`open`](Self::open).
+    pub fn open_with_timeouts(selectors: &[DeviceSelector], timeouts: Timeouts) -> (Self, Vec<DeviceError>) {
+        let mut scopes = Vec::new();
+        let mut errors = Vec::new();
+        for selector in selectors {
+            match OscilloscopeWaveform::open_with_timeouts(selector, timeouts) {
+                Ok(scope) => {
+                    let label = scope.identity().ok()
+                        .map(|identity| identity.serial)
+                        .filter(|serial| !serial.is_empty())
+                        .unwrap_or_else(|| describe(selector));
+                    scopes.push((label, scope));
+                }
+                Err(error) => {
+                    warn!("Could not open {}: {}", describe(selector), error);
+                    errors.push(DeviceError { device: describe(selector), error });
+                }
+            }
+        }
+        (Self { scopes }, errors)
+    }
+
+    /// Open every Batronix instrument found, see
+    /// [`discover_all_with_timeouts`](Self::discover_all_with_timeouts).
+    pub fn discover_all() -> Result<(Self, Vec<DeviceError>)> {
+        Self::discover_all_with_timeouts(Timeouts::default())
+    }
+
+    /// Open every Batronix instrument found with the given timeouts,
+    /// labelled with its serial number.
+    ///
+    /// Fails with `NoDeviceFound` if there is none. Instruments that are
+    /// found but fail to open are returned as errors and left out.
+    pub fn discover_all_with_timeouts(timeouts: Timeouts) -> Result<(Self, Vec<DeviceError>)> {
+        let rm = DefaultRM::new()?;
+        let selectors: Vec<DeviceSelector> = discover_devices(&rm, timeouts.discovery)?.into_iter()
+            .filter(DiscoveredDevice::is_batronix)
+            .map(|device| DeviceSelector::Resource(device.resource))
+            .collect();
+        if selectors.is_empty() {
+            return Err(ScopeError::NoDeviceFound);
+        }
+        info!("Found {} Batronix instruments", selectors.len());
+        Ok(Self::open_with_timeouts(&selectors, timeouts))
+    }
+
+    /// Combine already opened instruments with their labels.
+    pub fn from_scopes(scopes: Vec<(String, OscilloscopeWaveform)>) -> Self {
+        Self { scopes }
+    }
+
+    /// Labels of the instruments, in order.
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.scopes.iter().map(|(label, _)| label.as_str())
+    }
+
+    /// The instrument labelled `label`.
+    pub fn scope(&self, label: &str) -> Option<&OscilloscopeWaveform> {
+        self.scopes.iter().find(|(l, _)| l == label).map(|(_, scope)| scope)
+    }
+
+    /// The instruments with their labels, e.g. to configure them.
+    pub fn scopes_mut(&mut self) -> impl Iterator<Item = (&str, &mut OscilloscopeWaveform)> {
+        self.scopes.iter_mut().map(|(label, scope)| (label.as_str(), scope))
+    }
+
+    /// Capture `channel` on every instrument and return the time and
+    /// sample values per instrument.
+    ///
+    /// All instruments are armed first, back to back, so their
+    /// acquisitions start close together. They are not triggered in sync
+    /// though. Then each is waited for and read out on its own thread.
+    pub fn capture(&mut self, channel: u8, data_transfer_type: TransferFormat, memory_depth: Option<u32>)
+        -> MultiReadout<(Vec<f32>, Vec<f32>)> {
+        let mut readout = MultiReadout::default();
+        if check_channel(channel).is_err() {
+            for (label, _) in &self.scopes {
+                readout.errors.push(DeviceError { device: label.clone(), error: ScopeError::InvalidChannel(channel) });
+            }
+            return readout;
+        }
+
+        info!("Arming {} instruments", self.scopes.len());
+        let armed: Vec<_> = self.scopes.iter_mut()
+            .filter_map(|(label, scope)| {
+                let arm = || -> Result<(u32, u32)> {
+                    let sequences = scope.acquisition_mode()?.sequences();
+                    Ok((sequences, scope.arm_capture(&[channel], data_transfer_type, memory_depth)?))
+                };
+                match arm() {
+                    Ok((sequences, depth)) => Some((label.as_str(), scope, sequences, depth)),
+                    Err(error) => {
+                        warn!("Could not arm {}: {}", label, error);
+                        readout.errors.push(DeviceError { device: label.clone(), error });
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let data_cmd = pack_query(channel, DataRange::All, data_transfer_type);
+        let results: Vec<_> = thread::scope(|threads| {
+            let handles: Vec<_> = armed.into_iter()
+                .map(|(label, scope, sequences, depth)| {
+                    let data_cmd = &data_cmd;
+                    let handle = threads.spawn(move || {
+                        scope.wait_for_sequence(sequences)?;
+                        let scaling = scope.channel_scaling(channel)?;
+                        scope.read_waveform(data_cmd, data_transfer_type, Some(depth), scaling, &no_progress)
+                    });
+                    (label, handle)
+                })
+                .collect();
+            handles.into_iter()
+                .map(|(label, handle)| {
+                    let result = handle.join().unwrap_or_else(|_| {
+                        Err(ScopeError::InvalidArgument(format!("Readout thread of {} panicked", label)))
+                    });
+                    (label.to_string(), result)
+                })
+                .collect()
+        });
+
+        for (label, result) in results {
+            match result {
+                Ok(capture) => readout.results.push((label, capture)),
+                Err(error) => {
+                    warn!("Capture from {} failed: {}", label, error);
+                    readout.errors.push(DeviceError { device: label, error });
+                }
+            }
+        }
+        readout
+    }
+
+    /// Arm a single acquisition on every instrument at the same moment.
+    ///
+    /// Every instrument gets its own thread, and the threads send
+    /// `SINGle` together once all are ready, so the instruments are armed
+    /// within the scheduling jitter of the host rather than one after the
+    /// other.
+    pub fn synchronise_trigger(&mut self) -> MultiReadout<()> {
+        info!("Arming {} instruments together", self.scopes.len());
+        self.in_lockstep(|_| Ok(()), |scope, ()| scope.arm_single())
+    }
+
+    /// Capture `channel` from a single acquisition on every instrument,
+    /// armed together as by [`synchronise_trigger`](Self::synchronise_trigger).
+    ///
+    /// Each instrument waits up to its own wait timeout for a trigger, see
+    /// [`capture_single`](OscilloscopeWaveform::capture_single).
+    pub fn acquire_all(&mut self, channel: u8, data_transfer_type: TransferFormat)
+        -> MultiReadout<(Vec<f32>, Vec<f32>)> {
+        self.in_lockstep(
+            |scope| scope.prepare_single(channel, data_transfer_type),
+            |scope, ()| {
+                scope.arm_single()?;
+                let (_, time_values, waveform) =
+                    scope.complete_single(channel, data_transfer_type, scope.wait_timeout, None)?;
+                Ok((time_values, waveform))
+            },
+        )
+    }
+
+    /// Run `prepare` and then `fire` on every instrument, each on its own
+    /// thread. No instrument starts `fire` before all have finished
+    /// `prepare`. Instruments whose `prepare` failed skip `fire`.
+    fn in_lockstep<P, T: Send>(&mut self, prepare: impl Fn(&mut OscilloscopeWaveform) -> Result<P> + Sync,
+        fire: impl Fn(&mut OscilloscopeWaveform, P) -> Result<T> + Sync) -> MultiReadout<T> {
+        let barrier = Barrier::new(self.scopes.len());
+        let results: Vec<(String, Result<T>)> = thread::scope(|threads| {
+            let handles: Vec<_> = self.scopes.iter_mut()
+                .map(|(label, scope)| {
+                    let (barrier, prepare, fire) = (&barrier, &prepare, &fire);
+                    let handle = threads.spawn(move || {
+                        let prepared = prepare(scope);
+                        barrier.wait();
+                        fire(scope, prepared?)
+                    });
+                    (label.as_str(), handle)
+                })
+                .collect();
+            handles.into_iter()
+                .map(|(label, handle)| {
+                    let result = handle.join().unwrap_or_else(|_| {
+                        Err(ScopeError::InvalidArgument(format!("Thread of {} panicked", label)))
+                    });
+                    (label.to_string(), result)
+                })
+                .collect()
+        });
+
+        let mut readout = MultiReadout::default();
+        for (label, result) in results {
+            match result {
+                Ok(value) => readout.results.push((label, value)),
+                Err(error) => {
+                    warn!("{} failed: {}", label, error);
+                    readout.errors.push(DeviceError { device: label, error });
+                }
+            }
+        }
+        readout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::acquisition::TriggerStatus;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+    use crate::transport::Transport;
+
+    /// An instrument that never answers.
+    struct Silent;
+
+    impl Transport for Silent {
+        fn send(&self, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn receive(&self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::TimedOut.into())
+        }
+
+        fn clear(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn timeout(&self) -> Result<Duration> {
+            Ok(Duration::from_secs(1))
+        }
+
+        fn set_timeout(&self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn simulated(amplitude_v: f64) -> OscilloscopeWaveform {
+        let config = SimulationConfig { amplitude_v, noise_v: 0.0, ..SimulationConfig::default() };
+        OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)))
+    }
+
+    #[test]
+    fn arms_and_acquires_together() {
+        let untriggered = SimulationConfig { triggered: false, ..SimulationConfig::default() };
+        let untriggered = || OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(untriggered)));
+        let mut manager = InstrumentManager::from_scopes(vec![
+            ("SN1".to_string(), untriggered()),
+            ("SN2".to_string(), untriggered()),
+        ]);
+        assert!(manager.synchronise_trigger().is_complete());
+        for label in ["SN1", "SN2"] {
+            assert_eq!(manager.scope(label).unwrap().trigger_status().unwrap(), TriggerStatus::Ready);
+        }
+
+        let mut manager = InstrumentManager::from_scopes(vec![
+            ("SN1".to_string(), simulated(1.0)),
+            ("SN2".to_string(), simulated(2.0)),
+            ("SN3".to_string(), OscilloscopeWaveform::with_transport(Box::new(Silent))),
+        ]);
+        let readout = manager.acquire_all(2, TransferFormat::Volts);
+        assert_eq!(readout.errors.len(), 1);
+        assert_eq!(readout.errors[0].device, "SN3");
+        let labels: Vec<&str> = readout.results.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(labels, ["SN1", "SN2"]);
+        for ((_, (time, waveform)), amplitude) in readout.results.iter().zip([1.0, 2.0]) {
+            assert_eq!(time.len(), 10_000);
+            let max = waveform.iter().copied().fold(f32::MIN, f32::max);
+            assert!((max - amplitude).abs() < 0.01, "peaks at {} V", max);
+        }
+        assert!(manager.acquire_all(9, TransferFormat::Raw).results.is_empty());
+    }
+
+    #[test]
+    fn captures_all_instruments_despite_failures() {
+        let mut multi = MultiScope::from_scopes(vec![
+            ("SN1".to_string(), simulated(1.0)),
+            ("SN2".to_string(), OscilloscopeWaveform::with_transport(Box::new(Silent))),
+            ("SN3".to_string(), simulated(2.0)),
+        ]);
+        assert_eq!(multi.labels().collect::<Vec<_>>(), ["SN1", "SN2", "SN3"]);
+
+        let readout = multi.capture(1, TransferFormat::Raw, Some(1_000));
+        assert!(!readout.is_complete());
+        assert_eq!(readout.errors.len(), 1);
+        assert_eq!(readout.errors[0].device, "SN2");
+        assert!(matches!(readout.errors[0].error, ScopeError::Timeout));
+
+        assert_eq!(readout.results.len(), 2);
+        for ((label, (time, waveform)), amplitude) in readout.results.iter().zip([1.0, 2.0]) {
+            assert_eq!(time.len(), 1_000, "{}", label);
+            let max = waveform.iter().copied().fold(f32::MIN, f32::max);
+            assert!((max - amplitude).abs() < 0.01, "{} peaks at {} V", label, max);
+        }
+        assert_eq!(readout.results[1].0, "SN3");
+
+        let invalid = multi.capture(9, TransferFormat::Raw, None);
+        assert_eq!(invalid.errors.len(), 3);
+        assert!(invalid.results.is_empty());
+    }
+}
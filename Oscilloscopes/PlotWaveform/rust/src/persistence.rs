@@ -0,0 +1,204 @@
+//! Color-graded persistence plots of repeated acquisitions.
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+
+/// Color scale from rarely to often hit cells.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Colormap {
+    #[default]
+    Viridis,
+    Inferno,
+    Plasma,
+    Grayscale,
+    /// Black through red and yellow to white.
+    Hot,
+}
+
+impl Colormap {
+    /// Color stops evenly spread from 0 to 1.
+    fn stops(self) -> &'static [(u8, u8, u8)] {
+        match self {
+            Colormap::Viridis => &[(68, 1, 84), (59, 82, 139), (33, 145, 140), (94, 201, 98), (253, 231, 37)],
+            Colormap::Inferno => &[(0, 0, 4), (66, 10, 104), (147, 38, 103), (221, 81, 58), (252, 165, 10),
+                (252, 255, 164)],
+            Colormap::Plasma => &[(13, 8, 135), (106, 0, 168), (177, 42, 144), (225, 100, 98), (252, 166, 54),
+                (240, 249, 33)],
+            Colormap::Grayscale => &[(0, 0, 0), (255, 255, 255)],
+            Colormap::Hot => &[(0, 0, 0), (230, 0, 0), (255, 210, 0), (255, 255, 255)],
+        }
+    }
+
+    /// Color of `value` between 0 and 1, interpolated between the stops.
+    pub fn color(self, value: f64) -> RGBColor {
+        let stops = self.stops();
+        let position = value.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+        let index = (position as usize).min(stops.len() - 2);
+        let fraction = position - index as f64;
+        let (from, to) = (stops[index], stops[index + 1]);
+        let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * fraction).round() as u8;
+        RGBColor(channel(from.0, to.0), channel(from.1, to.1), channel(from.2, to.2))
+    }
+}
+
+/// Hit counts of many waveforms on a grid of cells, like the persistence
+/// display of a digital phosphor oscilloscope.
+#[derive(Debug, Clone)]
+pub struct PersistenceAccumulator {
+    width: u32,
+    height: u32,
+    x_range: (f32, f32),
+    y_range: (f32, f32),
+    /// Hit counts row by row, the first row at the bottom of `y_range`.
+    hits: Vec<u32>,
+    waveform_count: usize,
+}
+
+impl PersistenceAccumulator {
+    /// A grid of `width` by `height` cells spanning `x_range` in seconds
+    /// and `y_range` in volts.
+    pub fn new(width: u32, height: u32, x_range: (f32, f32), y_range: (f32, f32)) -> Self {
+        let (width, height) = (width.max(1), height.max(1));
+        Self { width, height, x_range, y_range, hits: vec![0; (width * height) as usize], waveform_count: 0 }
+    }
+
+    /// Number of waveforms added so far.
+    pub fn waveform_count(&self) -> usize {
+        self.waveform_count
+    }
+
+    /// Hits of the cell in `column`, counted from the left, and `row`,
+    /// counted from the bottom.
+    pub fn hits(&self, column: u32, row: u32) -> u32 {
+        self.hits[(row * self.width + column) as usize]
+    }
+
+    /// Cell index of a value within `range`, or `None` outside of it.
+    fn cell(value: f32, range: (f32, f32), cells: u32) -> Option<u32> {
+        let fraction = (value - range.0) / (range.1 - range.0);
+        (0.0..=1.0).contains(&fraction).then(|| ((fraction * cells as f32) as u32).min(cells - 1))
+    }
+
+    /// Add one waveform.
+    ///
+    /// Every sample hits its cell. Between consecutive samples, the cells
+    /// in between are filled vertically, so steep edges stay connected.
+    /// Samples outside the grid are skipped and break the trace. The cost
+    /// is linear in the number of samples and edge heights.
+    pub fn add_waveform(&mut self, time: &[f32], waveform: &[f32]) {
+        let mut previous_row: Option<u32> = None;
+        for (&t, &v) in time.iter().zip(waveform) {
+            let (Some(column), Some(row)) = (
+                Self::cell(t, self.x_range, self.width),
+                Self::cell(v, self.y_range, self.height),
+            ) else {
+                previous_row = None;
+                continue;
+            };
+            // Fill from next to the previous cell up to this one
+            let rows = match previous_row {
+                Some(previous) if previous < row => previous + 1..=row,
+                Some(previous) if previous > row => row..=previous - 1,
+                _ => row..=row,
+            };
+            for row in rows {
+                let hits = &mut self.hits[(row * self.width + column) as usize];
+                *hits = hits.saturating_add(1);
+            }
+            previous_row = Some(row);
+        }
+        self.waveform_count += 1;
+    }
+
+    /// Clear all hits.
+    pub fn reset(&mut self) {
+        self.hits.fill(0);
+        self.waveform_count = 0;
+    }
+
+    /// Render the hit counts as a heat map PNG on a logarithmic scale, so
+    /// rarely hit cells stay visible. Cells without hits are black.
+    pub fn plot(&self, output_path: &str, colormap: Colormap) -> Result<()> {
+        let max_hits = self.hits.iter().copied().max().unwrap_or(0);
+        if max_hits == 0 {
+            return Err(anyhow!("Persistence plot is empty"));
+        }
+
+        info!("Creating persistence plot of {} waveforms", self.waveform_count);
+        let root = BitMapBackend::new(output_path, (1200, 600)).into_drawing_area();
+        root.fill(&BLACK)?;
+
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("Persistence, {} waveforms", self.waveform_count),
+                ("sans-serif", 40).into_font().color(&WHITE))
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(60)
+            .build_cartesian_2d(self.x_range.0..self.x_range.1, self.y_range.0..self.y_range.1)?;
+
+        chart
+            .configure_mesh()
+            .disable_mesh()
+            .axis_style(WHITE)
+            .label_style(("sans-serif", 15).into_font().color(&WHITE))
+            .x_desc("Time (s)")
+            .y_desc("Voltage (V)")
+            .draw()?;
+
+        let x_step = (self.x_range.1 - self.x_range.0) / self.width as f32;
+        let y_step = (self.y_range.1 - self.y_range.0) / self.height as f32;
+        let log_max = (max_hits as f64).ln_1p();
+        chart.draw_series(self.hits.iter().enumerate().filter(|(_, &hits)| hits > 0).map(|(cell, &hits)| {
+            let column = (cell as u32 % self.width) as f32;
+            let row = (cell as u32 / self.width) as f32;
+            let x = self.x_range.0 + column * x_step;
+            let y = self.y_range.0 + row * y_step;
+            let color = colormap.color((hits as f64).ln_1p() / log_max);
+            Rectangle::new([(x, y), (x + x_step, y + y_step)], color.filled())
+        }))?;
+
+        root.present()?;
+        info!("Persistence plot saved as {}", output_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulates_hits_and_connects_edges() {
+        let mut persistence = PersistenceAccumulator::new(4, 10, (0.0, 4.0), (0.0, 10.0));
+        // Flat at 1.5 V, then a step to 8.5 V in the third column
+        let time = [0.5, 1.5, 2.5, 3.5];
+        let waveform = [1.5, 1.5, 8.5, 8.5];
+        persistence.add_waveform(&time, &waveform);
+        persistence.add_waveform(&time, &waveform);
+        assert_eq!(persistence.waveform_count(), 2);
+
+        assert_eq!(persistence.hits(0, 1), 2);
+        assert_eq!(persistence.hits(1, 1), 2);
+        // The edge fills rows 2 to 8 of the third column once per waveform
+        assert!((2..=8).all(|row| persistence.hits(2, row) == 2));
+        assert_eq!(persistence.hits(2, 1), 0);
+        assert_eq!(persistence.hits(3, 8), 2);
+        assert_eq!(persistence.hits.iter().sum::<u32>(), 2 * 10);
+
+        // Samples outside the grid are skipped
+        persistence.reset();
+        persistence.add_waveform(&[-1.0, 0.5, 5.0], &[1.0, 20.0, 1.0]);
+        assert!(persistence.hits.iter().all(|&hits| hits == 0));
+    }
+
+    #[test]
+    fn interpolates_colormaps() {
+        assert_eq!(Colormap::Grayscale.color(0.0), RGBColor(0, 0, 0));
+        assert_eq!(Colormap::Grayscale.color(0.5), RGBColor(128, 128, 128));
+        assert_eq!(Colormap::Viridis.color(1.0), RGBColor(253, 231, 37));
+        assert_eq!(Colormap::Inferno.color(2.0), RGBColor(252, 255, 164));
+        assert_eq!(Colormap::Plasma.color(0.0), RGBColor(13, 8, 135));
+        assert_eq!(Colormap::Hot.color(1.0), RGBColor(255, 255, 255));
+    }
+}
@@ -0,0 +1,1111 @@
+//! Waveform plots as PNG, SVG or interactive HTML.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::{info, warn};
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+
+use crate::capture_sink::format_timestamp;
+use crate::segments::Segment;
+use crate::units::format_si;
+use crate::{OscilloscopeWaveform, ScopeError, TimeReference, WaveformMetadata};
+
+/// Default number of points embedded into HTML plots.
+const DEFAULT_HTML_POINTS: usize = 20_000;
+
+/// Waveforms longer than this are decimated before drawing.
+const DECIMATION_THRESHOLD: usize = 10_000;
+
+/// Vertical distance between stacked digital traces. A trace spans 1, the
+/// rest is the gap to the next one.
+const DIGITAL_TRACE_PITCH: f32 = 1.5;
+
+/// XY plots up to this many points are drawn as a connected line. Denser
+/// ones are drawn as translucent points, which stay legible where a line
+/// would fill the whole figure and show where the trace dwells.
+const XY_LINE_MAX_POINTS: usize = 5_000;
+
+/// XY plots with more points than this keep only every n-th sample.
+const XY_MAX_POINTS: usize = 200_000;
+
+/// Output format of a waveform plot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotFormat {
+    Png,
+    Svg,
+    /// Interactive page using plotly.js. The waveform is decimated to at
+    /// most `max_points` points so browsers stay responsive.
+    Html { max_points: usize },
+}
+
+/// How [`OscilloscopeWaveform::plot_segments`] arranges the segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentPlotMode {
+    /// All segments in one chart, drawn semi-transparently so frequent
+    /// shapes stand out like on a persistence display.
+    Overlay,
+    /// One small chart per segment in a grid.
+    Tile,
+}
+
+/// Background of the warning banner above a waveform chart.
+const WARNING_BACKGROUND: RGBColor = RGBColor(255, 220, 220);
+
+/// Color of traces computed from channels rather than captured directly.
+const MATH_COLOR: RGBColor = RGBColor(170, 0, 170);
+
+/// Color of the dashed line marking the trigger point.
+const TRIGGER_MARKER_COLOR: RGBColor = RGBColor(255, 140, 0);
+
+/// Optional text and markers of a waveform chart drawn by [`draw_chart`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ChartDecorations<'a> {
+    pub subtitle: Option<&'a str>,
+    pub warning: Option<&'a str>,
+    pub trace_label: Option<&'a str>,
+    /// Draw a dashed vertical line at t = 0, the trigger point, if the time
+    /// axis includes it.
+    pub trigger_marker: bool,
+}
+
+/// Where and how a waveform plot is written.
+#[derive(Debug, Clone)]
+pub struct PlotOptions {
+    pub path: String,
+    pub format: PlotFormat,
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+    /// Second line under the title, e.g. [`PlotAnnotation::caption`].
+    /// Only waveform plots show it.
+    pub subtitle: Option<String>,
+    /// Banner in red above the chart, e.g. for a clipped capture. Only
+    /// PNG and SVG waveform plots show it.
+    pub warning: Option<String>,
+    /// Legend entry for a computed trace, e.g. `"MATH CH1-CH2"`. Labelled
+    /// traces are drawn in purple instead of blue, so they are not mistaken
+    /// for a channel.
+    pub trace_label: Option<String>,
+}
+
+impl Default for PlotOptions {
+    fn default() -> Self {
+        Self {
+            path: "waveform.png".to_string(),
+            format: PlotFormat::Png,
+            width: 1200,
+            height: 600,
+            title: "Oscilloscope Waveform".to_string(),
+            subtitle: None,
+            warning: None,
+            trace_label: None,
+        }
+    }
+}
+
+/// Capture settings shown under the title of a waveform plot, so the chart
+/// still tells how it was made. Fields left `None` are skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlotAnnotation {
+    pub channel: Option<u8>,
+    /// Sample rate and record length are taken from here.
+    pub metadata: Option<WaveformMetadata>,
+    pub volts_per_div: Option<f64>,
+    /// Capture time in seconds since the Unix epoch.
+    pub captured_at_unix_s: Option<f64>,
+    /// Serial number of the instrument.
+    pub serial: Option<String>,
+}
+
+impl PlotAnnotation {
+    /// One line such as `CH1 | 1 GSa/s | 1 Mpts | 500 mV/div | ...`.
+    pub fn caption(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(channel) = self.channel {
+            parts.push(format!("CH{}", channel));
+        }
+        if let Some(metadata) = &self.metadata {
+            if metadata.time_delta > 0.0 {
+                parts.push(format_si(1.0 / metadata.time_delta as f64, "Sa/s"));
+            }
+            parts.push(format_si(metadata.sample_count as f64, "pts"));
+        }
+        if let Some(volts_per_div) = self.volts_per_div {
+            parts.push(format_si(volts_per_div, "V/div"));
+        }
+        if let Some(unix_s) = self.captured_at_unix_s {
+            parts.push(format!("{} UTC", format_timestamp(unix_s as u64)));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("SN {}", serial));
+        }
+        parts.join(" | ")
+    }
+}
+
+/// Image backend and output path of [`plot_waveform_configured`] and
+/// [`plot_traces`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlotBackend {
+    Png(String),
+    Svg(String),
+}
+
+impl PlotBackend {
+    /// File the plot is written to.
+    pub fn path(&self) -> &str {
+        match self {
+            PlotBackend::Png(path) | PlotBackend::Svg(path) => path,
+        }
+    }
+}
+
+/// Size, backend and labels of a chart drawn by [`plot_waveform_configured`]
+/// or [`plot_traces`].
+#[derive(Debug, Clone)]
+pub struct PlotConfig {
+    pub width: u32,
+    pub height: u32,
+    pub backend: PlotBackend,
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+}
+
+impl Default for PlotConfig {
+    fn default() -> Self {
+        Self {
+            width: 1200,
+            height: 600,
+            backend: PlotBackend::Png("waveform.png".to_string()),
+            title: "Oscilloscope Waveform".to_string(),
+            x_label: "Time (s)".to_string(),
+            y_label: "Voltage (V)".to_string(),
+        }
+    }
+}
+
+impl PlotFormat {
+    /// Interactive HTML output with the default point budget.
+    pub fn html() -> Self {
+        PlotFormat::Html { max_points: DEFAULT_HTML_POINTS }
+    }
+}
+
+/// Reduce a waveform to `target_points` points while keeping peaks.
+///
+/// The samples are split into `target_points / 2` windows and the minimum
+/// and maximum of every window are kept in their original order, so short
+/// glitches survive the reduction. The result has exactly two points per
+/// window. Waveforms that already fit are returned unchanged.
+pub fn decimate_waveform(time_values: &[f32], waveform: &[f32], target_points: usize)
+    -> (Vec<f32>, Vec<f32>) {
+    let len = time_values.len().min(waveform.len());
+    let buckets = target_points / 2;
+    if len <= target_points || buckets == 0 {
+        return (time_values[..len].to_vec(), waveform[..len].to_vec());
+    }
+
+    let mut times = Vec::with_capacity(buckets * 2);
+    let mut values = Vec::with_capacity(buckets * 2);
+    for bucket in 0..buckets {
+        let start = bucket * len / buckets;
+        let end = (bucket + 1) * len / buckets;
+        let mut min = start;
+        let mut max = start;
+        for i in start..end {
+            if waveform[i] < waveform[min] {
+                min = i;
+            }
+            if waveform[i] > waveform[max] {
+                max = i;
+            }
+        }
+        for i in [min.min(max), min.max(max)] {
+            times.push(time_values[i]);
+            values.push(waveform[i]);
+        }
+    }
+    (times, values)
+}
+
+/// Keep every `factor`-th sample, starting with the first.
+///
+/// Cheaper than [`decimate_waveform`], but spikes between the kept samples
+/// are lost. A factor of 0 or 1 keeps every sample.
+pub fn decimate_uniform(waveform: &[f32], factor: usize) -> Vec<f32> {
+    waveform.iter().step_by(factor.max(1)).copied().collect()
+}
+
+/// Voltage range of the waveform with 10% padding on either side.
+///
+/// NaN and infinite samples are ignored. A constant waveform gets a range
+/// of 10% of its value, at least 1 mV, to either side, and one without
+/// finite samples the range -1 to 1 V.
+pub fn padded_voltage_range(waveform: &[f32]) -> (f32, f32) {
+    let finite = || waveform.iter().copied().filter(|v| v.is_finite());
+    let min_voltage = finite().fold(f32::INFINITY, f32::min);
+    let max_voltage = finite().fold(f32::NEG_INFINITY, f32::max);
+    if min_voltage > max_voltage {
+        return (-1.0, 1.0);
+    }
+    if min_voltage == max_voltage {
+        let padding = (min_voltage.abs() * 0.1).max(1e-3);
+        return (min_voltage - padding, max_voltage + padding);
+    }
+
+    // Add some padding to the voltage range
+    let voltage_padding = (max_voltage - min_voltage) * 0.1;
+    (min_voltage - voltage_padding, max_voltage + voltage_padding)
+}
+
+/// Time range from the first to the last finite time value. A single time
+/// gets 1 ns to either side, and no time at all the range 0 to 1 s.
+fn time_range(time_values: &[f32]) -> (f32, f32) {
+    let mut finite = time_values.iter().copied().filter(|t| t.is_finite());
+    match (finite.next(), finite.next_back()) {
+        (Some(first), Some(last)) if first < last => (first, last),
+        (Some(first), Some(last)) if first > last => (last, first),
+        (Some(time), _) => (time - 1e-9, time + 1e-9),
+        (None, _) => (0.0, 1.0),
+    }
+}
+
+/// The points whose time and voltage are both finite. The number of
+/// points left out is logged.
+fn finite_points(time_values: &[f32], waveform: &[f32]) -> (Vec<f32>, Vec<f32>) {
+    let len = time_values.len().min(waveform.len());
+    let (times, values): (Vec<f32>, Vec<f32>) = time_values[..len].iter().zip(&waveform[..len])
+        .filter(|(t, v)| t.is_finite() && v.is_finite())
+        .unzip();
+    if times.len() < len {
+        warn!("Left {} NaN or infinite samples out of the plot", len - times.len());
+    }
+    (times, values)
+}
+
+/// Draw a waveform chart. NaN and infinite samples are left out, and a
+/// waveform without any other sample gives an empty chart saying so.
+pub(crate) fn draw_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str,
+    decorations: ChartDecorations, time_values: &[f32], waveform: &[f32]) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let (time_values, waveform) = finite_points(time_values, waveform);
+    let waveform_len = waveform.len();
+    let (min_time, max_time) = time_range(&time_values);
+    let (min_voltage, max_voltage) = padded_voltage_range(&waveform);
+
+    let area = root.titled(title, ("sans-serif", 40))?;
+    let area = match decorations.subtitle {
+        Some(subtitle) => area.titled(subtitle, ("sans-serif", 18))?,
+        None => area,
+    };
+    let area = match decorations.warning {
+        Some(warning) => {
+            let (banner, rest) = area.split_vertically(30);
+            banner.fill(&WARNING_BACKGROUND)?;
+            banner.draw_text(warning, &("sans-serif", 20).into_font().color(&RED), (10, 6))?;
+            rest
+        }
+        None => area,
+    };
+    let mut chart = ChartBuilder::on(&area)
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(70)
+        .build_cartesian_2d(min_time..max_time, min_voltage..max_voltage)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("Time")
+        .y_desc("Voltage")
+        .x_label_formatter(&|time| format_si(*time as f64, "s"))
+        .y_label_formatter(&|voltage| format_si(*voltage as f64, "V"))
+        .draw()?;
+
+    if waveform.is_empty() {
+        let style = TextStyle::from(("sans-serif", 30)).pos(Pos::new(HPos::Center, VPos::Center));
+        let centre = ((min_time + max_time) / 2.0, (min_voltage + max_voltage) / 2.0);
+        chart.plotting_area().draw(&Text::new("No data", centre, style))?;
+        root.present()?;
+        return Ok(());
+    }
+
+    // Beyond one sample per pixel column, drawing every sample only costs
+    // time. Keep the minimum and maximum of each column instead so the line
+    // still spans every spike.
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    let (time_values, waveform) = if waveform_len > DECIMATION_THRESHOLD {
+        let decimated = decimate_waveform(&time_values, &waveform, 2 * columns);
+        info!("Decimated {} samples to {} points for {} pixel columns", waveform_len, decimated.1.len(), columns);
+        decimated
+    } else {
+        (time_values, waveform)
+    };
+
+    if decorations.trigger_marker && min_time <= 0.0 && max_time >= 0.0 {
+        let marker = [(0.0, min_voltage), (0.0, max_voltage)];
+        chart.draw_series(DashedLineSeries::new(marker, 8, 6, TRIGGER_MARKER_COLOR.stroke_width(1)))?;
+    }
+
+    let points = time_values.iter().zip(waveform.iter()).map(|(&x, &y)| (x, y));
+    match decorations.trace_label {
+        Some(label) => {
+            chart.draw_series(LineSeries::new(points, &MATH_COLOR))?
+                .label(label)
+                .legend(|(x, y)| PathElement::new([(x, y), (x + 20, y)], MATH_COLOR));
+            chart.configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()?;
+        }
+        None => {
+            chart.draw_series(LineSeries::new(points, &BLUE))?;
+        }
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+/// Draw several traces into one chart. Traces with a non-empty label get a
+/// legend entry.
+fn draw_traces_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, cfg: &PlotConfig,
+    traces: &[(&[f32], &[f32], &str, RGBColor)]) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let min_time = traces.iter().filter_map(|(t, ..)| t.first()).fold(f32::INFINITY, |a, &b| a.min(b));
+    let max_time = traces.iter().filter_map(|(t, ..)| t.last()).fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let (min_time, max_time) = if min_time < max_time { (min_time, max_time) } else { (0.0, 1.0) };
+    let all_samples: Vec<f32> = traces.iter().flat_map(|(_, v, ..)| v.iter().copied()).collect();
+    let (min_voltage, max_voltage) = padded_voltage_range(&all_samples);
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption(&cfg.title, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(min_time..max_time, min_voltage..max_voltage)?;
+
+    chart
+        .configure_mesh()
+        .x_desc(&cfg.x_label)
+        .y_desc(&cfg.y_label)
+        .draw()?;
+
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    for &(time_values, waveform, label, color) in traces {
+        let (times, values) = if waveform.len() > DECIMATION_THRESHOLD {
+            decimate_waveform(time_values, waveform, 2 * columns)
+        } else {
+            (time_values.to_vec(), waveform.to_vec())
+        };
+        let series = chart.draw_series(LineSeries::new(
+            times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+            &color,
+        ))?;
+        if !label.is_empty() {
+            series.label(label).legend(move |(x, y)| PathElement::new([(x, y), (x + 20, y)], color));
+        }
+    }
+    if traces.iter().any(|(.., label, _)| !label.is_empty()) {
+        chart.configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+fn draw_digital_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str, time_values: &[f32],
+    traces: &[(String, Vec<bool>)]) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let min_time = *time_values.first().unwrap_or(&0.0);
+    let max_time = *time_values.last().unwrap_or(&1.0);
+    let height = traces.len() as f32 * DIGITAL_TRACE_PITCH;
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(min_time..max_time, -0.25f32..height)?;
+
+    chart
+        .configure_mesh()
+        .disable_y_mesh()
+        .y_labels(0)
+        .x_desc("Time (s)")
+        .draw()?;
+
+    let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+    // The first trace is drawn at the top
+    for (row, (name, states)) in traces.iter().enumerate() {
+        let base = (traces.len() - 1 - row) as f32 * DIGITAL_TRACE_PITCH;
+        let levels: Vec<f32> = states.iter().map(|&high| base + high as u8 as f32).collect();
+        // Min/max decimation keeps single-sample glitches visible
+        let (times, levels) = decimate_waveform(time_values, &levels, 2 * columns);
+        let color = Palette99::pick(row);
+        chart.draw_series(LineSeries::new(
+            times.iter().zip(levels.iter()).map(|(&x, &y)| (x, y)),
+            &color,
+        ))?;
+        chart.draw_series(std::iter::once(Text::new(
+            name.clone(),
+            (min_time, base + 0.5),
+            ("sans-serif", 16).into_font().color(&BLACK),
+        )))?;
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+fn draw_xy_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str, x: &[f32], y: &[f32])
+    -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let (min_x, max_x) = padded_voltage_range(x);
+    let (min_y, max_y) = padded_voltage_range(y);
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 40))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(min_x..max_x, min_y..max_y)?;
+
+    chart
+        .configure_mesh()
+        .x_desc("X Voltage (V)")
+        .y_desc("Y Voltage (V)")
+        .draw()?;
+
+    let points = x.iter().zip(y.iter()).map(|(&x, &y)| (x, y));
+    if x.len() <= XY_LINE_MAX_POINTS {
+        chart.draw_series(LineSeries::new(points, &BLUE))?;
+    } else {
+        // The more points, the fainter each one, so overlapping points
+        // blend into a density
+        let color = BLUE.mix((XY_LINE_MAX_POINTS as f64 / x.len() as f64).clamp(0.05, 0.5));
+        chart.draw_series(PointSeries::<_, _, Circle<_, _>, _>::new(points, 1, color.filled()))?;
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+/// Rows and columns of a nearly square grid holding `count` tiles.
+fn tile_grid(count: usize) -> (usize, usize) {
+    let mut columns = 1;
+    while columns * columns < count {
+        columns += 1;
+    }
+    (count.div_ceil(columns).max(1), columns)
+}
+
+fn draw_segments_chart<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, title: &str, segments: &[Segment],
+    mode: SegmentPlotMode) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+
+    let times: Vec<Vec<f32>> = segments.iter().map(Segment::time_values).collect();
+    match mode {
+        SegmentPlotMode::Overlay => {
+            let min_time = times.iter().filter_map(|t| t.first()).fold(f32::INFINITY, |a, &b| a.min(b));
+            let max_time = times.iter().filter_map(|t| t.last()).fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+            let all_samples: Vec<f32> = segments.iter().flat_map(|s| s.samples.iter().copied()).collect();
+            let (min_voltage, max_voltage) = padded_voltage_range(&all_samples);
+
+            let mut chart = ChartBuilder::on(&root)
+                .caption(title, ("sans-serif", 40))
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(60)
+                .build_cartesian_2d(min_time..max_time, min_voltage..max_voltage)?;
+
+            chart
+                .configure_mesh()
+                .x_desc("Time (s)")
+                .y_desc("Voltage (V)")
+                .draw()?;
+
+            // The more segments, the fainter each one, so only repeated
+            // shapes build up to a solid line
+            let color = BLUE.mix((2.0 / segments.len() as f64).clamp(0.05, 0.5));
+            let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+            for (segment, time_values) in segments.iter().zip(&times) {
+                let (times, values) = decimate_waveform(time_values, &segment.samples, 2 * columns);
+                chart.draw_series(LineSeries::new(
+                    times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+                    &color,
+                ))?;
+            }
+        }
+        SegmentPlotMode::Tile => {
+            let root = root.titled(title, ("sans-serif", 40))?;
+            for ((segment, time_values), area) in segments.iter().zip(&times)
+                .zip(root.split_evenly(tile_grid(segments.len()))) {
+                let min_time = *time_values.first().unwrap_or(&0.0);
+                let max_time = *time_values.last().unwrap_or(&1.0);
+                let (min_voltage, max_voltage) = padded_voltage_range(&segment.samples);
+
+                let mut chart = ChartBuilder::on(&area)
+                    .caption(format!("#{} at {:.6} s", segment.index, segment.trigger_time_s), ("sans-serif", 14))
+                    .margin(5)
+                    .x_label_area_size(20)
+                    .y_label_area_size(40)
+                    .build_cartesian_2d(min_time..max_time, min_voltage..max_voltage)?;
+
+                chart
+                    .configure_mesh()
+                    .x_labels(3)
+                    .y_labels(3)
+                    .draw()?;
+
+                let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+                let (times, values) = decimate_waveform(time_values, &segment.samples, 2 * columns);
+                chart.draw_series(LineSeries::new(
+                    times.iter().zip(values.iter()).map(|(&x, &y)| (x, y)),
+                    &BLUE,
+                ))?;
+            }
+        }
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+/// Quote a string as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            // Keep "</script>" in titles from closing the script element
+            '<' => quoted.push_str("\\u003c"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Format samples as a JSON array. Non-finite values become `null`.
+fn json_array(values: &[f32]) -> String {
+    let mut json = String::with_capacity(values.len() * 12 + 2);
+    json.push('[');
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        if value.is_finite() {
+            let _ = write!(json, "{}", value);
+        } else {
+            json.push_str("null");
+        }
+    }
+    json.push(']');
+    json
+}
+
+fn write_html(options: &PlotOptions, time_values: &[f32], waveform: &[f32], max_points: usize,
+    trigger_marker: bool) -> std::io::Result<()> {
+    let (time_values, waveform) = finite_points(time_values, waveform);
+    let (times, values) = decimate_waveform(&time_values, &waveform, max_points);
+    info!("Embedding {} of {} points into HTML plot", times.len(), waveform.len());
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<script src="https://cdn.plot.ly/plotly-2.35.2.min.js"></script>
+</head>
+<body>
+<div id="plot" style="width:{width}px;height:{height}px;"></div>
+<script>
+const title = {title};
+const data = {{"time": {time}, "voltage": {voltage}}};
+document.title = title;
+Plotly.newPlot("plot", [{{
+    x: data.time,
+    y: data.voltage,
+    name: {name},
+    type: "scattergl",
+    mode: "lines",
+    line: {{color: "{color}"}}
+}}], {{
+    title: {{text: title}},
+    showlegend: {show_legend},
+    shapes: {shapes},
+    xaxis: {{title: {{text: "Time (s)"}}}},
+    yaxis: {{title: {{text: "Voltage (V)"}}}}
+}});
+</script>
+</body>
+</html>
+"#,
+        width = options.width,
+        height = options.height,
+        title = json_string(&options.title),
+        name = json_string(options.trace_label.as_deref().unwrap_or("")),
+        color = if options.trace_label.is_some() { "purple" } else { "blue" },
+        show_legend = options.trace_label.is_some(),
+        shapes = if trigger_marker {
+            r#"[{type: "line", x0: 0, x1: 0, yref: "paper", y0: 0, y1: 1, line: {color: "darkorange", dash: "dash"}}]"#
+        } else {
+            "[]"
+        },
+        time = json_array(&times),
+        voltage = json_array(&values),
+    );
+    fs::write(&options.path, html)?;
+    Ok(())
+}
+
+/// Plot a single waveform with the size, backend and axis labels of `cfg`.
+pub fn plot_waveform_configured(time: &[f32], waveform: &[f32], cfg: &PlotConfig) -> Result<()> {
+    plot_traces(&[(time, waveform, "", BLUE)], cfg)
+}
+
+/// Plot several waveforms into one chart, each with its own time axis,
+/// legend label and colour.
+///
+/// An empty label leaves the trace out of the legend.
+pub fn plot_traces(traces: &[(&[f32], &[f32], &str, RGBColor)], cfg: &PlotConfig) -> Result<()> {
+    if traces.is_empty() {
+        return Err(anyhow::anyhow!("Nothing to plot"));
+    }
+
+    let start_time = Instant::now();
+    let size = (cfg.width, cfg.height);
+    match &cfg.backend {
+        PlotBackend::Png(path) => draw_traces_chart(BitMapBackend::new(path, size).into_drawing_area(), cfg, traces)?,
+        PlotBackend::Svg(path) => draw_traces_chart(SVGBackend::new(path, size).into_drawing_area(), cfg, traces)?,
+    }
+
+    info!("Plot render time: {:.3} seconds", start_time.elapsed().as_secs_f32());
+    info!("Plot saved as {}", cfg.backend.path());
+    Ok(())
+}
+
+impl OscilloscopeWaveform {
+    /// Annotation of a capture of `channel` taken just now, with the
+    /// vertical scale and serial number read from the instrument.
+    pub fn plot_annotation(&self, channel: u8, metadata: &WaveformMetadata) -> Result<PlotAnnotation, ScopeError> {
+        let captured_at_unix_s = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs_f64());
+        Ok(PlotAnnotation {
+            channel: Some(channel),
+            metadata: Some(*metadata),
+            volts_per_div: Some(self.vertical_scale(channel)?),
+            captured_at_unix_s: captured_at_unix_s.ok(),
+            serial: Some(self.identity()?.serial).filter(|serial| !serial.is_empty()),
+        })
+    }
+
+    /// Plot a waveform in the format and size given by `options`.
+    ///
+    /// While the time axis is relative to the trigger, see
+    /// [`set_time_reference`](Self::set_time_reference), a dashed line
+    /// marks the trigger point.
+    pub fn plot_waveform(&self, time_values: &[f32], waveform: &[f32], options: &PlotOptions)
+        -> Result<(), ScopeError> {
+        info!("Creating plot");
+        let start_time = Instant::now();
+        let size = (options.width, options.height);
+        let decorations = ChartDecorations {
+            subtitle: options.subtitle.as_deref(),
+            warning: options.warning.as_deref(),
+            trace_label: options.trace_label.as_deref(),
+            trigger_marker: self.time_reference() == TimeReference::Trigger,
+        };
+        match options.format {
+            PlotFormat::Png => {
+                let root = BitMapBackend::new(&options.path, size).into_drawing_area();
+                draw_chart(root, &options.title, decorations, time_values, waveform)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Svg => {
+                let root = SVGBackend::new(&options.path, size).into_drawing_area();
+                draw_chart(root, &options.title, decorations, time_values, waveform)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Html { max_points } => {
+                write_html(options, time_values, waveform, max_points, decorations.trigger_marker)?;
+            }
+        }
+
+        info!("Plot render time: {:.3} seconds", start_time.elapsed().as_secs_f32());
+        info!("Plot saved as {}", options.path);
+        Ok(())
+    }
+
+    /// Plot digital samples as one stacked trace per line.
+    ///
+    /// `lines` selects the bits to draw, e.g. `digital::pod_lines(1)`, the
+    /// first one at the top. Only PNG and SVG output are supported.
+    pub fn plot_digital(&self, time_values: &[f32], samples: &[u16], lines: &[u8], options: &PlotOptions)
+        -> Result<(), ScopeError> {
+        if let Some(&line) = lines.iter().find(|&&line| line >= 16) {
+            return Err(ScopeError::InvalidArgument(format!("Invalid digital line D{}", line)));
+        }
+
+        let traces: Vec<(String, Vec<bool>)> = lines.iter()
+            .map(|&line| (format!("D{}", line), samples.iter().map(|&bits| (bits >> line) & 1 != 0).collect()))
+            .collect();
+        self.plot_digital_channels(time_values, &traces, options)
+    }
+
+    /// Plot named digital traces stacked like on a logic analyser, the
+    /// first one at the top.
+    ///
+    /// Every trace needs a state per time value. Only PNG and SVG output
+    /// are supported.
+    pub fn plot_digital_channels(&self, time_values: &[f32], channels: &[(String, Vec<bool>)],
+        options: &PlotOptions) -> Result<(), ScopeError> {
+        if channels.is_empty() {
+            return Err(ScopeError::InvalidArgument("Nothing to plot".to_string()));
+        }
+        if let Some((name, states)) = channels.iter().find(|(_, states)| states.len() != time_values.len()) {
+            return Err(ScopeError::InvalidArgument(format!(
+                "{} has {} samples for {} time values", name, states.len(), time_values.len()
+            )));
+        }
+
+        info!("Creating digital plot of {} lines", channels.len());
+        let size = (options.width, options.height);
+        match options.format {
+            PlotFormat::Png => {
+                let root = BitMapBackend::new(&options.path, size).into_drawing_area();
+                draw_digital_chart(root, &options.title, time_values, channels)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Svg => {
+                let root = SVGBackend::new(&options.path, size).into_drawing_area();
+                draw_digital_chart(root, &options.title, time_values, channels)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Html { .. } => {
+                return Err(ScopeError::InvalidArgument("Digital plots support PNG and SVG only".to_string()));
+            }
+        }
+        info!("Plot saved as {}", options.path);
+        Ok(())
+    }
+
+    /// Plot the segments of a segmented acquisition, overlaid or tiled.
+    ///
+    /// Only PNG and SVG output are supported.
+    pub fn plot_segments(&self, segments: &[Segment], mode: SegmentPlotMode, options: &PlotOptions)
+        -> Result<(), ScopeError> {
+        if segments.iter().all(|segment| segment.samples.is_empty()) {
+            return Err(ScopeError::InvalidArgument("Nothing to plot".to_string()));
+        }
+
+        info!("Creating {:?} plot of {} segments", mode, segments.len());
+        let size = (options.width, options.height);
+        match options.format {
+            PlotFormat::Png => {
+                let root = BitMapBackend::new(&options.path, size).into_drawing_area();
+                draw_segments_chart(root, &options.title, segments, mode)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Svg => {
+                let root = SVGBackend::new(&options.path, size).into_drawing_area();
+                draw_segments_chart(root, &options.title, segments, mode)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Html { .. } => {
+                return Err(ScopeError::InvalidArgument("Segment plots support PNG and SVG only".to_string()));
+            }
+        }
+        info!("Plot saved as {}", options.path);
+        Ok(())
+    }
+
+    /// Plot `y` against `x`, e.g. two channels for Lissajous figures or
+    /// I/V curves.
+    ///
+    /// The samples must be aligned, i.e. captured from the same trigger as
+    /// by [`get_xy_data`](Self::get_xy_data), and both channels must have
+    /// the same number of samples. The axes span each channel with 10%
+    /// padding. The plot is square, using the smaller of the width and
+    /// height in `options`. Only PNG and SVG output are supported.
+    pub fn plot_xy(&self, x: &[f32], y: &[f32], options: &PlotOptions) -> Result<(), ScopeError> {
+        if x.len() != y.len() {
+            return Err(ScopeError::InvalidArgument(format!(
+                "XY channels have {} and {} samples", x.len(), y.len()
+            )));
+        }
+        let mut len = x.len();
+        if len == 0 {
+            return Err(ScopeError::InvalidArgument("Nothing to plot".to_string()));
+        }
+
+        let factor = len.div_ceil(XY_MAX_POINTS);
+        let (x, y) = if factor > 1 {
+            let decimated = (decimate_uniform(&x[..len], factor), decimate_uniform(&y[..len], factor));
+            info!("Decimated {} samples to {} points", len, decimated.0.len());
+            len = decimated.0.len();
+            decimated
+        } else {
+            (x[..len].to_vec(), y[..len].to_vec())
+        };
+
+        info!("Creating XY plot of {} points", len);
+        let side = options.width.min(options.height);
+        match options.format {
+            PlotFormat::Png => {
+                let root = BitMapBackend::new(&options.path, (side, side)).into_drawing_area();
+                draw_xy_chart(root, &options.title, &x, &y)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Svg => {
+                let root = SVGBackend::new(&options.path, (side, side)).into_drawing_area();
+                draw_xy_chart(root, &options.title, &x, &y)
+                    .map_err(|e| ScopeError::Plot(e.to_string()))?;
+            }
+            PlotFormat::Html { .. } => {
+                return Err(ScopeError::InvalidArgument("XY plots support PNG and SVG only".to_string()));
+            }
+        }
+        info!("Plot saved as {}", options.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_min_and_max_of_every_window() {
+        let time: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let mut waveform = vec![0.0f32; 100];
+        waveform[17] = 5.0;
+        waveform[62] = -3.0;
+        let (times, values) = decimate_waveform(&time, &waveform, 10);
+        assert_eq!(values.len(), 10);
+        assert_eq!(times.len(), 10);
+        // The spikes survive in their windows, in time order
+        assert_eq!(&times[0..2], [0.0, 17.0]);
+        assert_eq!(values[1], 5.0);
+        assert_eq!(&times[6..8], [60.0, 62.0]);
+        assert_eq!(values[7], -3.0);
+    }
+
+    #[test]
+    fn leaves_short_waveforms_unchanged() {
+        let time = [0.0, 1.0, 2.0];
+        let waveform = [1.0, -1.0, 1.0];
+        assert_eq!(decimate_waveform(&time, &waveform, 10), (time.to_vec(), waveform.to_vec()));
+    }
+
+    #[test]
+    fn selects_every_nth_sample() {
+        let waveform = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        assert_eq!(decimate_uniform(&waveform, 3), [0.0, 3.0, 6.0]);
+        assert_eq!(decimate_uniform(&waveform, 0), waveform);
+    }
+
+    #[test]
+    fn arranges_tiles_in_a_nearly_square_grid() {
+        assert_eq!(tile_grid(1), (1, 1));
+        assert_eq!(tile_grid(2), (1, 2));
+        assert_eq!(tile_grid(5), (2, 3));
+        assert_eq!(tile_grid(9), (3, 3));
+        assert_eq!(tile_grid(10), (3, 4));
+    }
+
+    #[test]
+    fn draws_sparse_xy_as_line_and_dense_as_points() {
+        let circle = |n: usize| -> (Vec<f32>, Vec<f32>) {
+            (0..n).map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / n as f32;
+                (phase.cos(), phase.sin())
+            }).unzip()
+        };
+        let render = |x: &[f32], y: &[f32]| {
+            let mut svg = String::new();
+            draw_xy_chart(SVGBackend::with_string(&mut svg, (400, 400)).into_drawing_area(), "XY", x, y).unwrap();
+            svg
+        };
+
+        // Mesh lines are polylines too, but with only two points each
+        let longest_polyline = |svg: &str| svg.split("<polyline").skip(1)
+            .map(|tag| tag.split('"').skip_while(|&part| !part.ends_with("points=")).nth(1)
+                .map_or(0, |points| points.split_whitespace().count()))
+            .max()
+            .unwrap_or(0);
+        let points = |svg: &str| svg.matches("<circle").count();
+
+        let (x, y) = circle(100);
+        let sparse = render(&x, &y);
+        assert!(longest_polyline(&sparse) >= 100);
+        assert_eq!(points(&sparse), 0);
+
+        let (x, y) = circle(XY_LINE_MAX_POINTS + 1);
+        let dense = render(&x, &y);
+        assert!(longest_polyline(&dense) < 100);
+        assert_eq!(points(&dense), XY_LINE_MAX_POINTS + 1);
+        assert!(dense.contains("opacity=\"0.5"), "points are translucent");
+    }
+
+    #[test]
+    fn annotates_capture_settings() {
+        let metadata = WaveformMetadata {
+            time_delta: 1e-9,
+            start_time: -5e-7,
+            end_time: 5e-7,
+            sample_start: 0,
+            sample_length: 1000,
+            vertical_start: -5.0,
+            vertical_step: 10.0,
+            sample_count: 1000,
+        };
+        let annotation = PlotAnnotation {
+            channel: Some(2),
+            metadata: Some(metadata),
+            volts_per_div: Some(0.5),
+            captured_at_unix_s: Some(86_400.0),
+            serial: Some("1234".to_string()),
+        };
+        assert_eq!(annotation.caption(), "CH2 | 1 GSa/s | 1 kpts | 500 mV/div | 1970-01-02T00-00-00 UTC | SN 1234");
+        assert_eq!(PlotAnnotation { channel: Some(1), ..PlotAnnotation::default() }.caption(), "CH1");
+
+        let time: Vec<f32> = (0..1000).map(|i| -5e-7 + i as f32 * 1e-9).collect();
+        let waveform: Vec<f32> = time.iter().map(|t| 0.2 * (t * 1e7).sin()).collect();
+        let mut svg = String::new();
+        let root = SVGBackend::with_string(&mut svg, (800, 400)).into_drawing_area();
+        let decorations = ChartDecorations { subtitle: Some(&annotation.caption()), ..ChartDecorations::default() };
+        draw_chart(root, "Waveform", decorations, &time, &waveform).unwrap();
+        assert!(svg.contains("SN 1234"));
+        // Ticks in ns and mV instead of raw floats
+        assert!(svg.contains("-100 ns"));
+        assert!(svg.contains("50 mV"));
+        assert!(!svg.contains("e-7"));
+    }
+
+    #[test]
+    fn plots_empty_constant_and_nan_waveforms() {
+        let render = |time: &[f32], waveform: &[f32], warning: Option<&str>| {
+            let mut svg = String::new();
+            let root = SVGBackend::with_string(&mut svg, (600, 400)).into_drawing_area();
+            draw_chart(root, "Waveform", ChartDecorations { warning, ..ChartDecorations::default() }, time, waveform)
+                .unwrap();
+            svg
+        };
+
+        assert!(render(&[], &[], None).contains("No data"));
+        assert!(render(&[0.0, 1e-6], &[f32::NAN, f32::INFINITY], None).contains("No data"));
+
+        let time: Vec<f32> = (0..100).map(|i| i as f32 * 1e-6).collect();
+        let constant = render(&time, &[1.5; 100], None);
+        assert!(!constant.contains("No data"));
+        assert!(constant.contains("1.5 V"));
+
+        let mut waveform: Vec<f32> = time.iter().map(|t| (t * 1e5).sin()).collect();
+        waveform[10] = f32::NAN;
+        waveform[20] = f32::NEG_INFINITY;
+        let clipped = render(&time, &waveform, Some("Clipped: 20 samples"));
+        assert!(clipped.contains("Clipped: 20 samples"));
+        assert!(!clipped.contains("NaN") && !clipped.contains("inf"));
+
+        assert_eq!(padded_voltage_range(&[]), (-1.0, 1.0));
+        assert_eq!(padded_voltage_range(&[f32::NAN, 2.0, 2.0]), (1.8, 2.2));
+        assert_eq!(padded_voltage_range(&[0.0]), (-1e-3, 1e-3));
+        assert_eq!(padded_voltage_range(&[0.0, f32::INFINITY, 1.0]), (-0.1, 1.1));
+    }
+
+    #[test]
+    fn labels_math_traces() {
+        let time: Vec<f32> = (0..100).map(|i| i as f32 * 1e-6).collect();
+        let waveform: Vec<f32> = time.iter().map(|t| (t * 1e5).sin()).collect();
+        let render = |label: Option<&str>| {
+            let mut svg = String::new();
+            let root = SVGBackend::with_string(&mut svg, (600, 400)).into_drawing_area();
+            let decorations = ChartDecorations { trace_label: label, ..ChartDecorations::default() };
+            draw_chart(root, "Waveform", decorations, &time, &waveform).unwrap();
+            svg
+        };
+
+        let channel = render(None);
+        assert!(channel.contains("#0000FF"));
+        assert!(!channel.contains("#AA00AA"));
+
+        let math = render(Some("MATH CH1-CH2"));
+        assert!(math.contains("MATH CH1-CH2"));
+        assert!(math.contains("#AA00AA"));
+        assert!(!math.contains("#0000FF"));
+    }
+
+    #[test]
+    fn marks_the_trigger_point() {
+        let render = |start: f32, trigger_marker: bool| {
+            let time: Vec<f32> = (0..100).map(|i| start + i as f32 * 1e-6).collect();
+            let waveform: Vec<f32> = time.iter().map(|t| (t * 1e5).sin()).collect();
+            let mut svg = String::new();
+            let root = SVGBackend::with_string(&mut svg, (600, 400)).into_drawing_area();
+            let decorations = ChartDecorations { trigger_marker, ..ChartDecorations::default() };
+            draw_chart(root, "Waveform", decorations, &time, &waveform).unwrap();
+            svg
+        };
+        assert!(render(-50e-6, true).contains("#FF8C00"));
+        assert!(!render(-50e-6, false).contains("#FF8C00"));
+        // Not on the time axis
+        assert!(!render(10e-6, true).contains("#FF8C00"));
+    }
+
+    #[test]
+    fn plots_several_traces_into_one_svg() {
+        let path = std::env::temp_dir().join(format!("plot_traces_{}.svg", std::process::id()));
+        let time: Vec<f32> = (0..200).map(|i| i as f32 * 1e-6).collect();
+        let sine: Vec<f32> = time.iter().map(|t| (t * 1e5).sin()).collect();
+        let cosine: Vec<f32> = time.iter().map(|t| (t * 1e5).cos()).collect();
+        let cfg = PlotConfig {
+            width: 640,
+            height: 480,
+            backend: PlotBackend::Svg(path.to_string_lossy().into_owned()),
+            title: "IQ signals".to_string(),
+            x_label: "t (s)".to_string(),
+            y_label: "U (V)".to_string(),
+        };
+        plot_traces(&[(&time, &sine, "In-phase", RED), (&time, &cosine, "Quadrature", GREEN)], &cfg).unwrap();
+
+        let svg = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(svg.contains("width=\"640\""));
+        for text in ["IQ signals", "t (s)", "U (V)", "In-phase", "Quadrature", "#FF0000", "#00FF00"] {
+            assert!(svg.contains(text), "{} missing", text);
+        }
+
+        assert!(plot_traces(&[], &cfg).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+//! Named measurement presets.
+//!
+//! A [`MeasurementPreset`] bundles the trigger, vertical, horizontal and
+//! memory settings of a recurring measurement, so a test sequence sets up
+//! the instrument with one [`apply_preset`](OscilloscopeWaveform::apply_preset)
+//! call. Presets are stored as TOML:
+//!
+//! ```toml
+//! name = "power_rail_ripple"
+//! memory_depth = 1000000
+//!
+//! [timebase]
+//! secs_per_div = 2e-5
+//! delay_s = 0.0
+//! reference = "center"   # left, center or right
+//!
+//! [trigger]
+//! source = 1
+//! level_v = 0.0
+//! slope = "rising"       # rising, falling or both
+//! sweep = "auto"         # auto or normal
+//!
+//! [[vertical]]
+//! channel = 1
+//! volts_per_div = 0.02
+//! offset_v = 0.0
+//! coupling = "ac"        # dc, ac or gnd
+//! bandwidth_limit = "20M" # full, 20M or 200M
+//! probe_attenuation = 1.0
+//! ```
+//!
+//! The `presets` directory of the crate holds examples.
+
+use std::fs;
+use std::path::Path;
+
+use log::info;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::acquisition::{TriggerConfig, TriggerSweep};
+use crate::analysis::Edge;
+use crate::settings::{BandwidthLimit, Coupling, HorizRef, MemoryDepth, ProbeRatio, TimebaseSettings, VerticalSettings};
+use crate::{OscilloscopeWaveform, Result, ScopeError, CHANNEL_COUNT};
+
+/// Complete instrument configuration of a measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementPreset {
+    pub name: String,
+    pub trigger: TriggerConfig,
+    /// Settings of the channels the measurement uses. Other channels are
+    /// left as they are.
+    pub vertical: Vec<(u8, VerticalSettings)>,
+    pub timebase: TimebaseSettings,
+    pub memory_depth: MemoryDepth,
+}
+
+fn invalid(message: impl Into<String>) -> ScopeError {
+    ScopeError::InvalidPreset(message.into())
+}
+
+/// A TOML basic string.
+fn quoted(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn edge_name(edge: Edge) -> &'static str {
+    match edge {
+        Edge::Rising => "rising",
+        Edge::Falling => "falling",
+        Edge::Both => "both",
+    }
+}
+
+/// Keys of one TOML table, with the table named in errors.
+struct Section<'a> {
+    name: &'a str,
+    table: &'a dyn TableLike,
+}
+
+impl<'a> Section<'a> {
+    fn item(&self, key: &str) -> Result<&'a Item> {
+        self.table.get(key).ok_or_else(|| invalid(format!("Missing '{}' in {}", key, self.name)))
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str) -> ScopeError {
+        invalid(format!("'{}' in {} must be {}", key, self.name, expected))
+    }
+
+    fn str(&self, key: &str) -> Result<&'a str> {
+        self.item(key)?.as_str().ok_or_else(|| self.wrong_type(key, "a string"))
+    }
+
+    /// A float, also written as an integer.
+    fn f32(&self, key: &str) -> Result<f32> {
+        let item = self.item(key)?;
+        let value = item.as_float().or_else(|| item.as_integer().map(|value| value as f64));
+        value.filter(|value| value.is_finite()).map(|value| value as f32)
+            .ok_or_else(|| self.wrong_type(key, "a finite number"))
+    }
+
+    fn u32(&self, key: &str) -> Result<u32> {
+        self.item(key)?.as_integer().and_then(|value| u32::try_from(value).ok())
+            .ok_or_else(|| self.wrong_type(key, "a positive integer"))
+    }
+
+    fn channel(&self, key: &str) -> Result<u8> {
+        u8::try_from(self.u32(key)?).ok().filter(|channel| (1..=CHANNEL_COUNT).contains(channel))
+            .ok_or_else(|| self.wrong_type(key, &format!("a channel from 1 to {}", CHANNEL_COUNT)))
+    }
+
+    fn table(&self, key: &'a str) -> Result<Section<'a>> {
+        let table = self.item(key)?.as_table_like().ok_or_else(|| self.wrong_type(key, "a table"))?;
+        Ok(Section { name: key, table })
+    }
+}
+
+fn parse_vertical(section: &Section) -> Result<(u8, VerticalSettings)> {
+    let coupling = match section.str("coupling")?.to_ascii_lowercase().as_str() {
+        "dc" => Coupling::Dc,
+        "ac" => Coupling::Ac,
+        "gnd" | "ground" => Coupling::Ground,
+        _ => return Err(section.wrong_type("coupling", "dc, ac or gnd")),
+    };
+    let bandwidth_limit = match section.str("bandwidth_limit")?.to_ascii_uppercase().as_str() {
+        "FULL" => BandwidthLimit::Full,
+        "20M" => BandwidthLimit::Limit20MHz,
+        "200M" => BandwidthLimit::Limit200MHz,
+        _ => return Err(section.wrong_type("bandwidth_limit", "full, 20M or 200M")),
+    };
+    let probe_attenuation = section.f32("probe_attenuation")?;
+    if ProbeRatio::from_factor(probe_attenuation).is_none() {
+        return Err(section.wrong_type("probe_attenuation", "a probe ratio from 1 to 1000"));
+    }
+    let volts_per_div = section.f32("volts_per_div")?;
+    if volts_per_div <= 0.0 {
+        return Err(section.wrong_type("volts_per_div", "positive"));
+    }
+
+    let settings = VerticalSettings {
+        volts_per_div,
+        offset_v: section.f32("offset_v")?,
+        coupling,
+        bandwidth_limit,
+        probe_attenuation,
+    };
+    Ok((section.channel("channel")?, settings))
+}
+
+impl MeasurementPreset {
+    /// Parse a preset from TOML text.
+    pub fn parse_toml(text: &str) -> Result<Self> {
+        let document: DocumentMut = text.parse().map_err(|e: toml_edit::TomlError| invalid(e.to_string()))?;
+        let root = Section { name: "the preset", table: document.as_table() };
+
+        let points = root.u32("memory_depth")?;
+        let memory_depth = MemoryDepth::from_points(points)
+            .ok_or_else(|| invalid(format!("Unsupported memory depth {} points", points)))?;
+
+        let timebase = root.table("timebase")?;
+        let reference = match timebase.str("reference")?.to_ascii_lowercase().as_str() {
+            "left" => HorizRef::Left,
+            "center" | "centre" => HorizRef::Center,
+            "right" => HorizRef::Right,
+            _ => return Err(timebase.wrong_type("reference", "left, center or right")),
+        };
+        let secs_per_div = timebase.f32("secs_per_div")?;
+        if secs_per_div <= 0.0 {
+            return Err(timebase.wrong_type("secs_per_div", "positive"));
+        }
+        let timebase = TimebaseSettings { secs_per_div, delay_s: timebase.f32("delay_s")?, reference };
+
+        let trigger = root.table("trigger")?;
+        let sweep = match trigger.str("sweep")?.to_ascii_lowercase().as_str() {
+            "auto" => TriggerSweep::Auto,
+            "normal" => TriggerSweep::Normal,
+            _ => return Err(trigger.wrong_type("sweep", "auto or normal")),
+        };
+        let trigger = TriggerConfig {
+            source: trigger.channel("source")?,
+            level_v: trigger.f32("level_v")?,
+            slope: trigger.str("slope")?.parse().map_err(invalid)?,
+            sweep,
+        };
+
+        // Either [[vertical]] tables or an array of inline tables
+        let vertical = match root.item("vertical")? {
+            Item::ArrayOfTables(tables) => tables.iter()
+                .map(|table| parse_vertical(&Section { name: "[[vertical]]", table }))
+                .collect::<Result<Vec<_>>>()?,
+            item => item.as_array().ok_or_else(|| root.wrong_type("vertical", "an array of tables"))?
+                .iter()
+                .map(|value| {
+                    let table = value.as_inline_table()
+                        .ok_or_else(|| root.wrong_type("vertical", "an array of tables"))?;
+                    parse_vertical(&Section { name: "vertical", table })
+                })
+                .collect::<Result<Vec<_>>>()?,
+        };
+        for (i, (channel, _)) in vertical.iter().enumerate() {
+            if vertical[..i].iter().any(|(other, _)| other == channel) {
+                return Err(invalid(format!("Channel {} is configured twice", channel)));
+            }
+        }
+
+        Ok(Self { name: root.str("name")?.to_string(), trigger, vertical, timebase, memory_depth })
+    }
+
+    /// The preset as TOML text, readable by [`parse_toml`](Self::parse_toml).
+    pub fn to_toml(&self) -> String {
+        let reference = match self.timebase.reference {
+            HorizRef::Left => "left",
+            HorizRef::Center => "center",
+            HorizRef::Right => "right",
+        };
+        let sweep = match self.trigger.sweep {
+            TriggerSweep::Auto => "auto",
+            TriggerSweep::Normal => "normal",
+        };
+        // {:?} keeps the decimal point, so floats stay floats
+        let mut text = format!(
+            "name = {}\nmemory_depth = {}\n\n[timebase]\nsecs_per_div = {:?}\ndelay_s = {:?}\nreference = \"{}\"\n\n\
+             [trigger]\nsource = {}\nlevel_v = {:?}\nslope = \"{}\"\nsweep = \"{}\"\n",
+            quoted(&self.name), self.memory_depth.points(), self.timebase.secs_per_div, self.timebase.delay_s,
+            reference, self.trigger.source, self.trigger.level_v, edge_name(self.trigger.slope), sweep,
+        );
+        for (channel, settings) in &self.vertical {
+            let coupling = match settings.coupling {
+                Coupling::Dc => "dc",
+                Coupling::Ac => "ac",
+                Coupling::Ground => "gnd",
+            };
+            let bandwidth_limit = match settings.bandwidth_limit {
+                BandwidthLimit::Full => "full",
+                BandwidthLimit::Limit20MHz => "20M",
+                BandwidthLimit::Limit200MHz => "200M",
+            };
+            text.push_str(&format!(
+                "\n[[vertical]]\nchannel = {}\nvolts_per_div = {:?}\noffset_v = {:?}\ncoupling = \"{}\"\n\
+                 bandwidth_limit = \"{}\"\nprobe_attenuation = {:?}\n",
+                channel, settings.volts_per_div, settings.offset_v, coupling, bandwidth_limit,
+                settings.probe_attenuation,
+            ));
+        }
+        text
+    }
+
+    /// Read a preset from a TOML file.
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<Self> {
+        let preset = Self::parse_toml(&fs::read_to_string(path.as_ref())?)?;
+        info!("Loaded preset {} from {}", preset.name, path.as_ref().display());
+        Ok(preset)
+    }
+
+    /// Write the preset to a TOML file.
+    pub fn save_toml(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path.as_ref(), self.to_toml())?;
+        info!("Saved preset {} to {}", self.name, path.as_ref().display());
+        Ok(())
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Configure the instrument as `preset` describes.
+    ///
+    /// The memory depth goes first, as it limits the timebase, and the
+    /// probe of each channel before its scale, as it sets the allowed
+    /// range. Every setting is verified, so the first one the instrument
+    /// rejects stops the setup with its error.
+    pub fn apply_preset(&self, preset: &MeasurementPreset) -> Result<()> {
+        self.set_memory_depth(preset.memory_depth)?;
+        for (channel, settings) in &preset.vertical {
+            let ratio = ProbeRatio::from_factor(settings.probe_attenuation).ok_or_else(|| {
+                ScopeError::InvalidArgument(format!("Unsupported probe ratio {}:1", settings.probe_attenuation))
+            })?;
+            self.set_probe_attenuation(*channel, ratio)?;
+            self.set_coupling(*channel, settings.coupling)?;
+            self.set_bandwidth_limit(*channel, settings.bandwidth_limit)?;
+            self.set_vertical(*channel, settings.volts_per_div.into(), settings.offset_v.into())?;
+        }
+        self.set_horizontal_reference(preset.timebase.reference)?;
+        self.set_timebase(preset.timebase.secs_per_div, preset.timebase.delay_s)?;
+        self.configure_trigger(&preset.trigger)?;
+        info!("Applied preset {}", preset.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+
+    fn preset_path(name: &str) -> String {
+        format!("{}/presets/{}.toml", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn round_trips_presets() {
+        let preset = MeasurementPreset::from_toml(preset_path("i2c_400khz_capture")).unwrap();
+        assert_eq!(preset.name, "i2c_400khz_capture");
+        assert_eq!(preset.vertical.len(), 2);
+        assert_eq!(preset.trigger.slope, Edge::Falling);
+
+        let mut renamed = preset.clone();
+        renamed.name = "quoted \"name\" \\ with\ttab".to_string();
+        assert_eq!(MeasurementPreset::parse_toml(&renamed.to_toml()).unwrap(), renamed);
+
+        let path = std::env::temp_dir().join(format!("preset-{}.toml", std::process::id()));
+        preset.save_toml(&path).unwrap();
+        let loaded = MeasurementPreset::from_toml(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), preset);
+    }
+
+    #[test]
+    fn rejects_invalid_presets() {
+        let text = std::fs::read_to_string(preset_path("power_rail_ripple")).unwrap();
+        assert!(MeasurementPreset::parse_toml(&text).is_ok());
+        for (from, to) in [
+            ("memory_depth = 1000000", "memory_depth = 1234"),
+            ("source = 1", "source = 5"),
+            ("slope = \"rising\"", "slope = \"up\""),
+            ("coupling = \"ac\"", "coupling = \"hf\""),
+            ("probe_attenuation = 1.0", "probe_attenuation = 3.0"),
+            ("volts_per_div = 0.02", "volts_per_div = \"fast\""),
+            ("[timebase]", "[horizontal]"),
+            ("name = ", "title = "),
+        ] {
+            assert!(text.contains(from), "{}", from);
+            let error = MeasurementPreset::parse_toml(&text.replace(from, to)).unwrap_err();
+            assert!(matches!(error, ScopeError::InvalidPreset(_)), "{}", error);
+        }
+        assert!(MeasurementPreset::parse_toml("name = ").is_err());
+    }
+
+    #[test]
+    fn applies_presets() {
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        let preset = MeasurementPreset::from_toml(preset_path("i2c_400khz_capture")).unwrap();
+        scope.apply_preset(&preset).unwrap();
+
+        assert_eq!(scope.memory_depth().unwrap(), preset.memory_depth.points());
+        assert_eq!(scope.get_timebase().unwrap(), preset.timebase);
+        for (channel, settings) in &preset.vertical {
+            assert_eq!(scope.get_vertical(*channel).unwrap(), *settings);
+        }
+        assert_eq!(scope.query("TRIGger:EDGE:SOURce?").unwrap(), "CHAN2");
+        assert_eq!(scope.query("TRIGger:EDGE:SLOPe?").unwrap(), "NEG");
+        assert_eq!(scope.query("TRIGger:SWEep?").unwrap(), "NORM");
+    }
+}
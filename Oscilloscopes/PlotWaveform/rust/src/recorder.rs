@@ -0,0 +1,419 @@
+//! Recording SCPI sessions and replaying them without an instrument.
+//!
+//! [`ScpiRecorder`] logs every command an [`OscilloscopeWaveform`] sends and
+//! every response it receives. [`ScpiPlayback`] answers the same sequence
+//! of commands from such a log, so code built on the library can be tested
+//! against a session captured once from real hardware:
+//!
+//! ```no_run
+//! use oscilloscope_waveform::recorder::{ScpiPlayback, ScpiRecorder};
+//! use oscilloscope_waveform::{DataRange, DeviceSelector, OscilloscopeWaveform, TransferFormat};
+//!
+//! let recorder = ScpiRecorder::new(OscilloscopeWaveform::open(&DeviceSelector::Auto)?);
+//! recorder.get_waveform_data(1, DataRange::All, TransferFormat::Raw, None)?;
+//! recorder.save_session("capture.jsonl")?;
+//!
+//! let scope = OscilloscopeWaveform::with_transport(Box::new(ScpiPlayback::from_file("capture.jsonl")?));
+//! let (time, voltage) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, None)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Both sides are [`Transport`]s, the recorder wrapping the instrument's
+//! own.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::transport::Transport;
+use crate::{OscilloscopeWaveform, Result, ScopeError};
+
+/// What happened on the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScpiDirection {
+    /// Bytes written to the instrument.
+    Sent,
+    /// Bytes read from the instrument, one read each.
+    Received,
+    /// A device clear, aborting pending responses.
+    Clear,
+}
+
+/// One entry of a recorded session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScpiEvent {
+    /// Seconds since the recording started.
+    pub time_s: f64,
+    pub direction: ScpiDirection,
+    pub data: Vec<u8>,
+}
+
+/// JSONL line of an event. Text stays readable, anything that is not
+/// UTF-8, like most data blocks, is stored as base64.
+#[derive(Serialize, Deserialize)]
+struct JsonEvent {
+    time_s: f64,
+    direction: ScpiDirection,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base64: Option<String>,
+}
+
+impl From<&ScpiEvent> for JsonEvent {
+    fn from(event: &ScpiEvent) -> Self {
+        let (text, base64) = match std::str::from_utf8(&event.data) {
+            Ok(text) => (Some(text.to_string()), None),
+            Err(_) => (None, Some(BASE64_STANDARD.encode(&event.data))),
+        };
+        JsonEvent { time_s: event.time_s, direction: event.direction, text, base64 }
+    }
+}
+
+impl TryFrom<JsonEvent> for ScpiEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(event: JsonEvent) -> anyhow::Result<Self> {
+        let data = match (event.text, event.base64) {
+            (Some(text), None) => text.into_bytes(),
+            (None, Some(packed)) => BASE64_STANDARD.decode(packed)?,
+            (None, None) => Vec::new(),
+            (Some(_), Some(_)) => return Err(anyhow!("Event has both text and base64 data")),
+        };
+        Ok(ScpiEvent { time_s: event.time_s, direction: event.direction, data })
+    }
+}
+
+/// Events recorded so far.
+struct Log {
+    start: Instant,
+    events: Vec<ScpiEvent>,
+}
+
+impl Log {
+    fn push(&mut self, direction: ScpiDirection, data: &[u8]) {
+        let time_s = self.start.elapsed().as_secs_f64();
+        self.events.push(ScpiEvent { time_s, direction, data: data.to_vec() });
+    }
+}
+
+fn lock(log: &Mutex<Log>) -> MutexGuard<'_, Log> {
+    // A panic elsewhere leaves the log consistent enough to go on
+    log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Transport passing everything through to `inner` and logging it.
+struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    log: Arc<Mutex<Log>>,
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.inner.send(data)?;
+        lock(&self.log).push(ScpiDirection::Sent, data);
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // Timeouts are not logged, playback times out by itself when no
+        // response is recorded
+        let count = self.inner.receive(buf)?;
+        if count > 0 {
+            lock(&self.log).push(ScpiDirection::Received, &buf[..count]);
+        }
+        Ok(count)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()?;
+        lock(&self.log).push(ScpiDirection::Clear, &[]);
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn go_to_local(&self) -> Result<()> {
+        self.inner.go_to_local()
+    }
+}
+
+/// Stand-in while the transport is moved into the recorder.
+struct Detached;
+
+impl Transport for Detached {
+    fn send(&self, _data: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn receive(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn clear(&self) -> Result<()> {
+        Err(ScopeError::Io(io::ErrorKind::NotConnected.into()))
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        Err(ScopeError::Io(io::ErrorKind::NotConnected.into()))
+    }
+
+    fn set_timeout(&self, _timeout: Duration) -> Result<()> {
+        Err(ScopeError::Io(io::ErrorKind::NotConnected.into()))
+    }
+}
+
+/// An instrument whose SCPI traffic is recorded with timestamps.
+///
+/// It dereferences to the wrapped [`OscilloscopeWaveform`], so all of its
+/// methods are available.
+pub struct ScpiRecorder {
+    scope: OscilloscopeWaveform,
+    log: Arc<Mutex<Log>>,
+}
+
+impl ScpiRecorder {
+    /// Record everything `scope` sends and receives from now on.
+    pub fn new(mut scope: OscilloscopeWaveform) -> Self {
+        let log = Arc::new(Mutex::new(Log { start: Instant::now(), events: Vec::new() }));
+        let inner = std::mem::replace(&mut scope.device, Box::new(Detached));
+        scope.device = Box::new(RecordingTransport { inner, log: Arc::clone(&log) });
+        Self { scope, log }
+    }
+
+    /// The events recorded so far.
+    pub fn events(&self) -> Vec<ScpiEvent> {
+        lock(&self.log).events.clone()
+    }
+
+    /// Write the recorded events to `path` as JSON lines, one per event,
+    /// for [`ScpiPlayback::from_file`].
+    pub fn save_session(&self, path: &str) -> anyhow::Result<()> {
+        let log = lock(&self.log);
+        let mut writer = BufWriter::new(File::create(path)?);
+        for event in &log.events {
+            serde_json::to_writer(&mut writer, &JsonEvent::from(event))?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        info!("Saved {} SCPI events to {}", log.events.len(), path);
+        Ok(())
+    }
+}
+
+impl Deref for ScpiRecorder {
+    type Target = OscilloscopeWaveform;
+
+    fn deref(&self) -> &OscilloscopeWaveform {
+        &self.scope
+    }
+}
+
+impl DerefMut for ScpiRecorder {
+    fn deref_mut(&mut self) -> &mut OscilloscopeWaveform {
+        &mut self.scope
+    }
+}
+
+#[derive(Debug)]
+struct PlaybackState {
+    events: VecDeque<ScpiEvent>,
+    /// Bytes of the first event already returned by `receive`
+    offset: usize,
+    timeout: Duration,
+}
+
+/// A recorded session replayed as a [`Transport`], without an instrument.
+///
+/// Commands must arrive exactly as recorded, a different one fails with
+/// `io::ErrorKind::InvalidData`. Responses are returned as they were read,
+/// and reading when the next event is not a response times out, as the
+/// instrument would have.
+#[derive(Debug)]
+pub struct ScpiPlayback {
+    state: Mutex<PlaybackState>,
+}
+
+/// Printable form of recorded bytes for error messages.
+fn describe(data: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(&data[..data.len().min(80)]))
+}
+
+impl ScpiPlayback {
+    pub fn new(events: Vec<ScpiEvent>) -> Self {
+        Self {
+            state: Mutex::new(PlaybackState {
+                events: events.into(),
+                offset: 0,
+                timeout: Duration::from_secs(2),
+            }),
+        }
+    }
+
+    /// Load a session written by [`ScpiRecorder::save_session`].
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path).with_context(|| format!("Could not open {}", path))?);
+        let mut events = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: JsonEvent = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid event in line {} of {}", number + 1, path))?;
+            events.push(event.try_into()?);
+        }
+        info!("Loaded {} SCPI events from {}", events.len(), path);
+        Ok(Self::new(events))
+    }
+
+    /// Number of events not replayed yet, 0 once the whole session ran.
+    pub fn remaining(&self) -> usize {
+        self.state().events.len()
+    }
+
+    fn state(&self) -> MutexGuard<'_, PlaybackState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PlaybackState {
+    /// Take the next event, which has to go in `direction` and carry
+    /// `data` if given.
+    fn expect(&mut self, direction: ScpiDirection, data: Option<&[u8]>) -> io::Result<ScpiEvent> {
+        let matches = self.events.front()
+            .is_some_and(|event| event.direction == direction && data.is_none_or(|data| event.data == data));
+        if !matches {
+            let got = match data {
+                Some(data) => format!("{:?} {}", direction, describe(data)),
+                None => format!("{:?}", direction),
+            };
+            let expected = match self.events.front() {
+                Some(event) => format!("{:?} {}", event.direction, describe(&event.data)),
+                None => "the end of the session".to_string(),
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Playback expected {}, got {}", expected, got)));
+        }
+        self.offset = 0;
+        Ok(self.events.pop_front().expect("checked above"))
+    }
+}
+
+impl Transport for ScpiPlayback {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.state().expect(ScpiDirection::Sent, Some(data))?;
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        let offset = state.offset;
+        let Some(event) = state.events.front().filter(|event| event.direction == ScpiDirection::Received) else {
+            return Err(io::ErrorKind::TimedOut.into());
+        };
+        let count = buf.len().min(event.data.len() - offset);
+        buf[..count].copy_from_slice(&event.data[offset..offset + count]);
+        if offset + count == event.data.len() {
+            state.expect(ScpiDirection::Received, None)?;
+        } else {
+            state.offset += count;
+        }
+        Ok(count)
+    }
+
+    fn clear(&self) -> Result<()> {
+        let mut state = self.state();
+        // Responses the caller gave up on were not read in the recording
+        // either, but may follow here if the recording read them partially
+        if state.offset > 0 {
+            state.expect(ScpiDirection::Received, None)?;
+        }
+        state.expect(ScpiDirection::Clear, None)?;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        Ok(self.state().timeout)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.state().timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+    use crate::{DataRange, TransferFormat};
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("{}-{}.jsonl", name, std::process::id())).display().to_string()
+    }
+
+    #[test]
+    fn replays_a_recorded_capture() {
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        let recorder = ScpiRecorder::new(scope);
+        let identity = recorder.identity().unwrap();
+        let recorded = recorder.get_waveform_data(2, DataRange::All, TransferFormat::Raw, Some(1_000)).unwrap();
+
+        let events = recorder.events();
+        assert_eq!(events[0].direction, ScpiDirection::Sent);
+        assert_eq!(events[0].data, b"*IDN?\n");
+        assert_eq!(events[1].direction, ScpiDirection::Received);
+        assert!(events.windows(2).all(|pair| pair[1].time_s >= pair[0].time_s));
+
+        let path = temp_path("scpi-session");
+        recorder.save_session(&path).unwrap();
+        let first_line = std::fs::read_to_string(&path).unwrap().lines().next().unwrap().to_string();
+        let first: serde_json::Value = serde_json::from_str(&first_line).unwrap();
+        assert_eq!(first["direction"], "sent");
+        assert_eq!(first["text"], "*IDN?\n");
+
+        let playback = ScpiPlayback::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(playback.remaining(), events.len());
+        let scope = OscilloscopeWaveform::with_transport(Box::new(playback));
+        assert_eq!(scope.identity().unwrap(), identity);
+        assert_eq!(scope.get_waveform_data(2, DataRange::All, TransferFormat::Raw, Some(1_000)).unwrap(), recorded);
+        // The session is used up
+        assert!(matches!(scope.idn(), Err(ScopeError::Io(_))));
+    }
+
+    #[test]
+    fn rejects_commands_that_differ_from_the_recording() {
+        let playback = ScpiPlayback::new(vec![
+            ScpiEvent { time_s: 0.0, direction: ScpiDirection::Sent, data: b"*IDN?\n".to_vec() },
+            ScpiEvent { time_s: 0.1, direction: ScpiDirection::Received, data: b"Batronix,Magnova,1,2\n".to_vec() },
+        ]);
+        let error = playback.send(b"*RST\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("*IDN?"), "{}", error);
+
+        // Responses are not there before their command
+        assert_eq!(playback.receive(&mut [0; 4]).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        playback.send(b"*IDN?\n").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(playback.receive(&mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"Batronix");
+        assert!(playback.clear().is_err());
+    }
+}
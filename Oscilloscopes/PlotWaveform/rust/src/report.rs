@@ -0,0 +1,194 @@
+//! PDF reports of captures for lab notebooks and compliance records.
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use anyhow::{Result, anyhow};
+use log::info;
+use plotters::prelude::*;
+use printpdf::{
+    BuiltinFont, ColorBits, ColorSpace, Image, ImageTransform, ImageXObject, IndirectFontRef, Mm,
+    PdfDocument, PdfLayerReference, Px,
+};
+
+use crate::analysis::stats::WaveformStats;
+use crate::capture_sink::format_timestamp;
+use crate::export::WaveformCapture;
+use crate::plot::{draw_chart, ChartDecorations};
+
+/// A4 portrait.
+const PAGE_WIDTH: Mm = Mm(210.0);
+const PAGE_HEIGHT: Mm = Mm(297.0);
+const MARGIN: f32 = 15.0;
+
+/// Size of the embedded chart image in pixels. It fills the page width
+/// between the margins and keeps the aspect ratio of the PNG plots.
+const CHART_PIXELS: (u32, u32) = (1200, 600);
+
+/// Height of a table row.
+const ROW_HEIGHT: f32 = 5.5;
+
+/// One capture of a report, printed on a page of its own.
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    pub capture: WaveformCapture,
+    pub stats: WaveformStats,
+    /// Page heading, `CH<n>` and the capture time if `None`.
+    pub title: Option<String>,
+}
+
+impl ReportEntry {
+    /// An entry with the statistics computed from the capture's samples.
+    pub fn new(capture: WaveformCapture, title: Option<String>) -> Self {
+        let stats = WaveformStats::compute(&capture.voltage);
+        Self { capture, stats, title }
+    }
+
+    fn heading(&self) -> String {
+        self.title.clone().unwrap_or_else(|| format!("CH{} at {} UTC",
+            self.capture.channel, format_timestamp(self.capture.captured_at_unix_s as u64)))
+    }
+
+    /// Label and value of every table row, metadata first.
+    fn rows(&self) -> Vec<(&'static str, String)> {
+        let capture = &self.capture;
+        let metadata = &capture.metadata;
+        let stats = &self.stats;
+        vec![
+            ("Instrument", capture.idn.clone()),
+            ("Channel", format!("CH{}", capture.channel)),
+            ("Captured", format!("{} UTC", format_timestamp(capture.captured_at_unix_s as u64))),
+            ("Samples", format!("{}", metadata.sample_count)),
+            ("Sample interval", format!("{:e} s", metadata.time_delta)),
+            ("Start time", format!("{:e} s", metadata.start_time)),
+            ("End time", format!("{:e} s", metadata.end_time)),
+            ("First valid sample", format!("{}", metadata.sample_start)),
+            ("Valid samples", format!("{}", metadata.sample_length)),
+            ("Vertical start", format!("{} V", metadata.vertical_start)),
+            ("Vertical step", format!("{} V", metadata.vertical_step)),
+            ("Mean", format!("{:.6} V", stats.mean)),
+            ("RMS", format!("{:.6} V", stats.rms)),
+            ("Standard deviation", format!("{:.6} V", stats.std_dev)),
+            ("Peak to peak", format!("{:.6} V", stats.peak_to_peak)),
+            ("Minimum", format!("{:.6} V", stats.min)),
+            ("Maximum", format!("{:.6} V", stats.max)),
+            ("Crest factor", format!("{:.3}", stats.crest_factor)),
+        ]
+    }
+}
+
+/// Render the waveform chart of an entry into an RGB image.
+fn chart_image(entry: &ReportEntry) -> Result<ImageXObject> {
+    let (width, height) = CHART_PIXELS;
+    let mut pixels = vec![0u8; width as usize * height as usize * 3];
+    {
+        let root = BitMapBackend::with_buffer(&mut pixels, CHART_PIXELS).into_drawing_area();
+        draw_chart(root, &entry.heading(), ChartDecorations::default(), &entry.capture.time, &entry.capture.voltage)?;
+    }
+    Ok(ImageXObject {
+        width: Px(width as usize),
+        height: Px(height as usize),
+        color_space: ColorSpace::Rgb,
+        bits_per_component: ColorBits::Bit8,
+        interpolate: true,
+        image_data: pixels,
+        image_filter: None,
+        smask: None,
+        clipping_bbox: None,
+    })
+}
+
+/// Chart on the upper half of the page, the table below it.
+fn draw_page(layer: &PdfLayerReference, entry: &ReportEntry, fonts: &(IndirectFontRef, IndirectFontRef))
+    -> Result<()> {
+    let (regular, bold) = fonts;
+    let chart_width = PAGE_WIDTH.0 - 2.0 * MARGIN;
+    let chart_height = chart_width * CHART_PIXELS.1 as f32 / CHART_PIXELS.0 as f32;
+    let chart_bottom = PAGE_HEIGHT.0 - MARGIN - chart_height;
+    Image::from(chart_image(entry)?).add_to_layer(layer.clone(), ImageTransform {
+        translate_x: Some(Mm(MARGIN)),
+        translate_y: Some(Mm(chart_bottom)),
+        // Scales the image to the chart width
+        dpi: Some(CHART_PIXELS.0 as f32 * 25.4 / chart_width),
+        ..ImageTransform::default()
+    });
+
+    let mut y = chart_bottom - 2.0 * ROW_HEIGHT;
+    for (i, (label, value)) in entry.rows().into_iter().enumerate() {
+        if i == 3 || i == 11 {
+            y -= ROW_HEIGHT / 2.0;
+        }
+        layer.use_text(label, 10.0, Mm(MARGIN), Mm(y), bold);
+        layer.use_text(value, 10.0, Mm(MARGIN + 50.0), Mm(y), regular);
+        y -= ROW_HEIGHT;
+    }
+    Ok(())
+}
+
+/// Write a PDF with one page per capture: the waveform chart on the upper
+/// half, its metadata and statistics as a table below.
+pub fn generate_report(captures: &[ReportEntry], output_path: &str) -> Result<()> {
+    if captures.is_empty() {
+        return Err(anyhow!("A report needs at least one capture"));
+    }
+
+    info!("Creating report of {} captures", captures.len());
+    let (doc, page, layer) = PdfDocument::new("Waveform Report", PAGE_WIDTH, PAGE_HEIGHT, "Capture 1");
+    let fonts = (doc.add_builtin_font(BuiltinFont::Helvetica)?, doc.add_builtin_font(BuiltinFont::HelveticaBold)?);
+    let mut layers = vec![doc.get_page(page).get_layer(layer)];
+    for i in 1..captures.len() {
+        let (page, layer) = doc.add_page(PAGE_WIDTH, PAGE_HEIGHT, format!("Capture {}", i + 1));
+        layers.push(doc.get_page(page).get_layer(layer));
+    }
+    for (layer, entry) in layers.iter().zip(captures) {
+        draw_page(layer, entry, &fonts)?;
+    }
+
+    doc.save(&mut BufWriter::new(File::create(output_path)?))?;
+    info!("Report saved as {}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WaveformMetadata;
+
+    fn capture(channel: u8) -> WaveformCapture {
+        let time: Vec<f32> = (0..500).map(|i| i as f32 * 1e-6).collect();
+        let voltage: Vec<f32> = time.iter().map(|t| (t * 2e4).sin()).collect();
+        let metadata = WaveformMetadata {
+            time_delta: 1e-6,
+            start_time: 0.0,
+            end_time: 499e-6,
+            sample_start: 0,
+            sample_length: 500,
+            vertical_start: -5.0,
+            vertical_step: 10.0,
+            sample_count: 500,
+        };
+        WaveformCapture::new(channel, "Batronix,Magnova,0001,1.0", metadata, time, voltage)
+    }
+
+    #[test]
+    fn writes_one_page_per_capture() {
+        let path = std::env::temp_dir().join(format!("report_{}.pdf", std::process::id()));
+        let entries = [
+            ReportEntry::new(capture(1), Some("Clock".to_string())),
+            ReportEntry::new(capture(2), None),
+        ];
+        assert!((entries[0].stats.peak_to_peak - 2.0).abs() < 0.01);
+        assert!(entries[1].heading().starts_with("CH2 at "));
+
+        generate_report(&entries, &path.to_string_lossy()).unwrap();
+        let pdf = std::fs::read(&path).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(pdf.starts_with(b"%PDF"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert_eq!(text.matches("/Type/Page").count() - text.matches("/Type/Pages").count(), 2);
+        assert_eq!(text.matches("/Subtype/Image").count(), 2);
+
+        assert!(generate_report(&[], &path.to_string_lossy()).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Checking the instrument's SCPI error queue.
+
+use std::fmt;
+
+use log::warn;
+
+use crate::{OscilloscopeWaveform, Result, ScopeError};
+
+/// Upper bound on `SYSTem:ERRor?` reads, in case an instrument never
+/// reports an empty queue.
+const MAX_ERROR_QUEUE_READS: usize = 32;
+
+/// One entry of the instrument's error queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScpiError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl fmt::Display for ScpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},\"{}\"", self.code, self.message)
+    }
+}
+
+impl ScpiError {
+    /// Parse a `SYSTem:ERRor?` reply such as `-222,"Data out of range"`.
+    pub(crate) fn parse(response: &str) -> Result<Self> {
+        let malformed = || ScopeError::UnexpectedResponse {
+            command: "SYSTem:ERRor?".to_string(),
+            response: response.to_string(),
+        };
+        let (code, message) = response.split_once(',').ok_or_else(malformed)?;
+        Ok(Self {
+            code: code.trim().parse().map_err(|_| malformed())?,
+            message: message.trim().trim_matches('"').to_string(),
+        })
+    }
+}
+
+/// What to do with the error queue after configuration commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorCheck {
+    /// Don't query the error queue.
+    #[default]
+    Off,
+    /// Log reported errors as warnings and carry on.
+    Warn,
+    /// Abort with the reported errors.
+    Strict,
+}
+
+impl OscilloscopeWaveform {
+    /// Select whether configuration commands are followed by an error
+    /// queue check.
+    pub fn set_error_check(&mut self, mode: ErrorCheck) {
+        self.error_check = mode;
+    }
+
+    /// Drain the instrument's error queue and return all reported errors.
+    pub fn check_errors(&self) -> Result<Vec<ScpiError>> {
+        let mut errors = Vec::new();
+        for _ in 0..MAX_ERROR_QUEUE_READS {
+            let error = ScpiError::parse(&self.query("SYSTem:ERRor?")?)?;
+            if error.code == 0 {
+                return Ok(errors);
+            }
+            errors.push(error);
+        }
+        warn!("Error queue still not empty after {} reads", MAX_ERROR_QUEUE_READS);
+        Ok(errors)
+    }
+
+    /// Check the error queue according to the configured `ErrorCheck` mode.
+    /// `context` names the commands that were just sent.
+    pub(crate) fn verify_no_errors(&self, context: &str) -> Result<()> {
+        if self.error_check == ErrorCheck::Off {
+            return Ok(());
+        }
+
+        let mut errors = self.check_errors()?;
+        if errors.is_empty() {
+            return Ok(());
+        }
+        for error in &errors {
+            warn!("Instrument error after {}: {}", context, error);
+        }
+        if self.error_check == ErrorCheck::Strict {
+            // All errors were logged above, report the first one
+            let ScpiError { code, message } = errors.swap_remove(0);
+            return Err(ScopeError::ScpiError { code, message });
+        }
+        Ok(())
+    }
+}
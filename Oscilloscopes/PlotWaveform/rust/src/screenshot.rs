@@ -0,0 +1,136 @@
+//! Saving the instrument's display contents.
+
+use std::fs;
+use std::path::Path;
+
+use log::info;
+
+use crate::{OscilloscopeWaveform, Result, ScopeError};
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+const BMP_MAGIC: &[u8] = b"BM";
+
+/// Image format of a display capture, detected from its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+}
+
+impl ImageFormat {
+    /// Identify the format from the first bytes of the image.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(PNG_MAGIC) {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(BMP_MAGIC) {
+            Some(ImageFormat::Bmp)
+        } else {
+            None
+        }
+    }
+
+    /// Usual file extension of the format, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Bmp => "bmp",
+        }
+    }
+}
+
+/// Bring a received image into the format of the file extension.
+///
+/// Images already in that format are passed through unchanged.
+#[cfg(feature = "image")]
+fn convert_image(data: Vec<u8>, format: ImageFormat, extension: &str) -> Result<Vec<u8>> {
+    if extension.eq_ignore_ascii_case(format.extension()) {
+        return Ok(data);
+    }
+    let target = image::ImageFormat::from_extension(extension)
+        .ok_or_else(|| ScopeError::Image(format!("Unknown image file extension .{}", extension)))?;
+    let image = image::load_from_memory(&data).map_err(|e| ScopeError::Image(e.to_string()))?;
+    let mut converted = Vec::new();
+    image.write_to(&mut std::io::Cursor::new(&mut converted), target)
+        .map_err(|e| ScopeError::Image(e.to_string()))?;
+    info!("Converted {:?} image to {:?}", format, target);
+    Ok(converted)
+}
+
+/// Bring a received image into the format of the file extension.
+///
+/// Without the `image` feature only PNG can be written, so BMP images are
+/// rejected.
+#[cfg(not(feature = "image"))]
+fn convert_image(data: Vec<u8>, format: ImageFormat, _extension: &str) -> Result<Vec<u8>> {
+    match format {
+        ImageFormat::Png => Ok(data),
+        ImageFormat::Bmp => Err(ScopeError::Image(
+            "Converting the BMP screenshot to PNG requires the image feature".to_string()
+        )),
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Save the current display contents of the instrument to `path`.
+    ///
+    /// The file is written as PNG. With the `image` feature, BMP captures
+    /// are transcoded and other extensions such as `.bmp` are honoured as
+    /// well. Without it, `path` must end in `.png`.
+    pub fn capture_screenshot(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if cfg!(not(feature = "image")) && !extension.eq_ignore_ascii_case("png") {
+            return Err(ScopeError::InvalidArgument(format!(
+                "Screenshot {} must be a .png file, other formats need the image feature", path.display()
+            )));
+        }
+
+        info!("Capturing screenshot");
+        self.send_command("DISPlay:DATA?")?;
+        let data = self.read_binary_block()?;
+
+        let format = ImageFormat::detect(&data).ok_or_else(|| ScopeError::UnexpectedResponse {
+            command: "DISPlay:DATA?".to_string(),
+            response: format!("{} bytes of neither PNG nor BMP data", data.len()),
+        })?;
+        info!("Received {} bytes of {:?} image data", data.len(), format);
+
+        let data = convert_image(data, format, extension)?;
+        fs::write(path, &data)?;
+        info!("Screenshot saved as {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_image_formats() {
+        assert_eq!(ImageFormat::detect(b"\x89PNG\r\n\x1a\n...."), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::detect(b"BM...."), Some(ImageFormat::Bmp));
+        assert_eq!(ImageFormat::detect(b"GIF89a"), None);
+    }
+
+    #[cfg(not(feature = "image"))]
+    #[test]
+    fn rejects_bmp_without_image_feature() {
+        let png = PNG_MAGIC.to_vec();
+        assert_eq!(convert_image(png.clone(), ImageFormat::Png, "png").unwrap(), png);
+        assert!(convert_image(BMP_MAGIC.to_vec(), ImageFormat::Bmp, "png").is_err());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn transcodes_bmp_to_png() {
+        let pixels = image::RgbImage::from_fn(3, 2, |x, y| image::Rgb([x as u8 * 80, y as u8 * 120, 7]));
+        let mut bmp = Vec::new();
+        pixels.write_to(&mut std::io::Cursor::new(&mut bmp), image::ImageFormat::Bmp).unwrap();
+
+        let png = convert_image(bmp.clone(), ImageFormat::Bmp, "png").unwrap();
+        assert_eq!(ImageFormat::detect(&png), Some(ImageFormat::Png));
+        assert_eq!(image::load_from_memory(&png).unwrap().to_rgb8(), pixels);
+        assert_eq!(convert_image(bmp.clone(), ImageFormat::Bmp, "bmp").unwrap(), bmp);
+    }
+}
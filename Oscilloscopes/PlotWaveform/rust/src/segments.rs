@@ -0,0 +1,224 @@
+//! Readout of segmented (history) acquisitions.
+
+use log::{info, warn};
+
+use crate::waveform::{extract_waveform, parse_metadata};
+use crate::{check_channel, OscilloscopeWaveform, Result, ScopeError, TransferFormat, WaveformMetadata};
+
+/// One stored trigger event of a segmented acquisition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// Zero-based position in the instrument's segment memory.
+    pub index: u32,
+    pub metadata: WaveformMetadata,
+    /// Trigger time relative to the first segment, in seconds.
+    pub trigger_time_s: f64,
+    /// Sample values in the channel's unit.
+    pub samples: Vec<f32>,
+}
+
+impl Segment {
+    /// Sample times relative to this segment's trigger.
+    pub fn time_values(&self) -> Vec<f32> {
+        (0..self.samples.len())
+            .map(|i| self.metadata.start_time + (i as f32) * self.metadata.time_delta)
+            .collect()
+    }
+}
+
+/// A segment that could not be read.
+#[derive(Debug)]
+pub struct SegmentError {
+    pub index: u32,
+    pub error: ScopeError,
+}
+
+/// Result of a segment readout. A failing segment does not abort the
+/// readout, so both lists can be non-empty.
+#[derive(Debug, Default)]
+pub struct SegmentReadout {
+    /// Successfully read segments in acquisition order.
+    pub segments: Vec<Segment>,
+    /// Segments that failed, in acquisition order.
+    pub errors: Vec<SegmentError>,
+}
+
+impl SegmentReadout {
+    /// True if every requested segment was read.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Enable or disable segmented acquisition with `count` segments.
+    pub fn set_segmented_acquisition(&self, enable: bool, count: u32) -> Result<()> {
+        if enable && count == 0 {
+            return Err(ScopeError::InvalidArgument("Segment count must be positive".to_string()));
+        }
+        if enable {
+            self.send_command(&format!("ACQuire:SEGMented:COUNt {}", count))?;
+        }
+        self.send_command(&format!("ACQuire:SEGMented:STATe {}", if enable { 1 } else { 0 }))?;
+        self.verify_no_errors("segmented acquisition setup")
+    }
+
+    /// Number of segments stored by the last segmented acquisition.
+    pub fn acquired_segments(&self) -> Result<u32> {
+        let command = "ACQuire:SEGMented:ACQuired?";
+        let response = self.query(command)?;
+        response.parse()
+            .map_err(|_| ScopeError::UnexpectedResponse { command: command.to_string(), response })
+    }
+
+    /// Read the stored segments of a channel, at most `max_segments` of
+    /// them or all with `None`.
+    ///
+    /// The acquisition should be stopped, so the segment memory does not
+    /// change while it is read. If the instrument holds fewer segments
+    /// than requested, the available ones are read. A segment that fails
+    /// to read is recorded in [`SegmentReadout::errors`] and the readout
+    /// continues with the next one. Only errors before the first segment,
+    /// e.g. an invalid channel, are returned as `Err`.
+    pub fn get_segments(&self, channel: u8, max_segments: Option<u32>) -> Result<SegmentReadout> {
+        check_channel(channel)?;
+        let available = self.acquired_segments()?;
+        let count = match max_segments {
+            Some(max) if max > available => {
+                warn!("Requested {} segments, but only {} are stored", max, available);
+                available
+            }
+            Some(max) => max,
+            None => available,
+        };
+
+        info!("Reading {} segments of channel {}", count, channel);
+        self.send_command(&format!("CHAN{}:DATa:TYPE RAW", channel))?;
+        self.verify_no_errors("data type configuration")?;
+
+        let mut readout = SegmentReadout::default();
+        for index in 0..count {
+            match self.read_segment(channel, index) {
+                Ok(segment) => readout.segments.push(segment),
+                Err(error) => {
+                    warn!("Reading segment {} failed: {}", index, error);
+                    // Drop the rest of a broken transfer before the next segment
+                    if let Err(recovery) = self.discard_pending() {
+                        warn!("Recovering after segment {} failed: {}", index, recovery);
+                    }
+                    readout.errors.push(SegmentError { index, error });
+                }
+            }
+        }
+
+        info!("Read {} of {} segments", readout.segments.len(), count);
+        Ok(readout)
+    }
+
+    /// Clear a half-read response and drain the error queue.
+    fn discard_pending(&self) -> Result<()> {
+        self.device.clear()?;
+        self.check_errors()?;
+        Ok(())
+    }
+
+    fn read_segment(&self, channel: u8, index: u32) -> Result<Segment> {
+        self.send_command(&format!("ACQuire:SEGMented:INDex {}", index))?;
+        let trigger_time_s = self.query_f64("ACQuire:SEGMented:TIMestamp?")?;
+        self.send_command(&format!("CHAN{}:DATa:PACK? ALL, RAW", channel))?;
+        let data = self.read_binary_block()?;
+        let metadata = parse_metadata(&data, TransferFormat::Raw, self.byte_order)?;
+        let scaling = self.channel_scaling(channel)?;
+        let samples = extract_waveform(&data, &metadata, TransferFormat::Raw, scaling, self.byte_order)?;
+        Ok(Segment { index, metadata, trigger_time_s, samples })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+    use crate::DataRange;
+
+    fn segmented_scope(config: SimulationConfig, count: u32) -> OscilloscopeWaveform {
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        scope.set_segmented_acquisition(true, count).unwrap();
+        scope.set_running(false).unwrap();
+        scope
+    }
+
+    fn indices(segments: &[Segment]) -> Vec<u32> {
+        segments.iter().map(|segment| segment.index).collect()
+    }
+
+    #[test]
+    fn reads_the_stored_segments() {
+        let config = SimulationConfig { memory_depth: 1_000, ..SimulationConfig::default() };
+        let scope = segmented_scope(config, 4);
+        assert_eq!(scope.acquired_segments().unwrap(), 4);
+
+        let readout = scope.get_segments(1, Some(3)).unwrap();
+        assert!(readout.is_complete());
+        assert_eq!(indices(&readout.segments), [0, 1, 2]);
+        for segment in &readout.segments {
+            assert_eq!(segment.samples.len(), 1_000);
+            // The simulator triggers once per signal period
+            assert!((segment.trigger_time_s - segment.index as f64 * 1e-3).abs() < 1e-12);
+        }
+        assert_eq!(indices(&scope.get_segments(1, None).unwrap().segments), [0, 1, 2, 3]);
+
+        assert!(matches!(scope.get_segments(5, None), Err(ScopeError::InvalidChannel(5))));
+        assert!(matches!(scope.set_segmented_acquisition(true, 0), Err(ScopeError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn reads_fewer_segments_than_requested() {
+        let config = SimulationConfig { memory_depth: 1_000, segments: 3, ..SimulationConfig::default() };
+        let scope = segmented_scope(config, 10);
+        let readout = scope.get_segments(2, Some(10)).unwrap();
+        assert!(readout.is_complete());
+        assert_eq!(indices(&readout.segments), [0, 1, 2]);
+
+        // Without segmented acquisition nothing is stored
+        scope.set_segmented_acquisition(false, 0).unwrap();
+        assert!(scope.get_segments(2, None).unwrap().segments.is_empty());
+    }
+
+    #[test]
+    fn continues_after_a_failed_segment() {
+        let config = SimulationConfig {
+            memory_depth: 1_000,
+            corrupt_segment: Some(1),
+            ..SimulationConfig::default()
+        };
+        let scope = segmented_scope(config, 4);
+        let readout = scope.get_segments(1, None).unwrap();
+
+        assert!(!readout.is_complete());
+        assert_eq!(readout.errors.len(), 1);
+        assert_eq!(readout.errors[0].index, 1);
+        assert!(matches!(readout.errors[0].error, ScopeError::InvalidHeader { got: b'X' }),
+            "{}", readout.errors[0].error);
+        // The rest of the broken block was discarded, so later reads get
+        // their own responses
+        assert_eq!(indices(&readout.segments), [0, 2, 3]);
+        assert!(readout.segments.iter().all(|segment| segment.samples.len() == 1_000));
+        assert!((readout.segments[1].trigger_time_s - 2e-3).abs() < 1e-12);
+        assert!(scope.check_errors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn time_values_follow_the_metadata() {
+        let config = SimulationConfig { memory_depth: 1_000, ..SimulationConfig::default() };
+        let scope = segmented_scope(config, 2);
+        let segment = scope.get_segments(1, Some(1)).unwrap().segments.remove(0);
+        let time = segment.time_values();
+        assert_eq!(time.len(), segment.samples.len());
+        assert_eq!(time[0], segment.metadata.start_time);
+        assert!((time[999] - (segment.metadata.start_time + 999.0 * segment.metadata.time_delta)).abs() < 1e-9);
+
+        // A single capture has the same time axis
+        let (capture_time, _) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, None).unwrap();
+        assert_eq!(time, capture_time);
+    }
+}
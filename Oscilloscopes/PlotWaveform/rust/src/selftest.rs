@@ -0,0 +1,161 @@
+//! Instrument self-test and self-calibration.
+
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::{OscilloscopeWaveform, Result, ScopeError};
+
+/// How long `*TST?` may take to answer.
+pub const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `*CAL?` may take to answer. Calibrating every channel and
+/// range takes well over half a minute.
+pub const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Faults reported by `*TST?`.
+const FAULT_CODES: [(u32, &str); 6] = [
+    (1, "Analog front end: attenuator or amplifier out of range"),
+    (2, "ADC: conversion test failed"),
+    (3, "Acquisition memory: read-back mismatch"),
+    (4, "Trigger: comparator or level DAC fault"),
+    (5, "Timebase: sample clock not locked"),
+    (6, "Calibration data missing or corrupt"),
+];
+
+/// Outcome of [`OscilloscopeWaveform::self_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfTestResult {
+    Pass,
+    Fail { code: u32, description: String },
+}
+
+impl SelfTestResult {
+    /// Result of a `*TST?` reply of `code`. Codes missing from the fault
+    /// table are described as unknown.
+    pub fn from_code(code: u32) -> Self {
+        if code == 0 {
+            return SelfTestResult::Pass;
+        }
+        let description = FAULT_CODES.iter()
+            .find(|(fault, _)| *fault == code)
+            .map(|(_, description)| description.to_string())
+            .unwrap_or_else(|| format!("Unknown fault {}", code));
+        SelfTestResult::Fail { code, description }
+    }
+
+    pub fn passed(&self) -> bool {
+        *self == SelfTestResult::Pass
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Run the instrument's self-test (`*TST?`).
+    ///
+    /// The instrument stops acquiring during the test, so afterwards it is
+    /// set running or stopped again as before.
+    pub fn self_test(&self) -> Result<SelfTestResult> {
+        self.keeping_run_state("the self-test", || {
+            info!("Running self-test");
+            let response = self.with_io_timeout(SELF_TEST_TIMEOUT, || self.query("*TST?"))?;
+            let code = response.parse::<u32>().map_err(|_| ScopeError::UnexpectedResponse {
+                command: "*TST?".to_string(),
+                response: response.clone(),
+            })?;
+            let result = SelfTestResult::from_code(code);
+            match &result {
+                SelfTestResult::Pass => info!("Self-test passed"),
+                SelfTestResult::Fail { code, description } => warn!("Self-test failed with {}: {}", code, description),
+            }
+            Ok(result)
+        })
+    }
+
+    /// Run the instrument's self-calibration (`*CAL?`), waiting up to
+    /// [`CALIBRATION_TIMEOUT`] for it.
+    ///
+    /// Any response but 0 fails with [`ScopeError::CalibrationFailed`]
+    /// carrying the response. The run state is restored afterwards as with
+    /// [`self_test`](Self::self_test). Disconnect all signals from the
+    /// inputs before calibrating.
+    pub fn run_calibration(&self) -> Result<()> {
+        self.keeping_run_state("the calibration", || {
+            info!("Running self-calibration, this takes a while");
+            let response = self.with_io_timeout(CALIBRATION_TIMEOUT, || self.query("*CAL?"))?;
+            if response.parse::<i32>() != Ok(0) {
+                return Err(ScopeError::CalibrationFailed(response));
+            }
+            info!("Self-calibration completed");
+            Ok(())
+        })
+    }
+
+    /// Run `operation` and set the instrument running or stopped again as
+    /// before. A failure to restore the state is only logged if
+    /// `operation` failed, so its error is kept.
+    fn keeping_run_state<T>(&self, operation: &str, run: impl FnOnce() -> Result<T>) -> Result<T> {
+        let running = self.trigger_status()?.is_running();
+        let result = run();
+        if matches!(result, Err(ScopeError::Timeout)) {
+            // Abort the pending query before sending anything else
+            if let Err(e) = self.device.clear() {
+                warn!("Could not abort {}: {}", operation, e);
+            }
+        }
+        match self.set_running(running) {
+            Err(e) if result.is_ok() => Err(e),
+            Err(e) => {
+                warn!("Could not restore the run state after {}: {}", operation, e);
+                result
+            }
+            Ok(()) => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+
+    fn simulated_scope(self_test_code: u32) -> OscilloscopeWaveform {
+        let config = SimulationConfig { self_test_code, ..SimulationConfig::default() };
+        OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)))
+    }
+
+    #[test]
+    fn maps_fault_codes() {
+        assert!(SelfTestResult::from_code(0).passed());
+        assert_eq!(SelfTestResult::from_code(2), SelfTestResult::Fail {
+            code: 2,
+            description: "ADC: conversion test failed".to_string(),
+        });
+        assert!(matches!(SelfTestResult::from_code(77),
+            SelfTestResult::Fail { code: 77, description } if description == "Unknown fault 77"));
+    }
+
+    #[test]
+    fn self_test_restores_the_run_state() {
+        let scope = simulated_scope(0);
+        assert_eq!(scope.self_test().unwrap(), SelfTestResult::Pass);
+        assert!(scope.trigger_status().unwrap().is_running());
+
+        let scope = simulated_scope(5);
+        scope.set_running(false).unwrap();
+        let result = scope.self_test().unwrap();
+        assert!(matches!(result, SelfTestResult::Fail { code: 5, .. }), "{:?}", result);
+        assert!(!scope.trigger_status().unwrap().is_running());
+    }
+
+    #[test]
+    fn calibration_reports_the_raw_failure() {
+        let scope = simulated_scope(0);
+        scope.run_calibration().unwrap();
+        assert!(scope.trigger_status().unwrap().is_running());
+
+        let scope = simulated_scope(3);
+        let error = scope.run_calibration().unwrap_err();
+        assert!(matches!(&error, ScopeError::CalibrationFailed(response) if response == "3"), "{}", error);
+        assert!(scope.trigger_status().unwrap().is_running());
+    }
+}
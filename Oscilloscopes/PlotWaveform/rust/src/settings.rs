@@ -0,0 +1,1066 @@
+//! Horizontal, vertical and acquisition settings.
+
+use log::{info, warn};
+
+use crate::{check_channel, DataRange, OscilloscopeWaveform, Result, ScopeError, CHANNEL_COUNT};
+
+/// Vertical scale of one channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelScale {
+    pub volts_per_div: f64,
+    pub offset: f64,
+}
+
+/// Depths tried by `supported_memory_depths`.
+const CANDIDATE_MEMORY_DEPTHS: [u32; 6] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000];
+
+/// Highest real-time sample rate of the instrument.
+const MAX_SAMPLE_RATE_HZ: f64 = 1.6e9;
+
+/// Number of horizontal divisions on screen.
+pub(crate) const HORIZONTAL_DIVISIONS: f64 = 10.0;
+/// Number of vertical divisions on screen.
+pub(crate) const VERTICAL_DIVISIONS: f64 = 8.0;
+
+/// Smallest vertical scale at the probe input, in volts per division.
+const MIN_VOLTS_PER_DIV: f64 = 1e-3;
+/// Largest vertical scale at the probe input, in volts per division.
+const MAX_VOLTS_PER_DIV: f64 = 5.0;
+
+/// Memory depth of the quick captures taken by `autoscale_vertical`.
+const AUTOSCALE_MEMORY_DEPTH: u32 = 10_000;
+/// Captures `autoscale_vertical` takes before giving up.
+const MAX_AUTOSCALE_ITERATIONS: usize = 8;
+/// Fractions of the screen height the signal should span after autoscale.
+const AUTOSCALE_MIN_FILL: f64 = 0.6;
+const AUTOSCALE_MAX_FILL: f64 = 0.9;
+
+/// Input resistance of a channel in ohms.
+const INPUT_RESISTANCE_OHM: f32 = 1e6;
+/// AC coupling capacitor in farads. With the input resistance it sets the
+/// low-frequency corner to about 7 Hz.
+const AC_COUPLING_CAPACITANCE_F: f32 = 22e-9;
+/// Resolution of the ADC in bits.
+const ADC_BITS: i32 = 12;
+/// Noise at the probe input, below which finer settling is not visible.
+const INPUT_NOISE_V: f32 = 100e-6;
+/// Fewest time constants to wait after switching to AC coupling.
+const MIN_SETTLING_TIME_CONSTANTS: f32 = 5.0;
+
+/// Time for a DC step spanning the full vertical range to decay below the
+/// visible resolution, i.e. one ADC step or the input noise.
+fn ac_settling_time_s(volts_per_div: f32, probe_attenuation: f32) -> f32 {
+    let time_constant = INPUT_RESISTANCE_OHM * AC_COUPLING_CAPACITANCE_F;
+    let full_range = volts_per_div * VERTICAL_DIVISIONS as f32;
+    let resolution = (full_range / 2f32.powi(ADC_BITS)).max(INPUT_NOISE_V * probe_attenuation);
+    let time_constants = (full_range / resolution).ln().max(MIN_SETTLING_TIME_CONSTANTS);
+    time_constant * time_constants
+}
+
+/// Smallest value of the 1-2-5 sequence at or above `value`.
+fn ceil_125(value: f64) -> f64 {
+    let decade = 10f64.powf(value.log10().floor());
+    // Tolerate rounding, so a value on a step stays there
+    [1.0, 2.0, 5.0, 10.0].into_iter()
+        .map(|mantissa| mantissa * decade)
+        .find(|&step| step >= value * (1.0 - 1e-9))
+        .unwrap_or(10.0 * decade)
+}
+
+/// The 1-2-5 step above `value`.
+fn next_125(value: f64) -> f64 {
+    ceil_125(value * (1.0 + 1e-6))
+}
+
+fn unexpected(command: &str, response: &str) -> ScopeError {
+    ScopeError::UnexpectedResponse { command: command.to_string(), response: response.to_string() }
+}
+
+/// Input coupling of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coupling {
+    Dc,
+    Ac,
+    Ground,
+}
+
+impl Coupling {
+    pub(crate) fn scpi_name(self) -> &'static str {
+        match self {
+            Coupling::Dc => "DC",
+            Coupling::Ac => "AC",
+            Coupling::Ground => "GND",
+        }
+    }
+
+    pub(crate) fn parse(response: &str) -> Result<Self> {
+        match response.to_ascii_uppercase().as_str() {
+            "DC" => Ok(Coupling::Dc),
+            "AC" => Ok(Coupling::Ac),
+            "GND" | "GROUND" => Ok(Coupling::Ground),
+            _ => Err(unexpected("CHANn:COUPling?", response)),
+        }
+    }
+}
+
+/// Physical quantity a channel measures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelUnit {
+    #[default]
+    Volt,
+    Ampere,
+    Watt,
+    Unknown,
+}
+
+impl ChannelUnit {
+    /// Unit symbol for axis labels and exports.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ChannelUnit::Volt => "V",
+            ChannelUnit::Ampere => "A",
+            ChannelUnit::Watt => "W",
+            ChannelUnit::Unknown => "U",
+        }
+    }
+}
+
+/// Conversion of a channel's voltages into its unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelScaling {
+    pub unit: ChannelUnit,
+    /// Probe output in volts per unit, e.g. 0.1 for a 100 mV/A current
+    /// probe.
+    pub scale_factor: f32,
+}
+
+impl Default for ChannelScaling {
+    fn default() -> Self {
+        Self { unit: ChannelUnit::Volt, scale_factor: 1.0 }
+    }
+}
+
+impl ChannelScaling {
+    /// Convert a voltage at the input into the channel's unit.
+    pub fn apply(&self, volts: f32) -> f32 {
+        volts / self.scale_factor
+    }
+
+    /// Whether values pass through unchanged.
+    pub fn is_identity(&self) -> bool {
+        self.scale_factor == 1.0
+    }
+}
+
+/// Attenuation of the probe connected to a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeRatio {
+    X1,
+    X2,
+    X5,
+    X10,
+    X20,
+    X50,
+    X100,
+    X200,
+    X500,
+    X1000,
+}
+
+impl ProbeRatio {
+    pub const ALL: [ProbeRatio; 10] = [
+        ProbeRatio::X1, ProbeRatio::X2, ProbeRatio::X5, ProbeRatio::X10, ProbeRatio::X20, ProbeRatio::X50,
+        ProbeRatio::X100, ProbeRatio::X200, ProbeRatio::X500, ProbeRatio::X1000,
+    ];
+
+    /// The ratio with attenuation `factor`, if there is one.
+    pub fn from_factor(factor: f32) -> Option<Self> {
+        ProbeRatio::ALL.into_iter().find(|ratio| ratio.factor() == factor)
+    }
+
+    /// Attenuation factor, e.g. 10 for a 10:1 probe.
+    pub fn factor(self) -> f32 {
+        match self {
+            ProbeRatio::X1 => 1.0,
+            ProbeRatio::X2 => 2.0,
+            ProbeRatio::X5 => 5.0,
+            ProbeRatio::X10 => 10.0,
+            ProbeRatio::X20 => 20.0,
+            ProbeRatio::X50 => 50.0,
+            ProbeRatio::X100 => 100.0,
+            ProbeRatio::X200 => 200.0,
+            ProbeRatio::X500 => 500.0,
+            ProbeRatio::X1000 => 1000.0,
+        }
+    }
+}
+
+/// Bandwidth limit filter of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthLimit {
+    Full,
+    Limit20MHz,
+    Limit200MHz,
+}
+
+impl BandwidthLimit {
+    pub(crate) fn scpi_name(self) -> &'static str {
+        match self {
+            BandwidthLimit::Full => "FULL",
+            BandwidthLimit::Limit20MHz => "20M",
+            BandwidthLimit::Limit200MHz => "200M",
+        }
+    }
+
+    pub(crate) fn parse(response: &str) -> Result<Self> {
+        match response.to_ascii_uppercase().as_str() {
+            "FULL" | "OFF" => Ok(BandwidthLimit::Full),
+            "20M" => Ok(BandwidthLimit::Limit20MHz),
+            "200M" => Ok(BandwidthLimit::Limit200MHz),
+            _ => Err(unexpected("CHANn:BWLimit?", response)),
+        }
+    }
+}
+
+/// Acquisition memory depths of the instruments. Which ones a model
+/// offers depends on its installed memory, see
+/// [`Capabilities`](crate::model::Capabilities).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryDepth {
+    Points1k,
+    Points10k,
+    Points100k,
+    Points1M,
+    Points10M,
+    Points25M,
+    Points50M,
+}
+
+impl MemoryDepth {
+    pub const ALL: [MemoryDepth; 7] = [
+        MemoryDepth::Points1k,
+        MemoryDepth::Points10k,
+        MemoryDepth::Points100k,
+        MemoryDepth::Points1M,
+        MemoryDepth::Points10M,
+        MemoryDepth::Points25M,
+        MemoryDepth::Points50M,
+    ];
+
+    /// Number of sample points.
+    pub fn points(self) -> u32 {
+        match self {
+            MemoryDepth::Points1k => 1_000,
+            MemoryDepth::Points10k => 10_000,
+            MemoryDepth::Points100k => 100_000,
+            MemoryDepth::Points1M => 1_000_000,
+            MemoryDepth::Points10M => 10_000_000,
+            MemoryDepth::Points25M => 25_000_000,
+            MemoryDepth::Points50M => 50_000_000,
+        }
+    }
+
+    /// The depth of exactly `points` points, if there is one.
+    pub fn from_points(points: u32) -> Option<Self> {
+        MemoryDepth::ALL.into_iter().find(|depth| depth.points() == points)
+    }
+
+    /// Time the memory holds at `sample_rate` samples per second.
+    pub fn capture_duration_s(&self, sample_rate: f64) -> f64 {
+        self.points() as f64 / sample_rate
+    }
+}
+
+impl From<MemoryDepth> for u32 {
+    fn from(depth: MemoryDepth) -> Self {
+        depth.points()
+    }
+}
+
+/// Horizontal position on screen that the trigger delay refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HorizRef {
+    Left,
+    Center,
+    Right,
+}
+
+impl HorizRef {
+    pub(crate) fn scpi_name(self) -> &'static str {
+        match self {
+            HorizRef::Left => "LEFT",
+            HorizRef::Center => "CENTer",
+            HorizRef::Right => "RIGHt",
+        }
+    }
+
+    pub(crate) fn parse(response: &str) -> Result<Self> {
+        match response.to_ascii_uppercase().as_str() {
+            "LEFT" => Ok(HorizRef::Left),
+            "CENT" | "CENTER" => Ok(HorizRef::Center),
+            "RIGH" | "RIGHT" => Ok(HorizRef::Right),
+            _ => Err(unexpected("TIMebase:REFerence?", response)),
+        }
+    }
+}
+
+/// Horizontal configuration of the instrument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimebaseSettings {
+    pub secs_per_div: f32,
+    /// Trigger delay relative to the reference position.
+    pub delay_s: f32,
+    pub reference: HorizRef,
+}
+
+/// Where the trigger point lies in the record, set with
+/// [`set_trigger_position`](OscilloscopeWaveform::set_trigger_position).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerPosition {
+    /// Share of the record before the trigger in percent, from 0 for a
+    /// record starting at the trigger to 100 for one ending there.
+    Percent(f32),
+    /// Time recorded before the trigger in seconds. A negative time starts
+    /// the record after the trigger, one beyond the record length ends it
+    /// before the trigger.
+    PreTrigger(f32),
+}
+
+/// How successive samples are combined into the acquired record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquisitionMode {
+    Normal,
+    /// Average over `count` triggers, at least 2.
+    Average { count: u16 },
+    /// Keep the minimum and maximum of each sample interval.
+    PeakDetect,
+    /// Average consecutive ADC samples for more vertical resolution.
+    HighRes,
+}
+
+impl AcquisitionMode {
+    fn scpi_name(self) -> &'static str {
+        match self {
+            AcquisitionMode::Normal => "NORMal",
+            AcquisitionMode::Average { .. } => "AVERage",
+            AcquisitionMode::PeakDetect => "PEAK",
+            AcquisitionMode::HighRes => "HRESolution",
+        }
+    }
+
+    /// Parse an `ACQuire:TYPE?` reply. The average count is filled in by
+    /// the caller.
+    fn parse(response: &str) -> Result<Self> {
+        match response.to_ascii_uppercase().as_str() {
+            "NORM" | "NORMAL" => Ok(AcquisitionMode::Normal),
+            "AVER" | "AVERAGE" => Ok(AcquisitionMode::Average { count: 0 }),
+            "PEAK" => Ok(AcquisitionMode::PeakDetect),
+            "HRES" | "HRESOLUTION" => Ok(AcquisitionMode::HighRes),
+            _ => Err(unexpected("ACQuire:TYPE?", response)),
+        }
+    }
+
+    /// Number of triggers a complete acquisition takes.
+    pub(crate) fn sequences(self) -> u32 {
+        match self {
+            AcquisitionMode::Average { count } => count as u32,
+            _ => 1,
+        }
+    }
+}
+
+/// Complete vertical configuration of one channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerticalSettings {
+    pub volts_per_div: f32,
+    pub offset_v: f32,
+    pub coupling: Coupling,
+    pub bandwidth_limit: BandwidthLimit,
+    /// Probe attenuation factor, e.g. 10 for a 10:1 probe.
+    pub probe_attenuation: f32,
+}
+
+/// Snapshot of the horizontal and vertical scaling of the instrument.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeSettings {
+    pub secs_per_div: f64,
+    /// Scale of channels 1 to 4, in channel order.
+    pub channels: Vec<ChannelScale>,
+}
+
+impl OscilloscopeWaveform {
+    /// Set the horizontal scale and the trigger delay.
+    ///
+    /// The scale is limited to what the current memory depth can fill at
+    /// the model's maximum sample rate, 1.6 GSa/s for models without known
+    /// limits. The instrument snaps to the nearest 1-2-5
+    /// step, so the applied scale can differ from the requested one.
+    pub fn set_timebase(&self, secs_per_div: f32, delay_s: f32) -> Result<()> {
+        if !secs_per_div.is_finite() || secs_per_div <= 0.0 {
+            return Err(ScopeError::InvalidArgument(format!("Invalid timebase {} s/div", secs_per_div)));
+        }
+        if !delay_s.is_finite() {
+            return Err(ScopeError::InvalidArgument(format!("Invalid delay {} s", delay_s)));
+        }
+
+        let max_sample_rate_hz = self.capabilities()?
+            .map_or(MAX_SAMPLE_RATE_HZ, |capabilities| capabilities.max_sample_rate_hz);
+        let memory_depth = self.memory_depth()?;
+        let max_secs_per_div = memory_depth as f64 / (HORIZONTAL_DIVISIONS * max_sample_rate_hz);
+        if secs_per_div as f64 > max_secs_per_div {
+            return Err(ScopeError::InvalidArgument(format!(
+                "Timebase {} s/div exceeds {} s/div, the maximum for {} points at {} Sa/s",
+                secs_per_div, max_secs_per_div, memory_depth, max_sample_rate_hz
+            )));
+        }
+
+        self.send_command(&format!("TIMebase:SCALe {}", secs_per_div))?;
+        self.send_command(&format!("TIMebase:DELay {}", delay_s))?;
+        self.verify_no_errors("timebase setup")?;
+        info!("Timebase: requested {} s/div, applied {} s/div, delay {} s",
+            secs_per_div, self.timebase()?, delay_s);
+        Ok(())
+    }
+
+    /// Set the screen position the trigger delay refers to.
+    pub fn set_horizontal_reference(&self, reference: HorizRef) -> Result<()> {
+        self.send_command(&format!("TIMebase:REFerence {}", reference.scpi_name()))?;
+        self.verify_no_errors("horizontal reference setup")?;
+        info!("Horizontal reference: {:?}", reference);
+        Ok(())
+    }
+
+    /// Place the trigger point in the record, e.g.
+    /// `TriggerPosition::PreTrigger(200e-6)` at 100 µs/div for 200 µs
+    /// before the trigger through 800 µs after it.
+    ///
+    /// The record length follows from the current timebase, so set that
+    /// first. The reference position moves to the left edge, where the
+    /// delay is the (negative) time of the first sample relative to the
+    /// trigger.
+    pub fn set_trigger_position(&self, position: TriggerPosition) -> Result<()> {
+        let record_s = self.timebase()? * HORIZONTAL_DIVISIONS;
+        let pre_trigger_s = match position {
+            TriggerPosition::Percent(percent) if (0.0..=100.0).contains(&percent) => record_s * percent as f64 / 100.0,
+            TriggerPosition::PreTrigger(seconds) if seconds.is_finite() => seconds as f64,
+            _ => return Err(ScopeError::InvalidArgument(format!("Invalid trigger position {:?}", position))),
+        };
+        if !(0.0..=record_s).contains(&pre_trigger_s) {
+            warn!("The trigger {} s before the record start lies outside the {} s record", pre_trigger_s, record_s);
+        }
+
+        self.set_horizontal_reference(HorizRef::Left)?;
+        self.send_command(&format!("TIMebase:DELay {}", -pre_trigger_s as f32))?;
+        self.verify_no_errors("trigger position setup")?;
+        info!("Trigger position: {} s of the {} s record before the trigger", pre_trigger_s, record_s);
+        Ok(())
+    }
+
+    /// Read the horizontal scale, delay and reference position.
+    pub fn get_timebase(&self) -> Result<TimebaseSettings> {
+        Ok(TimebaseSettings {
+            secs_per_div: self.timebase()? as f32,
+            delay_s: self.query_f64("TIMebase:DELay?")? as f32,
+            reference: HorizRef::parse(&self.query("TIMebase:REFerence?")?)?,
+        })
+    }
+
+    /// Current horizontal scale in seconds per division.
+    pub fn timebase(&self) -> Result<f64> {
+        self.query_f64("TIMebase:SCALe?")
+    }
+
+    /// Current acquisition memory depth in points.
+    pub fn memory_depth(&self) -> Result<u32> {
+        let response = self.query("ACQuire:MDEPth?")?;
+        // Large depths may be reported in exponent notation
+        response.parse::<f64>().ok()
+            .filter(|depth| depth.is_finite() && *depth >= 1.0 && *depth <= u32::MAX as f64)
+            .map(|depth| depth as u32)
+            .ok_or_else(|| unexpected("ACQuire:MDEPth?", &response))
+    }
+
+    /// Set the acquisition memory depth, a [`MemoryDepth`] or any number of
+    /// points, and return the applied value.
+    ///
+    /// Depths beyond the installed memory of the model are rejected with
+    /// `ScopeError::InvalidArgument`, see
+    /// [`capabilities`](Self::capabilities). Instruments outside the Magnova
+    /// family are not checked. The instrument clamps other unsupported depths to the
+    /// nearest one it offers, which is logged as a warning.
+    pub fn set_memory_depth(&self, depth: impl Into<u32>) -> Result<u32> {
+        let depth = depth.into();
+        if depth == 0 {
+            return Err(ScopeError::InvalidArgument("Memory depth must be positive".to_string()));
+        }
+        if let Some(capabilities) = self.capabilities()? {
+            if depth > capabilities.max_memory_depth {
+                return Err(ScopeError::InvalidArgument(format!(
+                    "Memory depth {} exceeds the {} points installed", depth, capabilities.max_memory_depth
+                )));
+            }
+        }
+        self.send_command(&format!("ACQuire:MDEPth {}", depth))?;
+        self.verify_no_errors("memory depth setup")?;
+        let applied = self.memory_depth()?;
+        if applied != depth {
+            warn!("Requested memory depth {}, instrument applied {}", depth, applied);
+        }
+        Ok(applied)
+    }
+
+    /// Highest sample rate at which `depth` points fill the current time
+    /// window, limited to the model's maximum real-time rate. Models
+    /// without known limits are assumed to sample at up to 1.6 GSa/s.
+    pub fn get_max_sample_rate_for_depth(&self, depth: MemoryDepth) -> Result<f64> {
+        let max_sample_rate_hz = self.capabilities()?
+            .map_or(MAX_SAMPLE_RATE_HZ, |capabilities| capabilities.max_sample_rate_hz);
+        let window_s = self.timebase()? * HORIZONTAL_DIVISIONS;
+        Ok((depth.points() as f64 / window_s).min(max_sample_rate_hz))
+    }
+
+    /// Find the memory depths the instrument accepts.
+    ///
+    /// There is no SCPI query listing them, so each of a range of decades
+    /// from 1k to 100M points is set and read back. The distinct applied
+    /// values are returned in ascending order and the original depth is
+    /// restored afterwards.
+    pub fn supported_memory_depths(&self) -> Result<Vec<u32>> {
+        let original = self.memory_depth()?;
+        let mut depths = Vec::new();
+        for candidate in CANDIDATE_MEMORY_DEPTHS {
+            self.send_command(&format!("ACQuire:MDEPth {}", candidate))?;
+            depths.push(self.memory_depth()?);
+        }
+        // Discard errors caused by rejected candidates
+        self.check_errors()?;
+        self.set_memory_depth(original)?;
+
+        depths.sort_unstable();
+        depths.dedup();
+        info!("Supported memory depths: {:?}", depths);
+        Ok(depths)
+    }
+
+    /// Select the acquisition mode and return the applied one.
+    ///
+    /// The mode is read back and `ScopeError::SettingRejected` returned if
+    /// the instrument did not switch. Average counts the instrument does not
+    /// support are clamped by it, which is logged as a warning and shows in
+    /// the returned mode.
+    pub fn set_acquisition_mode(&self, mode: AcquisitionMode) -> Result<AcquisitionMode> {
+        if let AcquisitionMode::Average { count } = mode {
+            if count < 2 {
+                return Err(ScopeError::InvalidArgument(
+                    format!("Averaging needs at least 2 acquisitions, got {}", count)
+                ));
+            }
+        }
+
+        self.send_command(&format!("ACQuire:TYPE {}", mode.scpi_name()))?;
+        if let AcquisitionMode::Average { count } = mode {
+            self.send_command(&format!("ACQuire:COUNt {}", count))?;
+        }
+        self.verify_no_errors("acquisition mode setup")?;
+
+        let applied = self.acquisition_mode()?;
+        if let (AcquisitionMode::Average { count }, AcquisitionMode::Average { count: applied_count }) = (mode, applied) {
+            if applied_count != count {
+                warn!("Average count {} not supported, instrument uses {}", count, applied_count);
+            }
+        } else if applied != mode {
+            return Err(ScopeError::SettingRejected {
+                setting: "Acquisition mode".to_string(),
+                requested: format!("{:?}", mode),
+                applied: format!("{:?}", applied),
+            });
+        }
+        info!("Acquisition mode: {:?}", applied);
+        Ok(applied)
+    }
+
+    /// Current acquisition mode, including the average count.
+    pub fn acquisition_mode(&self) -> Result<AcquisitionMode> {
+        match AcquisitionMode::parse(&self.query("ACQuire:TYPE?")?)? {
+            AcquisitionMode::Average { .. } => {
+                let count = self.query_f64("ACQuire:COUNt?")?;
+                if !(0.0..=u16::MAX as f64).contains(&count) {
+                    return Err(unexpected("ACQuire:COUNt?", &count.to_string()));
+                }
+                Ok(AcquisitionMode::Average { count: count as u16 })
+            }
+            mode => Ok(mode),
+        }
+    }
+
+    /// Set the vertical scale of a channel and return the applied value.
+    ///
+    /// The scale must lie in the range of [`set_vertical`](Self::set_vertical).
+    pub fn set_vertical_scale(&self, channel: u8, volts_per_div: f64) -> Result<f64> {
+        self.require_channel(channel)?;
+        self.check_vertical_scale(channel, volts_per_div)?;
+        self.send_command(&format!("CHAN{}:SCALe {}", channel, volts_per_div))?;
+        self.verify_no_errors("vertical scale setup")?;
+        let actual = self.vertical_scale(channel)?;
+        info!("Channel {} scale: requested {} V/div, applied {} V/div", channel, volts_per_div, actual);
+        Ok(actual)
+    }
+
+    /// Current vertical scale of a channel in volts per division.
+    pub fn vertical_scale(&self, channel: u8) -> Result<f64> {
+        check_channel(channel)?;
+        self.query_f64(&format!("CHAN{}:SCALe?", channel))
+    }
+
+    /// Set the vertical offset of a channel and return the applied value.
+    pub fn set_vertical_offset(&self, channel: u8, volts: f64) -> Result<f64> {
+        self.require_channel(channel)?;
+        self.send_command(&format!("CHAN{}:OFFSet {}", channel, volts))?;
+        self.verify_no_errors("vertical offset setup")?;
+        let actual = self.vertical_offset(channel)?;
+        info!("Channel {} offset: requested {} V, applied {} V", channel, volts, actual);
+        Ok(actual)
+    }
+
+    /// Current vertical offset of a channel in volts.
+    pub fn vertical_offset(&self, channel: u8) -> Result<f64> {
+        check_channel(channel)?;
+        self.query_f64(&format!("CHAN{}:OFFSet?", channel))
+    }
+
+    /// Set scale and offset of a channel in one go.
+    ///
+    /// The allowed scale range grows with the probe attenuation: 1 mV/div
+    /// to 5 V/div at the probe input, so up to 50 V/div with a 10:1 probe.
+    pub fn set_vertical(&self, channel: u8, volts_per_div: f64, offset_v: f64) -> Result<()> {
+        self.require_channel(channel)?;
+        self.check_vertical_scale(channel, volts_per_div)?;
+        if !offset_v.is_finite() {
+            return Err(ScopeError::InvalidArgument(format!("Invalid offset {} V", offset_v)));
+        }
+
+        self.send_command(&format!("CHAN{}:SCALe {}", channel, volts_per_div))?;
+        self.send_command(&format!("CHAN{}:OFFSet {}", channel, offset_v))?;
+        self.verify_no_errors("vertical setup")?;
+        info!("Channel {}: {} V/div, offset {} V", channel, volts_per_div, offset_v);
+        Ok(())
+    }
+
+    /// Fail unless `volts_per_div` is in the scale range for the probe on
+    /// `channel`.
+    fn check_vertical_scale(&self, channel: u8, volts_per_div: f64) -> Result<()> {
+        let probe = self.probe_attenuation(channel)? as f64;
+        let (min, max) = (MIN_VOLTS_PER_DIV * probe, MAX_VOLTS_PER_DIV * probe);
+        if !(min..=max).contains(&volts_per_div) {
+            return Err(ScopeError::InvalidArgument(format!(
+                "Channel {} scale {} V/div out of range {} to {} V/div for a {}:1 probe",
+                channel, volts_per_div, min, max, probe
+            )));
+        }
+        Ok(())
+    }
+
+    /// Adjust the vertical scale of a channel to the signal, so RAW captures
+    /// use most of the ADC codes, and return the volts per division chosen.
+    ///
+    /// Quick captures of 10k points are taken, and the scale is set to the
+    /// 1-2-5 step at which the peak-to-peak amplitude spans most of the
+    /// screen, at most 90 %. The offset is moved to the signal's mean. A
+    /// clipped capture increases the scale first. After 8 captures, or if
+    /// the scale would return to a step already tried, the larger of the
+    /// last two steps is kept. The memory depth is restored afterwards, also
+    /// if autoscaling fails.
+    pub fn autoscale_vertical(&self, channel: u8) -> Result<f64> {
+        check_channel(channel)?;
+        let probe = self.probe_attenuation(channel)? as f64;
+        let (min_scale, max_scale) = (MIN_VOLTS_PER_DIV * probe, MAX_VOLTS_PER_DIV * probe);
+        let memory_depth = self.memory_depth()?;
+        let search = || -> Result<f64> {
+            let mut scale = self.vertical_scale(channel)?;
+            let mut tried = Vec::new();
+
+            'search: {
+                for _ in 0..MAX_AUTOSCALE_ITERATIONS {
+                    let mut record = self.get_waveform_record(channel, DataRange::All, Some(AUTOSCALE_MEMORY_DEPTH))?;
+                    // The scale applies to volts at the input, whatever the channel's unit
+                    record.scaling = ChannelScaling::default();
+                    tried.push(scale);
+                    let (min, max, sum) = record.voltages()
+                        .fold((f64::INFINITY, f64::NEG_INFINITY, 0.0), |(min, max, sum), v| {
+                            (min.min(v as f64), max.max(v as f64), sum + v as f64)
+                        });
+                    if record.raw_codes.is_empty() {
+                        warn!("Autoscale of channel {} received no samples", channel);
+                        break 'search;
+                    }
+                    let peak_to_peak = max - min;
+                    let fill = peak_to_peak / (scale * VERTICAL_DIVISIONS);
+                    self.set_vertical_offset(channel, sum / record.raw_codes.len() as f64)?;
+
+                    let clipped = record.clipped_samples() > 0;
+                    let wanted = ceil_125(peak_to_peak / (AUTOSCALE_MAX_FILL * VERTICAL_DIVISIONS));
+                    let next = if clipped {
+                        // The amplitude is at least what was captured
+                        info!("Channel {} clipped at {} V/div", channel, scale);
+                        wanted.max(next_125(scale))
+                    } else {
+                        info!("Channel {} spans {:.0} % of the screen at {} V/div", channel, fill * 100.0, scale);
+                        if (AUTOSCALE_MIN_FILL..=AUTOSCALE_MAX_FILL).contains(&fill) {
+                            break 'search;
+                        }
+                        wanted
+                    }.clamp(min_scale, max_scale);
+
+                    if (next - scale).abs() <= scale * 1e-6 {
+                        if clipped {
+                            warn!("Channel {} clips even at the largest scale of {} V/div", channel, scale);
+                        }
+                        break 'search;
+                    }
+                    if tried.iter().any(|&step| (step - next).abs() <= step * 1e-6) {
+                        // Alternating between two steps; the larger one does not clip
+                        if next > scale {
+                            scale = self.set_vertical_scale(channel, next)?;
+                        }
+                        info!("Channel {} autoscale settled between two steps", channel);
+                        break 'search;
+                    }
+                    scale = self.set_vertical_scale(channel, next)?;
+                }
+                warn!("Channel {} autoscale did not settle after {} captures", channel, MAX_AUTOSCALE_ITERATIONS);
+            }
+            Ok(scale)
+        };
+
+        // The depth of the quick captures must not stay set after a failure
+        let scale = search();
+        if let Err(e) = &scale {
+            if e.is_out_of_step() {
+                // The broken block is still pending
+                self.resynchronize()?;
+            }
+        }
+        if let Err(e) = self.set_memory_depth(memory_depth) {
+            if scale.is_ok() {
+                return Err(e);
+            }
+            warn!("Could not restore the memory depth of {}: {}", memory_depth, e);
+        }
+        let scale = scale?;
+        info!("Channel {} autoscaled to {} V/div", channel, scale);
+        Ok(scale)
+    }
+
+    /// Read the complete vertical configuration of a channel.
+    pub fn get_vertical(&self, channel: u8) -> Result<VerticalSettings> {
+        check_channel(channel)?;
+        Ok(VerticalSettings {
+            volts_per_div: self.vertical_scale(channel)? as f32,
+            offset_v: self.vertical_offset(channel)? as f32,
+            coupling: Coupling::parse(&self.query(&format!("CHAN{}:COUPling?", channel))?)?,
+            bandwidth_limit: BandwidthLimit::parse(&self.query(&format!("CHAN{}:BWLimit?", channel))?)?,
+            probe_attenuation: self.probe_attenuation(channel)?,
+        })
+    }
+
+    /// Set the probe attenuation of a channel.
+    ///
+    /// The value is read back, so a ratio the model does not support fails
+    /// with `ScopeError::SettingRejected` instead of silently scaling wrong.
+    pub fn set_probe_attenuation(&self, channel: u8, ratio: ProbeRatio) -> Result<()> {
+        self.require_channel(channel)?;
+        self.send_command(&format!("CHAN{}:PROBe {}", channel, ratio.factor()))?;
+        self.verify_no_errors("probe setup")?;
+
+        let applied = self.probe_attenuation(channel)?;
+        if (applied - ratio.factor()).abs() > ratio.factor() * 1e-3 {
+            return Err(ScopeError::SettingRejected {
+                setting: format!("Channel {} probe attenuation", channel),
+                requested: format!("{}:1", ratio.factor()),
+                applied: format!("{}:1", applied),
+            });
+        }
+        info!("Channel {} probe: {}:1", channel, applied);
+        Ok(())
+    }
+
+    /// Set the input coupling of a channel and verify it was applied.
+    pub fn set_coupling(&self, channel: u8, coupling: Coupling) -> Result<()> {
+        self.require_channel(channel)?;
+        self.send_command(&format!("CHAN{}:COUPling {}", channel, coupling.scpi_name()))?;
+        self.verify_no_errors("coupling setup")?;
+
+        let applied = Coupling::parse(&self.query(&format!("CHAN{}:COUPling?", channel))?)?;
+        if applied != coupling {
+            return Err(ScopeError::SettingRejected {
+                setting: format!("Channel {} coupling", channel),
+                requested: format!("{:?}", coupling),
+                applied: format!("{:?}", applied),
+            });
+        }
+        info!("Channel {} coupling: {:?}", channel, applied);
+        if applied == Coupling::Ac {
+            warn!("AC coupling on channel {}; allow ~5 time constants for settling", channel);
+        }
+        Ok(())
+    }
+
+    /// Set the bandwidth limit filter of a channel and verify it was
+    /// applied.
+    pub fn set_bandwidth_limit(&self, channel: u8, limit: BandwidthLimit) -> Result<()> {
+        self.require_channel(channel)?;
+        self.send_command(&format!("CHAN{}:BWLimit {}", channel, limit.scpi_name()))?;
+        self.verify_no_errors("bandwidth limit setup")?;
+
+        let applied = BandwidthLimit::parse(&self.query(&format!("CHAN{}:BWLimit?", channel))?)?;
+        if applied != limit {
+            return Err(ScopeError::SettingRejected {
+                setting: format!("Channel {} bandwidth limit", channel),
+                requested: format!("{:?}", limit),
+                applied: format!("{:?}", applied),
+            });
+        }
+        info!("Channel {} bandwidth limit: {:?}", channel, applied);
+        Ok(())
+    }
+
+    /// Estimated time until an AC coupled channel has settled after a DC
+    /// step, in seconds.
+    ///
+    /// The coupling time constant is about 22 ms. The number of time
+    /// constants to wait depends on the current vertical range: a step
+    /// across the full range has to decay below one ADC step or the input
+    /// noise, whichever is larger, which takes between 5 and about 8 time
+    /// constants.
+    pub fn estimated_ac_settling_time_s(&self, channel: u8) -> Result<f32> {
+        let volts_per_div = self.vertical_scale(channel)? as f32;
+        let probe = self.probe_attenuation(channel)?;
+        Ok(ac_settling_time_s(volts_per_div, probe))
+    }
+
+    /// Invert a channel on the instrument and verify it was applied.
+    pub fn set_channel_invert(&self, channel: u8, invert: bool) -> Result<()> {
+        self.require_channel(channel)?;
+        self.send_command(&format!("CHAN{}:INVert {}", channel, if invert { 1 } else { 0 }))?;
+        self.verify_no_errors("channel invert setup")?;
+
+        let applied = self.get_channel_invert(channel)?;
+        if applied != invert {
+            return Err(ScopeError::SettingRejected {
+                setting: format!("Channel {} invert", channel),
+                requested: invert.to_string(),
+                applied: applied.to_string(),
+            });
+        }
+        info!("Channel {} inverted: {}", channel, applied);
+        Ok(())
+    }
+
+    /// Whether a channel is inverted on the instrument.
+    pub fn get_channel_invert(&self, channel: u8) -> Result<bool> {
+        check_channel(channel)?;
+        let command = format!("CHAN{}:INVert?", channel);
+        let response = self.query(&command)?;
+        match response.to_ascii_uppercase().as_str() {
+            "1" | "ON" => Ok(true),
+            "0" | "OFF" => Ok(false),
+            _ => Err(unexpected(&command, &response)),
+        }
+    }
+
+    /// Convert the samples of a channel into `unit` when they are read.
+    ///
+    /// `scale_factor` is the probe output in volts per unit, e.g. 0.1 for
+    /// a 100 mV/A current probe. The conversion is done on this side after
+    /// the samples are decoded to volts, the instrument is not changed.
+    pub fn set_channel_unit(&self, channel: u8, unit: ChannelUnit, scale_factor: f32) -> Result<()> {
+        check_channel(channel)?;
+        if !scale_factor.is_finite() || scale_factor <= 0.0 {
+            return Err(ScopeError::InvalidArgument(format!("Invalid scale factor {} V/{}", scale_factor, unit.symbol())));
+        }
+        self.channel_scaling[(channel - 1) as usize].set(ChannelScaling { unit, scale_factor });
+        info!("Channel {} unit: {} at {} V/{}", channel, unit.symbol(), scale_factor, unit.symbol());
+        Ok(())
+    }
+
+    /// Unit conversion applied to the samples of a channel.
+    pub fn channel_scaling(&self, channel: u8) -> Result<ChannelScaling> {
+        check_channel(channel)?;
+        Ok(self.channel_scaling[(channel - 1) as usize].get())
+    }
+
+    fn probe_attenuation(&self, channel: u8) -> Result<f32> {
+        let command = format!("CHAN{}:PROBe?", channel);
+        let ratio = self.query_f64(&command)? as f32;
+        if !ratio.is_finite() || ratio <= 0.0 {
+            return Err(unexpected(&command, &ratio.to_string()));
+        }
+        Ok(ratio)
+    }
+
+    /// Read the current timebase and the scale of every channel.
+    pub fn capture_settings(&self) -> Result<ScopeSettings> {
+        let channels = (1..=CHANNEL_COUNT)
+            .map(|channel| Ok(ChannelScale {
+                volts_per_div: self.vertical_scale(channel)?,
+                offset: self.vertical_offset(channel)?,
+            }))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ScopeSettings {
+            secs_per_div: self.timebase()?,
+            channels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+    use crate::TransferFormat;
+
+    #[test]
+    fn parses_acquisition_modes() {
+        assert_eq!(AcquisitionMode::parse("NORM").unwrap(), AcquisitionMode::Normal);
+        assert_eq!(AcquisitionMode::parse("hres").unwrap(), AcquisitionMode::HighRes);
+        assert_eq!(AcquisitionMode::parse("AVERage").unwrap(), AcquisitionMode::Average { count: 0 });
+        assert!(AcquisitionMode::parse("ENV").is_err());
+        assert_eq!(AcquisitionMode::Average { count: 16 }.sequences(), 16);
+        assert_eq!(AcquisitionMode::PeakDetect.sequences(), 1);
+    }
+
+    #[test]
+    fn converts_current_probe_voltages() {
+        let scaling = ChannelScaling { unit: ChannelUnit::Ampere, scale_factor: 0.1 };
+        assert_eq!(scaling.apply(0.25), 2.5);
+        assert!(!scaling.is_identity());
+        assert!(ChannelScaling::default().is_identity());
+    }
+
+    #[test]
+    fn round_trips_bandwidth_limits() {
+        for limit in [BandwidthLimit::Full, BandwidthLimit::Limit20MHz, BandwidthLimit::Limit200MHz] {
+            assert_eq!(BandwidthLimit::parse(limit.scpi_name()).unwrap(), limit);
+        }
+        assert_eq!(BandwidthLimit::parse("off").unwrap(), BandwidthLimit::Full);
+    }
+
+    #[test]
+    fn limits_memory_depth_to_the_model() {
+        let config = SimulationConfig { time_span_s: 1e-3, ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        assert_eq!(scope.set_memory_depth(MemoryDepth::Points10M).unwrap(), 10_000_000);
+        assert!(matches!(scope.set_memory_depth(100_000_000u32), Err(ScopeError::InvalidArgument(_))));
+        assert_eq!(scope.memory_depth().unwrap(), 10_000_000);
+
+        // 1k points fill 1 ms at 1 MSa/s, 50M points would need 50 GSa/s
+        assert_eq!(scope.get_max_sample_rate_for_depth(MemoryDepth::Points1k).unwrap(), 1e6);
+        assert_eq!(scope.get_max_sample_rate_for_depth(MemoryDepth::Points50M).unwrap(), MAX_SAMPLE_RATE_HZ);
+        assert_eq!(MemoryDepth::Points1M.capture_duration_s(1e9), 1e-3);
+
+        // Instruments outside the Magnova family are left to clamp the depth
+        let config = SimulationConfig { model: "DS1054Z", ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        assert_eq!(scope.set_memory_depth(100_000_000u32).unwrap(), 100_000_000);
+    }
+
+    #[test]
+    fn limits_timebase_to_the_model_sample_rate() {
+        // 10k points at the 1 GSa/s of the Magnova 206 fill 1 µs/div, at the
+        // 1.6 GSa/s of the base model only 0.625 µs/div
+        let config = SimulationConfig { model: "Magnova 206", ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        scope.set_timebase(8e-7, 0.0).unwrap();
+        assert!(matches!(scope.set_timebase(2e-6, 0.0), Err(ScopeError::InvalidArgument(_))));
+
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        assert!(matches!(scope.set_timebase(8e-7, 0.0), Err(ScopeError::InvalidArgument(_))));
+        assert!(scope.timebase().unwrap() > 1e-6);
+    }
+
+    #[test]
+    fn rounds_to_one_two_five_steps() {
+        for (value, step) in [(0.083, 0.1), (0.5, 0.5), (0.51, 1.0), (3.0, 5.0), (7.0, 10.0), (1e-3, 1e-3)] {
+            assert!((ceil_125(value) - step).abs() < step * 1e-9, "{} gave {}", value, ceil_125(value));
+        }
+        assert!((next_125(0.5) - 1.0).abs() < 1e-9);
+        assert!((next_125(2.0) - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_vertical_scales_out_of_range() {
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        for scale in [f64::NAN, f64::INFINITY, 0.0, -0.1, 1e-4, 6.0] {
+            assert!(matches!(scope.set_vertical_scale(1, scale), Err(ScopeError::InvalidArgument(_))), "{}", scale);
+            assert!(matches!(scope.set_vertical(1, scale, 0.0), Err(ScopeError::InvalidArgument(_))), "{}", scale);
+        }
+        assert_eq!(scope.vertical_scale(1).unwrap(), 0.5);
+        assert_eq!(scope.set_vertical_scale(1, 5.0).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn autoscales_small_and_clipped_signals() {
+        // 0.6 V peak-to-peak span 75 % of the screen at 100 mV/div
+        let config = SimulationConfig {
+            amplitude_v: 0.3,
+            offset_v: 0.2,
+            memory_depth: 100_000,
+            ..SimulationConfig::default()
+        };
+        for initial in [5.0, 5e-3] {
+            let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+            scope.set_vertical_scale(1, initial).unwrap();
+            let scale = scope.autoscale_vertical(1).unwrap();
+            assert!((scale - 0.1).abs() < 1e-9, "{} V/div from {} V/div", scale, initial);
+            assert_eq!(scope.vertical_scale(1).unwrap(), scale);
+            assert!((scope.vertical_offset(1).unwrap() - 0.2).abs() < 0.01);
+            assert_eq!(scope.memory_depth().unwrap(), 100_000);
+        }
+    }
+
+    #[test]
+    fn autoscale_restores_the_memory_depth_after_a_failure() {
+        // The second capture breaks off, after the scale was changed once
+        let config = SimulationConfig {
+            amplitude_v: 0.3,
+            memory_depth: 100_000,
+            corrupt_captures_from: Some(1),
+            ..SimulationConfig::default()
+        };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        scope.set_vertical_scale(1, 5.0).unwrap();
+        assert!(matches!(scope.autoscale_vertical(1), Err(ScopeError::InvalidHeader { got: b'X' })));
+        assert!(scope.vertical_scale(1).unwrap() < 5.0);
+        assert_eq!(scope.memory_depth().unwrap(), 100_000);
+    }
+
+    #[test]
+    fn positions_the_trigger_in_the_record() {
+        // 5 ms over 10000 points, 0.5 us per sample
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        scope.set_trigger_position(TriggerPosition::PreTrigger(1e-3)).unwrap();
+        let (time, _) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Volts, None).unwrap();
+        assert!((time[0] + 1e-3).abs() < 1e-9, "{}", time[0]);
+        assert!((time[time.len() - 1] - 3.9995e-3).abs() < 1e-9, "{}", time[time.len() - 1]);
+
+        scope.set_trigger_position(TriggerPosition::Percent(100.0)).unwrap();
+        let timebase = scope.get_timebase().unwrap();
+        assert_eq!((timebase.delay_s, timebase.reference), (-5e-3, HorizRef::Left));
+        for invalid in [TriggerPosition::Percent(101.0), TriggerPosition::PreTrigger(f32::NAN)] {
+            assert!(matches!(scope.set_trigger_position(invalid), Err(ScopeError::InvalidArgument(_))));
+        }
+    }
+
+    #[test]
+    fn settling_takes_longer_on_coarse_ranges() {
+        let time_constant = INPUT_RESISTANCE_OHM * AC_COUPLING_CAPACITANCE_F;
+        // 8 mV full range against 100 uV noise needs less than the minimum
+        assert_eq!(ac_settling_time_s(1e-3, 1.0), 5.0 * time_constant);
+        // Above the noise the ADC resolution limits, ln(4096) time constants
+        let coarse = ac_settling_time_s(5.0, 1.0);
+        assert!((coarse / time_constant - 4096f32.ln()).abs() < 1e-4);
+        // The probe scales the noise with the range
+        assert_eq!(ac_settling_time_s(10e-3, 10.0), ac_settling_time_s(1e-3, 1.0));
+    }
+}
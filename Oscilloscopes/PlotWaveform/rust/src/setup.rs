@@ -0,0 +1,235 @@
+//! Saving and restoring the complete instrument setup.
+//!
+//! A setup file holds the block returned by `SYSTem:SET?` together with
+//! the model and firmware it came from:
+//!
+//! ```text
+//! magic     8 bytes  "BXSETUP" followed by the format version 1
+//! model     u16 length + UTF-8
+//! firmware  u16 length + UTF-8
+//! setup     u32 length + instrument data
+//! checksum  u32 CRC-32 of everything before it
+//! ```
+//!
+//! All integers are little-endian.
+//!
+//! [`InstrumentState`] is the text alternative: the `*LRN?` learn string,
+//! a sequence of SCPI commands that recreates the front panel settings.
+
+use std::fs;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{OscilloscopeWaveform, Result, ScopeError};
+
+const MAGIC: &[u8; 8] = b"BXSETUP\x01";
+
+/// Instrument setup together with the instrument it was saved from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetupFile {
+    pub model: String,
+    pub firmware: String,
+    /// Opaque setup block as returned by the instrument.
+    pub data: Vec<u8>,
+}
+
+/// Front panel settings as returned by `*LRN?`.
+///
+/// Serializes as a plain JSON string.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InstrumentState(pub String);
+
+/// CRC-32 as used by zip and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn invalid(message: impl Into<String>) -> ScopeError {
+    ScopeError::InvalidSetupFile(message.into())
+}
+
+/// Reads the length-prefixed fields of a setup file.
+struct FieldReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, len: usize, field: &str) -> Result<&'a [u8]> {
+        let bytes = self.position.checked_add(len)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| invalid(format!("truncated in {}", field)))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn text(&mut self, field: &str) -> Result<String> {
+        let len = LittleEndian::read_u16(self.take(2, field)?) as usize;
+        let bytes = self.take(len, field)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid(format!("{} is not UTF-8", field)))
+    }
+}
+
+impl SetupFile {
+    /// Serialize into the file format.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + self.model.len() + self.firmware.len() + self.data.len() + 12);
+        bytes.extend_from_slice(MAGIC);
+        for text in [&self.model, &self.firmware] {
+            let len = u16::try_from(text.len()).map_err(|_| invalid(format!("identity field too long: {}", text)))?;
+            bytes.extend_from_slice(&len.to_le_bytes());
+            bytes.extend_from_slice(text.as_bytes());
+        }
+        let len = u32::try_from(self.data.len()).map_err(|_| invalid("setup data too long"))?;
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Parse the file format, checking length fields and checksum.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a setup file"));
+        }
+        if bytes.len() < MAGIC.len() + 4 {
+            return Err(invalid("truncated"));
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(content) != LittleEndian::read_u32(checksum) {
+            return Err(invalid("checksum mismatch, the file is corrupt or truncated"));
+        }
+
+        let mut reader = FieldReader { data: content, position: MAGIC.len() };
+        let model = reader.text("model")?;
+        let firmware = reader.text("firmware")?;
+        let len = LittleEndian::read_u32(reader.take(4, "setup length")?) as usize;
+        let data = reader.take(len, "setup data")?.to_vec();
+        if reader.position != content.len() {
+            return Err(invalid(format!("{} unexpected bytes after the setup data", content.len() - reader.position)));
+        }
+        Ok(Self { model, firmware, data })
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Save the current instrument setup to `path`.
+    pub fn save_setup(&self, path: impl AsRef<Path>) -> Result<()> {
+        let identity = self.identity()?;
+        self.send_command("SYSTem:SET?")?;
+        let data = self.read_binary_block()?;
+        if data.is_empty() {
+            return Err(ScopeError::UnexpectedResponse {
+                command: "SYSTem:SET?".to_string(),
+                response: "empty block".to_string(),
+            });
+        }
+
+        let setup = SetupFile { model: identity.model, firmware: identity.firmware, data };
+        fs::write(path.as_ref(), setup.encode()?)?;
+        info!("Saved {} byte setup of {} to {}", setup.data.len(), setup.model, path.as_ref().display());
+        Ok(())
+    }
+
+    /// Restore an instrument setup saved with [`save_setup`](Self::save_setup).
+    ///
+    /// The file is checked completely before anything is sent. A setup
+    /// from a different model or firmware is still loaded, with a warning.
+    pub fn load_setup(&self, path: impl AsRef<Path>) -> Result<()> {
+        let setup = SetupFile::decode(&fs::read(path.as_ref())?)?;
+        let identity = self.identity()?;
+        if setup.model != identity.model {
+            warn!("Setup was saved on a {}, loading it onto a {}", setup.model, identity.model);
+        } else if setup.firmware != identity.firmware {
+            warn!("Setup was saved with firmware {}, instrument runs {}", setup.firmware, identity.firmware);
+        }
+
+        self.write_binary_block("SYSTem:SET", &setup.data)?;
+        self.verify_no_errors("setup restore")?;
+        info!("Loaded setup from {}", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Read the front panel settings with `*LRN?`.
+    pub fn save_state(&self) -> Result<InstrumentState> {
+        let state = self.query("*LRN?")?;
+        if state.is_empty() {
+            return Err(ScopeError::UnexpectedResponse { command: "*LRN?".to_string(), response: state });
+        }
+        Ok(InstrumentState(state))
+    }
+
+    /// Send settings read with [`save_state`](Self::save_state) back to
+    /// the instrument.
+    pub fn restore_state(&self, state: &InstrumentState) -> Result<()> {
+        self.send_command(&state.0)?;
+        self.verify_no_errors("state restore")
+    }
+
+    /// Save the front panel settings to `path` as JSON.
+    pub fn save_state_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let state = self.save_state()?;
+        let json = serde_json::to_string(&state).map_err(|e| invalid(e.to_string()))?;
+        fs::write(path.as_ref(), json)?;
+        info!("Saved instrument state to {}", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Restore front panel settings saved with
+    /// [`save_state_to_file`](Self::save_state_to_file).
+    pub fn restore_state_from_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let state: InstrumentState = serde_json::from_slice(&fs::read(path.as_ref())?)
+            .map_err(|e| invalid(e.to_string()))?;
+        self.restore_state(&state)?;
+        info!("Restored instrument state from {}", path.as_ref().display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> SetupFile {
+        SetupFile { model: "Magnova".to_string(), firmware: "1.2.3".to_string(), data: (0..=255).collect() }
+    }
+
+    #[test]
+    fn round_trips_setup_files() {
+        let bytes = sample().encode().unwrap();
+        assert_eq!(SetupFile::decode(&bytes).unwrap(), sample());
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_files() {
+        let bytes = sample().encode().unwrap();
+        for len in 0..bytes.len() {
+            assert!(matches!(SetupFile::decode(&bytes[..len]), Err(ScopeError::InvalidSetupFile(_))), "length {}", len);
+        }
+        for position in [0, 9, 20, bytes.len() - 1] {
+            let mut corrupt = bytes.clone();
+            corrupt[position] ^= 0x10;
+            assert!(SetupFile::decode(&corrupt).is_err(), "flipped byte {}", position);
+        }
+    }
+
+    #[test]
+    fn serializes_state_as_string() {
+        let state = InstrumentState(":TIM:SCAL 1E-3;:CHAN1:SCAL 1".to_string());
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(json, "\":TIM:SCAL 1E-3;:CHAN1:SCAL 1\"");
+        assert_eq!(serde_json::from_str::<InstrumentState>(&json).unwrap(), state);
+    }
+}
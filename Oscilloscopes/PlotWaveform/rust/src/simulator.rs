@@ -0,0 +1,824 @@
+//! A simulated instrument for development and tests without hardware.
+//!
+//! [`SimulatedScope`] answers the SCPI commands used for waveform captures
+//! and sends synthetic sine waves in the instrument's RAW and `V` block
+//! formats:
+//!
+//! ```no_run
+//! use oscilloscope_waveform::simulator::{SimulatedScope, SimulationConfig};
+//! use oscilloscope_waveform::{DataRange, OscilloscopeWaveform, TransferFormat};
+//!
+//! let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+//! let (time, voltage) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, Some(10_000))?;
+//! # Ok::<(), oscilloscope_waveform::ScopeError>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::io;
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::settings::HORIZONTAL_DIVISIONS;
+use crate::transport::Transport;
+use crate::{Result, CHANNEL_COUNT};
+
+/// `*IDN?` reply of the simulator with the default model.
+pub const SIMULATOR_IDN: &str = "Batronix,Magnova Simulator,SIM00001,1.0";
+
+/// Shape of the simulated signals.
+///
+/// Channel n carries the sine delayed by n - 1 quarter periods, so
+/// multi-channel captures show distinct, aligned traces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimulationConfig {
+    pub frequency_hz: f64,
+    /// Peak amplitude in volts.
+    pub amplitude_v: f64,
+    pub offset_v: f64,
+    /// Peak amplitude of uniform noise added to every sample, in volts.
+    pub noise_v: f64,
+    /// Time span of a capture, centred on the trigger until
+    /// `TIMebase:DELay` or `TIMebase:REFerence` move it. The sample rate
+    /// follows from the memory depth.
+    pub time_span_s: f64,
+    /// Memory depth until `ACQuire:MDEPth` changes it.
+    pub memory_depth: u32,
+    /// Vertical scale until `CHAN<n>:SCALe` changes it. The RAW code range
+    /// spans 10 divisions centred on the channel offset, 0 V at first.
+    pub volts_per_div: f64,
+    /// Seed of the noise generator, for reproducible captures.
+    pub seed: u64,
+    /// Whether the signal meets the trigger condition. Without a trigger,
+    /// `SINGle` waits until `TFORce` and `SEQuence:WAIT?` never completes.
+    pub triggered: bool,
+    /// Response of `*TST?` and `*CAL?`, 0 for a pass.
+    pub self_test_code: u32,
+    /// Model field of the `*IDN?` reply, e.g. `Magnova 254` for the
+    /// capabilities of that variant.
+    pub model: &'static str,
+    /// Trigger events a segmented acquisition captured. Up to
+    /// `ACQuire:SEGMented:COUNt` of them are stored.
+    pub segments: u32,
+    /// Segment whose `DATa:PACK?` response has a broken block header, to
+    /// test the recovery from a failed read.
+    pub corrupt_segment: Option<u32>,
+    /// First `DATa:PACK?` response outside segmented acquisition, counted
+    /// from 0, that has a broken block header, as have all after it.
+    pub corrupt_captures_from: Option<u32>,
+    /// Reply of `FORMat:BORDer?`, `None` for a firmware without the query.
+    /// Data blocks stay little-endian whatever it says.
+    pub byte_order: Option<&'static str>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            frequency_hz: 1e3,
+            amplitude_v: 1.0,
+            offset_v: 0.0,
+            noise_v: 0.02,
+            time_span_s: 5e-3,
+            memory_depth: 10_000,
+            volts_per_div: 0.5,
+            seed: 1,
+            triggered: true,
+            self_test_code: 0,
+            model: "Magnova Simulator",
+            segments: 100,
+            corrupt_segment: None,
+            corrupt_captures_from: None,
+            byte_order: None,
+        }
+    }
+}
+
+/// Settings of the simulated waveform generator, in the short forms of
+/// their SCPI values.
+#[derive(Debug)]
+struct SimulatedAwg {
+    function: &'static str,
+    frequency_hz: f64,
+    amplitude_vpp: f64,
+    offset_v: f64,
+    phase_deg: f64,
+    duty_cycle: f64,
+    symmetry: f64,
+    /// Block last sent with `AWG:ARBitrary:DATA`
+    arbitrary: Vec<u8>,
+    output: bool,
+}
+
+impl Default for SimulatedAwg {
+    fn default() -> Self {
+        Self {
+            function: "SIN",
+            frequency_hz: 1e3,
+            amplitude_vpp: 1.0,
+            offset_v: 0.0,
+            phase_deg: 0.0,
+            duty_cycle: 50.0,
+            symmetry: 50.0,
+            arbitrary: Vec::new(),
+            output: false,
+        }
+    }
+}
+
+impl SimulatedAwg {
+    /// The numeric setting `name` of `AWG:<name>[?]` stands for.
+    fn setting(&mut self, name: &str) -> Option<&mut f64> {
+        match name {
+            "FREQ" | "FREQUENCY" => Some(&mut self.frequency_hz),
+            "AMPL" | "AMPLITUDE" => Some(&mut self.amplitude_vpp),
+            "OFFS" | "OFFSET" => Some(&mut self.offset_v),
+            "PHAS" | "PHASE" => Some(&mut self.phase_deg),
+            "SQU:DCYC" | "SQUARE:DCYCLE" => Some(&mut self.duty_cycle),
+            "RAMP:SYMM" | "RAMP:SYMMETRY" => Some(&mut self.symmetry),
+            _ => None,
+        }
+    }
+}
+
+/// Position of a definite-length block argument within a command.
+struct BlockArgument {
+    /// Index of the `#`
+    header: usize,
+    data: Range<usize>,
+}
+
+/// Length of the next complete command in `input` up to and including its
+/// newline, and its definite-length block argument if it has one. The
+/// block data may contain newlines. `None` until the command is complete.
+fn next_command(input: &[u8]) -> Option<(usize, Option<BlockArgument>)> {
+    let mut block = None;
+    let mut index = 0;
+    while index < input.len() {
+        let header_digits = input.get(index + 1).filter(|digit| (b'1'..=b'9').contains(digit));
+        match (input[index], header_digits) {
+            (b'\n', _) => return Some((index + 1, block)),
+            (b'#', Some(&digits)) if block.is_none() && index > 0 && input[index - 1] == b' ' => {
+                let start = index + 2 + (digits - b'0') as usize;
+                let length = std::str::from_utf8(input.get(index + 2..start)?).ok()?.parse::<usize>().ok()?;
+                if input.len() < start + length {
+                    return None;
+                }
+                block = Some(BlockArgument { header: index, data: start..start + length });
+                index = start + length;
+            }
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+#[derive(Debug)]
+struct SimulatorState {
+    config: SimulationConfig,
+    memory_depth: u32,
+    channel_enabled: [bool; CHANNEL_COUNT as usize],
+    volts_per_div: [f64; CHANNEL_COUNT as usize],
+    offset_v: [f64; CHANNEL_COUNT as usize],
+    probe: [f64; CHANNEL_COUNT as usize],
+    /// `CHAN<n>:COUPling` and `CHAN<n>:BWLimit` in their short forms
+    coupling: [&'static str; CHANNEL_COUNT as usize],
+    bandwidth_limit: [&'static str; CHANNEL_COUNT as usize],
+    delay_s: f64,
+    reference: &'static str,
+    trigger_source: u8,
+    trigger_level_v: f64,
+    trigger_slope: &'static str,
+    trigger_sweep: &'static str,
+    /// `ACQuire:SEGMented:STATe`, `COUNt` and `INDex`
+    segmented: bool,
+    segment_count: u32,
+    segment_index: u32,
+    /// `DATa:PACK?` responses sent outside segmented acquisition
+    captures: u32,
+    /// Whether acquisition runs continuously, as after `RUN`
+    running: bool,
+    /// Whether a single acquisition waits for a trigger
+    armed: bool,
+    /// Incomplete command line written so far
+    input: Vec<u8>,
+    /// Response messages not read yet, the first one possibly in part
+    responses: VecDeque<Vec<u8>>,
+    errors: VecDeque<(i32, &'static str)>,
+    noise_state: u64,
+    timeout: Duration,
+    awg: SimulatedAwg,
+}
+
+/// An instrument simulated in memory, usable as the [`Transport`] of an
+/// [`OscilloscopeWaveform`](crate::OscilloscopeWaveform).
+///
+/// Supported are `*IDN?`, `*TST?`, `*CAL?`, `SYSTem:ERRor?`, `RUN`, `STOP`,
+/// `SINGle`, `TFORce`, `TRIGger:STATus?`, `TRIGger:EDGE:SOURce[?]`,
+/// `TRIGger:EDGE:LEVel[?]`, `TRIGger:EDGE:SLOPe[?]`, `TRIGger:SWEep[?]`,
+/// `TIMebase:SCALe[?]`, `TIMebase:DELay[?]`,
+/// `TIMebase:REFerence[?]`, `ACQuire:TYPE?`,
+/// `ACQuire:MDEPth[?]`, `ACQuire:SEGMented:STATe[?]`,
+/// `ACQuire:SEGMented:COUNt[?]`, `ACQuire:SEGMented:ACQuired?`,
+/// `ACQuire:SEGMented:INDex[?]`, `ACQuire:SEGMented:TIMestamp?`,
+/// `SEQuence:WAIT?`,
+/// `CHAN<n>:STATe[?]`, `CHAN<n>:SCALe[?]`, `CHAN<n>:OFFSet[?]`,
+/// `CHAN<n>:PROBe[?]`, `CHAN<n>:COUPling[?]`, `CHAN<n>:BWLimit[?]`,
+/// `CHAN<n>:DATa:TYPE`,
+/// `CHAN<n>:DATa:PACK? <length>, <type>` with `<start>, <count>` as the
+/// length of a partial read, `MEASure:<name>? CHAN<n>` for the
+/// measurements of [`MeasKind`](crate::measurement::MeasKind), and the
+/// waveform generator's `AWG:FUNCtion[?]`, `AWG:FREQuency[?]`,
+/// `AWG:AMPLitude[?]`, `AWG:OFFSet[?]`, `AWG:PHASe[?]`,
+/// `AWG:SQUare:DCYCle[?]`, `AWG:RAMP:SYMMetry[?]`,
+/// `AWG:ARBitrary:DATA[?]` and `AWG:OUTPut[?]`. Acquisitions complete
+/// instantly.
+/// Other commands add error -113 to the error queue and queries get no
+/// reply, so reading one times out.
+#[derive(Debug)]
+pub struct SimulatedScope {
+    state: Mutex<SimulatorState>,
+}
+
+impl SimulatedScope {
+    pub fn new(config: SimulationConfig) -> Self {
+        Self {
+            state: Mutex::new(SimulatorState {
+                config,
+                memory_depth: config.memory_depth.max(1),
+                channel_enabled: [true; CHANNEL_COUNT as usize],
+                volts_per_div: [config.volts_per_div; CHANNEL_COUNT as usize],
+                offset_v: [0.0; CHANNEL_COUNT as usize],
+                probe: [1.0; CHANNEL_COUNT as usize],
+                coupling: ["DC"; CHANNEL_COUNT as usize],
+                bandwidth_limit: ["FULL"; CHANNEL_COUNT as usize],
+                delay_s: 0.0,
+                reference: "CENTER",
+                trigger_source: 1,
+                trigger_level_v: 0.0,
+                trigger_slope: "POS",
+                trigger_sweep: "AUTO",
+                segmented: false,
+                segment_count: 1,
+                segment_index: 0,
+                captures: 0,
+                running: true,
+                armed: false,
+                input: Vec::new(),
+                responses: VecDeque::new(),
+                errors: VecDeque::new(),
+                // xorshift must not start at 0
+                noise_state: config.seed.max(1),
+                timeout: Duration::from_secs(2),
+                awg: SimulatedAwg::default(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SimulatorState> {
+        // A panic elsewhere leaves the state consistent enough to go on
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SimulatorState {
+    /// Execute one command line. `block` is the data of its
+    /// definite-length block argument, which `line` ends before.
+    fn execute(&mut self, line: &str, block: Option<Vec<u8>>) {
+        debug!("Simulator received {}", line);
+        let (header, arguments) = line.split_once(' ').unwrap_or((line, ""));
+        let header = header.to_ascii_uppercase();
+        let arguments = arguments.trim();
+
+        if let Some(rest) = header.strip_prefix("CHAN") {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let channel = rest[..digits].parse::<u8>().ok().filter(|n| (1..=CHANNEL_COUNT).contains(n));
+            if let Some(channel) = channel {
+                return self.execute_channel(channel, &rest[digits..], arguments);
+            }
+        }
+
+        if let Some(name) = header.strip_prefix("MEAS:").or_else(|| header.strip_prefix("MEASURE:")) {
+            return self.measure(name, arguments);
+        }
+        if let Some(name) = header.strip_prefix("AWG:") {
+            return self.execute_awg(name, arguments, block);
+        }
+
+        match header.as_str() {
+            "*IDN?" => {
+                let idn = format!("Batronix,{},SIM00001,1.0", self.config.model);
+                self.respond(&idn);
+            }
+            "*OPC?" => self.respond("1"),
+            // Acquisition stops while the instrument tests itself
+            "*TST?" | "*CAL?" => {
+                (self.running, self.armed) = (false, false);
+                self.respond(&self.config.self_test_code.to_string());
+            }
+            "SYST:ERR?" | "SYSTEM:ERROR?" => {
+                let (code, message) = self.errors.pop_front().unwrap_or((0, "No error"));
+                self.respond(&format!("{},\"{}\"", code, message));
+            }
+            "FORM:BORD?" | "FORMAT:BORDER?" => match self.config.byte_order {
+                Some(reply) => self.respond(reply),
+                None => self.undefined(&header),
+            },
+            "RUN" => (self.running, self.armed) = (true, false),
+            "STOP" => (self.running, self.armed) = (false, false),
+            // A triggered single acquisition completes at once and stops
+            "SING" | "SINGLE" => (self.running, self.armed) = (false, !self.config.triggered),
+            "TFOR" | "TFORCE" => self.armed = false,
+            "TRIG:STAT?" | "TRIGGER:STATUS?" => {
+                let status = match (self.armed, self.running, self.config.triggered) {
+                    (true, _, _) | (false, true, false) => "WAIT",
+                    (false, true, true) => "TD",
+                    (false, false, _) => "STOP",
+                };
+                self.respond(status);
+            }
+            "TRIG:EDGE:SOUR?" | "TRIGGER:EDGE:SOURCE?" => self.respond(&format!("CHAN{}", self.trigger_source)),
+            "TRIG:EDGE:SOUR" | "TRIGGER:EDGE:SOURCE" => {
+                let channel = arguments.to_ascii_uppercase().strip_prefix("CHAN").and_then(|n| n.parse::<u8>().ok());
+                match channel {
+                    Some(channel) if (1..=CHANNEL_COUNT).contains(&channel) => self.trigger_source = channel,
+                    _ => self.errors.push_back((-224, "Illegal parameter value")),
+                }
+            }
+            "TRIG:EDGE:LEV?" | "TRIGGER:EDGE:LEVEL?" => self.respond(&self.trigger_level_v.to_string()),
+            "TRIG:EDGE:LEV" | "TRIGGER:EDGE:LEVEL" => match arguments.parse::<f64>() {
+                Ok(level) if level.is_finite() => self.trigger_level_v = level,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            "TRIG:EDGE:SLOP?" | "TRIGGER:EDGE:SLOPE?" => self.respond(self.trigger_slope),
+            "TRIG:EDGE:SLOP" | "TRIGGER:EDGE:SLOPE" => {
+                self.trigger_slope = match arguments.to_ascii_uppercase().as_str() {
+                    "POS" | "POSITIVE" => "POS",
+                    "NEG" | "NEGATIVE" => "NEG",
+                    "EITH" | "EITHER" => "EITH",
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                }
+            }
+            "TRIG:SWE?" | "TRIGGER:SWEEP?" => self.respond(self.trigger_sweep),
+            "TRIG:SWE" | "TRIGGER:SWEEP" => {
+                self.trigger_sweep = match arguments.to_ascii_uppercase().as_str() {
+                    "AUTO" => "AUTO",
+                    "NORM" | "NORMAL" => "NORM",
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                }
+            }
+            "TIM:SCAL?" | "TIMEBASE:SCALE?" => {
+                self.respond(&(self.config.time_span_s / HORIZONTAL_DIVISIONS).to_string())
+            }
+            "TIM:SCAL" | "TIMEBASE:SCALE" => match arguments.parse::<f64>() {
+                Ok(scale) if scale > 0.0 && scale.is_finite() => self.config.time_span_s = scale * HORIZONTAL_DIVISIONS,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            "TIM:DEL?" | "TIMEBASE:DELAY?" => self.respond(&self.delay_s.to_string()),
+            "TIM:DEL" | "TIMEBASE:DELAY" => match arguments.parse::<f64>() {
+                Ok(delay) if delay.is_finite() => self.delay_s = delay,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            "TIM:REF?" | "TIMEBASE:REFERENCE?" => self.respond(self.reference),
+            "TIM:REF" | "TIMEBASE:REFERENCE" => {
+                self.reference = match arguments.to_ascii_uppercase().as_str() {
+                    "LEFT" => "LEFT",
+                    "CENT" | "CENTER" => "CENTER",
+                    "RIGH" | "RIGHT" => "RIGHT",
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                }
+            }
+            "ACQ:TYPE?" | "ACQUIRE:TYPE?" => self.respond("NORMAL"),
+            "ACQ:MDEP?" | "ACQUIRE:MDEPTH?" => self.respond(&self.memory_depth.to_string()),
+            "ACQ:MDEP" | "ACQUIRE:MDEPTH" => match arguments.parse::<f64>() {
+                Ok(depth) if depth >= 1.0 => self.memory_depth = depth.min(u32::MAX as f64) as u32,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            "ACQ:SEGM:STAT?" | "ACQUIRE:SEGMENTED:STATE?" => self.respond(if self.segmented { "1" } else { "0" }),
+            "ACQ:SEGM:STAT" | "ACQUIRE:SEGMENTED:STATE" => match arguments.to_ascii_uppercase().as_str() {
+                "1" | "ON" => self.segmented = true,
+                "0" | "OFF" => self.segmented = false,
+                _ => self.errors.push_back((-224, "Illegal parameter value")),
+            },
+            "ACQ:SEGM:COUN?" | "ACQUIRE:SEGMENTED:COUNT?" => self.respond(&self.segment_count.to_string()),
+            "ACQ:SEGM:COUN" | "ACQUIRE:SEGMENTED:COUNT" => match arguments.parse::<u32>() {
+                Ok(count) if count > 0 => self.segment_count = count,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            "ACQ:SEGM:ACQ?" | "ACQUIRE:SEGMENTED:ACQUIRED?" => self.respond(&self.stored_segments().to_string()),
+            "ACQ:SEGM:IND?" | "ACQUIRE:SEGMENTED:INDEX?" => self.respond(&self.segment_index.to_string()),
+            "ACQ:SEGM:IND" | "ACQUIRE:SEGMENTED:INDEX" => match arguments.parse::<u32>() {
+                Ok(index) if index < self.stored_segments() => self.segment_index = index,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            // One segment per signal period
+            "ACQ:SEGM:TIM?" | "ACQUIRE:SEGMENTED:TIMESTAMP?" => {
+                let timestamp = self.segment_index as f64 / self.config.frequency_hz;
+                self.respond(&timestamp.to_string());
+            }
+            // Every triggered acquisition completes at once
+            "SEQ:WAIT?" | "SEQUENCE:WAIT?" => self.respond(if self.config.triggered { "1" } else { "0" }),
+            _ => self.undefined(&header),
+        }
+    }
+
+    fn execute_channel(&mut self, channel: u8, header: &str, arguments: &str) {
+        let index = channel as usize - 1;
+        match header {
+            ":STAT?" | ":STATE?" => self.respond(if self.channel_enabled[index] { "1" } else { "0" }),
+            ":STAT" | ":STATE" => match arguments.to_ascii_uppercase().as_str() {
+                "1" | "ON" => self.channel_enabled[index] = true,
+                "0" | "OFF" => self.channel_enabled[index] = false,
+                _ => self.errors.push_back((-224, "Illegal parameter value")),
+            },
+            ":SCAL?" | ":SCALE?" => self.respond(&self.volts_per_div[index].to_string()),
+            ":SCAL" | ":SCALE" => match arguments.parse::<f64>() {
+                Ok(scale) if scale > 0.0 && scale.is_finite() => self.volts_per_div[index] = scale,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            ":OFFS?" | ":OFFSET?" => self.respond(&self.offset_v[index].to_string()),
+            ":OFFS" | ":OFFSET" => match arguments.parse::<f64>() {
+                Ok(offset) if offset.is_finite() => self.offset_v[index] = offset,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            ":PROB?" | ":PROBE?" => self.respond(&self.probe[index].to_string()),
+            ":PROB" | ":PROBE" => match arguments.parse::<f64>() {
+                Ok(ratio) if (1.0..=1000.0).contains(&ratio) => self.probe[index] = ratio,
+                _ => self.errors.push_back((-222, "Data out of range")),
+            },
+            ":COUP?" | ":COUPLING?" => self.respond(self.coupling[index]),
+            ":COUP" | ":COUPLING" => {
+                self.coupling[index] = match arguments.to_ascii_uppercase().as_str() {
+                    "DC" => "DC",
+                    "AC" => "AC",
+                    "GND" => "GND",
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                }
+            }
+            ":BWL?" | ":BWLIMIT?" => self.respond(self.bandwidth_limit[index]),
+            ":BWL" | ":BWLIMIT" => {
+                self.bandwidth_limit[index] = match arguments.to_ascii_uppercase().as_str() {
+                    "FULL" | "OFF" => "FULL",
+                    "20M" => "20M",
+                    "200M" => "200M",
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                }
+            }
+            // The type is given again with every PACK? query
+            ":DAT:TYPE" | ":DATA:TYPE" => {}
+            ":DAT:PACK?" | ":DATA:PACK?" => {
+                // <length>[, <type>] or <start>, <count>[, <type>]
+                let mut parts: Vec<&str> = arguments.split(',').map(str::trim).collect();
+                let data_type = match parts.last().map(|part| part.to_ascii_uppercase()).as_deref() {
+                    Some("RAW") => Some(true),
+                    Some("V") => Some(false),
+                    _ => None,
+                };
+                if data_type.is_some() {
+                    parts.pop();
+                }
+                let raw = data_type.unwrap_or(false);
+                let record = self.memory_depth;
+                let (start, count) = match parts.as_slice() {
+                    [] | [""] => (0, record),
+                    [length] if ["ALL", "DISP", "DISPLAY"].contains(&length.to_ascii_uppercase().as_str()) => {
+                        (0, record)
+                    }
+                    [length] => match length.parse::<u32>() {
+                        Ok(count) => (0, count.min(record)),
+                        Err(_) => return self.errors.push_back((-224, "Illegal parameter value")),
+                    },
+                    [start, count] => match (start.parse::<u32>(), count.parse::<u32>()) {
+                        (Ok(start), Ok(count)) if start as u64 + count as u64 <= record as u64 => (start, count),
+                        (Ok(_), Ok(_)) => return self.errors.push_back((-222, "Data out of range")),
+                        _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                    },
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                };
+                let block = self.waveform_block(channel, start, count, raw);
+                self.respond_block(&block);
+                let corrupt = if self.segmented {
+                    self.config.corrupt_segment == Some(self.segment_index)
+                } else {
+                    self.captures += 1;
+                    self.config.corrupt_captures_from.is_some_and(|first| self.captures > first)
+                };
+                if corrupt {
+                    // Not a length digit, so the data stays unread
+                    if let Some(message) = self.responses.back_mut() {
+                        message[1] = b'X';
+                    }
+                }
+            }
+            _ => self.undefined(&format!("CHAN{}{}", channel, header)),
+        }
+    }
+
+    fn execute_awg(&mut self, name: &str, arguments: &str, block: Option<Vec<u8>>) {
+        let (setting, is_query) = match name.strip_suffix('?') {
+            Some(setting) => (setting, true),
+            None => (name, false),
+        };
+        if let Some(value) = self.awg.setting(setting) {
+            if is_query {
+                let value = *value;
+                return self.respond(&value.to_string());
+            }
+            match arguments.parse::<f64>() {
+                Ok(new_value) if new_value.is_finite() => *value = new_value,
+                _ => self.errors.push_back((-224, "Illegal parameter value")),
+            }
+            return;
+        }
+        match name {
+            "FUNC?" | "FUNCTION?" => self.respond(self.awg.function),
+            "FUNC" | "FUNCTION" => {
+                self.awg.function = match arguments.to_ascii_uppercase().as_str() {
+                    "SIN" | "SINUSOID" => "SIN",
+                    "SQU" | "SQUARE" => "SQU",
+                    "RAMP" => "RAMP",
+                    "PULS" | "PULSE" => "PULS",
+                    "NOIS" | "NOISE" => "NOIS",
+                    "ARB" | "ARBITRARY" => "ARB",
+                    _ => return self.errors.push_back((-224, "Illegal parameter value")),
+                };
+            }
+            "ARB:DATA?" | "ARBITRARY:DATA?" => {
+                let data = self.awg.arbitrary.clone();
+                self.respond_block(&data);
+            }
+            "ARB:DATA" | "ARBITRARY:DATA" => match block {
+                Some(data) => self.awg.arbitrary = data,
+                None => self.errors.push_back((-224, "Illegal parameter value")),
+            },
+            "OUTP?" | "OUTPUT?" => self.respond(if self.awg.output { "1" } else { "0" }),
+            "OUTP" | "OUTPUT" => match arguments.to_ascii_uppercase().as_str() {
+                "1" | "ON" => self.awg.output = true,
+                "0" | "OFF" => self.awg.output = false,
+                _ => self.errors.push_back((-224, "Illegal parameter value")),
+            },
+            _ => self.undefined(&format!("AWG:{}", name)),
+        }
+    }
+
+    /// Answer `MEASure:<name>? CHAN<n>` for the sine without noise.
+    fn measure(&mut self, name: &str, arguments: &str) {
+        let channel = arguments.to_ascii_uppercase().strip_prefix("CHAN")
+            .and_then(|digits| digits.parse::<u8>().ok())
+            .filter(|n| (1..=CHANNEL_COUNT).contains(n));
+        if channel.is_none() {
+            return self.errors.push_back((-224, "Illegal parameter value"));
+        }
+        let SimulationConfig { frequency_hz, amplitude_v, offset_v, .. } = self.config;
+        let value = match name {
+            "FREQ?" | "FREQUENCY?" => frequency_hz,
+            "PER?" | "PERIOD?" => 1.0 / frequency_hz,
+            "VPP?" => 2.0 * amplitude_v,
+            "RMS?" => (amplitude_v * amplitude_v / 2.0 + offset_v * offset_v).sqrt(),
+            "MEAN?" => offset_v,
+            "MIN?" | "MINIMUM?" => offset_v - amplitude_v,
+            "MAX?" | "MAXIMUM?" => offset_v + amplitude_v,
+            // A sine has no flat top and base to measure these against
+            "AMPL?" | "AMPLITUDE?" | "RIS?" | "RISETIME?" | "FALL?" | "FALLTIME?" | "DUTY?" | "DUTYCYCLE?"
+                | "PWID?" | "PWIDTH?" | "NWID?" | "NWIDTH?" => 9.9e37,
+            _ => return self.undefined(&format!("MEAS:{}", name)),
+        };
+        self.respond(&format!("{:E}", value));
+    }
+
+    /// Segments the last segmented acquisition stored, none without one.
+    fn stored_segments(&self) -> u32 {
+        if self.segmented { self.config.segments.min(self.segment_count) } else { 0 }
+    }
+
+    fn undefined(&mut self, header: &str) {
+        debug!("Simulator does not support {}", header);
+        self.errors.push_back((-113, "Undefined header"));
+    }
+
+    fn respond(&mut self, response: &str) {
+        self.responses.push_back(format!("{}\n", response).into_bytes());
+    }
+
+    fn respond_block(&mut self, data: &[u8]) {
+        let length = data.len().to_string();
+        let mut message = format!("#{}{}", length.len(), length).into_bytes();
+        message.extend_from_slice(data);
+        message.push(b'\n');
+        self.responses.push_back(message);
+    }
+
+    /// Uniform noise between -1 and 1 from a xorshift generator.
+    fn next_noise(&mut self) -> f64 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.noise_state = x;
+        (x >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    /// A `DATa:PACK?` block of `count` samples of `channel` from index
+    /// `start` of the record, with the RAW or `V` metadata header. The
+    /// start time is that of the record.
+    fn waveform_block(&mut self, channel: u8, start: u32, count: u32, raw: bool) -> Vec<u8> {
+        let config = self.config;
+        let time_delta = config.time_span_s / self.memory_depth as f64;
+        // The record spans the screen, the delay is the time at the reference position
+        let reference = match self.reference {
+            "LEFT" => 0.0,
+            "RIGHT" => 1.0,
+            _ => 0.5,
+        };
+        let start_time = self.delay_s - reference * config.time_span_s;
+        let end_time = start_time + (start + count).saturating_sub(1) as f64 * time_delta;
+        let phase = (channel - 1) as f64 * PI / 2.0;
+        let index = channel as usize - 1;
+        let vertical_start = self.offset_v[index] - 5.0 * self.volts_per_div[index];
+        let vertical_step = 10.0 * self.volts_per_div[index];
+
+        let mut data = Vec::new();
+        for value in [time_delta, start_time, end_time] {
+            data.extend_from_slice(&(value as f32).to_le_bytes());
+        }
+        if raw {
+            // The whole block is valid
+            for value in [start, count] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in [vertical_start, vertical_step] {
+                data.extend_from_slice(&(value as f32).to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&count.to_le_bytes());
+
+        for i in start..start + count {
+            let t = start_time + i as f64 * time_delta;
+            let voltage = config.offset_v + config.amplitude_v * (2.0 * PI * config.frequency_hz * t - phase).sin()
+                + config.noise_v * self.next_noise();
+            if raw {
+                let code = ((voltage - vertical_start) / vertical_step * 65536.0).round().clamp(0.0, 65535.0);
+                data.extend_from_slice(&(code as u16).to_le_bytes());
+            } else {
+                data.extend_from_slice(&(voltage as f32).to_le_bytes());
+            }
+        }
+        data
+    }
+}
+
+impl Transport for SimulatedScope {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        state.input.extend_from_slice(data);
+        while let Some((length, block)) = next_command(&state.input) {
+            let command: Vec<u8> = state.input.drain(..length).collect();
+            let (text, block) = match block {
+                Some(BlockArgument { header, data }) => (&command[..header], Some(command[data].to_vec())),
+                None => (&command[..], None),
+            };
+            let line = String::from_utf8_lossy(text).trim().to_string();
+            if !line.is_empty() {
+                state.execute(&line, block);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        let Some(response) = state.responses.front_mut() else {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Simulator has no pending response"));
+        };
+        let count = buf.len().min(response.len());
+        buf[..count].copy_from_slice(&response[..count]);
+        response.drain(..count);
+        if response.is_empty() {
+            state.responses.pop_front();
+        }
+        Ok(count)
+    }
+
+    fn clear(&self) -> Result<()> {
+        let mut state = self.state();
+        state.input.clear();
+        state.responses.clear();
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        Ok(self.state().timeout)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.state().timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::TriggerStatus;
+    use crate::scpi::ErrorCheck;
+    use crate::{DataRange, OscilloscopeWaveform, ScopeError, TransferFormat};
+
+    fn simulated_scope(config: SimulationConfig) -> OscilloscopeWaveform {
+        OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)))
+    }
+
+    #[test]
+    fn captures_raw_waveforms_end_to_end() {
+        let config = SimulationConfig { noise_v: 0.0, ..SimulationConfig::default() };
+        let scope = simulated_scope(config);
+        assert!(scope.identity().unwrap().is_batronix());
+
+        let (time, voltage) = scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, Some(2_000)).unwrap();
+        assert_eq!(time.len(), 2_000);
+        assert_eq!(voltage.len(), 2_000);
+
+        // 5 ms centred on the trigger at 2.5 µs per sample
+        assert!((time[0] + 2.5e-3).abs() < 1e-9);
+        assert!((time[1] - time[0] - 2.5e-6).abs() < 1e-9);
+        assert!((time[1_999] - (2.5e-3 - 2.5e-6)).abs() < 1e-8);
+
+        // RAW codes resolve 5 V / 65536, so the scaling is checked closely
+        for (&t, &v) in time.iter().zip(&voltage) {
+            let expected = (2.0 * PI * 1e3 * t as f64).sin() as f32;
+            assert!((v - expected).abs() < 2e-4, "{} V at {} s, expected {} V", v, t, expected);
+        }
+    }
+
+    #[test]
+    fn captures_volts_and_partial_blocks() {
+        let config = SimulationConfig { amplitude_v: 2.0, offset_v: 0.5, noise_v: 0.1, ..SimulationConfig::default() };
+        let scope = simulated_scope(config);
+
+        let range = DataRange::Samples { start: 0, count: 500 };
+        let (time, _) = scope.get_waveform_data(2, range, TransferFormat::Volts, None).unwrap();
+        assert_eq!(time.len(), 500);
+        assert!((time[0] + 2.5e-3).abs() < 1e-9);
+
+        let (_, voltage) = scope.get_waveform_data(2, DataRange::All, TransferFormat::Volts, None).unwrap();
+        assert_eq!(voltage.len(), 10_000);
+        let max = voltage.iter().copied().fold(f32::MIN, f32::max);
+        let min = voltage.iter().copied().fold(f32::MAX, f32::min);
+        assert!((2.3..=2.6).contains(&max), "maximum {} V", max);
+        assert!((-1.6..=-1.3).contains(&min), "minimum {} V", min);
+
+        // The configured depth stays in effect
+        assert_eq!(scope.memory_depth().unwrap(), 10_000);
+    }
+
+    #[test]
+    fn places_partial_reads_in_the_record() {
+        let scope = simulated_scope(SimulationConfig { noise_v: 0.0, ..SimulationConfig::default() });
+        let range = DataRange::Samples { start: 1_000, count: 500 };
+        for dtype in TransferFormat::ALL {
+            let (all_time, all_voltage) = scope.get_waveform_data(1, DataRange::All, dtype, None).unwrap();
+            let (time, voltage) = scope.get_waveform_data(1, range, dtype, None).unwrap();
+            assert_eq!(time.len(), 500);
+            assert!((time[0] - all_time[1_000]).abs() < 1e-9, "{} starts at {} s", dtype, time[0]);
+            assert!((time[499] - all_time[1_499]).abs() < 1e-9);
+            assert_eq!(voltage, all_voltage[1_000..1_500]);
+        }
+        let record = scope.get_waveform_record(1, range, None).unwrap();
+        assert!((record.time_values().next().unwrap() + 2.5e-3 - 1_000.0 * 5e-7).abs() < 1e-9);
+        assert_eq!(scope.get_waveform_data(1, DataRange::Visible, TransferFormat::Raw, None).unwrap().0.len(), 10_000);
+
+        // A range beyond the record fails with the instrument's error
+        let beyond = DataRange::Samples { start: 9_800, count: 500 };
+        let error = scope.get_waveform_data(1, beyond, TransferFormat::Raw, None).unwrap_err();
+        assert!(matches!(error, ScopeError::ScpiError { code: -222, .. }), "{}", error);
+        assert!(scope.check_errors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_unsupported_commands() {
+        let mut scope = simulated_scope(SimulationConfig::default());
+        scope.set_error_check(ErrorCheck::Strict);
+        scope.send_command("TRIGger:MODE AUTO").unwrap();
+        let errors = scope.check_errors().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, -113);
+
+        assert!(matches!(scope.query("TRIGger:MODE?"), Err(ScopeError::Timeout)));
+        scope.device.clear().unwrap();
+        assert_eq!(scope.query("CHAN3:STATe?").unwrap(), "1");
+    }
+
+    #[test]
+    fn tracks_run_state() {
+        let scope = simulated_scope(SimulationConfig::default());
+        assert_eq!(scope.trigger_status().unwrap(), TriggerStatus::Triggered);
+        assert!((scope.timebase().unwrap() - 5e-4).abs() < 1e-12);
+
+        scope.set_running(false).unwrap();
+        assert_eq!(scope.trigger_status().unwrap(), TriggerStatus::Stopped);
+        // A capture starts the acquisition again
+        scope.get_waveform_data(1, DataRange::All, TransferFormat::Raw, None).unwrap();
+        assert!(scope.trigger_status().unwrap().is_running());
+    }
+}
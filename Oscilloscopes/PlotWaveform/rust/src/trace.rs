@@ -0,0 +1,342 @@
+//! Trace logging of the SCPI traffic with an instrument.
+//!
+//! Every [`OscilloscopeWaveform`] talks through a [`TracingTransport`],
+//! which logs each command, each text response and a summary of each data
+//! block at trace level under the `scpi` target, e.g. with
+//! `RUST_LOG=scpi=trace`. [`OscilloscopeWaveform::set_scpi_log`] mirrors
+//! the trace to a file whatever the log level. Lines carry a sequence
+//! number and the wall-clock time in UTC:
+//!
+//! ```text
+//! #000001 2026-10-16T09-30-12.041 > CHAN1:DATa:PACK? ALL, RAW
+//! #000002 2026-10-16T09-30-12.058 < #520032 block, 20032 bytes: 9a 99 99 34 ... 00 80 ff 7f
+//! ```
+//!
+//! Neither traces nor parses anything while both are off.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, log_enabled, trace, Level};
+
+use crate::capture_sink::format_timestamp;
+use crate::transport::Transport;
+use crate::{OscilloscopeWaveform, Result};
+
+/// Log target of the trace.
+pub const TRACE_TARGET: &str = "scpi";
+
+/// Bytes shown from either end of a data block.
+const BLOCK_EDGE_BYTES: usize = 16;
+
+/// Longest message traced as text. Longer ones, and any with control
+/// characters, are summarized like data blocks.
+const MAX_TRACE_TEXT: usize = 1024;
+
+/// File the trace is mirrored to, shared by a scope and its transport.
+pub(crate) type TraceFile = Arc<Mutex<Option<LineWriter<File>>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panic elsewhere leaves the trace consistent enough to go on
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Bytes as space separated hex pairs.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+/// `count` bytes given by their first and last few.
+fn summarize(count: usize, head: &[u8], tail: &[u8]) -> String {
+    let rest = count.saturating_sub(head.len()).min(tail.len());
+    let tail = &tail[tail.len() - rest..];
+    let gap = if count > head.len() + tail.len() { " ..." } else { "" };
+    let tail = if tail.is_empty() { String::new() } else { format!(" {}", hex(tail)) };
+    format!("{} bytes: {}{}{}", count, hex(head), gap, tail)
+}
+
+/// A message as text without its line terminator, or summarized if it is
+/// long or not printable.
+fn describe(data: &[u8]) -> String {
+    let text = data.strip_suffix(b"\n").unwrap_or(data);
+    let text = text.strip_suffix(b"\r").unwrap_or(text);
+    match std::str::from_utf8(text) {
+        Ok(text) if text.len() <= MAX_TRACE_TEXT && !text.chars().any(char::is_control) => text.to_string(),
+        _ => summarize(data.len(), &data[..data.len().min(BLOCK_EDGE_BYTES)],
+            &data[data.len().saturating_sub(BLOCK_EDGE_BYTES)..]),
+    }
+}
+
+/// Start and size of a data block being received, without its data.
+#[derive(Debug)]
+struct BlockSummary {
+    header: String,
+    /// Declared data size, `None` for an indefinite-length block.
+    length: Option<usize>,
+    received: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+}
+
+impl BlockSummary {
+    fn new(header: &[u8], length: Option<usize>) -> Self {
+        let header = String::from_utf8_lossy(header).into_owned();
+        Self { header, length, received: 0, head: Vec::new(), tail: VecDeque::new() }
+    }
+
+    fn add(&mut self, data: &[u8]) {
+        self.received += data.len();
+        let missing = BLOCK_EDGE_BYTES - self.head.len();
+        self.head.extend_from_slice(&data[..data.len().min(missing)]);
+        self.tail.extend(&data[data.len().saturating_sub(BLOCK_EDGE_BYTES)..]);
+        while self.tail.len() > BLOCK_EDGE_BYTES {
+            self.tail.pop_front();
+        }
+    }
+
+    fn describe(&self) -> String {
+        let incomplete = match self.length {
+            Some(length) if length != self.received => format!(" of {} declared", length),
+            _ => String::new(),
+        };
+        let tail: Vec<u8> = self.tail.iter().copied().collect();
+        format!("{} block, {}{}", self.header, summarize(self.received, &self.head, &tail), incomplete)
+    }
+}
+
+/// Splits the received bytes back into responses, which arrive in reads
+/// of any size.
+#[derive(Debug, Default)]
+enum Incoming {
+    #[default]
+    Idle,
+    Text(Vec<u8>),
+    /// Start of a block header, from the `#`.
+    Header(Vec<u8>),
+    Block(BlockSummary),
+}
+
+impl Incoming {
+    /// Take in received bytes, returning the responses they complete.
+    fn feed(&mut self, mut bytes: &[u8]) -> Vec<String> {
+        let mut responses = Vec::new();
+        while !bytes.is_empty() {
+            match self {
+                Incoming::Idle => {
+                    *self = if bytes[0] == b'#' { Incoming::Header(Vec::new()) } else { Incoming::Text(Vec::new()) };
+                }
+                Incoming::Text(text) => match bytes.iter().position(|&byte| byte == b'\n') {
+                    Some(end) => {
+                        text.extend_from_slice(&bytes[..=end]);
+                        bytes = &bytes[end + 1..];
+                        responses.extend(self.take());
+                    }
+                    None => {
+                        text.extend_from_slice(bytes);
+                        bytes = &[];
+                    }
+                },
+                Incoming::Header(header) => {
+                    header.push(bytes[0]);
+                    bytes = &bytes[1..];
+                    // `#`, the number of length digits and the length
+                    let digits = header.get(1).map(|&digit| digit.wrapping_sub(b'0') as usize);
+                    match digits {
+                        Some(0) => *self = Incoming::Block(BlockSummary::new(header, None)),
+                        Some(digits) if digits > 9 => *self = Incoming::Text(std::mem::take(header)),
+                        Some(digits) if header.len() == 2 + digits => {
+                            let length = std::str::from_utf8(&header[2..]).ok().and_then(|len| len.parse().ok());
+                            *self = match length {
+                                Some(length) => Incoming::Block(BlockSummary::new(header, Some(length))),
+                                None => Incoming::Text(std::mem::take(header)),
+                            };
+                        }
+                        _ => {}
+                    }
+                }
+                Incoming::Block(block) => {
+                    let count = match block.length {
+                        Some(length) => (length - block.received).min(bytes.len()),
+                        None => bytes.len(),
+                    };
+                    block.add(&bytes[..count]);
+                    bytes = &bytes[count..];
+                    if block.length == Some(block.received) {
+                        responses.extend(self.take());
+                    }
+                }
+            }
+            // An empty block is complete with its header
+            if let Incoming::Block(BlockSummary { length: Some(0), .. }) = self {
+                responses.extend(self.take());
+            }
+        }
+        responses
+    }
+
+    /// End the current response, as when the next command is sent. Blank
+    /// lines, such as the terminator after a block, are dropped.
+    fn take(&mut self) -> Option<String> {
+        match std::mem::take(self) {
+            Incoming::Idle => None,
+            Incoming::Text(text) | Incoming::Header(text) => {
+                Some(describe(&text)).filter(|text| !text.trim().is_empty())
+            }
+            Incoming::Block(block) => Some(block.describe()),
+        }
+    }
+}
+
+/// Transport passing everything through to `inner` and tracing it.
+pub(crate) struct TracingTransport {
+    inner: Box<dyn Transport>,
+    sequence: AtomicU64,
+    incoming: Mutex<Incoming>,
+    file: TraceFile,
+}
+
+impl TracingTransport {
+    pub(crate) fn new(inner: Box<dyn Transport>, file: TraceFile) -> Self {
+        Self { inner, sequence: AtomicU64::new(0), incoming: Mutex::new(Incoming::Idle), file }
+    }
+
+    /// Whether the trace goes anywhere.
+    fn active(&self) -> bool {
+        log_enabled!(target: TRACE_TARGET, Level::Trace) || lock(&self.file).is_some()
+    }
+
+    fn emit(&self, direction: &str, message: &str) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!("#{:06} {}.{:03} {} {}", sequence, format_timestamp(now.as_secs()), now.subsec_millis(),
+            direction, message);
+        trace!(target: TRACE_TARGET, "{}", line);
+        if let Some(file) = lock(&self.file).as_mut() {
+            // A full disk should not break the connection
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    /// Trace a response left incomplete, e.g. by a timeout.
+    fn finish_response(&self) {
+        if let Some(response) = lock(&self.incoming).take() {
+            self.emit("<", &response);
+        }
+    }
+}
+
+impl Transport for TracingTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        if self.active() {
+            self.finish_response();
+            self.emit(">", &describe(data));
+        }
+        self.inner.send(data)
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.receive(buf)?;
+        if self.active() {
+            let responses = lock(&self.incoming).feed(&buf[..count]);
+            for response in responses {
+                self.emit("<", &response);
+            }
+        }
+        Ok(count)
+    }
+
+    fn clear(&self) -> Result<()> {
+        if self.active() {
+            self.finish_response();
+            self.emit("-", "device clear");
+        }
+        self.inner.clear()
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn go_to_local(&self) -> Result<()> {
+        if self.active() {
+            self.finish_response();
+            self.emit("-", "go to local");
+        }
+        self.inner.go_to_local()
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Mirror the SCPI trace to `path`, replacing the file, whatever the
+    /// log level. `None` stops mirroring.
+    pub fn set_scpi_log(&self, path: Option<&str>) -> Result<()> {
+        let file = path.map(File::create).transpose()?.map(LineWriter::new);
+        *lock(&self.scpi_log) = file;
+        if let Some(path) = path {
+            info!("Writing the SCPI trace to {}", path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedScope, SimulationConfig};
+    use crate::{DataRange, TransferFormat};
+
+    #[test]
+    fn splits_responses_read_in_pieces() {
+        let mut block = b"#210".to_vec();
+        block.extend(0..10u8);
+        block.extend_from_slice(b"\r\n1.5\n#0");
+        block.extend(0..40u8);
+
+        // Byte by byte, as block headers are read
+        let mut incoming = Incoming::default();
+        let mut responses: Vec<String> = block.iter().flat_map(|byte| incoming.feed(&[*byte])).collect();
+        responses.extend(incoming.take());
+        assert_eq!(responses, [
+            "#210 block, 10 bytes: 00 01 02 03 04 05 06 07 08 09",
+            "1.5",
+            "#0 block, 40 bytes: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f ... \
+                18 19 1a 1b 1c 1d 1e 1f 20 21 22 23 24 25 26 27",
+        ]);
+
+        let mut incoming = Incoming::default();
+        assert_eq!(incoming.feed(b"#15abc"), Vec::<String>::new());
+        assert_eq!(incoming.take().unwrap(), "#15 block, 3 bytes: 61 62 63 of 5 declared");
+        assert_eq!(describe(&[0x80; 40]), summarize(40, &[0x80; 16], &[0x80; 16]));
+    }
+
+    #[test]
+    fn mirrors_the_trace_to_a_file() {
+        let path = std::env::temp_dir().join(format!("scpi-trace-{}.log", std::process::id()));
+        let path = path.display().to_string();
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        scope.set_scpi_log(Some(&path)).unwrap();
+        scope.idn().unwrap();
+        scope.get_waveform_data(1, DataRange::Samples { start: 0, count: 100 }, TransferFormat::Raw, None).unwrap();
+        scope.set_scpi_log(None).unwrap();
+        scope.idn().unwrap();
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert!(lines[0].starts_with("#000001 ") && lines[0].ends_with(" > *IDN?"), "{}", lines[0]);
+        assert!(lines[1].starts_with("#000002 ") && lines[1].ends_with(" < Batronix,Magnova Simulator,SIM00001,1.0"));
+        // 32 bytes of metadata and 100 samples
+        let block = lines.iter().find(|line| line.contains(" block, ")).unwrap();
+        assert!(block.contains("< #3232 block, 232 bytes: "), "{}", block);
+        assert!(lines.iter().any(|line| line.ends_with("> CHAN1:DATa:PACK? 0,100, RAW")));
+        assert_eq!(lines.iter().filter(|line| line.ends_with("> *IDN?")).count(), 1);
+    }
+}
@@ -0,0 +1,360 @@
+//! Byte transport between `OscilloscopeWaveform` and an instrument.
+
+#[cfg(feature = "serial")]
+use std::collections::VecDeque;
+use std::io::{self, Read};
+#[cfg(feature = "serial")]
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+#[cfg(feature = "serial")]
+use visa_rs::enums::attribute::{AttrAsrlBaud, AttrAsrlEndIn, AttrTermcharEn};
+use visa_rs::enums::attribute::{AttrKind, AttrTmoValue, Attribute, HasAttribute};
+use visa_rs::enums::gpib::RenMode;
+use visa_rs::enums::status::ErrorCode;
+use visa_rs::prelude::*;
+
+use crate::{Result, ScopeError};
+
+/// Message based connection to an instrument.
+///
+/// Like a VISA session, a read returns at most the rest of the current
+/// response message, and all methods take `&self` so a query can write and
+/// read through the same shared reference.
+pub trait Transport: Send {
+    /// Write all of `data`.
+    fn send(&self, data: &[u8]) -> io::Result<()>;
+
+    /// Read up to `buf.len()` bytes of the pending response. Fails with
+    /// `io::ErrorKind::TimedOut` if nothing arrives within the timeout.
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Abort pending responses, like a VISA device clear.
+    fn clear(&self) -> Result<()>;
+
+    /// Current I/O timeout.
+    fn timeout(&self) -> Result<Duration>;
+
+    /// Change the I/O timeout.
+    fn set_timeout(&self, timeout: Duration) -> Result<()>;
+
+    /// Return the instrument to local control, unlocking its front panel.
+    /// Links without a remote state, like serial lines, do nothing.
+    fn go_to_local(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for &dyn Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.receive(buf)
+    }
+}
+
+/// An open VISA session.
+pub struct VisaTransport {
+    instrument: Instrument,
+    #[allow(dead_code)]
+    rm: DefaultRM,  // Keep the resource manager alive
+}
+
+impl VisaTransport {
+    pub fn new(instrument: Instrument, rm: DefaultRM) -> Self {
+        Self { instrument, rm }
+    }
+}
+
+/// Change the VISA I/O timeout of an open session.
+pub(crate) fn set_visa_timeout(instrument: &Instrument, timeout: Duration) -> Result<()> {
+    // VI_TMO_INFINITE is u32::MAX, stay below it
+    let millis = timeout.as_millis().min(u32::MAX as u128 - 1);
+    let value = AttrTmoValue::new_checked(millis as _)
+        .ok_or_else(|| ScopeError::InvalidArgument(format!("Invalid timeout {:?}", timeout)))?;
+    instrument.set_attr(value)?;
+    Ok(())
+}
+
+impl Transport for VisaTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        io::Write::write_all(&mut &self.instrument, data)
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.instrument).read(buf)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.instrument.clear()?;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        match self.instrument.get_attr(AttrKind::AttrTmoValue)? {
+            Attribute::AttrTmoValue(value) => Ok(Duration::from_millis(value.into_inner() as _)),
+            other => Err(ScopeError::UnexpectedResponse {
+                command: "VI_ATTR_TMO_VALUE".to_string(),
+                response: format!("{:?}", other),
+            }),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        set_visa_timeout(&self.instrument, timeout)
+    }
+
+    fn go_to_local(&self) -> Result<()> {
+        match self.instrument.gpib_control_ren(RenMode::GpibRenAddressGtl) {
+            // Raw sockets have no remote state to leave
+            Err(e) if e.0 == ErrorCode::ErrorNsupOper => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Where [`SerialTransport`] is within the current response.
+#[cfg(feature = "serial")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Response {
+    /// The next byte starts a response.
+    #[default]
+    Idle,
+    /// A text line, which ends at its newline.
+    Text,
+    /// The data of a definite-length block.
+    Block { remaining: usize },
+}
+
+#[cfg(feature = "serial")]
+#[derive(Debug, Default)]
+struct Framing {
+    response: Response,
+    /// Bytes read ahead to parse a block header, still to be returned.
+    pending: VecDeque<u8>,
+}
+
+/// A serial line, such as the virtual COM port of the instrument, as a
+/// message based transport.
+///
+/// Serial reads don't end with the response, so the byte stream of the
+/// link is split into responses here. Text responses end at their newline,
+/// and the data of a definite-length block is read by the length in its
+/// header, newlines included. An indefinite-length block ends at its first
+/// newline, so instruments have to send definite-length ones.
+#[cfg(feature = "serial")]
+pub struct SerialTransport {
+    link: Box<dyn Transport>,
+    framing: Mutex<Framing>,
+}
+
+/// VISA resource of a serial port given as `COM3`, `/dev/ttyACM0` or as a
+/// resource string already.
+#[cfg(feature = "serial")]
+pub(crate) fn serial_resource(port: &str) -> String {
+    let upper = port.to_ascii_uppercase();
+    if upper.starts_with("ASRL") {
+        port.to_string()
+    } else if let Some(number) = upper.strip_prefix("COM").filter(|number| number.parse::<u32>().is_ok()) {
+        format!("ASRL{}::INSTR", number)
+    } else {
+        format!("ASRL{}::INSTR", port)
+    }
+}
+
+#[cfg(feature = "serial")]
+impl SerialTransport {
+    /// Split the byte stream of `link` into responses. A read of `link`
+    /// must return once the requested bytes or some of them have arrived,
+    /// regardless of their content.
+    pub fn new(link: Box<dyn Transport>) -> Self {
+        Self { link, framing: Mutex::new(Framing::default()) }
+    }
+
+    /// Open the serial port `port` at `baud` through VISA, waiting up to
+    /// `timeout` for access.
+    pub fn open(port: &str, baud: u32, timeout: Duration) -> Result<Self> {
+        let rm = DefaultRM::new()?;
+        let resource = serial_resource(port);
+        log::info!("Opening {} at {} baud", resource, baud);
+        let instrument = rm.open(&crate::device::visa_string(&resource)?, AccessMode::NO_LOCK, timeout)?;
+        let baud_rate = AttrAsrlBaud::new_checked(baud as _)
+            .ok_or_else(|| ScopeError::InvalidArgument(format!("Invalid baud rate {}", baud)))?;
+        instrument.set_attr(baud_rate)?;
+        // Reads must not stop at newlines within blocks
+        instrument.set_attr(AttrAsrlEndIn::VI_ASRL_END_NONE)?;
+        instrument.set_attr(AttrTermcharEn::VI_FALSE)?;
+        Ok(Self::new(Box::new(VisaTransport::new(instrument, rm))))
+    }
+
+    /// Read a single byte, `None` at the end of the stream.
+    fn read_byte(&self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8; 1];
+        Ok((self.link.receive(&mut byte)? == 1).then_some(byte[0]))
+    }
+
+    /// Read the first byte of a response and, for a definite-length block,
+    /// the rest of its header into `pending`. Anything else is text.
+    fn start_response(&self, framing: &mut Framing) -> io::Result<()> {
+        framing.response = Response::Text;
+        let mut length = 0usize;
+        let mut digits = None;
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(());
+            };
+            framing.pending.push_back(byte);
+            if byte == b'\n' {
+                framing.response = Response::Idle;
+                return Ok(());
+            }
+            match (framing.pending.len(), digits) {
+                (1, _) if byte == b'#' => {}
+                (2, _) if (b'1'..=b'9').contains(&byte) => digits = Some((byte - b'0') as usize),
+                (_, Some(count)) if byte.is_ascii_digit() => {
+                    length = length * 10 + (byte - b'0') as usize;
+                    if framing.pending.len() == count + 2 {
+                        framing.response = Response::Block { remaining: length };
+                        return Ok(());
+                    }
+                }
+                // Text, including indefinite-length blocks
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Transport for SerialTransport {
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.link.send(data)
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut framing = self.framing.lock().unwrap_or_else(PoisonError::into_inner);
+        if framing.pending.is_empty() && framing.response == Response::Idle {
+            self.start_response(&mut framing)?;
+        }
+        let mut count = framing.pending.len().min(buf.len());
+        for (slot, byte) in buf.iter_mut().zip(framing.pending.drain(..count)) {
+            *slot = byte;
+        }
+        if !framing.pending.is_empty() {
+            return Ok(count);
+        }
+        match framing.response {
+            Response::Block { remaining: 0 } => framing.response = Response::Idle,
+            // A block header is returned on its own
+            Response::Block { .. } if count > 0 => {}
+            Response::Block { remaining } => {
+                let wanted = remaining.min(buf.len());
+                count = self.link.receive(&mut buf[..wanted])?;
+                framing.response = match remaining - count {
+                    0 => Response::Idle,
+                    remaining => Response::Block { remaining },
+                };
+            }
+            Response::Text => {
+                while count < buf.len() {
+                    let Some(byte) = self.read_byte()? else { break };
+                    buf[count] = byte;
+                    count += 1;
+                    if byte == b'\n' {
+                        framing.response = Response::Idle;
+                        break;
+                    }
+                }
+            }
+            Response::Idle => {}
+        }
+        Ok(count)
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.framing.lock().unwrap_or_else(PoisonError::into_inner) = Framing::default();
+        self.link.clear()
+    }
+
+    fn timeout(&self) -> Result<Duration> {
+        self.link.timeout()
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.link.set_timeout(timeout)
+    }
+}
+
+#[cfg(all(test, feature = "serial"))]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::OscilloscopeWaveform;
+
+    /// Serial link handing out at most 3 bytes per read.
+    struct Link {
+        received: Mutex<Cursor<Vec<u8>>>,
+    }
+
+    impl Transport for Link {
+        fn send(&self, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.received.lock().unwrap().read(&mut buf[..len])
+        }
+
+        fn clear(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn timeout(&self) -> Result<Duration> {
+            Ok(Duration::from_secs(1))
+        }
+
+        fn set_timeout(&self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serial(stream: &[u8]) -> SerialTransport {
+        SerialTransport::new(Box::new(Link { received: Mutex::new(Cursor::new(stream.to_vec())) }))
+    }
+
+    #[test]
+    fn splits_the_stream_into_responses() {
+        // A block with newlines in its data between two text responses
+        let transport = serial(b"1.5\n#15a\nb\nc\nBatronix,Magnova,1,1\n");
+        let receive = || {
+            let mut buf = [0u8; 64];
+            let count = transport.receive(&mut buf).unwrap();
+            buf[..count].to_vec()
+        };
+        assert_eq!(receive(), b"1.5\n");
+        let mut block = Vec::new();
+        while block.len() < 8 {
+            block.extend(receive());
+        }
+        assert_eq!(block, b"#15a\nb\nc");
+        assert_eq!(receive(), b"\n");
+        assert_eq!(receive(), b"Batronix,Magnova,1,1\n");
+        assert!(receive().is_empty());
+    }
+
+    #[test]
+    fn reads_blocks_through_the_scope() {
+        let scope = OscilloscopeWaveform::with_transport(Box::new(serial(b"#210\n\n\n\n\n\n\n\n\n\n\n42\n")));
+        assert_eq!(scope.read_binary_block().unwrap(), b"\n\n\n\n\n\n\n\n\n\n");
+        assert_eq!(scope.read_line().unwrap(), "42");
+    }
+
+    #[test]
+    fn names_serial_resources() {
+        assert_eq!(serial_resource("COM3"), "ASRL3::INSTR");
+        assert_eq!(serial_resource("/dev/ttyACM0"), "ASRL/dev/ttyACM0::INSTR");
+        assert_eq!(serial_resource("ASRL1::INSTR"), "ASRL1::INSTR");
+    }
+}
@@ -0,0 +1,66 @@
+//! Values with SI prefixes, for axis labels and plot captions.
+
+/// SI prefixes from pico to giga with their powers of ten.
+const PREFIXES: [(i32, &str); 8] = [
+    (-12, "p"), (-9, "n"), (-6, "µ"), (-3, "m"), (0, ""), (3, "k"), (6, "M"), (9, "G"),
+];
+
+/// Significant digits of a formatted value.
+const SIGNIFICANT_DIGITS: i32 = 3;
+
+/// Format `value` with the SI prefix that brings it into 1 to 999, rounded
+/// to three significant digits without trailing zeros, e.g. `250 ns` for
+/// `2.5e-7` seconds.
+///
+/// Values rounding up to the next prefix take it, so 999.9 µs gives
+/// `1 ms`. Values beyond pico or giga keep that prefix.
+pub fn format_si(value: f64, unit: &str) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return format!("{} {}", value, unit);
+    }
+
+    // Round first, so the prefix is chosen for the value as printed
+    let scale = 10f64.powi(value.abs().log10().floor() as i32 - (SIGNIFICANT_DIGITS - 1));
+    let rounded = (value / scale).round() * scale;
+    let magnitude = rounded.abs().log10().floor() as i32;
+    let (power, prefix) = PREFIXES.iter().rev()
+        .find(|(power, _)| *power <= magnitude)
+        .unwrap_or(&PREFIXES[0]);
+
+    let mantissa = rounded / 10f64.powi(*power);
+    let decimals = (SIGNIFICANT_DIGITS - 1 - mantissa.abs().log10().floor() as i32).max(0) as usize;
+    let digits = format!("{:.*}", decimals, mantissa);
+    let digits = if digits.contains('.') { digits.trim_end_matches('0').trim_end_matches('.') } else { &digits };
+    format!("{} {}{}", digits, prefix, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_prefix_of_the_rounded_value() {
+        assert_eq!(format_si(2.5e-7, "s"), "250 ns");
+        assert_eq!(format_si(999e-9, "s"), "999 ns");
+        assert_eq!(format_si(999.4e-9, "s"), "999 ns");
+        assert_eq!(format_si(999.6e-9, "s"), "1 µs");
+        assert_eq!(format_si(1e-6, "s"), "1 µs");
+        assert_eq!(format_si(1.5e-3, "s"), "1.5 ms");
+        assert_eq!(format_si(0.001, "V"), "1 mV");
+        assert_eq!(format_si(0.9999, "V"), "1 V");
+        assert_eq!(format_si(12.34, "V"), "12.3 V");
+        assert_eq!(format_si(100.0, "V"), "100 V");
+        assert_eq!(format_si(1.6e9, "Sa/s"), "1.6 GSa/s");
+        assert_eq!(format_si(25e6, "pts"), "25 Mpts");
+    }
+
+    #[test]
+    fn formats_signs_and_edge_cases() {
+        assert_eq!(format_si(-0.0025, "V"), "-2.5 mV");
+        assert_eq!(format_si(-999.96, "V"), "-1 kV");
+        assert_eq!(format_si(0.0, "s"), "0 s");
+        assert_eq!(format_si(1.234e-15, "s"), "0.00123 ps");
+        assert_eq!(format_si(5e12, "Hz"), "5000 GHz");
+        assert_eq!(format_si(f64::NAN, "V"), "NaN V");
+    }
+}
@@ -0,0 +1,1275 @@
+//! Capturing waveforms and decoding waveform data blocks.
+
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Instant;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::{debug, info, error, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cursor::visible_time_range;
+use crate::device::no_progress;
+use crate::sample_format::{RawU16Decoder, SampleDecoder, TransferFormat};
+use crate::scpi::ScpiError;
+use crate::settings::ChannelScaling;
+use crate::{check_channel, OscilloscopeWaveform, Result, ScopeError, CHANNEL_COUNT};
+
+/// Consecutive samples at the lowest or highest ADC code that count as
+/// clipping. Shorter runs happen when a signal just touches the range.
+const MIN_CLIPPED_RUN: usize = 8;
+
+/// Header of a waveform data block.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WaveformMetadata {
+    /// Time between two samples in seconds.
+    pub time_delta: f32,
+    /// Time of the first sample relative to the trigger, in seconds.
+    pub start_time: f32,
+    /// Time of the last sample relative to the trigger, in seconds.
+    pub end_time: f32,
+    /// Index of the first valid sample. RAW only.
+    pub sample_start: u32,
+    /// Number of valid samples from `sample_start`. Instruments reporting
+    /// the acquisition memory size here send a value beyond the block,
+    /// which then counts as entirely valid. RAW only.
+    pub sample_length: u32,
+    /// Voltage of ADC code 0. RAW only.
+    pub vertical_start: f32,
+    /// Voltage span of the full 16-bit code range. RAW only.
+    pub vertical_step: f32,
+    /// Number of samples in the block.
+    pub sample_count: u32,
+}
+
+/// Deviation between the sample count of a block and the one implied by its
+/// time range, as a fraction of the sample count. One sample more or less
+/// is always accepted, for short blocks.
+const SAMPLE_COUNT_TOLERANCE: f64 = 0.01;
+
+/// A contradiction in a block's [`WaveformMetadata`], which points to a
+/// firmware bug or a corrupted transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum MetadataValidationError {
+    /// The time between samples is zero, negative or not a number.
+    #[error("Time delta {0} s is not positive")]
+    NonPositiveTimeDelta(f32),
+    #[error("Start time {start_time} s is not before end time {end_time} s")]
+    InvertedTimeRange { start_time: f32, end_time: f32 },
+    /// The time range holds a different number of samples than the block.
+    #[error("Time range holds {implied:.1} samples, but the block has {sample_count}")]
+    SampleCountMismatch { implied: f64, sample_count: u32 },
+    /// RAW codes cannot be converted to volts.
+    #[error("Vertical step {0} V is not positive")]
+    NonPositiveVerticalStep(f32),
+}
+
+impl WaveformMetadata {
+    /// Check that the metadata of a block sent as `data_transfer_type` is
+    /// consistent: a positive time delta, a start before the end time, a
+    /// time range that holds `sample_count` samples within 1 % and, for
+    /// RAW, a positive vertical step.
+    ///
+    /// The time range is that of the block, so metadata of a partial read
+    /// has to be moved to the block's place in the record first.
+    pub fn validate(&self, data_transfer_type: TransferFormat)
+        -> std::result::Result<(), MetadataValidationError> {
+        // Written to also reject NaN
+        if !(self.time_delta > 0.0 && self.time_delta.is_finite()) {
+            return Err(MetadataValidationError::NonPositiveTimeDelta(self.time_delta));
+        }
+        let in_order = match self.sample_count {
+            0 | 1 => self.start_time <= self.end_time,
+            _ => self.start_time < self.end_time,
+        };
+        if !in_order {
+            return Err(MetadataValidationError::InvertedTimeRange {
+                start_time: self.start_time,
+                end_time: self.end_time,
+            });
+        }
+        if self.sample_count > 0 {
+            let implied = (self.end_time as f64 - self.start_time as f64) / self.time_delta as f64 + 1.0;
+            let tolerance = (self.sample_count as f64 * SAMPLE_COUNT_TOLERANCE).max(1.0);
+            if (implied - self.sample_count as f64).abs() > tolerance {
+                return Err(MetadataValidationError::SampleCountMismatch { implied, sample_count: self.sample_count });
+            }
+        }
+        if data_transfer_type == TransferFormat::Raw && !(self.vertical_step > 0.0 && self.vertical_step.is_finite()) {
+            return Err(MetadataValidationError::NonPositiveVerticalStep(self.vertical_step));
+        }
+        Ok(())
+    }
+}
+
+/// Byte order of the numbers in data blocks and their metadata.
+///
+/// Instruments send little-endian blocks by default, but some SCPI-over-TCP
+/// configurations use network byte order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrderMode {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrderMode {
+    /// Parse a `FORMat:BORDer?` reply. SCPI calls big-endian `NORMal` and
+    /// little-endian `SWAPped`, some instruments `MSBFirst` and `LSBFirst`.
+    pub fn parse(response: &str) -> Option<Self> {
+        match response.trim().to_ascii_uppercase().as_str() {
+            "SWAP" | "SWAPPED" | "LSBF" | "LSBFIRST" | "LITT" | "LITTLE" => Some(ByteOrderMode::LittleEndian),
+            "NORM" | "NORMAL" | "MSBF" | "MSBFIRST" | "BIG" => Some(ByteOrderMode::BigEndian),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn read_u16(self, bytes: &[u8]) -> u16 {
+        match self {
+            ByteOrderMode::LittleEndian => LittleEndian::read_u16(bytes),
+            ByteOrderMode::BigEndian => BigEndian::read_u16(bytes),
+        }
+    }
+
+    pub(crate) fn read_u32(self, bytes: &[u8]) -> u32 {
+        match self {
+            ByteOrderMode::LittleEndian => LittleEndian::read_u32(bytes),
+            ByteOrderMode::BigEndian => BigEndian::read_u32(bytes),
+        }
+    }
+
+    pub(crate) fn read_f32(self, bytes: &[u8]) -> f32 {
+        match self {
+            ByteOrderMode::LittleEndian => LittleEndian::read_f32(bytes),
+            ByteOrderMode::BigEndian => BigEndian::read_f32(bytes),
+        }
+    }
+
+    pub(crate) fn f32_bytes(self, value: f32) -> [u8; 4] {
+        match self {
+            ByteOrderMode::LittleEndian => value.to_le_bytes(),
+            ByteOrderMode::BigEndian => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Decode the metadata header of a `DATa:PACK?` block.
+///
+/// `data_transfer_type` is the type the block was requested with, which
+/// selects the header layout, see [`TransferFormat::decoder`]. `order` is
+/// the byte order the instrument sends, see
+/// [`OscilloscopeWaveform::byte_order`].
+///
+/// Inconsistent metadata gives [`ScopeError::InvalidMetadata`], see
+/// [`WaveformMetadata::validate`]. The start time of a partial read is that
+/// of the whole record, so only complete blocks pass.
+pub fn parse_metadata(data: &[u8], data_transfer_type: TransferFormat, order: ByteOrderMode)
+    -> Result<WaveformMetadata> {
+    let metadata = decode_metadata(data, data_transfer_type, order)?;
+    log_metadata(&metadata, data_transfer_type);
+    metadata.validate(data_transfer_type)?;
+    Ok(metadata)
+}
+
+fn log_metadata(metadata: &WaveformMetadata, data_transfer_type: TransferFormat) {
+    info!("Metadata:");
+    info!("  TimeDelta = {}", metadata.time_delta);
+    info!("  StartTime = {}", metadata.start_time);
+    info!("  EndTime = {}", metadata.end_time);
+    if data_transfer_type == TransferFormat::Raw {
+        info!("  SampleStart = {}", metadata.sample_start);
+        info!("  SampleLength = {}", metadata.sample_length);
+        info!("  VerticalStart = {}", metadata.vertical_start);
+        info!("  VerticalStep = {}", metadata.vertical_step);
+    }
+    info!("  SampleCount = {}", metadata.sample_count);
+}
+
+/// Decode the metadata header without logging it.
+pub(crate) fn decode_metadata(data: &[u8], data_transfer_type: TransferFormat, order: ByteOrderMode)
+    -> Result<WaveformMetadata> {
+    data_transfer_type.decoder(order).parse_metadata(data)
+}
+
+/// Range of the valid samples among the `available` ones of a block.
+///
+/// The window given by `sample_start` and `sample_length` is used if it is
+/// set and lies within the block, otherwise every sample is valid.
+pub(crate) fn valid_samples(metadata: &WaveformMetadata, available: usize) -> Range<usize> {
+    if available != metadata.sample_count as usize {
+        warn!("Block holds {} samples, but its metadata reports {}", available, metadata.sample_count);
+    }
+    let start = metadata.sample_start as usize;
+    let end = start.saturating_add(metadata.sample_length as usize);
+    if metadata.sample_length == 0 || end > available {
+        return 0..available;
+    }
+    if end - start < available {
+        debug!("Using samples {} to {} of {}", start, end, available);
+    }
+    start..end
+}
+
+/// Decode the samples of a `DATa:PACK?` block into the channel's unit.
+///
+/// The samples are decoded with the [`SampleDecoder`] of
+/// `data_transfer_type`: RAW samples are 16-bit ADC codes scaled with the
+/// metadata's vertical start and step, V samples 32-bit floats. The resulting
+/// voltages are then converted with `scaling`, e.g. to amps for a current
+/// probe. `ChannelScaling::default()` keeps volts.
+///
+/// If the metadata marks only part of a RAW block as valid, only that
+/// part is returned, and the first returned sample is at `start_time`.
+pub fn extract_waveform(data: &[u8], metadata: &WaveformMetadata, data_transfer_type: TransferFormat,
+    scaling: ChannelScaling, order: ByteOrderMode) -> Result<Vec<f32>> {
+    let mut values = Vec::new();
+    extract_waveform_into(data, metadata, data_transfer_type, scaling, order, &mut values)?;
+    Ok(values)
+}
+
+/// Decode the samples of a block into `values`, reusing its allocation.
+pub(crate) fn extract_waveform_into(data: &[u8], metadata: &WaveformMetadata, data_transfer_type: TransferFormat,
+    scaling: ChannelScaling, order: ByteOrderMode, values: &mut Vec<f32>) -> Result<()> {
+    let decoder = data_transfer_type.decoder(order);
+    let metadata_size = decoder.metadata_len();
+    if data.len() < metadata_size {
+        return Err(ScopeError::MetadataTooShort { len: data.len(), needed: metadata_size });
+    }
+    decoder.decode_into(&data[metadata_size..], metadata, values);
+    if !scaling.is_identity() {
+        for value in values.iter_mut() {
+            *value = scaling.apply(*value);
+        }
+    }
+    Ok(())
+}
+
+/// Convert a RAW ADC code to volts.
+pub fn code_to_voltage(code: u16, metadata: &WaveformMetadata) -> f32 {
+    // The vertical step is already scaled for 16-bit range
+    metadata.vertical_start + (code as f32) * metadata.vertical_step / 65536.0
+}
+
+/// Decode the samples of a RAW `DATa:PACK?` block into ADC codes.
+///
+/// Like [`extract_waveform`], only the valid part of the block is returned.
+pub fn extract_waveform_raw(data: &[u8], order: ByteOrderMode) -> Result<Vec<u16>> {
+    let decoder = RawU16Decoder::new(order);
+    let metadata = decoder.parse_metadata(data)?;
+    Ok(decoder.decode_codes(&data[decoder.metadata_len()..], &metadata))
+}
+
+/// How [`WaveformRecord::decimate`] reduces every block of samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Decimation {
+    /// Keep the first sample of every block.
+    NthSample,
+    /// Average every block, placed at the block's centre.
+    Mean,
+    /// Keep the minimum and maximum of every block in their original
+    /// order, so spikes survive. Yields two samples per block.
+    #[default]
+    MinMax,
+}
+
+impl FromStr for Decimation {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "nth" | "nth-sample" => Ok(Decimation::NthSample),
+            "mean" | "average" => Ok(Decimation::Mean),
+            "minmax" | "min-max" => Ok(Decimation::MinMax),
+            _ => Err(format!("Unknown decimation '{}', expected nth, mean or minmax", value)),
+        }
+    }
+}
+
+/// Zero point of the time axis of decoded captures, set with
+/// [`set_time_reference`](OscilloscopeWaveform::set_time_reference).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeReference {
+    /// Times relative to the trigger point, negative before it.
+    #[default]
+    Trigger,
+    /// Times since the first sample of the acquisition record, as the
+    /// record's own sample clock counts them.
+    Acquisition,
+}
+
+/// Part of the record a `DATa:PACK?` query returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DataRange {
+    /// The whole record.
+    #[default]
+    All,
+    /// `count` samples from index `start` of the record, to cut the
+    /// transfer time at large memory depths.
+    Samples { start: u32, count: u32 },
+    /// The samples within the visible screen area.
+    Visible,
+}
+
+impl DataRange {
+    /// The length argument of a `DATa:PACK?` query.
+    pub(crate) fn scpi_argument(&self) -> String {
+        match self {
+            DataRange::All => "ALL".to_string(),
+            DataRange::Samples { start, count } => format!("{},{}", start, count),
+            DataRange::Visible => "DISPlay".to_string(),
+        }
+    }
+
+    /// Index in the record of the first sample of a block read with this
+    /// range. RAW blocks report it in their metadata, blocks in volts
+    /// don't, so the requested start is used for them.
+    fn first_sample(&self, metadata: &WaveformMetadata, data_transfer_type: TransferFormat) -> u32 {
+        match self {
+            DataRange::Samples { .. } if data_transfer_type == TransferFormat::Raw => metadata.sample_start,
+            DataRange::Samples { start, .. } => *start,
+            DataRange::All | DataRange::Visible => 0,
+        }
+    }
+
+    /// Move the time base of a block read with this range to the block's
+    /// place in the record. The metadata's start time is that of the whole
+    /// record, so a partial read begins `first_sample` intervals later.
+    fn align_metadata(&self, metadata: &mut WaveformMetadata, data_transfer_type: TransferFormat) {
+        let first = self.first_sample(metadata, data_transfer_type);
+        if first > 0 {
+            let offset = first as f64 * metadata.time_delta as f64;
+            metadata.start_time = (metadata.start_time as f64 + offset) as f32;
+            metadata.end_time = (metadata.start_time as f64
+                + metadata.sample_count.saturating_sub(1) as f64 * metadata.time_delta as f64) as f32;
+            debug!("Block starts at sample {} of the record, {} s", first, metadata.start_time);
+        }
+    }
+}
+
+impl FromStr for DataRange {
+    type Err = String;
+
+    /// Parse `all`, `visible` or `START:COUNT` in samples.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let value = value.trim();
+        match value.to_ascii_lowercase().as_str() {
+            "all" => return Ok(DataRange::All),
+            "visible" | "screen" => return Ok(DataRange::Visible),
+            _ => {}
+        }
+        let invalid = || format!("Invalid data range '{}', expected all, visible or START:COUNT", value);
+        let (start, count) = value.split_once(':').ok_or_else(invalid)?;
+        let start = start.trim().parse::<u32>().map_err(|_| invalid())?;
+        let count = count.trim().parse::<u32>().map_err(|_| invalid())?;
+        if count == 0 {
+            return Err(format!("Data range '{}' holds no samples", value));
+        }
+        Ok(DataRange::Samples { start, count })
+    }
+}
+
+/// `DATa:PACK?` query of `range` of a channel.
+pub(crate) fn pack_query(channel: u8, range: DataRange, data_transfer_type: TransferFormat) -> String {
+    format!("CHAN{}:DATa:PACK? {}, {}", channel, range.scpi_argument(), data_transfer_type)
+}
+
+/// Snap an index computed from times to the nearest integer if float
+/// rounding put it just beside one.
+fn snap_index(index: f64) -> f64 {
+    if (index - index.round()).abs() < 1e-3 { index.round() } else { index }
+}
+
+/// The samples of a record of `memory_depth` points spanning `record`
+/// that lie from `start_s` to `end_s`.
+fn window_range(record: (f32, f32), memory_depth: u32, start_s: f32, end_s: f32) -> Result<DataRange> {
+    let time_delta = (record.1 as f64 - record.0 as f64) / memory_depth.max(1) as f64;
+    let record_end = (record.0 as f64 + memory_depth.saturating_sub(1) as f64 * time_delta) as f32;
+    if start_s < record.0 || end_s > record_end {
+        return Err(ScopeError::InvalidArgument(format!(
+            "Window {} to {} s lies outside the record from {} to {} s", start_s, end_s, record.0, record_end
+        )));
+    }
+    let index = |t: f32| snap_index((t as f64 - record.0 as f64) / time_delta);
+    let (first, last) = (index(start_s).ceil(), index(end_s).floor());
+    if last < first {
+        return Err(ScopeError::InvalidArgument(format!(
+            "Window {} to {} s holds no samples at {} s intervals", start_s, end_s, time_delta
+        )));
+    }
+    Ok(DataRange::Samples { start: first as u32, count: (last - first) as u32 + 1 })
+}
+
+/// A RAW capture as ADC codes together with its metadata.
+///
+/// Only the codes are stored. Voltages and time values are computed on
+/// demand, so a 1M point record takes 2 MB instead of 10 MB with both
+/// arrays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaveformRecord {
+    pub metadata: WaveformMetadata,
+    pub raw_codes: Vec<u16>,
+    /// Conversion of the voltages into the channel's unit, see
+    /// [`set_channel_unit`](OscilloscopeWaveform::set_channel_unit).
+    pub scaling: ChannelScaling,
+}
+
+impl WaveformRecord {
+    /// Decode a RAW `DATa:PACK?` block sent in byte order `order`. The
+    /// samples are in volts.
+    pub fn from_block(data: &[u8], order: ByteOrderMode) -> Result<Self> {
+        let metadata = parse_metadata(data, TransferFormat::Raw, order)?;
+        Ok(Self { metadata, raw_codes: extract_waveform_raw(data, order)?, scaling: ChannelScaling::default() })
+    }
+
+    /// Sample values in the channel's unit, converted as they are iterated.
+    pub fn voltages(&self) -> impl ExactSizeIterator<Item = f32> + '_ {
+        self.raw_codes.iter().map(|&code| self.scaling.apply(code_to_voltage(code, &self.metadata)))
+    }
+
+    /// Sample times on the time axis the record was captured with,
+    /// relative to the trigger by default, see [`TimeReference`].
+    pub fn time_values(&self) -> impl ExactSizeIterator<Item = f32> + '_ {
+        (0..self.raw_codes.len()).map(|i| self.metadata.start_time + (i as f32) * self.metadata.time_delta)
+    }
+
+    /// Samples in runs of at least 8 pinned at the lowest or highest ADC
+    /// code, 0 if the signal stayed within the vertical range.
+    pub fn clipped_samples(&self) -> usize {
+        self.raw_codes.chunk_by(|a, b| a == b)
+            .filter(|run| matches!(run[0], 0 | u16::MAX) && run.len() >= MIN_CLIPPED_RUN)
+            .map(<[u16]>::len)
+            .sum()
+    }
+
+    /// A record of `raw_codes` on a new time grid, with the metadata
+    /// updated to match.
+    fn with_codes(&self, raw_codes: Vec<u16>, start_time: f32, time_delta: f32) -> WaveformRecord {
+        let count = raw_codes.len() as u32;
+        let metadata = WaveformMetadata {
+            time_delta,
+            start_time,
+            end_time: start_time + count.saturating_sub(1) as f32 * time_delta,
+            sample_start: 0,
+            sample_length: count,
+            sample_count: count,
+            ..self.metadata
+        };
+        WaveformRecord { metadata, raw_codes, scaling: self.scaling }
+    }
+
+    /// The samples from `start` to `end` seconds relative to the trigger.
+    ///
+    /// The indices follow from the start time and sample interval. A
+    /// window reaching beyond the capture is clamped to it with a warning,
+    /// and one entirely outside of it gives an empty record.
+    pub fn slice_time(&self, start: f32, end: f32) -> WaveformRecord {
+        let len = self.raw_codes.len();
+        let time_delta = self.metadata.time_delta;
+        if len == 0 || !time_delta.is_finite() || time_delta <= 0.0 {
+            warn!("Cannot slice a record without samples or time base");
+            return self.clone();
+        }
+        let index = |t: f32| snap_index((t as f64 - self.metadata.start_time as f64) / time_delta as f64);
+        let (first, last) = (index(start.min(end)).ceil(), index(start.max(end)).floor());
+        if first < 0.0 || last > (len - 1) as f64 {
+            let capture_end = self.metadata.start_time + (len - 1) as f32 * time_delta;
+            warn!("Window {} to {} s reaches beyond the capture from {} to {} s, clamping",
+                start, end, self.metadata.start_time, capture_end);
+        }
+        let first = first.max(0.0) as usize;
+        let last = last.min((len - 1) as f64);
+        if last < first as f64 {
+            warn!("Window {} to {} s holds no samples", start, end);
+            return self.with_codes(Vec::new(), self.metadata.start_time + first as f32 * time_delta, time_delta);
+        }
+        let codes = self.raw_codes[first..=last as usize].to_vec();
+        self.with_codes(codes, self.metadata.start_time + first as f32 * time_delta, time_delta)
+    }
+
+    /// Reduce the sample rate by `factor`, with the metadata's time base
+    /// adjusted so `time_values` stays consistent.
+    ///
+    /// A trailing block shorter than `factor` is dropped by `Mean` and
+    /// `MinMax`. A factor of 0 or 1 keeps every sample.
+    pub fn decimate(&self, factor: usize, strategy: Decimation) -> WaveformRecord {
+        if factor <= 1 {
+            return self.clone();
+        }
+        let time_delta = self.metadata.time_delta * factor as f32;
+        match strategy {
+            Decimation::NthSample => {
+                let codes = self.raw_codes.iter().step_by(factor).copied().collect();
+                self.with_codes(codes, self.metadata.start_time, time_delta)
+            }
+            Decimation::Mean => {
+                let codes = self.raw_codes.chunks_exact(factor)
+                    .map(|block| (block.iter().map(|&code| code as f64).sum::<f64>() / factor as f64).round() as u16)
+                    .collect();
+                let centre = self.metadata.start_time + (factor - 1) as f32 / 2.0 * self.metadata.time_delta;
+                self.with_codes(codes, centre, time_delta)
+            }
+            Decimation::MinMax => {
+                let mut codes = Vec::with_capacity(self.raw_codes.len() / factor * 2);
+                for block in self.raw_codes.chunks_exact(factor) {
+                    let mut min = 0;
+                    let mut max = 0;
+                    for (i, &code) in block.iter().enumerate() {
+                        if code < block[min] {
+                            min = i;
+                        }
+                        if code > block[max] {
+                            max = i;
+                        }
+                    }
+                    codes.extend([block[min.min(max)], block[min.max(max)]]);
+                }
+                self.with_codes(codes, self.metadata.start_time, time_delta / 2.0)
+            }
+        }
+    }
+}
+
+impl OscilloscopeWaveform {
+    /// Zero point of the time axis of decoded captures.
+    pub fn time_reference(&self) -> TimeReference {
+        self.time_reference
+    }
+
+    /// Place the zero of the time axis of captures at the trigger point,
+    /// the default, or at the first sample of the acquisition record.
+    ///
+    /// It applies to [`get_waveform_data`](Self::get_waveform_data),
+    /// [`get_waveform_record`](Self::get_waveform_record) and multi-channel
+    /// captures. Plots mark the trigger point while it is the zero.
+    pub fn set_time_reference(&mut self, reference: TimeReference) {
+        self.time_reference = reference;
+    }
+
+    /// Byte order data blocks are decoded with, little-endian unless set
+    /// or detected otherwise.
+    pub fn byte_order(&self) -> ByteOrderMode {
+        self.byte_order
+    }
+
+    /// Decode data blocks with byte order `order`, e.g. for an instrument
+    /// configured for network byte order.
+    pub fn set_byte_order(&mut self, order: ByteOrderMode) {
+        self.byte_order = order;
+    }
+
+    /// Ask the instrument for its byte order with `FORMat:BORDer?` and
+    /// decode data blocks with it.
+    ///
+    /// Instruments without the query keep little-endian, as does an
+    /// unknown reply. The query is followed by `SYSTem:ERRor?`, whose reply
+    /// arrives either way, so an unsupported query costs no timeout and its
+    /// error is cleared.
+    pub fn detect_byte_order(&mut self) -> Result<ByteOrderMode> {
+        self.send_command("FORMat:BORDer?")?;
+        let reply = self.query("SYSTem:ERRor?")?;
+        let order = if let Some(order) = ByteOrderMode::parse(&reply) {
+            // The error queue reply is still pending
+            self.read_line()?;
+            order
+        } else if let Ok(error) = ScpiError::parse(&reply) {
+            // No reply to FORMat:BORDer?, this was the error queue's
+            debug!("FORMat:BORDer? is not supported: {}", error);
+            self.check_errors()?;
+            ByteOrderMode::LittleEndian
+        } else {
+            warn!("Unknown byte order {:?}, assuming little-endian", reply);
+            self.read_line()?;
+            ByteOrderMode::LittleEndian
+        };
+        info!("Byte order of data blocks: {:?}", order);
+        self.byte_order = order;
+        Ok(order)
+    }
+
+    /// Capture a channel and return its time and voltage values.
+    ///
+    /// `range` selects the part of the record to transfer, with the time
+    /// values placed where it lies in the record. A range beyond the record
+    /// fails with the instrument's error. `memory_depth` sets the
+    /// acquisition memory depth in points first, `None` keeps the
+    /// instrument's current setting. In averaging mode the data is read
+    /// once all averaged triggers have arrived.
+    pub fn get_waveform_data(&self, channel: u8, range: DataRange, data_transfer_type: TransferFormat,
+        memory_depth: Option<u32>) -> Result<(Vec<f32>, Vec<f32>)> {
+        let sequences = self.acquisition_mode()?.sequences();
+        self.capture(channel, range, data_transfer_type, memory_depth, sequences)
+    }
+
+    /// Like [`get_waveform_data`](Self::get_waveform_data) with the current
+    /// memory depth, calling `progress` with the bytes read so far and the
+    /// total after every 64 KiB of the transfer.
+    ///
+    /// The total is 0 if the instrument does not announce the block size,
+    /// so the progress is indeterminate until a last call with both values
+    /// equal. The callback runs on the reading
+    /// thread between VISA reads, so it must not block or panic.
+    pub fn get_waveform_data_with_progress<F>(&self, channel: u8, range: DataRange, dtype: TransferFormat, progress: F)
+        -> Result<(Vec<f32>, Vec<f32>)>
+    where
+        F: Fn(usize, usize),
+    {
+        let sequences = self.acquisition_mode()?.sequences();
+        self.capture_with_progress(channel, range, dtype, None, sequences, &progress)
+    }
+
+    /// Capture a channel and read only the samples from `start_s` to
+    /// `end_s` relative to the trigger, e.g. a few microseconds around it
+    /// at a memory depth of millions of points.
+    ///
+    /// The window is translated into sample indices assuming the record
+    /// spans the screen at the current timebase and memory depth, and only
+    /// those samples are transferred. The window must lie within the record
+    /// and hold at least one sample.
+    pub fn get_waveform_window(&self, channel: u8, start_s: f32, end_s: f32, dtype: TransferFormat)
+        -> Result<(Vec<f32>, Vec<f32>)> {
+        check_channel(channel)?;
+        if !start_s.is_finite() || !end_s.is_finite() || start_s >= end_s {
+            return Err(ScopeError::InvalidArgument(format!("Invalid time window {} to {} s", start_s, end_s)));
+        }
+        let record = visible_time_range(&self.get_timebase()?);
+        let memory_depth = self.memory_depth()?;
+        let range = window_range(record, memory_depth, start_s, end_s)?;
+        info!("Reading samples {} of {} for {} to {} s", range.scpi_argument(), memory_depth, start_s, end_s);
+        let sequences = self.acquisition_mode()?.sequences();
+        self.capture(channel, range, dtype, None, sequences)
+    }
+
+    /// Capture a channel in RAW format and return the ADC codes with their
+    /// metadata and the channel's unit conversion. Like [`get_waveform_data`](Self::get_waveform_data), the
+    /// data is read once all averaged triggers have arrived.
+    pub fn get_waveform_record(&self, channel: u8, range: DataRange, memory_depth: Option<u32>)
+        -> Result<WaveformRecord> {
+        let sequences = self.acquisition_mode()?.sequences();
+        let memory_depth = self.start_capture(&[channel], TransferFormat::Raw, memory_depth, sequences)?;
+        let block = self.read_block(&pack_query(channel, range, TransferFormat::Raw), &no_progress);
+        let data = self.check_range(range, block)?;
+        let mut metadata = decode_metadata(&data, TransferFormat::Raw, self.byte_order)?;
+        log_metadata(&metadata, TransferFormat::Raw);
+        let record_start = metadata.start_time;
+        range.align_metadata(&mut metadata, TransferFormat::Raw);
+        metadata.validate(TransferFormat::Raw)?;
+        self.apply_time_reference(&mut metadata, record_start, range);
+        let record = WaveformRecord {
+            metadata,
+            raw_codes: extract_waveform_raw(&data, self.byte_order)?,
+            scaling: self.channel_scaling(channel)?,
+        };
+        self.check_range_length(range, record.raw_codes.len())?;
+        if range == DataRange::All && record.metadata.sample_count != memory_depth {
+            warn!("Received {} samples, but the memory depth is {}", record.metadata.sample_count, memory_depth);
+        }
+        Ok(record)
+    }
+
+    /// Run an acquisition of `sequences` triggers on one channel and read
+    /// it back.
+    pub(crate) fn capture(&self, channel: u8, range: DataRange, data_transfer_type: TransferFormat,
+        memory_depth: Option<u32>, sequences: u32) -> Result<(Vec<f32>, Vec<f32>)> {
+        self.capture_with_progress(channel, range, data_transfer_type, memory_depth, sequences, &no_progress)
+    }
+
+    fn capture_with_progress(&self, channel: u8, range: DataRange, data_transfer_type: TransferFormat,
+        memory_depth: Option<u32>, sequences: u32, progress: &dyn Fn(usize, usize)) -> Result<(Vec<f32>, Vec<f32>)> {
+        let memory_depth = self.start_capture(&[channel], data_transfer_type, memory_depth, sequences)?;
+        let block = self.read_block(&pack_query(channel, range, data_transfer_type), progress);
+        let data = self.check_range(range, block)?;
+        // Only a complete transfer has to match the memory depth
+        let expected_samples = (range == DataRange::All).then_some(memory_depth);
+        let (time_values, waveform) =
+            self.decode_waveform(&data, data_transfer_type, range, expected_samples, self.channel_scaling(channel)?)?;
+        self.check_range_length(range, waveform.len())?;
+        Ok((time_values, waveform))
+    }
+
+    /// Report why a partial read got no block. A range beyond the record is
+    /// not answered, with the reason in the error queue, which is returned
+    /// whatever the error check mode.
+    fn check_range(&self, range: DataRange, block: Result<Vec<u8>>) -> Result<Vec<u8>> {
+        match (range, block) {
+            (DataRange::Samples { .. }, Err(ScopeError::Timeout)) => {
+                match self.check_errors()?.into_iter().next() {
+                    Some(error) => Err(ScopeError::ScpiError { code: error.code, message: error.message }),
+                    None => Err(ScopeError::Timeout),
+                }
+            }
+            (_, block) => block,
+        }
+    }
+
+    /// Reject a partial read that returned fewer samples than requested,
+    /// as happens when the range reaches beyond the record, with the
+    /// instrument's error if it reported one.
+    fn check_range_length(&self, range: DataRange, received: usize) -> Result<()> {
+        let DataRange::Samples { start, count } = range else {
+            return Ok(());
+        };
+        if received >= count as usize {
+            return Ok(());
+        }
+        if let Some(error) = self.check_errors()?.into_iter().next() {
+            return Err(ScopeError::ScpiError { code: error.code, message: error.message });
+        }
+        Err(ScopeError::UnexpectedResponse {
+            command: format!("DATa:PACK? {}", range.scpi_argument()),
+            response: format!("{} samples from {} instead of {}", received, start, count),
+        })
+    }
+
+    /// Capture several channels from the same trigger, so their samples
+    /// line up. Returns the metadata and voltages of every channel in the
+    /// order of `channels`.
+    pub(crate) fn capture_channels(&self, channels: &[u8], data_transfer_type: TransferFormat,
+        memory_depth: Option<u32>) -> Result<Vec<(WaveformMetadata, Vec<f32>)>> {
+        for &channel in channels {
+            check_channel(channel)?;
+        }
+        let sequences = self.acquisition_mode()?.sequences();
+        self.start_capture(channels, data_transfer_type, memory_depth, sequences)?;
+        channels.iter().map(|channel| {
+            let data = self.read_block(&pack_query(*channel, DataRange::All, data_transfer_type), &no_progress)?;
+            let mut metadata = parse_metadata(&data, data_transfer_type, self.byte_order)?;
+            let scaling = self.channel_scaling(*channel)?;
+            let waveform = extract_waveform(&data, &metadata, data_transfer_type, scaling, self.byte_order)?;
+            let record_start = metadata.start_time;
+            self.apply_time_reference(&mut metadata, record_start, DataRange::All);
+            Ok((metadata, waveform))
+        }).collect()
+    }
+
+    /// Capture two channels from the same trigger for an XY plot, see
+    /// [`plot_xy`](Self::plot_xy). Both stay enabled throughout, and the
+    /// current memory depth is kept.
+    pub fn get_xy_data(&self, channel_x: u8, channel_y: u8, data_transfer_type: TransferFormat)
+        -> Result<(Vec<f32>, Vec<f32>)> {
+        let mut captures = self.capture_channels(&[channel_x, channel_y], data_transfer_type, None)?;
+        let (_, y) = captures.pop().expect("two channels captured");
+        let (_, x) = captures.pop().expect("two channels captured");
+        Ok((x, y))
+    }
+
+    /// Enable the given channels, run an acquisition of `sequences`
+    /// triggers and wait for it. Returns the memory depth in use.
+    pub(crate) fn start_capture(&self, channels: &[u8], data_transfer_type: TransferFormat, memory_depth: Option<u32>,
+        sequences: u32) -> Result<u32> {
+        let memory_depth = self.arm_capture(channels, data_transfer_type, memory_depth)?;
+        self.wait_for_sequence(sequences)?;
+        Ok(memory_depth)
+    }
+
+    /// Enable the given channels and start an acquisition without waiting
+    /// for it. Returns the memory depth in use.
+    pub(crate) fn arm_capture(&self, channels: &[u8], data_transfer_type: TransferFormat, memory_depth: Option<u32>)
+        -> Result<u32> {
+        self.enable_only_channels(channels)?;
+        
+        info!("Starting acquisition");
+        self.send_command("RUN")?;
+        self.verify_no_errors("acquisition setup")?;
+        let memory_depth = match memory_depth {
+            Some(depth) => self.set_memory_depth(depth)?,
+            None => self.memory_depth()?,
+        };
+        info!("Memory Depth: {}", memory_depth);
+        
+        // Configure channel settings
+        for channel in channels {
+            self.send_command(&format!("CHAN{}:DATa:TYPE {}", channel, data_transfer_type))?;
+        }
+        self.verify_no_errors("data type configuration")?;
+        Ok(memory_depth)
+    }
+
+    /// Enable the given channels and disable all others.
+    pub(crate) fn enable_only_channels(&self, channels: &[u8]) -> Result<()> {
+        info!("Configuring channels");
+        for i in 1..=CHANNEL_COUNT {
+            let state = if channels.contains(&i) { 1 } else { 0 };
+            self.send_command(&format!("CHAN{}:STATe {}", i, state))?;
+        }
+        Ok(())
+    }
+
+    /// Send a waveform data query and decode the returned block into time
+    /// and sample values, converted with `scaling`. A sample count
+    /// differing from `expected_samples` is logged.
+    pub(crate) fn read_waveform(&self, data_cmd: &str, data_transfer_type: TransferFormat,
+        expected_samples: Option<u32>, scaling: ChannelScaling, progress: &dyn Fn(usize, usize))
+        -> Result<(Vec<f32>, Vec<f32>)> {
+        let data = self.read_block(data_cmd, progress)?;
+        self.decode_waveform(&data, data_transfer_type, DataRange::All, expected_samples, scaling)
+    }
+
+    /// Decode a block read with `range` into time and sample values,
+    /// converted with `scaling`. A sample count differing from
+    /// `expected_samples` is logged.
+    fn decode_waveform(&self, data: &[u8], data_transfer_type: TransferFormat, range: DataRange,
+        expected_samples: Option<u32>, scaling: ChannelScaling) -> Result<(Vec<f32>, Vec<f32>)> {
+        if data.is_empty() {
+            error!("No data received");
+            return Ok((vec![], vec![]));
+        }
+        
+        // Parse metadata, validated once it describes the block
+        let mut metadata = decode_metadata(data, data_transfer_type, self.byte_order)?;
+        log_metadata(&metadata, data_transfer_type);
+        let waveform = extract_waveform(data, &metadata, data_transfer_type, scaling, self.byte_order)?;
+        let record_start = metadata.start_time;
+        range.align_metadata(&mut metadata, data_transfer_type);
+        metadata.validate(data_transfer_type)?;
+        self.apply_time_reference(&mut metadata, record_start, range);
+        if let Some(expected) = expected_samples.filter(|&count| count != metadata.sample_count) {
+            warn!("Received {} samples, but the memory depth is {}", metadata.sample_count, expected);
+        }
+        
+        // Create time base
+        let time_values: Vec<f32> = (0..waveform.len())
+            .map(|i| metadata.start_time + (i as f32) * metadata.time_delta)
+            .collect();
+            
+        Ok((time_values, waveform))
+    }
+
+    /// Check that a complete record holds the trigger point, and move the
+    /// time axis of `metadata` to the zero point of
+    /// [`set_time_reference`](Self::set_time_reference). `record_start`
+    /// is the start of the whole record relative to the trigger.
+    fn apply_time_reference(&self, metadata: &mut WaveformMetadata, record_start: f32, range: DataRange) {
+        // A partial read may well lie before or after the trigger
+        if range == DataRange::All && !(metadata.start_time <= 0.0 && metadata.end_time >= 0.0) {
+            warn!("The record from {} to {} s does not contain the trigger point", metadata.start_time,
+                metadata.end_time);
+        }
+        if self.time_reference == TimeReference::Acquisition {
+            metadata.start_time = (metadata.start_time as f64 - record_start as f64) as f32;
+            metadata.end_time = (metadata.end_time as f64 - record_start as f64) as f32;
+        }
+    }
+
+    /// Send a waveform data query and read the returned block. If the
+    /// response is not a valid block, e.g. because an earlier response was
+    /// left unread, the connection is resynchronized and the query sent
+    /// once more.
+    fn read_block(&self, data_cmd: &str, progress: &dyn Fn(usize, usize)) -> Result<Vec<u8>> {
+        info!("Capturing waveform data");
+        let start_time = Instant::now();
+        let mut data = Vec::new();
+        let query = |data: &mut Vec<u8>| {
+            self.send_command(data_cmd)?;
+            self.read_binary_block_with(data, progress)
+        };
+        match query(&mut data) {
+            Err(e) if e.is_out_of_step() => {
+                warn!("{}, resynchronizing and retrying", e);
+                self.resynchronize()?;
+                query(&mut data)?;
+            }
+            result => {
+                result?;
+            }
+        }
+        info!("Data capture time: {:.3} seconds", start_time.elapsed().as_secs_f32());
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ChannelUnit;
+    use std::time::Duration;
+
+    const LITTLE: ByteOrderMode = ByteOrderMode::LittleEndian;
+    const RAW: TransferFormat = TransferFormat::Raw;
+    const VOLTS: TransferFormat = TransferFormat::Volts;
+
+    fn raw_block(codes: &[u16]) -> Vec<u8> {
+        raw_block_with_window(codes, 0, 1000)
+    }
+
+    /// End time of `count` samples 1 µs apart from -0.5 ms.
+    fn raw_end_time(count: usize) -> f32 {
+        -5e-4 + count.saturating_sub(1) as f32 * 1e-6
+    }
+
+    fn raw_block_with_window(codes: &[u16], sample_start: u32, sample_length: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [1e-6f32, -5e-4, raw_end_time(codes.len())] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [sample_start, sample_length] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        // Vertical start -1 V, full code range spans 2 V
+        for value in [-1.0f32, 2.0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&(codes.len() as u32).to_le_bytes());
+        for code in codes {
+            data.extend_from_slice(&code.to_le_bytes());
+        }
+        data
+    }
+
+    fn volts_block(samples: &[f32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [2e-9f32, 0.0, samples.len().saturating_sub(1) as f32 * 2e-9] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        for sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parses_raw_metadata() {
+        let metadata = parse_metadata(&raw_block(&[0, 1, 2]), RAW, LITTLE).unwrap();
+        assert_eq!(metadata, WaveformMetadata {
+            time_delta: 1e-6,
+            start_time: -5e-4,
+            end_time: raw_end_time(3),
+            sample_start: 0,
+            sample_length: 1000,
+            vertical_start: -1.0,
+            vertical_step: 2.0,
+            sample_count: 3,
+        });
+    }
+
+    #[test]
+    fn parses_volts_metadata() {
+        let metadata = parse_metadata(&volts_block(&[0.5, 1.5]), VOLTS, LITTLE).unwrap();
+        assert_eq!(metadata.time_delta, 2e-9);
+        assert_eq!(metadata.end_time, 2e-9);
+        assert_eq!(metadata.sample_count, 2);
+        assert_eq!(metadata.vertical_step, 0.0);
+    }
+
+    #[test]
+    fn rejects_inconsistent_metadata() {
+        let valid = parse_metadata(&raw_block(&[0; 200]), RAW, LITTLE).unwrap();
+        let check = |change: &dyn Fn(&mut WaveformMetadata), data_transfer_type| {
+            let mut metadata = valid;
+            change(&mut metadata);
+            metadata.validate(data_transfer_type)
+        };
+        assert_eq!(check(&|_| {}, RAW), Ok(()));
+        assert_eq!(check(&|m| m.time_delta = 0.0, RAW), Err(MetadataValidationError::NonPositiveTimeDelta(0.0)));
+        assert!(matches!(check(&|m| m.time_delta = f32::NAN, VOLTS),
+            Err(MetadataValidationError::NonPositiveTimeDelta(_))));
+        assert!(matches!(check(&|m| (m.start_time, m.end_time) = (m.end_time, m.start_time), RAW),
+            Err(MetadataValidationError::InvertedTimeRange { .. })));
+        // 1 % of 200 samples is two
+        assert_eq!(check(&|m| m.sample_count = 202, RAW), Ok(()));
+        assert!(matches!(check(&|m| m.sample_count = 197, RAW),
+            Err(MetadataValidationError::SampleCountMismatch { sample_count: 197, .. })));
+        assert_eq!(check(&|m| m.vertical_step = 0.0, RAW),
+            Err(MetadataValidationError::NonPositiveVerticalStep(0.0)));
+        assert_eq!(check(&|m| m.vertical_step = 0.0, VOLTS), Ok(()));
+        // A single sample has no time range
+        assert_eq!(check(&|m| (m.end_time, m.sample_count) = (m.start_time, 1), RAW), Ok(()));
+
+        let mut data = volts_block(&[0.5, 1.5]);
+        data[..4].copy_from_slice(&0f32.to_le_bytes());
+        assert!(matches!(parse_metadata(&data, VOLTS, LITTLE),
+            Err(ScopeError::InvalidMetadata(MetadataValidationError::NonPositiveTimeDelta(_)))));
+    }
+
+    #[test]
+    fn decodes_little_endian_blocks() {
+        // Time delta 0.5, start -2.5, end -2.0, 2 samples: 1.0 and -2.5
+        let data = [
+            0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x20, 0xc0, 0x00, 0x00, 0x00, 0xc0, 0x02, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x20, 0xc0,
+        ];
+        let metadata = parse_metadata(&data, VOLTS, LITTLE).unwrap();
+        assert_eq!((metadata.time_delta, metadata.start_time, metadata.end_time), (0.5, -2.5, -2.0));
+        assert_eq!(metadata.sample_count, 2);
+        assert_eq!(extract_waveform(&data, &metadata, VOLTS, ChannelScaling::default(), LITTLE).unwrap(), [1.0, -2.5]);
+    }
+
+    #[test]
+    fn decodes_big_endian_blocks() {
+        let data = [
+            0x3f, 0x00, 0x00, 0x00, 0xc0, 0x20, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+            0x3f, 0x80, 0x00, 0x00, 0xc0, 0x20, 0x00, 0x00,
+        ];
+        let big = ByteOrderMode::BigEndian;
+        let metadata = parse_metadata(&data, VOLTS, big).unwrap();
+        assert_eq!((metadata.time_delta, metadata.start_time, metadata.end_time), (0.5, -2.5, -2.0));
+        assert_eq!(metadata.sample_count, 2);
+        assert_eq!(extract_waveform(&data, &metadata, VOLTS, ChannelScaling::default(), big).unwrap(), [1.0, -2.5]);
+        // Read as little-endian, the same bytes make no sense
+        assert!(matches!(parse_metadata(&data, VOLTS, LITTLE), Err(ScopeError::InvalidMetadata(_))));
+
+        // RAW: start 0 and length 2, vertical start -1.0 and step 2.0, codes
+        // 0x0100 and 0x8000
+        let mut raw = data[..12].to_vec();
+        raw.extend([0, 0, 0, 0, 0, 0, 0, 2, 0xbf, 0x80, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 2, 0x01, 0x00, 0x80, 0x00]);
+        let record = WaveformRecord::from_block(&raw, big).unwrap();
+        assert_eq!(record.raw_codes, [0x0100, 0x8000]);
+        assert_eq!(record.voltages().collect::<Vec<_>>(), [-1.0 + 2.0 / 256.0, 0.0]);
+    }
+
+    #[test]
+    fn detects_the_byte_order() {
+        assert_eq!(ByteOrderMode::parse("NORM"), Some(ByteOrderMode::BigEndian));
+        assert_eq!(ByteOrderMode::parse("swapped\n"), Some(ByteOrderMode::LittleEndian));
+        assert_eq!(ByteOrderMode::parse("LSBFirst"), Some(ByteOrderMode::LittleEndian));
+        assert_eq!(ByteOrderMode::parse("-113,\"Undefined header\""), None);
+
+        // The simulator has no FORMat:BORDer?, which must not time out
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        let simulator = SimulatedScope::new(SimulationConfig::default());
+        let mut scope = OscilloscopeWaveform::with_transport(Box::new(simulator));
+        scope.set_byte_order(ByteOrderMode::BigEndian);
+        let start = Instant::now();
+        assert_eq!(scope.detect_byte_order().unwrap(), ByteOrderMode::LittleEndian);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(scope.byte_order(), ByteOrderMode::LittleEndian);
+        assert!(scope.check_errors().unwrap().is_empty());
+        assert_eq!(scope.query("CHAN1:STATe?").unwrap(), "1");
+
+        let config = SimulationConfig { byte_order: Some("MSBF"), ..SimulationConfig::default() };
+        let mut scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        assert_eq!(scope.detect_byte_order().unwrap(), ByteOrderMode::BigEndian);
+        assert_eq!(scope.byte_order(), ByteOrderMode::BigEndian);
+        assert!(scope.check_errors().unwrap().is_empty());
+        assert_eq!(scope.query("CHAN1:STATe?").unwrap(), "1");
+    }
+
+    #[test]
+    fn ignores_an_unknown_byte_order() {
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        let config = SimulationConfig { byte_order: Some("\"NETWORK\""), ..SimulationConfig::default() };
+        let mut scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        scope.set_byte_order(ByteOrderMode::BigEndian);
+        assert_eq!(scope.detect_byte_order().unwrap(), ByteOrderMode::LittleEndian);
+        assert_eq!(scope.byte_order(), ByteOrderMode::LittleEndian);
+        // The error queue reply was read, not left for the next query
+        assert_eq!(scope.query("CHAN1:STATe?").unwrap(), "1");
+        assert!(scope.check_errors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn scales_raw_codes() {
+        let data = raw_block(&[0, 32768, 65535]);
+        let metadata = parse_metadata(&data, RAW, LITTLE).unwrap();
+        let waveform = extract_waveform(&data, &metadata, RAW, ChannelScaling::default(), LITTLE).unwrap();
+        assert_eq!(waveform.len(), 3);
+        assert_eq!(waveform[0], -1.0);
+        assert_eq!(waveform[1], 0.0);
+        assert!((waveform[2] - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reads_float_samples() {
+        let data = volts_block(&[0.25, -3.5, 12.0]);
+        let metadata = parse_metadata(&data, VOLTS, LITTLE).unwrap();
+        let waveform = extract_waveform(&data, &metadata, VOLTS, ChannelScaling::default(), LITTLE).unwrap();
+        assert_eq!(waveform, [0.25, -3.5, 12.0]);
+    }
+
+    #[test]
+    fn rejects_short_blocks() {
+        let data = raw_block(&[]);
+        assert!(matches!(
+            parse_metadata(&data[..31], RAW, LITTLE),
+            Err(ScopeError::MetadataTooShort { len: 31, needed: 32 })
+        ));
+        assert!(matches!(parse_metadata(&[0; 15], VOLTS, LITTLE), Err(ScopeError::MetadataTooShort { .. })));
+    }
+
+    #[test]
+    fn converts_codes_to_voltage() {
+        let metadata = parse_metadata(&raw_block(&[]), RAW, LITTLE).unwrap();
+        assert_eq!(code_to_voltage(0, &metadata), -1.0);
+        assert_eq!(code_to_voltage(16384, &metadata), -0.5);
+        assert_eq!(code_to_voltage(32768, &metadata), 0.0);
+        assert_eq!(code_to_voltage(65535, &metadata), 1.0 - 2.0 / 65536.0);
+    }
+
+    #[test]
+    fn keeps_raw_codes_in_record() {
+        let record = WaveformRecord::from_block(&raw_block(&[0, 32768, 65535]), LITTLE).unwrap();
+        assert_eq!(record.raw_codes, [0, 32768, 65535]);
+        let data = raw_block(&[0, 32768, 65535]);
+        let volts = extract_waveform(&data, &record.metadata, RAW, ChannelScaling::default(), LITTLE).unwrap();
+        assert_eq!(record.voltages().collect::<Vec<_>>(), volts);
+        assert_eq!(record.time_values().collect::<Vec<_>>(), [-5e-4, -5e-4 + 1e-6, -5e-4 + 2e-6]);
+    }
+
+    #[test]
+    fn slices_records_by_time() {
+        // Samples every 1 µs from -500 µs
+        let codes: Vec<u16> = (0..1000).collect();
+        let record = WaveformRecord::from_block(&raw_block(&codes), LITTLE).unwrap();
+        let slice = record.slice_time(-1e-6, 5e-6);
+        assert_eq!(slice.raw_codes, (499..=505).collect::<Vec<u16>>());
+        assert_eq!(slice.metadata.sample_count, 7);
+        assert!((slice.metadata.start_time + 1e-6).abs() < 1e-9);
+        assert!((slice.metadata.end_time - 5e-6).abs() < 1e-9);
+        assert_eq!(slice.time_values().len(), 7);
+
+        // Clamped to the capture, or empty outside of it
+        assert_eq!(record.slice_time(4.9e-4, 1.0).raw_codes, (990..1000).collect::<Vec<u16>>());
+        assert_eq!(record.slice_time(-1.0, -4.99e-4).raw_codes, [0, 1]);
+        assert!(record.slice_time(1.0, 2.0).raw_codes.is_empty());
+    }
+
+    #[test]
+    fn decimates_records() {
+        let record = WaveformRecord::from_block(&raw_block(&[10, 50, 30, 0, 20, 90, 40, 60, 5]), LITTLE).unwrap();
+
+        let nth = record.decimate(3, Decimation::NthSample);
+        assert_eq!(nth.raw_codes, [10, 0, 40]);
+        assert!((nth.metadata.time_delta - 3e-6).abs() < 1e-12);
+        assert_eq!(nth.metadata.start_time, record.metadata.start_time);
+
+        let mean = record.decimate(4, Decimation::Mean);
+        assert_eq!(mean.raw_codes, [23, 53]);
+        assert!((mean.metadata.start_time - (record.metadata.start_time + 1.5e-6)).abs() < 1e-9);
+
+        let min_max = record.decimate(3, Decimation::MinMax);
+        assert_eq!(min_max.raw_codes, [10, 50, 0, 90, 60, 5]);
+        assert!((min_max.metadata.time_delta - 1.5e-6).abs() < 1e-12);
+        assert_eq!(min_max.metadata.sample_count, 6);
+        assert_eq!(record.decimate(1, Decimation::MinMax), record);
+        assert_eq!("min-max".parse::<Decimation>(), Ok(Decimation::MinMax));
+    }
+
+    #[test]
+    fn reads_only_the_requested_time_window() {
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        // 5 ms over 10000 points, 0.5 us per sample from -2.5 ms
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        let (time, voltage) = scope.get_waveform_window(1, -10e-6, 10e-6, VOLTS).unwrap();
+        assert_eq!(voltage.len(), 41);
+        assert!((time[0] + 10e-6).abs() < 1e-9, "{}", time[0]);
+        assert!((time[40] - 10e-6).abs() < 1e-9, "{}", time[40]);
+        // The trigger is at the sine's zero crossing
+        assert!(voltage[20].abs() < 0.05, "{}", voltage[20]);
+
+        let (_, raw) = scope.get_waveform_window(2, -2.5e-3, -2.4e-3, RAW).unwrap();
+        assert_eq!(raw.len(), 201);
+
+        for (start, end) in [(10e-6, -10e-6), (0.0, 0.0), (-3e-3, 0.0), (0.0, 2.5e-3), (0.1e-6, 0.2e-6)] {
+            assert!(matches!(scope.get_waveform_window(1, start, end, VOLTS), Err(ScopeError::InvalidArgument(_))),
+                "{} to {}", start, end);
+        }
+    }
+
+    #[test]
+    fn references_time_to_the_trigger_or_the_record_start() {
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        let mut scope =
+            OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        let (time, _) = scope.get_waveform_data(1, DataRange::All, VOLTS, None).unwrap();
+        assert!((time[0] + 2.5e-3).abs() < 1e-9, "{}", time[0]);
+
+        scope.set_time_reference(TimeReference::Acquisition);
+        let (time, _) = scope.get_waveform_data(1, DataRange::All, VOLTS, None).unwrap();
+        assert_eq!(time[0], 0.0);
+        // A partial read keeps its place in the record
+        let range = DataRange::Samples { start: 100, count: 10 };
+        let (time, _) = scope.get_waveform_data(1, range, RAW, None).unwrap();
+        assert!((time[0] - 50e-6).abs() < 1e-9, "{}", time[0]);
+        let record = scope.get_waveform_record(1, DataRange::All, None).unwrap();
+        assert_eq!(record.metadata.start_time, 0.0);
+        assert!((record.metadata.end_time - 4.9995e-3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn counts_clipped_samples() {
+        let mut codes = vec![100; 50];
+        codes[10..20].fill(u16::MAX);
+        codes[30..38].fill(0);
+        // Too short to count
+        codes[40..43].fill(u16::MAX);
+        let record = WaveformRecord::from_block(&raw_block(&codes), LITTLE).unwrap();
+        assert_eq!(record.clipped_samples(), 18);
+        assert_eq!(WaveformRecord::from_block(&raw_block(&[100; 20]), LITTLE).unwrap().clipped_samples(), 0);
+    }
+
+    #[test]
+    fn converts_voltages_into_channel_unit() {
+        let data = raw_block(&[0, 32768]);
+        let metadata = parse_metadata(&data, RAW, LITTLE).unwrap();
+        let scaling = ChannelScaling { unit: ChannelUnit::Ampere, scale_factor: 0.1 };
+        assert_eq!(extract_waveform(&data, &metadata, RAW, scaling, LITTLE).unwrap(), [-10.0, 0.0]);
+    }
+
+    #[test]
+    fn record_and_capture_share_the_channel_unit() {
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        let config = SimulationConfig { noise_v: 0.0, memory_depth: 1_000, ..SimulationConfig::default() };
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(config)));
+        scope.set_channel_unit(1, ChannelUnit::Ampere, 0.1).unwrap();
+
+        let (_, amps) = scope.get_waveform_data(1, DataRange::All, RAW, None).unwrap();
+        let record = scope.get_waveform_record(1, DataRange::All, None).unwrap();
+        assert_eq!(record.scaling.unit, ChannelUnit::Ampere);
+        assert_eq!(record.voltages().collect::<Vec<_>>(), amps);
+        // The 1 V sine reads as 10 A
+        assert!(amps.iter().any(|&a| a > 9.0));
+        // Slices keep the unit
+        let slice = record.slice_time(record.metadata.start_time, 0.0);
+        assert_eq!(slice.voltages().next(), Some(amps[0]));
+    }
+
+    #[test]
+    fn decodes_only_the_valid_window() {
+        let codes = [1, 2, 32768, 32768, 32768, 3];
+        let data = raw_block_with_window(&codes, 2, 3);
+        let metadata = parse_metadata(&data, RAW, LITTLE).unwrap();
+        assert_eq!(extract_waveform(&data, &metadata, RAW, ChannelScaling::default(), LITTLE).unwrap(), [0.0; 3]);
+        assert_eq!(extract_waveform_raw(&data, LITTLE).unwrap(), [32768; 3]);
+        assert_eq!(WaveformRecord::from_block(&data, LITTLE).unwrap().time_values().len(), 3);
+
+        // A window reaching past the block or of zero length keeps all
+        for (start, length) in [(4, 3), (0, 0), (0, 6)] {
+            let data = raw_block_with_window(&codes, start, length);
+            assert_eq!(extract_waveform_raw(&data, LITTLE).unwrap(), codes, "window {}+{}", start, length);
+        }
+    }
+
+    #[test]
+    fn ignores_trailing_partial_sample() {
+        let mut data = raw_block(&[100, 200]);
+        data.push(0xFF);
+        let metadata = parse_metadata(&data, RAW, LITTLE).unwrap();
+        assert_eq!(extract_waveform(&data, &metadata, RAW, ChannelScaling::default(), LITTLE).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parses_data_ranges() {
+        assert_eq!("all".parse(), Ok(DataRange::All));
+        assert_eq!("Visible".parse(), Ok(DataRange::Visible));
+        assert_eq!("1000:500".parse(), Ok(DataRange::Samples { start: 1_000, count: 500 }));
+        for invalid in ["1000", "1000:0", "-1:5", "a:b", ""] {
+            assert!(invalid.parse::<DataRange>().is_err(), "{}", invalid);
+        }
+        assert_eq!(pack_query(2, DataRange::Samples { start: 10, count: 20 }, RAW), "CHAN2:DATa:PACK? 10,20, RAW");
+        assert_eq!(pack_query(1, DataRange::All, VOLTS), "CHAN1:DATa:PACK? ALL, V");
+    }
+
+    #[test]
+    fn resynchronizes_when_an_earlier_response_was_left_unread() {
+        use crate::simulator::{SimulatedScope, SimulationConfig};
+        let scope = OscilloscopeWaveform::with_transport(Box::new(SimulatedScope::new(SimulationConfig::default())));
+        scope.resynchronize().unwrap();
+
+        // The identity is still pending when the waveform block is expected
+        scope.send_command("*IDN?").unwrap();
+        let data = scope.read_block("CHAN1:DATa:PACK? ALL, RAW", &no_progress).unwrap();
+        assert!(WaveformRecord::from_block(&data, LITTLE).is_ok());
+        assert_eq!(scope.query("CHAN1:STATe?").unwrap(), "1");
+    }
+}